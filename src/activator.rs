@@ -0,0 +1,197 @@
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, uri::PathAndQuery, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use kube::api::{Api, Patch, PatchParams, ResourceExt};
+use kube::runtime::wait::await_condition;
+use kube::Resource;
+use serde_json::json;
+use tracing::*;
+
+use crate::{
+    controller::{
+        virtualmachine::{VirtualMachine, VirtualMachineCurrentState, VirtualMachineDesiredState},
+        virtualmachinetemplate,
+    },
+    errors::Error,
+    state::AppState,
+};
+
+/// Field manager of the state changes made by the activator
+static ACTIVATOR_FIELD_MANAGER: &str = "fink-activator";
+
+#[derive(Clone)]
+struct Activator {
+    state: AppState,
+    client: Client<HttpConnector, Body>,
+}
+
+// Serve the activator proxy when enabled, never returns otherwise
+pub async fn run(state: AppState) -> anyhow::Result<()> {
+    if !state.config.activator.enabled {
+        return std::future::pending().await;
+    }
+
+    let activator = Activator {
+        client: Client::builder(TokioExecutor::new()).build_http(),
+        state: state.clone(),
+    };
+    let app = Router::new().fallback(proxy).with_state(activator);
+    let listener = tokio::net::TcpListener::bind(&state.config.activator.bind)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to bind the activator to {}",
+                state.config.activator.bind
+            )
+        })?;
+    info!("activator listening on {}", listener.local_addr()?);
+    axum::serve(listener, app)
+        .await
+        .context("activator server failed")
+}
+
+// Start the VM the request is meant for, wait for it to boot and forward the request to it
+async fn proxy(State(activator): State<Activator>, mut request: Request) -> Response {
+    let Some(host) = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+    else {
+        return (StatusCode::BAD_REQUEST, "missing Host header").into_response();
+    };
+    let Some((namespace, name)) = resolve(&activator.state, host) else {
+        return (StatusCode::NOT_FOUND, "no VirtualMachine for this host").into_response();
+    };
+
+    let vms: Api<VirtualMachine> = Api::namespaced(activator.state.client.clone(), &namespace);
+    // Only VMs opting in, themselves or through their template, are started by requests
+    let vm = match vms.get_opt(&name).await.map_err(Error::KubeError) {
+        Ok(Some(vm)) => virtualmachinetemplate::resolve(vm, &activator.state.to_context())
+            .await
+            .map(Some),
+        other => other,
+    };
+    let vm = match vm {
+        Ok(Some(vm)) if vm.spec.networking.wake_on_request => vm,
+        Ok(_) => return (StatusCode::NOT_FOUND, "no VirtualMachine for this host").into_response(),
+        Err(e) => {
+            warn!("failed to get VirtualMachine {name} in {namespace}: {e:?}");
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+    let target = match wake(&activator.state, &vms, vm).await {
+        Ok(Some(target)) => target,
+        Ok(None) => {
+            let mut response = (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "VirtualMachine is starting",
+            )
+                .into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from_static("5"));
+            return response;
+        }
+        Err(e) => {
+            warn!("failed to wake VirtualMachine {name} in {namespace}: {e:?}");
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(PathAndQuery::as_str)
+        .unwrap_or("/");
+    let Ok(uri) = format!("http://{target}{path}").parse::<Uri>() else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    *request.uri_mut() = uri;
+
+    match activator.client.request(request).await {
+        Ok(response) => response.map(Body::new),
+        Err(e) => {
+            warn!("failed to proxy to VirtualMachine {name} in {namespace}: {e:?}");
+            StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+// Namespace and name of the VM behind `host`, matched against the hostname and Ingress host of
+// the cached VMs, or else taken from the `<name>.<namespace>` prefix of Service DNS names
+fn resolve(state: &AppState, host: &str) -> Option<(String, String)> {
+    let host = host.split(':').next()?;
+    let store = state.vm_store.read().unwrap().clone();
+    let matched = store.into_iter().flat_map(|s| s.state()).find(|vm| {
+        let networking = &vm.spec.networking;
+        networking.hostname.as_deref() == Some(host)
+            || networking.ingress.as_ref().is_some_and(|i| i.host == host)
+    });
+    if let Some(vm) = matched {
+        return Some((vm.namespace()?, vm.name_any()));
+    }
+
+    let mut labels = host.split('.');
+    let name = labels.next()?;
+    let namespace = labels.next()?;
+    Some((namespace.to_string(), name.to_string()))
+}
+
+// Request the VM to start and watch it until it runs, returning the address of its pod or None
+// once the wake timeout passes
+async fn wake(
+    state: &AppState,
+    vms: &Api<VirtualMachine>,
+    vm: VirtualMachine,
+) -> anyhow::Result<Option<String>> {
+    let name = &vm.name_any();
+    // The port is taken from the resolved spec, the watched VM only tells when its pod runs
+    let Some(port) = vm.activator_target_port() else {
+        anyhow::bail!("VirtualMachine {name} has no port to forward requests to");
+    };
+    if vm.spec.state != VirtualMachineDesiredState::STARTED {
+        info!("Waking VirtualMachine {} in {:?}", name, vm.namespace());
+        let patch = Patch::Apply(json!({
+            "apiVersion": VirtualMachine::api_version(&()),
+            "kind": VirtualMachine::kind(&()),
+            "spec": { "state": VirtualMachineDesiredState::STARTED },
+        }));
+        let _o = vms
+            .patch(
+                name,
+                &PatchParams::apply(ACTIVATOR_FIELD_MANAGER).force(),
+                &patch,
+            )
+            .await?;
+    }
+
+    let started = |vm: Option<&VirtualMachine>| vm.and_then(|vm| target(vm, port)).is_some();
+    let woken = tokio::time::timeout(
+        state.config.activator.wake_timeout(),
+        await_condition(vms.clone(), name, started),
+    )
+    .await;
+    match woken {
+        Ok(vm) => Ok(vm?.as_ref().and_then(|vm| target(vm, port))),
+        Err(_) => Ok(None),
+    }
+}
+
+// Address of the pod of the STARTED VM the requests are forwarded to on `port`
+fn target(vm: &VirtualMachine, port: i32) -> Option<String> {
+    let pod_ip = vm
+        .status
+        .as_ref()
+        .filter(|s| s.state == VirtualMachineCurrentState::STARTED)
+        .and_then(|s| s.pod_ip.clone())?;
+    Some(format!("{pod_ip}:{port}"))
+}
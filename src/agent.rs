@@ -0,0 +1,235 @@
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::{Api, AttachParams};
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+use crate::{
+    controller::virtualmachine::VM_CONTAINER_NAME, errors::Error, firecracker::VSOCK_UDS_PATH,
+    utils::Result,
+};
+
+// Time the agent gets to answer a request, on top of the runtime of an exec
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+/// Request sent to the guest agent, one JSON line per vsock connection. The agent answers with
+/// a single JSON line and closes the connection.
+#[derive(Serialize, Debug)]
+#[serde(tag = "method", content = "params", rename_all = "camelCase")]
+pub enum Request {
+    /// Heartbeat, answered with `null`
+    Ping,
+    /// Operating system of the guest, answered with [`OsInfo`]
+    Info,
+    /// Shut the guest down cleanly, answered with `null` before shutting down
+    Shutdown,
+    /// Run a command in the guest, answered with [`ExecOutput`]
+    Exec(ExecRequest),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecRequest {
+    pub command: Vec<String>,
+    /// Data written to the stdin of the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<String>,
+    /// Time after which the agent kills the command
+    pub timeout_secs: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct Response<T> {
+    result: Option<T>,
+    error: Option<String>,
+}
+
+/// Operating system of the guest as reported by its agent
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OsInfo {
+    /// Distribution name, e.g. `Ubuntu`
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub kernel_version: Option<String>,
+    pub hostname: Option<String>,
+    /// Version of the agent itself
+    pub agent_version: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecOutput {
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+// Send a request to the agent listening on `port` of the guest of a VM pod. The vsock socket only
+// exists inside the vm-container, so the request is relayed by socat executed there, following the
+// Firecracker handshake for host initiated connections: `CONNECT <port>` answered by `OK <port>`.
+pub async fn call<T: DeserializeOwned>(
+    pods: &Api<Pod>,
+    pod_name: &str,
+    port: u32,
+    request: &Request,
+) -> Result<T> {
+    let timeout = REQUEST_TIMEOUT_SECS
+        + match request {
+            Request::Exec(exec) => exec.timeout_secs,
+            _ => 0,
+        };
+    let command = vec![
+        "socat".to_string(),
+        format!("-t{timeout}"),
+        "-".to_string(),
+        format!("UNIX-CONNECT:{VSOCK_UDS_PATH}"),
+    ];
+    let params = AttachParams::default()
+        .container(VM_CONTAINER_NAME)
+        .stdin(true)
+        .stdout(true)
+        .stderr(true);
+    let body = serde_json::to_string(request).map_err(Error::SerializationError)?;
+
+    let exchange = async {
+        let mut process = pods
+            .exec(pod_name, command, &params)
+            .await
+            .map_err(Error::KubeError)?;
+        // The exec stream is closed once stdin is dropped, so it is kept until the answer is read.
+        // Only the ack and the answer are read, socat lingers after the agent closed the connection.
+        let mut stdin = process.stdin();
+        if let Some(stdin) = &mut stdin {
+            stdin
+                .write_all(format!("CONNECT {port}\n{body}\n").as_bytes())
+                .await
+                .map_err(|e| Error::GuestAgentError(format!("failed to send the request: {e}")))?;
+        }
+        let mut lines = vec![];
+        if let Some(stdout) = process.stdout() {
+            let mut stdout = BufReader::new(stdout).lines();
+            while lines.len() < 2 {
+                match stdout.next_line().await {
+                    Ok(Some(line)) => lines.push(line),
+                    _ => break,
+                }
+            }
+        }
+        drop(stdin);
+        let mut errors = String::new();
+        if lines.len() < 2 {
+            if let Some(mut stderr) = process.stderr() {
+                let _ = stderr.read_to_string(&mut errors).await;
+            }
+        }
+        Ok((lines, errors))
+    };
+    let (lines, errors) = tokio::time::timeout(Duration::from_secs(timeout + 5), exchange)
+        .await
+        .map_err(|_| Error::GuestAgentError(format!("no answer after {timeout}s")))??;
+
+    parse_response(&lines, &errors, port)
+}
+
+// Parse the ack of the Firecracker handshake and the answer of the agent
+fn parse_response<T: DeserializeOwned>(lines: &[String], errors: &str, port: u32) -> Result<T> {
+    match lines.first() {
+        Some(ack) if ack.starts_with("OK ") => {}
+        _ => {
+            return Err(Error::GuestAgentError(format!(
+                "failed to connect to vsock port {port}: {}",
+                errors.trim()
+            )))
+        }
+    }
+    let answer = lines.get(1).map(String::as_str).unwrap_or_default();
+    let response: Response<T> = serde_json::from_str(answer)
+        .map_err(|e| Error::GuestAgentError(format!("invalid response: {e}")))?;
+    match response {
+        Response {
+            error: Some(error), ..
+        } => Err(Error::GuestAgentError(error)),
+        Response {
+            result: Some(result),
+            ..
+        } => Ok(result),
+        // Requests answered with `null`
+        Response { result: None, .. } => serde_json::from_value(serde_json::Value::Null)
+            .map_err(|e| Error::GuestAgentError(format!("invalid response: {e}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn requests_are_tagged_by_method() {
+        let exec = Request::Exec(ExecRequest {
+            command: vec!["uname".to_string()],
+            stdin: None,
+            timeout_secs: 5,
+        });
+        assert_eq!(
+            serde_json::to_string(&exec).unwrap(),
+            r#"{"method":"exec","params":{"command":["uname"],"timeoutSecs":5}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Request::Ping).unwrap(),
+            r#"{"method":"ping"}"#
+        );
+    }
+
+    #[test]
+    fn parses_result() {
+        let output: ExecOutput = parse_response(
+            &lines(&[
+                "OK 1073741824",
+                r#"{"result":{"exitCode":1,"stdout":"out","stderr":"err"}}"#,
+            ]),
+            "",
+            52,
+        )
+        .unwrap();
+        assert_eq!(output.exit_code, 1);
+        assert_eq!(output.stdout, "out");
+        assert_eq!(output.stderr, "err");
+    }
+
+    #[test]
+    fn parses_null_result() {
+        parse_response::<()>(&lines(&["OK 1073741824", r#"{"result":null}"#]), "", 52).unwrap();
+    }
+
+    #[test]
+    fn reports_agent_error() {
+        let err = parse_response::<()>(
+            &lines(&["OK 1073741824", r#"{"error":"unknown method"}"#]),
+            "",
+            52,
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::GuestAgentError(e) if e == "unknown method"));
+    }
+
+    #[test]
+    fn reports_failed_handshake() {
+        let err = parse_response::<()>(&[], "Connection refused\n", 52).unwrap_err();
+        assert!(
+            matches!(err, Error::GuestAgentError(e) if e == "failed to connect to vsock port 52: Connection refused")
+        );
+    }
+
+    #[test]
+    fn reports_missing_answer() {
+        let err = parse_response::<OsInfo>(&lines(&["OK 1073741824"]), "", 52).unwrap_err();
+        assert!(matches!(err, Error::GuestAgentError(e) if e.starts_with("invalid response")));
+    }
+}
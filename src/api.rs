@@ -0,0 +1,1105 @@
+use std::{collections::BTreeSet, convert::Infallible, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension, Path, Query, Request, State,
+    },
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::{AsyncBufReadExt, SinkExt, StreamExt, TryStreamExt};
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec, UserInfo};
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec,
+};
+use k8s_openapi::api::core::v1::{ConfigMap, Event, Pod, Service};
+use kube::{
+    api::{
+        Api, AttachParams, ListParams, LogParams, Patch, PatchParams, Portforwarder, PostParams,
+    },
+    runtime::reflector::{ObjectRef, Store},
+    Client, Resource, ResourceExt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::*;
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    IntoParams, Modify, OpenApi, ToSchema,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::{
+    agent,
+    controller::{
+        virtualmachine::{
+            usage_records, VirtualMachine, VirtualMachineCurrentState, VirtualMachineDesiredState,
+            VirtualMachineSpec, VirtualMachineStatus, VirtualMachineUsage,
+            LAST_ACTIVITY_ANNOTATION, VM_CONTAINER_NAME,
+        },
+        virtualmachinetemplate,
+    },
+    state::AppState,
+    telemetry,
+};
+
+// Field manager of changes made through the HTTP API
+static API_FIELD_MANAGER: &str = "fink-api";
+
+// Access reviews run at once when checking the namespaces a user may list
+const AUTHORIZATION_CONCURRENCY: usize = 16;
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        list_vms,
+        get_vm,
+        vm_action,
+        vm_activity,
+        vm_logs,
+        vm_exec,
+        vm_port_forward,
+        usage
+    ),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
+    components(schemas(
+        VirtualMachineSummary,
+        VirtualMachineDetail,
+        PodSummary,
+        ServiceSummary,
+        EventSummary,
+        UsageReport,
+        VirtualMachineUsageSummary,
+        ExecParams,
+        ExecFrame
+    ))
+)]
+pub struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+// VM endpoints, only reachable with a bearer token the API server accepts
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/vms", get(list_vms))
+        .route("/vms/:namespace/:name", get(get_vm))
+        .route("/vms/:namespace/:name/logs", get(vm_logs))
+        .route("/vms/:namespace/:name/activity", post(vm_activity))
+        .route(
+            "/vms/:namespace/:name/exec",
+            get(vm_exec_socket).post(vm_exec),
+        )
+        .route(
+            "/vms/:namespace/:name/portforward/:port",
+            get(vm_port_forward),
+        )
+        .route("/vms/:namespace/:name/:action", post(vm_action))
+        .route("/usage", get(usage))
+        .route_layer(middleware::from_fn_with_state(state, authenticate))
+}
+
+// Validate the bearer token with a TokenReview and hand the user it belongs to to the handlers
+async fn authenticate(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let reviews: Api<TokenReview> = Api::all(state.client.clone());
+    let review = TokenReview {
+        spec: TokenReviewSpec {
+            token: Some(token.to_string()),
+            ..TokenReviewSpec::default()
+        },
+        ..TokenReview::default()
+    };
+    let status = reviews
+        .create(&PostParams::default(), &review)
+        .await
+        .map_err(internal_error)?
+        .status
+        .unwrap_or_default();
+    if status.authenticated != Some(true) {
+        debug!("Rejected token: {}", status.error.unwrap_or_default());
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    request
+        .extensions_mut()
+        .insert(status.user.unwrap_or_default());
+    Ok(next.run(request).await)
+}
+
+// Check with a SubjectAccessReview that the user may perform `verb` on the resource, so callers
+// only reach what their own RBAC allows
+async fn authorize(
+    state: &AppState,
+    user: &UserInfo,
+    verb: &str,
+    resource: &str,
+    subresource: Option<&str>,
+    namespace: Option<&str>,
+    name: Option<&str>,
+) -> Result<(), StatusCode> {
+    let group = match resource {
+        "virtualmachines" => VirtualMachine::group(&()).to_string(),
+        _ => String::new(),
+    };
+    let review = SubjectAccessReview {
+        spec: SubjectAccessReviewSpec {
+            user: user.username.clone(),
+            uid: user.uid.clone(),
+            groups: user.groups.clone(),
+            extra: user.extra.clone(),
+            resource_attributes: Some(ResourceAttributes {
+                verb: Some(verb.to_string()),
+                group: Some(group),
+                resource: Some(resource.to_string()),
+                subresource: subresource.map(str::to_string),
+                namespace: namespace.map(str::to_string),
+                name: name.map(str::to_string),
+                ..ResourceAttributes::default()
+            }),
+            ..SubjectAccessReviewSpec::default()
+        },
+        ..SubjectAccessReview::default()
+    };
+
+    let reviews: Api<SubjectAccessReview> = Api::all(state.client.clone());
+    let allowed = reviews
+        .create(&PostParams::default(), &review)
+        .await
+        .map_err(internal_error)?
+        .status
+        .is_some_and(|s| s.allowed);
+    if !allowed {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+// Swagger UI at /api-docs serving the OpenAPI document of the HTTP API
+pub fn docs() -> SwaggerUi {
+    SwaggerUi::new("/api-docs").url("/api-docs/openapi.json", ApiDoc::openapi())
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineSummary {
+    pub name: String,
+    pub namespace: String,
+    #[schema(value_type = String, example = "STARTED")]
+    pub desired_state: VirtualMachineDesiredState,
+    #[schema(value_type = Option<String>, example = "STARTING")]
+    pub current_state: Option<VirtualMachineCurrentState>,
+}
+
+// List the VirtualMachines from the controller cache, unavailable until the controller has started
+#[utoipa::path(
+    get,
+    path = "/vms",
+    responses(
+        (status = 200, body = [VirtualMachineSummary]),
+        (status = 503, description = "The controller cache is not available yet")
+    )
+)]
+pub async fn list_vms(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserInfo>,
+) -> Result<Json<Vec<VirtualMachineSummary>>, StatusCode> {
+    let store = state.vms().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let allowed = listable_namespaces(&state, &user, &store).await?;
+    let mut vms: Vec<VirtualMachineSummary> = store
+        .state()
+        .iter()
+        .filter(|vm| {
+            allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(&vm.namespace().unwrap_or_default()))
+        })
+        .map(|vm| VirtualMachineSummary {
+            name: vm.name_any(),
+            namespace: vm.namespace().unwrap_or_default(),
+            desired_state: vm.spec.state.clone(),
+            current_state: vm.status.as_ref().map(|s| s.state.clone()),
+        })
+        .collect();
+    vms.sort_by(|a, b| (&a.namespace, &a.name).cmp(&(&b.namespace, &b.name)));
+    Ok(Json(vms))
+}
+
+// Namespaces the user may list VMs in, None for all of them. A user who may not list VMs
+// cluster-wide only sees the namespaces they may list, out of the namespace allow-list or, without
+// one, the namespaces of the VMs in the cache.
+async fn listable_namespaces(
+    state: &AppState,
+    user: &UserInfo,
+    vms: &Store<VirtualMachine>,
+) -> Result<Option<Vec<String>>, StatusCode> {
+    let candidates: BTreeSet<String> = if state.namespaces.is_empty() {
+        match authorize(state, user, "list", "virtualmachines", None, None, None).await {
+            Ok(()) => return Ok(None),
+            Err(StatusCode::FORBIDDEN) => {}
+            Err(status) => return Err(status),
+        }
+        vms.state().iter().filter_map(|vm| vm.namespace()).collect()
+    } else {
+        state.namespaces.iter().cloned().collect()
+    };
+
+    let checks = futures::stream::iter(candidates)
+        .map(|namespace| async move {
+            let check = authorize(
+                state,
+                user,
+                "list",
+                "virtualmachines",
+                None,
+                Some(&namespace),
+                None,
+            )
+            .await;
+            (namespace, check)
+        })
+        .buffer_unordered(AUTHORIZATION_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+    let mut allowed = Vec::new();
+    for (namespace, check) in checks {
+        match check {
+            Ok(()) => allowed.push(namespace),
+            Err(StatusCode::FORBIDDEN) => {}
+            Err(status) => return Err(status),
+        }
+    }
+    if allowed.is_empty() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(Some(allowed))
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineDetail {
+    pub name: String,
+    pub namespace: String,
+    /// Spec of the VirtualMachine resource, see its CRD
+    #[schema(value_type = Object)]
+    pub spec: VirtualMachineSpec,
+    /// Status of the VirtualMachine resource, see its CRD
+    #[schema(value_type = Option<Object>)]
+    pub status: Option<VirtualMachineStatus>,
+    pub pod: Option<PodSummary>,
+    pub service: Option<ServiceSummary>,
+    pub events: Vec<EventSummary>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PodSummary {
+    pub name: String,
+    pub phase: Option<String>,
+    #[serde(rename = "podIP")]
+    pub pod_ip: Option<String>,
+    pub node_name: Option<String>,
+    pub ready: bool,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceSummary {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    #[serde(rename = "clusterIP")]
+    pub cluster_ip: Option<String>,
+    pub ports: Vec<i32>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EventSummary {
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub time: Option<DateTime<Utc>>,
+}
+
+// Number of most recent events returned with a VM
+static RECENT_EVENTS: usize = 20;
+
+// Show a VM from the controller cache along with its pod, service and recent events
+#[utoipa::path(
+    get,
+    path = "/vms/{namespace}/{name}",
+    params(("namespace" = String, Path, description = "Namespace of the VirtualMachine"), ("name" = String, Path, description = "Name of the VirtualMachine")),
+    responses(
+        (status = 200, body = VirtualMachineDetail),
+        (status = 404, description = "The VirtualMachine does not exist")
+    )
+)]
+pub async fn get_vm(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserInfo>,
+    Path((namespace, name)): Path<(String, String)>,
+) -> Result<Json<VirtualMachineDetail>, StatusCode> {
+    authorize(
+        &state,
+        &user,
+        "get",
+        "virtualmachines",
+        None,
+        Some(&namespace),
+        Some(&name),
+    )
+    .await?;
+    let store = state.vms().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let vm = store
+        .get(&ObjectRef::new(&name).within(&namespace))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let pods: Api<Pod> = Api::namespaced(state.client.clone(), &namespace);
+    let pod = pods.get_opt(&name).await.map_err(internal_error)?;
+    let services: Api<Service> = Api::namespaced(state.client.clone(), &namespace);
+    let service = services.get_opt(&name).await.map_err(internal_error)?;
+
+    let events: Api<Event> = Api::namespaced(state.client.clone(), &namespace);
+    let selector = format!(
+        "involvedObject.kind=VirtualMachine,involvedObject.uid={}",
+        vm.uid().unwrap_or_default()
+    );
+    let mut events: Vec<EventSummary> = events
+        .list(&ListParams::default().fields(&selector))
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|e| EventSummary {
+            type_: e.type_,
+            reason: e.reason,
+            message: e.message,
+            time: e.last_timestamp.map(|t| t.0).or(e.event_time.map(|t| t.0)),
+        })
+        .collect();
+    events.sort_by_key(|e| e.time);
+    let events = events.split_off(events.len().saturating_sub(RECENT_EVENTS));
+
+    Ok(Json(VirtualMachineDetail {
+        name: vm.name_any(),
+        namespace: vm.namespace().unwrap_or_default(),
+        spec: vm.spec.clone(),
+        status: vm.status.clone(),
+        pod: pod.map(|p| PodSummary {
+            name: p.name_any(),
+            phase: p.status.as_ref().and_then(|s| s.phase.clone()),
+            pod_ip: p.status.as_ref().and_then(|s| s.pod_ip.clone()),
+            node_name: p.spec.as_ref().and_then(|s| s.node_name.clone()),
+            ready: p
+                .status
+                .and_then(|s| s.conditions)
+                .unwrap_or_default()
+                .iter()
+                .any(|c| c.type_ == "Ready" && c.status == "True"),
+        }),
+        service: service.map(|s| ServiceSummary {
+            name: s.name_any(),
+            type_: s.spec.as_ref().and_then(|s| s.type_.clone()),
+            cluster_ip: s.spec.as_ref().and_then(|s| s.cluster_ip.clone()),
+            ports: s
+                .spec
+                .and_then(|s| s.ports)
+                .unwrap_or_default()
+                .iter()
+                .map(|p| p.port)
+                .collect(),
+        }),
+        events,
+    }))
+}
+
+fn internal_error(e: impl std::fmt::Display) -> StatusCode {
+    warn!("API request failed: {}", e);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+// Drive the VM lifecycle by applying spec.state, forcing ownership of the field away from
+// whoever applied the manifest
+#[utoipa::path(
+    post,
+    path = "/vms/{namespace}/{name}/{action}",
+    params(
+        ("namespace" = String, Path, description = "Namespace of the VirtualMachine"),
+        ("name" = String, Path, description = "Name of the VirtualMachine"),
+        ("action" = String, Path, description = "One of start, stop or hibernate")
+    ),
+    responses(
+        (status = 200, body = VirtualMachineSummary),
+        (status = 404, description = "The VirtualMachine or action does not exist")
+    )
+)]
+#[instrument(skip_all, fields(trace_id))]
+pub async fn vm_action(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserInfo>,
+    Path((namespace, name, action)): Path<(String, String, String)>,
+) -> Result<Json<VirtualMachineSummary>, StatusCode> {
+    telemetry::record_trace_id();
+    let desired_state = match action.as_str() {
+        "start" => VirtualMachineDesiredState::STARTED,
+        "stop" => VirtualMachineDesiredState::STOPPED,
+        "hibernate" => VirtualMachineDesiredState::HIBERNATED,
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+    authorize(
+        &state,
+        &user,
+        "patch",
+        "virtualmachines",
+        None,
+        Some(&namespace),
+        Some(&name),
+    )
+    .await?;
+
+    let vms: Api<VirtualMachine> = Api::namespaced(state.client.clone(), &namespace);
+    // An apply creates missing objects, which would leave a VM with nothing but a state
+    if vms.get_opt(&name).await.map_err(internal_error)?.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let patch = Patch::Apply(json!({
+        "apiVersion": VirtualMachine::api_version(&()),
+        "kind": VirtualMachine::kind(&()),
+        "spec": { "state": desired_state },
+    }));
+    let vm = vms
+        .patch(
+            &name,
+            &PatchParams::apply(API_FIELD_MANAGER).force(),
+            &patch,
+        )
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(VirtualMachineSummary {
+        name: vm.name_any(),
+        namespace: vm.namespace().unwrap_or_default(),
+        desired_state: vm.spec.state.clone(),
+        current_state: vm.status.as_ref().map(|s| s.state.clone()),
+    }))
+}
+
+// Mark the VM as in use now, postponing its idle timeout
+#[utoipa::path(
+    post,
+    path = "/vms/{namespace}/{name}/activity",
+    params(
+        ("namespace" = String, Path, description = "Namespace of the VirtualMachine"),
+        ("name" = String, Path, description = "Name of the VirtualMachine")
+    ),
+    responses(
+        (status = 204, description = "The activity was recorded"),
+        (status = 404, description = "The VirtualMachine does not exist")
+    )
+)]
+#[instrument(skip_all, fields(trace_id))]
+pub async fn vm_activity(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserInfo>,
+    Path((namespace, name)): Path<(String, String)>,
+) -> Result<StatusCode, StatusCode> {
+    telemetry::record_trace_id();
+    authorize(
+        &state,
+        &user,
+        "patch",
+        "virtualmachines",
+        None,
+        Some(&namespace),
+        Some(&name),
+    )
+    .await?;
+
+    let vms: Api<VirtualMachine> = Api::namespaced(state.client.clone(), &namespace);
+    let patch = Patch::Merge(json!({
+        "metadata": { "annotations": { LAST_ACTIVITY_ANNOTATION: Utc::now().to_rfc3339() } }
+    }));
+    match vms.patch(&name, &PatchParams::default(), &patch).await {
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(kube::Error::Api(e)) if e.code == 404 => Err(StatusCode::NOT_FOUND),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[serde(rename_all = "camelCase")]
+pub struct LogsQuery {
+    #[serde(default)]
+    pub follow: bool,
+    pub tail_lines: Option<i64>,
+}
+
+// Stream the logs of the VM container, following them while the pod runs when requested
+#[utoipa::path(
+    get,
+    path = "/vms/{namespace}/{name}/logs",
+    params(("namespace" = String, Path, description = "Namespace of the VirtualMachine"), ("name" = String, Path, description = "Name of the VirtualMachine"), LogsQuery),
+    responses(
+        (status = 200, description = "Logs of the VM container", content_type = "text/plain"),
+        (status = 404, description = "The VM pod does not exist")
+    )
+)]
+pub async fn vm_logs(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserInfo>,
+    Path((namespace, name)): Path<(String, String)>,
+    Query(query): Query<LogsQuery>,
+) -> Result<Response, StatusCode> {
+    authorize(
+        &state,
+        &user,
+        "get",
+        "pods",
+        Some("log"),
+        Some(&namespace),
+        Some(&name),
+    )
+    .await?;
+    let pods: Api<Pod> = Api::namespaced(state.client.clone(), &namespace);
+    if pods.get_opt(&name).await.map_err(internal_error)?.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let params = LogParams {
+        container: Some(VM_CONTAINER_NAME.to_string()),
+        follow: query.follow,
+        tail_lines: query.tail_lines,
+        ..LogParams::default()
+    };
+    let lines = pods
+        .log_stream(&name, &params)
+        .await
+        .map_err(internal_error)?
+        .lines()
+        .map_ok(|line| line + "\n");
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        Body::from_stream(lines),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageQuery {
+    /// Only report the VMs of this namespace
+    pub namespace: Option<String>,
+    /// First UTC day of the report, within the last 90 days
+    #[param(value_type = Option<String>, format = Date)]
+    pub from: Option<NaiveDate>,
+    /// Last UTC day of the report, included
+    #[param(value_type = Option<String>, format = Date)]
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    #[param(inline)]
+    pub format: UsageFormat,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageReport {
+    #[schema(value_type = Option<String>, format = Date)]
+    pub from: Option<NaiveDate>,
+    #[schema(value_type = Option<String>, format = Date)]
+    pub to: Option<NaiveDate>,
+    pub vm_hours: f64,
+    pub boots: u64,
+    pub vms: Vec<VirtualMachineUsageSummary>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineUsageSummary {
+    pub namespace: String,
+    pub name: String,
+    pub vm_hours: f64,
+    pub boots: u64,
+    /// Whether the VM was deleted, its usage is kept for 90 days
+    pub deleted: bool,
+}
+
+// Report the metered VM-hours and boots of the VMs in the controller cache, up to their last
+// reconcile, and of the VMs deleted within the last 90 days. Without `from` and `to` the whole
+// lifetime of the VMs is reported, otherwise the days of the range.
+#[utoipa::path(
+    get,
+    path = "/usage",
+    params(UsageQuery),
+    responses(
+        (status = 200, body = UsageReport),
+        (status = 200, description = "Usage per VM as CSV with format=csv", content_type = "text/csv"),
+        (status = 503, description = "The controller cache is not available yet")
+    )
+)]
+pub async fn usage(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserInfo>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Response, StatusCode> {
+    let store = state.vms().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let allowed = match &query.namespace {
+        Some(namespace) => {
+            if !state.namespaces.is_empty() && !state.namespaces.contains(namespace) {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            authorize(
+                &state,
+                &user,
+                "list",
+                "virtualmachines",
+                None,
+                Some(namespace),
+                None,
+            )
+            .await?;
+            Some(vec![namespace.clone()])
+        }
+        None => listable_namespaces(&state, &user, &store).await?,
+    };
+    let mut vms: Vec<VirtualMachineUsageSummary> = store
+        .state()
+        .iter()
+        .filter(|vm| {
+            allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(&vm.namespace().unwrap_or_default()))
+        })
+        .map(|vm| {
+            let usage = vm.status.as_ref().map(|s| &s.usage);
+            let (seconds, boots) = usage.map_or((0, 0), |usage| usage_between(usage, &query));
+            VirtualMachineUsageSummary {
+                namespace: vm.namespace().unwrap_or_default(),
+                name: vm.name_any(),
+                vm_hours: seconds as f64 / 3600.,
+                boots,
+                deleted: false,
+            }
+        })
+        .collect();
+
+    let record_apis: Vec<Api<ConfigMap>> = match &allowed {
+        Some(allowed) => allowed
+            .iter()
+            .map(|ns| Api::namespaced(state.client.clone(), ns))
+            .collect(),
+        None => vec![Api::all(state.client.clone())],
+    };
+    for configmaps in &record_apis {
+        let records = usage_records(configmaps).await.map_err(internal_error)?;
+        vms.extend(records.into_iter().map(|record| {
+            let (seconds, boots) = usage_between(&record.usage, &query);
+            VirtualMachineUsageSummary {
+                namespace: record.namespace,
+                name: record.vm_name,
+                vm_hours: seconds as f64 / 3600.,
+                boots,
+                deleted: true,
+            }
+        }));
+    }
+    vms.sort_by(|a, b| (&a.namespace, &a.name).cmp(&(&b.namespace, &b.name)));
+
+    if let UsageFormat::Csv = query.format {
+        let mut csv = String::from("namespace,name,vmHours,boots,deleted\n");
+        for vm in &vms {
+            csv += &format!(
+                "{},{},{},{},{}\n",
+                vm.namespace, vm.name, vm.vm_hours, vm.boots, vm.deleted
+            );
+        }
+        return Ok(([(header::CONTENT_TYPE, "text/csv; charset=utf-8")], csv).into_response());
+    }
+    Ok(Json(UsageReport {
+        from: query.from,
+        to: query.to,
+        vm_hours: vms.iter().map(|vm| vm.vm_hours).sum(),
+        boots: vms.iter().map(|vm| vm.boots).sum(),
+        vms,
+    })
+    .into_response())
+}
+
+// Running seconds and boots of `usage` over the range of the query, or its whole lifetime
+fn usage_between(usage: &VirtualMachineUsage, query: &UsageQuery) -> (u64, u64) {
+    match (query.from, query.to) {
+        (None, None) => (usage.running_seconds, usage.boots),
+        (from, to) => usage.between(from, to),
+    }
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecParams {
+    pub command: Vec<String>,
+    /// Data written to the stdin of the command
+    pub stdin: Option<String>,
+    /// Time after which the command is killed
+    #[serde(default = "default_exec_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_exec_timeout_secs() -> u64 {
+    60
+}
+
+/// Output of a command, sent as it comes and ended by `exit` or `error`
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum ExecFrame {
+    Stdout {
+        data: String,
+    },
+    Stderr {
+        data: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Exit {
+        exit_code: i32,
+    },
+    Error {
+        message: String,
+    },
+}
+
+// Run a command inside the guest through its agent, or in the vm-container of a VM without one.
+// The output is streamed back as newline delimited JSON frames.
+#[utoipa::path(
+    post,
+    path = "/vms/{namespace}/{name}/exec",
+    params(
+        ("namespace" = String, Path, description = "Namespace of the VirtualMachine"),
+        ("name" = String, Path, description = "Name of the VirtualMachine")
+    ),
+    request_body = ExecParams,
+    responses(
+        (status = 200, description = "Output of the command as newline delimited ExecFrame objects", content_type = "application/x-ndjson"),
+        (status = 404, description = "The VirtualMachine does not exist"),
+        (status = 409, description = "The VirtualMachine is not STARTED")
+    )
+)]
+#[instrument(skip_all, fields(trace_id))]
+pub async fn vm_exec(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserInfo>,
+    Path((namespace, name)): Path<(String, String)>,
+    Json(params): Json<ExecParams>,
+) -> Result<Response, StatusCode> {
+    telemetry::record_trace_id();
+    let vm = started_vm(&state, &user, "exec", &namespace, &name).await?;
+    let (frames, receiver) = mpsc::channel(16);
+    tokio::spawn(run_exec(state.client.clone(), vm, params, frames));
+    let lines = futures::stream::unfold(receiver, |mut receiver| async move {
+        let frame = receiver.recv().await?;
+        let line = serde_json::to_string(&frame).unwrap_or_default() + "\n";
+        Some((Ok::<_, Infallible>(line), receiver))
+    });
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response())
+}
+
+// Same as vm_exec over a WebSocket, the first message holds the ExecParams and the frames are sent
+// back as text messages
+pub async fn vm_exec_socket(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserInfo>,
+    Path((namespace, name)): Path<(String, String)>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    let vm = started_vm(&state, &user, "exec", &namespace, &name).await?;
+    Ok(upgrade.on_upgrade(move |socket| exec_socket(socket, state.client.clone(), vm)))
+}
+
+async fn exec_socket(mut socket: WebSocket, client: Client, vm: VirtualMachine) {
+    let params = match socket.recv().await {
+        Some(Ok(Message::Text(text))) => {
+            serde_json::from_str::<ExecParams>(&text).map_err(|e| e.to_string())
+        }
+        _ => Err("the first message must hold the command as JSON text".to_string()),
+    };
+    let (frames, mut receiver) = mpsc::channel(16);
+    match params {
+        Ok(params) => {
+            tokio::spawn(run_exec(client, vm, params, frames));
+        }
+        Err(message) => {
+            let _ = frames.send(ExecFrame::Error { message }).await;
+        }
+    }
+    while let Some(frame) = receiver.recv().await {
+        let text = serde_json::to_string(&frame).unwrap_or_default();
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+    }
+    let _ = socket.close().await;
+}
+
+// The STARTED VM a command may be run in or a port forwarded to, with its template merged in.
+// This requires the same access as `kubectl exec` or `kubectl port-forward` into the VM pod, the
+// `subresource` of the pod.
+async fn started_vm(
+    state: &AppState,
+    user: &UserInfo,
+    subresource: &str,
+    namespace: &str,
+    name: &str,
+) -> Result<VirtualMachine, StatusCode> {
+    authorize(
+        state,
+        user,
+        "create",
+        "pods",
+        Some(subresource),
+        Some(namespace),
+        Some(name),
+    )
+    .await?;
+    let store = state.vms().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let vm = store
+        .get(&ObjectRef::new(name).within(namespace))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if vm.status.as_ref().map(|s| &s.state) != Some(&VirtualMachineCurrentState::STARTED) {
+        return Err(StatusCode::CONFLICT);
+    }
+    virtualmachinetemplate::resolve((*vm).clone(), &state.to_context())
+        .await
+        .map_err(internal_error)
+}
+
+// Run the command and send its output to `frames`, ending with its exit code or the error
+async fn run_exec(
+    client: Client,
+    vm: VirtualMachine,
+    params: ExecParams,
+    frames: mpsc::Sender<ExecFrame>,
+) {
+    let result = match &vm.spec.guest_agent {
+        Some(_) => {
+            let request = agent::ExecRequest {
+                command: params.command,
+                stdin: params.stdin,
+                timeout_secs: params.timeout_secs,
+            };
+            match vm.guest_exec(client, request).await {
+                Ok(output) => {
+                    for frame in [
+                        ExecFrame::Stdout {
+                            data: output.stdout,
+                        },
+                        ExecFrame::Stderr {
+                            data: output.stderr,
+                        },
+                    ] {
+                        let _ = frames.send(frame).await;
+                    }
+                    Ok(output.exit_code)
+                }
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        None => {
+            let timeout = Duration::from_secs(params.timeout_secs);
+            tokio::time::timeout(timeout, pod_exec(client, &vm, &params, &frames))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(format!(
+                        "the command did not exit within {}s",
+                        params.timeout_secs
+                    ))
+                })
+        }
+    };
+    let frame = match result {
+        Ok(exit_code) => ExecFrame::Exit { exit_code },
+        Err(message) => ExecFrame::Error { message },
+    };
+    let _ = frames.send(frame).await;
+}
+
+// Run the command in the vm-container, forwarding its output as it is written
+async fn pod_exec(
+    client: Client,
+    vm: &VirtualMachine,
+    params: &ExecParams,
+    frames: &mpsc::Sender<ExecFrame>,
+) -> Result<i32, String> {
+    let pods: Api<Pod> = Api::namespaced(client, &vm.namespace().unwrap_or_default());
+    let attach = AttachParams::default()
+        .container(VM_CONTAINER_NAME)
+        .stdin(params.stdin.is_some())
+        .stdout(true)
+        .stderr(true);
+    let mut process = pods
+        .exec(&vm.name_any(), params.command.clone(), &attach)
+        .await
+        .map_err(|e| e.to_string())?;
+    // The exec stream is closed once stdin is dropped, so it is kept until the output is forwarded
+    let mut stdin = process.stdin();
+    if let (Some(stdin), Some(data)) = (&mut stdin, &params.stdin) {
+        let _ = stdin.write_all(data.as_bytes()).await;
+    }
+    tokio::join!(
+        forward_output(process.stdout(), frames, |data| ExecFrame::Stdout { data }),
+        forward_output(process.stderr(), frames, |data| ExecFrame::Stderr { data }),
+    );
+    drop(stdin);
+
+    let status = match process.take_status() {
+        Some(status) => status.await,
+        None => None,
+    };
+    // A non-zero exit code is reported as a failure with the code as the cause
+    let exit_code = match status {
+        Some(status) if status.status.as_deref() == Some("Success") => 0,
+        Some(status) => status
+            .details
+            .and_then(|d| d.causes)
+            .into_iter()
+            .flatten()
+            .find(|c| c.reason.as_deref() == Some("ExitCode"))
+            .and_then(|c| c.message?.parse().ok())
+            .ok_or_else(|| status.message.unwrap_or_default())?,
+        None => return Err("the exec stream closed without an exit status".to_string()),
+    };
+    Ok(exit_code)
+}
+
+async fn forward_output(
+    output: Option<impl AsyncRead + Unpin>,
+    frames: &mpsc::Sender<ExecFrame>,
+    frame: impl Fn(String) -> ExecFrame,
+) {
+    let Some(mut output) = output else {
+        return;
+    };
+    let mut buf = vec![0; 8192];
+    while let Ok(n @ 1..) = output.read(&mut buf).await {
+        let data = String::from_utf8_lossy(&buf[..n]).into_owned();
+        if frames.send(frame(data)).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Tunnel a connection to a port of the VM pod, which the runtime forwards to the guest, so ports
+// that are not exposed can be reached. The bytes of the connection are carried by binary WebSocket
+// messages, e.g. with `websocat --binary`.
+#[utoipa::path(
+    get,
+    path = "/vms/{namespace}/{name}/portforward/{port}",
+    params(
+        ("namespace" = String, Path, description = "Namespace of the VirtualMachine"),
+        ("name" = String, Path, description = "Name of the VirtualMachine"),
+        ("port" = u16, Path, description = "Port inside the VM")
+    ),
+    responses(
+        (status = 101, description = "The WebSocket carrying the connection"),
+        (status = 404, description = "The VirtualMachine does not exist"),
+        (status = 409, description = "The VirtualMachine is not STARTED")
+    )
+)]
+pub async fn vm_port_forward(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserInfo>,
+    Path((namespace, name, port)): Path<(String, String, u16)>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    let vm = started_vm(&state, &user, "portforward", &namespace, &name).await?;
+    let pods: Api<Pod> = Api::namespaced(state.client.clone(), &namespace);
+    let forwarder = pods
+        .portforward(&vm.name_any(), &[port])
+        .await
+        .map_err(internal_error)?;
+    Ok(upgrade.on_upgrade(move |socket| forward_socket(socket, forwarder, port)))
+}
+
+async fn forward_socket(socket: WebSocket, mut forwarder: Portforwarder, port: u16) {
+    let Some(stream) = forwarder.take_stream(port) else {
+        return;
+    };
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (mut sink, mut source) = socket.split();
+
+    let upstream = async {
+        while let Some(Ok(message)) = source.next().await {
+            let data = match message {
+                Message::Binary(data) => data,
+                Message::Text(text) => text.into_bytes(),
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            if writer.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    };
+    let downstream = async {
+        let mut buf = vec![0; 16384];
+        while let Ok(n @ 1..) = reader.read(&mut buf).await {
+            if sink.send(Message::Binary(buf[..n].to_vec())).await.is_err() {
+                return;
+            }
+        }
+        let _ = sink.close().await;
+    };
+    // Either side closing ends the tunnel
+    tokio::select! {
+        _ = upstream => {}
+        _ = downstream => {}
+    }
+    forwarder.abort();
+    if let Some(error) = forwarder.take_error(port) {
+        if let Some(error) = error.await {
+            debug!("port forward to {port} failed: {error}");
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::*;
+
+use crate::config::CloudEventsConfig;
+
+/// A CloudEvent in the structured JSON format
+#[derive(Serialize, Debug)]
+pub struct CloudEvent {
+    pub specversion: &'static str,
+    pub id: String,
+    pub source: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub subject: Option<String>,
+    pub time: DateTime<Utc>,
+    pub datacontenttype: &'static str,
+    pub data: Value,
+}
+
+// Sends CloudEvents to the sink of the config
+#[derive(Clone)]
+pub struct CloudEvents {
+    client: reqwest::Client,
+    sink: String,
+    source: String,
+}
+
+impl CloudEvents {
+    // None without a sink
+    pub fn new(config: &CloudEventsConfig) -> Option<Self> {
+        let sink = config.sink.clone()?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()
+            .expect("failed to create the CloudEvents client");
+        Some(Self {
+            client,
+            sink,
+            source: config.source.clone(),
+        })
+    }
+
+    // Event of `type_` about `subject` coming from this controller
+    pub fn event(&self, type_: &str, subject: Option<String>, data: Value) -> CloudEvent {
+        CloudEvent {
+            specversion: "1.0",
+            id: uuid::Uuid::new_v4().to_string(),
+            source: self.source.clone(),
+            type_: type_.to_string(),
+            subject,
+            time: Utc::now(),
+            datacontenttype: "application/json",
+            data,
+        }
+    }
+
+    // POST the event in the background, a sink that is down or refuses it only costs a warning
+    pub fn send(&self, event: CloudEvent) {
+        let request = self
+            .client
+            .post(&self.sink)
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                "application/cloudevents+json",
+            )
+            .json(&event);
+        tokio::spawn(async move {
+            match request.send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => debug!("sent CloudEvent {} {}", event.type_, event.id),
+                Err(e) => warn!("failed to send CloudEvent {}: {e}", event.type_),
+            }
+        });
+    }
+}
@@ -0,0 +1,480 @@
+use std::{collections::BTreeMap, path::Path, str::FromStr, time::Duration};
+
+use anyhow::Context as _;
+use serde::Deserialize;
+
+use crate::{controller::virtualmachine::PodPolicy, notifications::NotificationEvent};
+
+/// Controller settings, read from a YAML or TOML file and overridden by FINK_* env vars
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Config {
+    pub controller: ControllerConfig,
+    /// Policy applied to every VM pod
+    pub pod: PodPolicy,
+    pub features: FeatureFlags,
+    pub http: HttpConfig,
+    pub leader_election: LeaderElectionConfig,
+    pub activator: ActivatorConfig,
+    pub webhook: WebhookConfig,
+    pub telemetry: TelemetryConfig,
+    pub cloud_events: CloudEventsConfig,
+    pub notifications: NotificationsConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct NotificationsConfig {
+    /// Webhooks called when a VM fails or crash-loops, e.g. PagerDuty or Opsgenie integrations
+    pub webhooks: Vec<NotificationWebhook>,
+    /// Time to wait for a webhook to accept a notification
+    pub timeout_secs: u64,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        Self {
+            webhooks: vec![],
+            timeout_secs: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationWebhook {
+    pub url: String,
+    /// Key the payload is signed with, sent unsigned when unset
+    pub secret: Option<String>,
+    /// Events the webhook is called for, all of them by default
+    #[serde(default = "NotificationEvent::all")]
+    pub events: Vec<NotificationEvent>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CloudEventsConfig {
+    /// URL the CloudEvents of VM state transitions are POSTed to, none are sent when unset
+    pub sink: Option<String>,
+    /// `source` attribute of the events
+    pub source: String,
+    /// Time to wait for the sink to accept an event
+    pub timeout_secs: u64,
+}
+
+impl Default for CloudEventsConfig {
+    fn default() -> Self {
+        Self {
+            sink: None,
+            source: "fink".to_string(),
+            timeout_secs: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TelemetryConfig {
+    /// OTLP gRPC endpoint traces are exported to, e.g. `http://otel-collector:4317`, traces are
+    /// not exported when unset
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` resource of the exported traces
+    pub service_name: String,
+    /// Share of the traces exported, from 0 to 1
+    pub sample_ratio: f64,
+    /// Sentry DSN the panics and reconcile failures are reported to, nothing is reported when
+    /// unset
+    pub sentry_dsn: Option<String>,
+    /// Environment the Sentry events are tagged with, e.g. `production`
+    pub sentry_environment: Option<String>,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "fink".to_string(),
+            sample_ratio: 1.0,
+            sentry_dsn: None,
+            sentry_environment: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WebhookConfig {
+    /// Serve the admission webhooks
+    pub enabled: bool,
+    /// Address the webhooks listen on over TLS
+    pub bind: String,
+    /// PEM certificate and key the webhooks are served with
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: "0.0.0.0:8443".to_string(),
+            cert_path: "/tls/tls.crt".to_string(),
+            key_path: "/tls/tls.key".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ActivatorConfig {
+    /// Serve the proxy starting VMs with `networking.wakeOnRequest` on their first request
+    pub enabled: bool,
+    /// Address the activator proxy listens on
+    pub bind: String,
+    /// Service in front of the activator, the Services of sleeping VMs point at its endpoints
+    pub service_name: String,
+    /// Defaults to the namespace the controller runs in
+    pub service_namespace: String,
+    /// Time a request waits for its VM to boot before failing with 503
+    pub wake_timeout_secs: u64,
+    /// Labels of the controller pods serving the activator in `serviceNamespace`, which the
+    /// NetworkPolicy of isolated VMs lets through
+    pub pod_labels: BTreeMap<String, String>,
+}
+
+impl Default for ActivatorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: "0.0.0.0:8081".to_string(),
+            service_name: "fink-activator".to_string(),
+            service_namespace: std::env::var("POD_NAMESPACE").unwrap_or("default".to_string()),
+            wake_timeout_secs: 120,
+            pod_labels: BTreeMap::from([("app".to_string(), "fink".to_string())]),
+        }
+    }
+}
+
+impl ActivatorConfig {
+    pub fn wake_timeout(&self) -> Duration {
+        Duration::from_secs(self.wake_timeout_secs)
+    }
+
+    // Port of the bind address, which sleeping VM Services forward to
+    pub fn port(&self) -> Option<i32> {
+        self.bind.rsplit(':').next()?.parse().ok()
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LeaderElectionConfig {
+    /// Only reconcile while holding the Lease, for running several replicas
+    pub enabled: bool,
+    pub lease_name: String,
+    /// Defaults to the namespace the controller runs in
+    pub lease_namespace: String,
+    /// Name of this replica in the Lease, defaults to the pod name
+    pub identity: String,
+    /// Time after the last renewal before another replica takes over
+    pub lease_duration_secs: u64,
+    /// Time a leader keeps reconciling without renewing the Lease, shorter than the lease duration
+    /// so it stops before another replica can take over
+    pub renew_deadline_secs: u64,
+    pub renew_interval_secs: u64,
+}
+
+impl Default for LeaderElectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lease_name: "fink-controller".to_string(),
+            lease_namespace: std::env::var("POD_NAMESPACE").unwrap_or("default".to_string()),
+            identity: std::env::var("HOSTNAME").unwrap_or("fink-controller".to_string()),
+            lease_duration_secs: 15,
+            renew_deadline_secs: 10,
+            renew_interval_secs: 2,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ControllerConfig {
+    /// Interval VMs are reconciled at without changes
+    pub requeue_interval_secs: u64,
+    /// Interval VMs that are STARTING, STOPPING or HIBERNATING are reconciled at
+    pub transition_requeue_interval_secs: u64,
+    /// Interval VM reconciles failing with a terminal error are retried at
+    pub error_requeue_interval_secs: u64,
+    /// Interval VM reconciles failing with a transient error are retried at
+    pub retry_interval_secs: u64,
+    /// Failed reconciles in a row after which a VM is marked FAILED until its spec changes, 0
+    /// retries forever
+    pub max_consecutive_failures: u32,
+    /// Restarts of a VM pod that exited shortly after starting before the VM is Degraded
+    pub crash_loop_restarts: u32,
+    /// Interval the Firecracker metrics of VMs with `spec.firecrackerMetrics` are collected at
+    pub firecracker_metrics_interval_secs: u64,
+    /// Interval the guest agents of STARTED VMs are checked at
+    pub guest_agent_heartbeat_interval_secs: u64,
+    /// Interval failed or blocked snapshots are retried at
+    pub snapshot_requeue_interval_secs: u64,
+    /// Interval the copy job of a hibernated VM snapshot is checked at
+    pub snapshot_poll_interval_secs: u64,
+    /// Image of the job copying the snapshot of a hibernated VM
+    pub snapshot_copy_image: String,
+    /// Maximum number of parallel reconciles per controller, 0 is unbounded
+    pub concurrency: u16,
+    /// Time to wait for further changes to an object before reconciling it
+    pub debounce_millis: u64,
+    /// Label selector of the VirtualMachines and VirtualMachineSnapshots this instance manages
+    pub watch_label_selector: Option<String>,
+    /// Reject VMs without `spec.className`, so they can only use the sizes of VirtualMachineClasses
+    pub require_class_name: bool,
+    /// Apply the CRDs at startup instead of expecting them to be installed
+    pub install_crds: bool,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            requeue_interval_secs: 5 * 60,
+            transition_requeue_interval_secs: 5,
+            error_requeue_interval_secs: 5 * 60,
+            retry_interval_secs: 10,
+            max_consecutive_failures: 10,
+            crash_loop_restarts: 3,
+            firecracker_metrics_interval_secs: 60,
+            guest_agent_heartbeat_interval_secs: 30,
+            snapshot_requeue_interval_secs: 60,
+            snapshot_poll_interval_secs: 10,
+            snapshot_copy_image: "busybox".to_string(),
+            concurrency: 0,
+            debounce_millis: 0,
+            watch_label_selector: None,
+            require_class_name: false,
+            install_crds: false,
+        }
+    }
+}
+
+impl ControllerConfig {
+    pub fn requeue_interval(&self) -> Duration {
+        Duration::from_secs(self.requeue_interval_secs)
+    }
+
+    pub fn transition_requeue_interval(&self) -> Duration {
+        Duration::from_secs(self.transition_requeue_interval_secs)
+    }
+
+    pub fn firecracker_metrics_interval(&self) -> Duration {
+        Duration::from_secs(self.firecracker_metrics_interval_secs)
+    }
+
+    pub fn guest_agent_heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.guest_agent_heartbeat_interval_secs)
+    }
+
+    pub fn error_requeue_interval(&self) -> Duration {
+        Duration::from_secs(self.error_requeue_interval_secs)
+    }
+
+    pub fn retry_interval(&self) -> Duration {
+        Duration::from_secs(self.retry_interval_secs)
+    }
+
+    pub fn snapshot_requeue_interval(&self) -> Duration {
+        Duration::from_secs(self.snapshot_requeue_interval_secs)
+    }
+
+    pub fn snapshot_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.snapshot_poll_interval_secs)
+    }
+
+    // Watch of the resources reconciled by the controllers
+    pub fn watcher_config(&self) -> kube::runtime::watcher::Config {
+        let config = kube::runtime::watcher::Config::default().any_semantic();
+        match &self.watch_label_selector {
+            Some(selector) => config.labels(selector),
+            None => config,
+        }
+    }
+
+    // Scheduling of reconciles by the kube-runtime controllers
+    pub fn runtime_config(&self) -> kube::runtime::controller::Config {
+        kube::runtime::controller::Config::default()
+            .concurrency(self.concurrency)
+            .debounce(Duration::from_millis(self.debounce_millis))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FeatureFlags {
+    /// Publish Kubernetes Events for VirtualMachines
+    pub events: bool,
+}
+
+impl Default for FeatureFlags {
+    fn default() -> Self {
+        Self { events: true }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HttpConfig {
+    /// Address the HTTP server listens on
+    pub bind: String,
+    /// Serve the unauthenticated /debug endpoints: log level, tokio runtime stats and CPU profiles
+    pub debug_endpoints: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:3000".to_string(),
+            debug_endpoints: false,
+        }
+    }
+}
+
+impl Config {
+    // Read the config file if one is given, TOML for a .toml extension and YAML otherwise, then
+    // apply the env var overrides
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read config {}", path.display()))?;
+                if path.extension().is_some_and(|e| e == "toml") {
+                    toml::from_str(&contents)
+                        .with_context(|| format!("invalid config {}", path.display()))?
+                } else {
+                    serde_yaml::from_str(&contents)
+                        .with_context(|| format!("invalid config {}", path.display()))?
+                }
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        let leader_election = &config.leader_election;
+        if leader_election.renew_deadline_secs >= leader_election.lease_duration_secs {
+            anyhow::bail!(
+                "leaderElection.renewDeadlineSecs must be shorter than leaseDurationSecs"
+            );
+        }
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        let controller = &mut self.controller;
+        override_from_env(
+            "FINK_REQUEUE_INTERVAL_SECS",
+            &mut controller.requeue_interval_secs,
+        )?;
+        override_from_env(
+            "FINK_TRANSITION_REQUEUE_INTERVAL_SECS",
+            &mut controller.transition_requeue_interval_secs,
+        )?;
+        override_from_env(
+            "FINK_ERROR_REQUEUE_INTERVAL_SECS",
+            &mut controller.error_requeue_interval_secs,
+        )?;
+        override_from_env(
+            "FINK_RETRY_INTERVAL_SECS",
+            &mut controller.retry_interval_secs,
+        )?;
+        override_from_env(
+            "FINK_MAX_CONSECUTIVE_FAILURES",
+            &mut controller.max_consecutive_failures,
+        )?;
+        override_from_env(
+            "FINK_CRASH_LOOP_RESTARTS",
+            &mut controller.crash_loop_restarts,
+        )?;
+        override_from_env(
+            "FINK_FIRECRACKER_METRICS_INTERVAL_SECS",
+            &mut controller.firecracker_metrics_interval_secs,
+        )?;
+        override_from_env(
+            "FINK_GUEST_AGENT_HEARTBEAT_INTERVAL_SECS",
+            &mut controller.guest_agent_heartbeat_interval_secs,
+        )?;
+        override_from_env(
+            "FINK_SNAPSHOT_REQUEUE_INTERVAL_SECS",
+            &mut controller.snapshot_requeue_interval_secs,
+        )?;
+        override_from_env(
+            "FINK_SNAPSHOT_POLL_INTERVAL_SECS",
+            &mut controller.snapshot_poll_interval_secs,
+        )?;
+        override_from_env(
+            "FINK_SNAPSHOT_COPY_IMAGE",
+            &mut controller.snapshot_copy_image,
+        )?;
+        override_from_env("FINK_CONCURRENCY", &mut controller.concurrency)?;
+        override_from_env("FINK_DEBOUNCE_MILLIS", &mut controller.debounce_millis)?;
+        if let Ok(selector) = std::env::var("FINK_WATCH_LABEL_SELECTOR") {
+            controller.watch_label_selector = Some(selector);
+        }
+        override_from_env(
+            "FINK_REQUIRE_CLASS_NAME",
+            &mut controller.require_class_name,
+        )?;
+        override_from_env("FINK_INSTALL_CRDS", &mut controller.install_crds)?;
+
+        if let Ok(resource) = std::env::var("FINK_KVM_DEVICE_RESOURCE") {
+            self.pod.kvm_device_resource = Some(resource);
+        }
+        override_from_env("FINK_PRIVILEGED_VMS", &mut self.pod.privileged)?;
+        override_from_env(
+            "FINK_MEMORY_OVERHEAD_MIB",
+            &mut self.pod.memory_overhead_mib,
+        )?;
+
+        override_from_env("FINK_EVENTS", &mut self.features.events)?;
+        override_from_env("FINK_BIND", &mut self.http.bind)?;
+        override_from_env("FINK_DEBUG_ENDPOINTS", &mut self.http.debug_endpoints)?;
+
+        let leader_election = &mut self.leader_election;
+        override_from_env("FINK_LEADER_ELECTION", &mut leader_election.enabled)?;
+        override_from_env("FINK_LEASE_NAME", &mut leader_election.lease_name)?;
+        override_from_env("FINK_LEASE_NAMESPACE", &mut leader_election.lease_namespace)?;
+
+        override_from_env("FINK_ACTIVATOR", &mut self.activator.enabled)?;
+        override_from_env("FINK_ACTIVATOR_BIND", &mut self.activator.bind)?;
+
+        override_from_env("FINK_WEBHOOK", &mut self.webhook.enabled)?;
+        override_from_env("FINK_WEBHOOK_BIND", &mut self.webhook.bind)?;
+
+        if let Ok(endpoint) = std::env::var("FINK_OTLP_ENDPOINT") {
+            self.telemetry.otlp_endpoint = Some(endpoint);
+        }
+        override_from_env("FINK_OTLP_SAMPLE_RATIO", &mut self.telemetry.sample_ratio)?;
+        if let Ok(dsn) = std::env::var("FINK_SENTRY_DSN") {
+            self.telemetry.sentry_dsn = Some(dsn);
+        }
+        if let Ok(environment) = std::env::var("FINK_SENTRY_ENVIRONMENT") {
+            self.telemetry.sentry_environment = Some(environment);
+        }
+
+        if let Ok(sink) = std::env::var("FINK_CLOUDEVENTS_SINK") {
+            self.cloud_events.sink = Some(sink);
+        }
+        override_from_env("FINK_CLOUDEVENTS_SOURCE", &mut self.cloud_events.source)?;
+        Ok(())
+    }
+}
+
+fn override_from_env<T>(name: &str, value: &mut T) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    if let Ok(raw) = std::env::var(name) {
+        *value = raw.parse().with_context(|| format!("invalid {name}"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use chrono::Utc;
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use kube::{
+    api::{Api, PostParams},
+    client::Client,
+    core::ObjectMeta,
+    error::ErrorResponse,
+};
+use std::time::Duration;
+use tokio::time::{sleep, timeout_at, Instant};
+use tracing::*;
+
+use crate::{config::LeaderElectionConfig, errors::Error, utils::Result};
+
+// Lease based leader election, only the holder of the Lease runs the controllers
+pub struct LeaderElector {
+    leases: Api<Lease>,
+    name: String,
+    identity: String,
+    lease_duration: Duration,
+    renew_deadline: Duration,
+    renew_interval: Duration,
+}
+
+impl LeaderElector {
+    pub fn new(client: Client, config: &LeaderElectionConfig) -> Self {
+        LeaderElector {
+            leases: Api::namespaced(client, &config.lease_namespace),
+            name: config.lease_name.clone(),
+            identity: config.identity.clone(),
+            lease_duration: Duration::from_secs(config.lease_duration_secs),
+            renew_deadline: Duration::from_secs(config.renew_deadline_secs),
+            renew_interval: Duration::from_secs(config.renew_interval_secs),
+        }
+    }
+
+    // Wait until this replica holds the Lease
+    pub async fn acquire(&self) {
+        loop {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => {
+                    info!("Acquired leadership as {}", self.identity);
+                    return;
+                }
+                Ok(false) => debug!("Lease {} is held by another replica", self.name),
+                Err(e) => warn!("Failed to acquire lease {}: {}", self.name, e),
+            }
+            sleep(self.renew_interval).await;
+        }
+    }
+
+    // Keep renewing the Lease, returning once leadership is lost. Failed renewals are retried
+    // until the renew deadline, which passes before the Lease expires for the other replicas.
+    pub async fn hold(&self) {
+        let mut renewed = Instant::now();
+        loop {
+            sleep(self.renew_interval).await;
+            // A hanging request must not keep this replica leading past the deadline either
+            match timeout_at(renewed + self.renew_deadline, self.try_acquire_or_renew()).await {
+                Ok(Ok(true)) => renewed = Instant::now(),
+                Ok(Ok(false)) => break,
+                Ok(Err(e)) => warn!("Failed to renew lease {}: {}", self.name, e),
+                Err(_) => {
+                    warn!("Missed the renew deadline of lease {}", self.name);
+                    break;
+                }
+            }
+            if renewed.elapsed() >= self.renew_deadline {
+                break;
+            }
+        }
+        warn!("Lost leadership as {}", self.identity);
+    }
+
+    async fn try_acquire_or_renew(&self) -> Result<bool> {
+        let now = MicroTime(Utc::now());
+        let Some(mut lease) = self
+            .leases
+            .get_opt(&self.name)
+            .await
+            .map_err(Error::KubeError)?
+        else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(self.name.clone()),
+                    ..ObjectMeta::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(self.identity.clone()),
+                    lease_duration_seconds: Some(self.lease_duration.as_secs() as i32),
+                    acquire_time: Some(now.clone()),
+                    renew_time: Some(now),
+                    lease_transitions: Some(0),
+                }),
+            };
+            return self.write(self.leases.create(&PostParams::default(), &lease).await);
+        };
+
+        let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+        if spec.holder_identity.as_deref() != Some(&self.identity) {
+            let expires = spec.renew_time.as_ref().map(|t| {
+                t.0 + chrono::Duration::seconds(spec.lease_duration_seconds.unwrap_or(0).into())
+            });
+            if expires.is_some_and(|expires| expires > now.0) {
+                return Ok(false);
+            }
+            spec.holder_identity = Some(self.identity.clone());
+            spec.acquire_time = Some(now.clone());
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+        }
+        spec.lease_duration_seconds = Some(self.lease_duration.as_secs() as i32);
+        spec.renew_time = Some(now);
+
+        // The resourceVersion of the read makes a concurrent takeover fail with a conflict
+        self.write(
+            self.leases
+                .replace(&self.name, &PostParams::default(), &lease)
+                .await,
+        )
+    }
+
+    fn write(&self, result: kube::Result<Lease>) -> Result<bool> {
+        match result {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(ErrorResponse { code: 409, .. })) => Ok(false),
+            Err(e) => Err(Error::KubeError(e)),
+        }
+    }
+}
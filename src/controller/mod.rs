@@ -1,72 +1,801 @@
+pub mod leader;
 pub mod virtualmachine;
+pub mod virtualmachineclaim;
+pub mod virtualmachineclass;
+pub mod virtualmachinepool;
+pub mod virtualmachinereplicaset;
+pub mod virtualmachinesnapshot;
+pub mod virtualmachinetemplate;
 
 use crate::{
-    controller::virtualmachine::VIRTUAL_MACHINE_FINALIZER, errors::Error, state::AppState,
+    cloudevents::CloudEvents,
+    config,
+    controller::{
+        virtualmachine::{VirtualMachineCurrentState, VIRTUAL_MACHINE_FINALIZER},
+        virtualmachineclaim::VIRTUAL_MACHINE_CLAIM_FINALIZER,
+        virtualmachinepool::POOL_LABEL,
+        virtualmachinesnapshot::VIRTUAL_MACHINE_SNAPSHOT_FINALIZER,
+    },
+    crds,
+    errors::Error,
+    metrics::Metrics,
+    notifications::Notifier,
+    state::AppState,
+    telemetry,
     utils::Result,
 };
-use futures::StreamExt;
-use k8s_openapi::api::core::v1::{Pod, Service};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::{Endpoints, Event as CoreEvent, ObjectReference, Pod, Service};
+use k8s_openapi::api::networking::v1::{Ingress, NetworkPolicy};
+use k8s_openapi::api::policy::v1::PodDisruptionBudget;
 use kube::{
     api::{Api, ListParams, ResourceExt},
     client::Client,
+    core::{NamespaceResourceScope, PartialObjectMeta},
     runtime::{
         controller::{Action, Controller},
+        events::{Recorder, Reporter},
         finalizer::{finalizer, Event as Finalizer},
-        watcher::Config,
+        predicates, reflector,
+        reflector::{
+            store::{Store, Writer},
+            ObjectRef,
+        },
+        watcher::{self, metadata_watcher, watcher, Config},
+        Predicate, WatchStreamExt,
     },
+    Resource,
+};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
 };
-use std::sync::Arc;
-use tokio::time::Duration;
 use tracing::*;
 
-use self::virtualmachine::VirtualMachine;
+use self::{
+    leader::LeaderElector, virtualmachine::VirtualMachine,
+    virtualmachineclaim::VirtualMachineClaim, virtualmachineclass::VirtualMachineClass,
+    virtualmachinepool::VirtualMachinePool, virtualmachinereplicaset::VirtualMachineReplicaSet,
+    virtualmachinesnapshot::VirtualMachineSnapshot, virtualmachinetemplate::VirtualMachineTemplate,
+};
+
+// Guest agents checked at the same time
+const GUEST_AGENT_CHECK_CONCURRENCY: usize = 8;
+
+// Time the metrics of a VM get to be read, so a hanging exec does not stall the other VMs
+const FIRECRACKER_METRICS_TIMEOUT: Duration = Duration::from_secs(10);
 
 // Context for our reconciler
 #[derive(Clone)]
 pub struct Context {
     /// Kubernetes client
     pub client: Client,
+    /// Controller settings
+    pub config: config::Config,
+    /// Controller identity events are published under
+    pub reporter: Reporter,
+    /// Reconcile metrics
+    pub metrics: Metrics,
+    /// Sink of the VM state transitions, when configured
+    pub cloud_events: Option<CloudEvents>,
+    /// Webhooks notified of failing VMs, when configured
+    pub notifier: Option<Notifier>,
 }
 
+impl Context {
+    // Event recorder for the object behind `reference`
+    pub fn recorder(&self, reference: ObjectReference) -> Recorder {
+        Recorder::new(self.client.clone(), self.reporter.clone(), reference)
+    }
+}
+
+#[instrument(skip_all, fields(
+    trace_id,
+    name = %vm.name_any(),
+    namespace = vm.metadata.namespace.as_deref()
+))]
 async fn reconcile(vm: Arc<VirtualMachine>, ctx: Arc<Context>) -> Result<Action> {
+    telemetry::record_trace_id();
     let ns = vm.namespace().unwrap(); // doc is namespace scoped
     let vms: Api<VirtualMachine> = Api::namespaced(ctx.client.clone(), &ns);
 
+    let _timer = ctx.metrics.count_and_measure::<VirtualMachine>();
     info!("Reconciling \"{}\" in {}", vm.name_any(), ns);
+    // The finalizer helper removes the finalizer once a cleanup succeeds, so a cleanup still
+    // waiting for the children to be deleted is requeued before reaching it
+    let cleaning_up = vm.metadata.deletion_timestamp.is_some()
+        && vm
+            .finalizers()
+            .iter()
+            .any(|f| f == VIRTUAL_MACHINE_FINALIZER);
+    // A template or class that cannot be resolved fails the VM like an invalid spec, while a
+    // cleanup only retries the lookup
+    let resolved = match virtualmachinetemplate::resolve(vm.as_ref().clone(), &ctx).await {
+        Ok(resolved) => virtualmachineclass::resolve(resolved, &ctx).await,
+        Err(e) => Err(e),
+    };
+    let vm = match resolved {
+        Ok(resolved) => Arc::new(resolved),
+        Err(e) if cleaning_up => return Err(e),
+        Err(e) => return vm.fail_resolution(ctx, e).await,
+    };
+    if cleaning_up {
+        if let Some(action) = vm.cleanup(ctx.clone()).await? {
+            return Ok(action);
+        }
+    }
     finalizer(&vms, VIRTUAL_MACHINE_FINALIZER, vm, |event| async {
         match event {
             Finalizer::Apply(vm) => vm.reconcile(ctx.clone()).await,
-            Finalizer::Cleanup(vm) => vm.cleanup(ctx.clone()).await,
+            // Cleaned up above
+            Finalizer::Cleanup(_) => Ok(Action::await_change()),
         }
     })
     .await
     .map_err(|e| Error::FinalizerError(Box::new(e)))
 }
-fn error_policy(_vm: Arc<VirtualMachine>, error: &Error, _ctx: Arc<Context>) -> Action {
+fn error_policy(vm: Arc<VirtualMachine>, error: &Error, ctx: Arc<Context>) -> Action {
     warn!("reconcile failed: {:?}", error);
-    Action::requeue(Duration::from_secs(5 * 60))
+    telemetry::report_reconcile_error(vm.as_ref(), error);
+    ctx.metrics.reconcile_failure::<VirtualMachine>(error);
+    if error.is_retryable() {
+        Action::requeue(ctx.config.controller.retry_interval())
+    } else {
+        Action::requeue(ctx.config.controller.error_requeue_interval())
+    }
+}
+
+#[instrument(skip_all, fields(
+    trace_id,
+    name = %snapshot.name_any(),
+    namespace = snapshot.metadata.namespace.as_deref()
+))]
+async fn reconcile_snapshot(
+    snapshot: Arc<VirtualMachineSnapshot>,
+    ctx: Arc<Context>,
+) -> Result<Action> {
+    telemetry::record_trace_id();
+    let ns = snapshot.namespace().unwrap(); // snapshot is namespace scoped
+    let snapshots: Api<VirtualMachineSnapshot> = Api::namespaced(ctx.client.clone(), &ns);
+
+    let _timer = ctx.metrics.count_and_measure::<VirtualMachineSnapshot>();
+    info!("Reconciling snapshot \"{}\" in {}", snapshot.name_any(), ns);
+    finalizer(
+        &snapshots,
+        VIRTUAL_MACHINE_SNAPSHOT_FINALIZER,
+        snapshot,
+        |event| async {
+            match event {
+                Finalizer::Apply(snapshot) => snapshot.reconcile(ctx.clone()).await,
+                Finalizer::Cleanup(snapshot) => snapshot.cleanup(ctx.clone()).await,
+            }
+        },
+    )
+    .await
+    .map_err(|e| Error::FinalizerError(Box::new(e)))
+}
+fn snapshot_error_policy(
+    snapshot: Arc<VirtualMachineSnapshot>,
+    error: &Error,
+    ctx: Arc<Context>,
+) -> Action {
+    warn!("snapshot reconcile failed: {:?}", error);
+    telemetry::report_reconcile_error(snapshot.as_ref(), error);
+    ctx.metrics
+        .reconcile_failure::<VirtualMachineSnapshot>(error);
+    Action::requeue(ctx.config.controller.snapshot_requeue_interval())
+}
+
+#[instrument(skip_all, fields(
+    trace_id,
+    name = %pool.name_any(),
+    namespace = pool.metadata.namespace.as_deref()
+))]
+async fn reconcile_pool(pool: Arc<VirtualMachinePool>, ctx: Arc<Context>) -> Result<Action> {
+    telemetry::record_trace_id();
+    let _timer = ctx.metrics.count_and_measure::<VirtualMachinePool>();
+    info!(
+        "Reconciling pool \"{}\" in {}",
+        pool.name_any(),
+        pool.namespace().unwrap()
+    );
+    pool.reconcile(ctx).await
+}
+fn pool_error_policy(pool: Arc<VirtualMachinePool>, error: &Error, ctx: Arc<Context>) -> Action {
+    warn!("pool reconcile failed: {:?}", error);
+    telemetry::report_reconcile_error(pool.as_ref(), error);
+    ctx.metrics.reconcile_failure::<VirtualMachinePool>(error);
+    Action::requeue(ctx.config.controller.retry_interval())
+}
+
+#[instrument(skip_all, fields(
+    trace_id,
+    name = %claim.name_any(),
+    namespace = claim.metadata.namespace.as_deref()
+))]
+async fn reconcile_claim(claim: Arc<VirtualMachineClaim>, ctx: Arc<Context>) -> Result<Action> {
+    telemetry::record_trace_id();
+    let ns = claim.namespace().unwrap(); // claim is namespace scoped
+    let claims: Api<VirtualMachineClaim> = Api::namespaced(ctx.client.clone(), &ns);
+
+    let _timer = ctx.metrics.count_and_measure::<VirtualMachineClaim>();
+    info!("Reconciling claim \"{}\" in {}", claim.name_any(), ns);
+    finalizer(
+        &claims,
+        VIRTUAL_MACHINE_CLAIM_FINALIZER,
+        claim,
+        |event| async {
+            match event {
+                Finalizer::Apply(claim) => claim.reconcile(ctx.clone()).await,
+                Finalizer::Cleanup(claim) => claim.cleanup(ctx.clone()).await,
+            }
+        },
+    )
+    .await
+    .map_err(|e| Error::FinalizerError(Box::new(e)))
+}
+fn claim_error_policy(claim: Arc<VirtualMachineClaim>, error: &Error, ctx: Arc<Context>) -> Action {
+    warn!("claim reconcile failed: {:?}", error);
+    telemetry::report_reconcile_error(claim.as_ref(), error);
+    ctx.metrics.reconcile_failure::<VirtualMachineClaim>(error);
+    Action::requeue(ctx.config.controller.retry_interval())
+}
+
+#[instrument(skip_all, fields(
+    trace_id,
+    name = %replica_set.name_any(),
+    namespace = replica_set.metadata.namespace.as_deref()
+))]
+async fn reconcile_replica_set(
+    replica_set: Arc<VirtualMachineReplicaSet>,
+    ctx: Arc<Context>,
+) -> Result<Action> {
+    telemetry::record_trace_id();
+    let _timer = ctx.metrics.count_and_measure::<VirtualMachineReplicaSet>();
+    info!(
+        "Reconciling replica set \"{}\" in {}",
+        replica_set.name_any(),
+        replica_set.namespace().unwrap()
+    );
+    replica_set.reconcile(ctx).await
+}
+fn replica_set_error_policy(
+    replica_set: Arc<VirtualMachineReplicaSet>,
+    error: &Error,
+    ctx: Arc<Context>,
+) -> Action {
+    warn!("replica set reconcile failed: {:?}", error);
+    telemetry::report_reconcile_error(replica_set.as_ref(), error);
+    ctx.metrics
+        .reconcile_failure::<VirtualMachineReplicaSet>(error);
+    Action::requeue(ctx.config.controller.retry_interval())
+}
+
+// Finalizer changes have to come through, the finalizer helper waits for its own addition
+fn finalizers(obj: &VirtualMachine) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    obj.finalizers().hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+// Apis watched by the controllers, one per allowed namespace or a single cluster-wide one
+fn watched_apis<K>(state: &AppState) -> Vec<Api<K>>
+where
+    K: Resource<Scope = NamespaceResourceScope>,
+    K::DynamicType: Default,
+{
+    if state.namespaces.is_empty() {
+        return vec![Api::all(state.client.clone())];
+    }
+    state
+        .namespaces
+        .iter()
+        .map(|ns| Api::namespaced(state.client.clone(), ns))
+        .collect()
+}
+
+// Watch events of the reconciled objects, kept in the store of `writer`. With several namespaces
+// the watchers are merged, and a relist of one namespace only replaces that namespace in the
+// store instead of dropping the objects of the others.
+fn reflect_watched<K>(
+    state: &AppState,
+    mut writer: Writer<K>,
+) -> BoxStream<'static, std::result::Result<watcher::Event<K>, watcher::Error>>
+where
+    K: Resource<Scope = NamespaceResourceScope, DynamicType = ()>
+        + Clone
+        + DeserializeOwned
+        + Debug
+        + Send
+        + Sync
+        + 'static,
+{
+    let config = state.config.controller.watcher_config();
+    let mut apis = watched_apis::<K>(state);
+    if apis.len() == 1 {
+        let api = apis.remove(0);
+        return watcher(api, config)
+            .default_backoff()
+            .reflect(writer)
+            .boxed();
+    }
+
+    let reader = writer.as_reader();
+    let watchers = state.namespaces.iter().cloned().zip(apis).map(|(ns, api)| {
+        watcher(api, config.clone())
+            .default_backoff()
+            .map_ok(move |event| (ns.clone(), event))
+            .boxed()
+    });
+    futures::stream::select_all(watchers)
+        .map_ok(move |(ns, event)| {
+            match &event {
+                watcher::Event::Restarted(objs) => {
+                    let mut all: Vec<K> = reader
+                        .state()
+                        .iter()
+                        .filter(|obj| obj.namespace().as_deref() != Some(ns.as_str()))
+                        .map(|obj| obj.as_ref().clone())
+                        .collect();
+                    all.extend(objs.iter().cloned());
+                    writer.apply_watcher_event(&watcher::Event::Restarted(all));
+                }
+                _ => writer.apply_watcher_event(&event),
+            }
+            event
+        })
+        .boxed()
+}
+
+// Metadata of the objects in every watched namespace, triggering the reconciled objects that own
+// or reference them
+fn watch_metadata<K>(
+    state: &AppState,
+) -> BoxStream<'static, std::result::Result<PartialObjectMeta<K>, watcher::Error>>
+where
+    K: Resource<Scope = NamespaceResourceScope, DynamicType = ()>
+        + Clone
+        + DeserializeOwned
+        + Debug
+        + Send
+        + 'static,
+{
+    let watchers = watched_apis::<K>(state).into_iter().map(|api| {
+        metadata_watcher(api, Config::default().any_semantic())
+            .touched_objects()
+            .boxed()
+    });
+    futures::stream::select_all(watchers).boxed()
 }
 
 /// Initialize the controller and shared state (given the crd is installed)
 pub async fn run(state: AppState) {
-    let client = Client::try_default()
-        .await
-        .expect("failed to create kube Client");
-    let vms = Api::<VirtualMachine>::all(client.clone());
-    let pods = Api::<Pod>::all(client.clone());
-    let services = Api::<Service>::all(client.clone());
-
-    if let Err(e) = vms.list(&ListParams::default().limit(1)).await {
-        error!("CRD is not queryable; {e:?}. Is the CRD installed?");
-        info!("Installation: cargo run --bin crdgen | kubectl apply -f -");
-        std::process::exit(1);
+    if state.config.controller.install_crds {
+        if let Err(e) = crds::install(state.client.clone()).await {
+            error!("Failed to install the CRDs; {e:?}");
+            std::process::exit(1);
+        }
+    }
+    for vms in watched_apis::<VirtualMachine>(&state) {
+        if let Err(e) = vms.list(&ListParams::default().limit(1)).await {
+            error!("CRD is not queryable; {e:?}. Is the CRD installed?");
+            info!("Installation: cargo run --bin crdgen | kubectl apply -f -, or start with --install-crds");
+            std::process::exit(1);
+        }
+    }
+    if !state.config.leader_election.enabled {
+        state.readiness.set_syncing_caches(true);
+        state.readiness.set_crd_queryable();
+        state.readiness.set_leader(true);
+        run_controllers(&state).await;
+        return;
     }
-    Controller::new(vms, Config::default().any_semantic())
-        .owns(pods, Config::default().any_semantic())
-        .owns(services, Config::default().any_semantic())
+
+    // Standbys follow the VMs while they wait for the Lease, a leader that loses it stops its
+    // controllers and waits again
+    let elector = LeaderElector::new(state.client.clone(), &state.config.leader_election);
+    state.readiness.set_crd_queryable();
+    loop {
+        tokio::select! {
+            _ = elector.acquire() => {}
+            _ = follow_vms(&state) => return,
+        }
+        state.readiness.set_leader(true);
+        state.readiness.set_syncing_caches(true);
+
+        tokio::select! {
+            _ = run_controllers(&state) => return,
+            _ = elector.hold() => state.readiness.set_leader(false),
+        }
+    }
+}
+
+// Keep the VM cache of a standby, which answers the API requests sent to it directly while the
+// leader reconciles
+async fn follow_vms(state: &AppState) {
+    let (reader, writer) = reflector::store();
+    *state.vm_store.write().unwrap() = Some(reader.clone());
+    state.readiness.set_syncing_caches(true);
+    let readiness = state.readiness.clone();
+    let cache_synced = async move {
+        if reader.wait_until_ready().await.is_ok() {
+            readiness.set_syncing_caches(false);
+        }
+    };
+    let vms = reflect_watched(state, writer).for_each(|_| futures::future::ready(()));
+    futures::join!(vms, cache_synced);
+}
+
+// Run the VirtualMachine controller and the controllers of the resources built on top of it until
+// shutdown
+async fn run_controllers(state: &AppState) {
+    let ctx = state.to_context();
+
+    // Only changes made by users trigger a reconcile of the VM itself, not its own status writes
+    let (vm_reader, vm_writer) = reflector::store();
+    let vm_changes = reflect_watched(state, vm_writer)
+        .applied_objects()
+        .predicate_filter(
+            predicates::generation
+                .combine(predicates::labels)
+                .combine(predicates::annotations)
+                .combine(finalizers),
+        );
+    let vm_controller = Controller::for_stream(vm_changes, vm_reader);
+    let (snapshot_reader, snapshot_writer) = reflector::store();
+    let snapshot_changes = reflect_watched(state, snapshot_writer).applied_objects();
+    let snapshot_controller = Controller::for_stream(snapshot_changes, snapshot_reader);
+    let (pool_reader, pool_writer) = reflector::store();
+    let pool_changes = reflect_watched(state, pool_writer).applied_objects();
+    let pool_controller = Controller::for_stream(pool_changes, pool_reader);
+    let (claim_reader, claim_writer) = reflector::store();
+    let claim_changes = reflect_watched(state, claim_writer).applied_objects();
+    let claim_controller = Controller::for_stream(claim_changes, claim_reader);
+    let (replica_set_reader, replica_set_writer) = reflector::store();
+    let replica_set_changes = reflect_watched(state, replica_set_writer).applied_objects();
+    let replica_set_controller = Controller::for_stream(replica_set_changes, replica_set_reader);
+    // Templates are cached for the periodic checks, which resolve them for every VM
+    let (template_store, template_writer) = reflector::store::<VirtualMachineTemplate>();
+    let template_changes = reflect_watched(state, template_writer).touched_objects();
+
+    let (vm_store, snapshot_store, pool_store, claim_store, replica_set_store, templates) = (
+        vm_controller.store(),
+        snapshot_controller.store(),
+        pool_controller.store(),
+        claim_controller.store(),
+        replica_set_controller.store(),
+        template_store.clone(),
+    );
+    *state.vm_store.write().unwrap() = Some(vm_store.clone());
+    let activator_endpoints = sync_activator_endpoints(state, vm_store.clone());
+    let readiness = state.readiness.clone();
+    let caches_synced = async move {
+        let synced = futures::try_join!(
+            vm_store.wait_until_ready(),
+            snapshot_store.wait_until_ready(),
+            pool_store.wait_until_ready(),
+            claim_store.wait_until_ready(),
+            replica_set_store.wait_until_ready(),
+            templates.wait_until_ready()
+        );
+        if synced.is_ok() {
+            readiness.set_syncing_caches(false);
+        }
+    };
+
+    // Template and class changes are applied to every VM referencing them
+    let vms = vm_controller.store();
+    let class_vms = vm_controller.store();
+    let vm_events_store = vm_controller.store();
+    let classes = metadata_watcher(
+        Api::<VirtualMachineClass>::all(state.client.clone()),
+        Config::default().any_semantic(),
+    )
+    .touched_objects();
+    let vm_controller = vm_controller
+        .watches_stream(template_changes, move |template| {
+            let ns = template.namespace();
+            let name = template.name_any();
+            vms.state()
+                .into_iter()
+                .filter(|vm| {
+                    vm.namespace() == ns
+                        && vm
+                            .spec
+                            .template_ref
+                            .as_ref()
+                            .is_some_and(|t| t.name == name)
+                })
+                .map(|vm| ObjectRef::from_obj(vm.as_ref()))
+                .collect::<Vec<_>>()
+        })
+        .watches_stream(classes, move |class| {
+            let name = class.name_any();
+            class_vms
+                .state()
+                .into_iter()
+                .filter(|vm| vm.spec.class_name.as_ref() == Some(&name))
+                .map(|vm| ObjectRef::from_obj(vm.as_ref()))
+                .collect::<Vec<_>>()
+        })
+        .owns_stream(watch_metadata::<Pod>(state))
+        .owns_stream(watch_metadata::<Service>(state))
+        .owns_stream(watch_metadata::<Ingress>(state))
+        .owns_stream(watch_metadata::<NetworkPolicy>(state))
+        .owns_stream(watch_metadata::<PodDisruptionBudget>(state))
+        .with_config(state.config.controller.runtime_config())
+        .shutdown_on_signal()
+        .run(reconcile, error_policy, ctx.clone())
+        .filter_map(|x| async move { std::result::Result::ok(x) })
+        .for_each(|_| futures::future::ready(()));
+
+    let snapshot_controller = snapshot_controller
+        .owns_stream(watch_metadata::<Job>(state))
+        .with_config(state.config.controller.runtime_config())
+        .shutdown_on_signal()
+        .run(reconcile_snapshot, snapshot_error_policy, ctx.clone())
+        .filter_map(|x| async move { std::result::Result::ok(x) })
+        .for_each(|_| futures::future::ready(()));
+
+    // Pools are triggered by their label rather than ownership, which moves to the claim once a
+    // VM is handed out
+    let pool_controller = pool_controller
+        .watches_stream(watch_metadata::<VirtualMachine>(state), |vm| {
+            let pool = vm.labels().get(POOL_LABEL).cloned();
+            pool.map(|pool| ObjectRef::new(&pool).within(&vm.namespace().unwrap_or_default()))
+        })
+        .with_config(state.config.controller.runtime_config())
         .shutdown_on_signal()
-        .run(reconcile, error_policy, state.to_context(client))
+        .run(reconcile_pool, pool_error_policy, ctx.clone())
         .filter_map(|x| async move { std::result::Result::ok(x) })
-        .for_each(|_| futures::future::ready(()))
+        .for_each(|_| futures::future::ready(()));
+
+    let claim_controller = claim_controller
+        .owns_stream(watch_metadata::<VirtualMachine>(state))
+        .with_config(state.config.controller.runtime_config())
+        .shutdown_on_signal()
+        .run(reconcile_claim, claim_error_policy, ctx.clone())
+        .filter_map(|x| async move { std::result::Result::ok(x) })
+        .for_each(|_| futures::future::ready(()));
+
+    let replica_set_controller = replica_set_controller
+        .owns_stream(watch_metadata::<VirtualMachine>(state))
+        .with_config(state.config.controller.runtime_config())
+        .shutdown_on_signal()
+        .run(reconcile_replica_set, replica_set_error_policy, ctx.clone())
+        .filter_map(|x| async move { std::result::Result::ok(x) })
+        .for_each(|_| futures::future::ready(()));
+
+    let firecracker_metrics =
+        collect_firecracker_metrics(state, vm_events_store.clone(), template_store.clone());
+    let guest_agents = check_guest_agents(state, vm_events_store.clone(), template_store);
+    let pod_events = mirror_pod_events(state, ctx, vm_events_store);
+
+    let controllers = async {
+        futures::join!(
+            vm_controller,
+            snapshot_controller,
+            pool_controller,
+            claim_controller,
+            replica_set_controller,
+            caches_synced
+        )
+    };
+    // Pod events are mirrored, guest agents checked, Firecracker metrics collected and the
+    // activator endpoints followed for as long as the controllers run
+    tokio::select! {
+        _ = controllers => {}
+        _ = pod_events, if state.config.features.events => {}
+        _ = firecracker_metrics => {}
+        _ = guest_agents => {}
+        _ = activator_endpoints, if state.config.activator.enabled => {}
+    }
+}
+
+// Periodically check the guest agents of the STARTED VMs, a few at a time so guests that do not
+// answer only hold up their own checks
+async fn check_guest_agents(
+    state: &AppState,
+    vms: Store<VirtualMachine>,
+    templates: Store<VirtualMachineTemplate>,
+) {
+    let ctx = state.to_context();
+    let mut interval =
+        tokio::time::interval(state.config.controller.guest_agent_heartbeat_interval());
+    loop {
+        interval.tick().await;
+        // The agent may come from the template, which is only resolved for these VMs
+        let started = vms.state().into_iter().filter(|vm| {
+            (vm.spec.guest_agent.is_some() || vm.spec.template_ref.is_some())
+                && vm.status.as_ref().map(|s| &s.state)
+                    == Some(&VirtualMachineCurrentState::STARTED)
+        });
+        futures::stream::iter(started)
+            .for_each_concurrent(GUEST_AGENT_CHECK_CONCURRENCY, |vm| {
+                let ctx = ctx.clone();
+                let resolved =
+                    virtualmachinetemplate::resolve_cached(vm.as_ref().clone(), &templates);
+                async move {
+                    let result = match resolved {
+                        Ok(vm) => vm.check_guest_agent(ctx.client.clone()).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        debug!("failed to check the guest agent of {}: {e}", vm.name_any());
+                    }
+                }
+            })
+            .await;
+    }
+}
+
+// Follow the pods of the activator into the EndpointSlices of the sleeping VMs, which are settled
+// and not reconciled when the activator is rolled out or scaled
+async fn sync_activator_endpoints(state: &AppState, vms: Store<VirtualMachine>) {
+    let ctx = state.to_context();
+    let activator = &state.config.activator;
+    let Some(activator_port) = activator.port() else {
+        return std::future::pending().await;
+    };
+    let endpoints: Api<Endpoints> =
+        Api::namespaced(state.client.clone(), &activator.service_namespace);
+    let config = Config::default().fields(&format!("metadata.name={}", activator.service_name));
+    watcher(endpoints, config)
+        .default_backoff()
+        .applied_objects()
+        .for_each(|endpoints| {
+            let ctx = ctx.clone();
+            let vms = vms.clone();
+            async move {
+                let endpoints = match endpoints {
+                    Ok(endpoints) => endpoints,
+                    Err(e) => {
+                        warn!("activator endpoints watch failed: {e}");
+                        return;
+                    }
+                };
+                let addresses = virtualmachine::activator_addresses(Some(endpoints));
+                for vm in vms.state() {
+                    // Waking on request may come from the template
+                    let vm = match virtualmachinetemplate::resolve(vm.as_ref().clone(), &ctx).await
+                    {
+                        Ok(vm) => vm,
+                        Err(e) => {
+                            debug!("failed to resolve the template of {}: {e}", vm.name_any());
+                            continue;
+                        }
+                    };
+                    if !vm.routes_to_activator(&ctx) {
+                        continue;
+                    }
+                    if let Err(e) = vm
+                        .apply_activator_endpoints(
+                            ctx.client.clone(),
+                            activator_port,
+                            addresses.clone(),
+                        )
+                        .await
+                    {
+                        warn!(
+                            "failed to update the activator endpoints of {}: {e}",
+                            vm.name_any()
+                        );
+                    }
+                }
+            }
+        })
+        .await
+}
+
+// Periodically add the Firecracker metrics flushed since the last collection of the STARTED VMs
+// with `spec.firecrackerMetrics` to the metrics of the controller. Flushes are told apart by their
+// timestamp, so none is counted twice.
+async fn collect_firecracker_metrics(
+    state: &AppState,
+    vms: Store<VirtualMachine>,
+    templates: Store<VirtualMachineTemplate>,
+) {
+    let mut last_flushes: HashMap<ObjectRef<VirtualMachine>, u64> = HashMap::new();
+    let period = state.config.controller.firecracker_metrics_interval();
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        // The flag may come from the template, which is only resolved for these VMs
+        let started: Vec<_> = vms
+            .state()
+            .into_iter()
+            .filter(|vm| {
+                (vm.spec.firecracker_metrics || vm.spec.template_ref.is_some())
+                    && vm.status.as_ref().map(|s| &s.state)
+                        == Some(&VirtualMachineCurrentState::STARTED)
+            })
+            .collect();
+        last_flushes.retain(|vm, _| {
+            started
+                .iter()
+                .any(|s| ObjectRef::from_obj(s.as_ref()) == *vm)
+        });
+        for vm in started {
+            let vm = match virtualmachinetemplate::resolve_cached(vm.as_ref().clone(), &templates) {
+                Ok(vm) if vm.spec.firecracker_metrics => vm,
+                Ok(_) => continue,
+                Err(e) => {
+                    debug!("failed to resolve the template of {}: {e}", vm.name_any());
+                    continue;
+                }
+            };
+            let firecracker = vm.firecracker(state.client.clone());
+            let collected = tokio::time::timeout(
+                FIRECRACKER_METRICS_TIMEOUT,
+                firecracker.take_metrics(period),
+            )
+            .await;
+            let flushes = match collected {
+                Ok(Ok(flushes)) => flushes,
+                Ok(Err(e)) => {
+                    debug!(
+                        "failed to collect the Firecracker metrics of {}: {e}",
+                        vm.name_any()
+                    );
+                    continue;
+                }
+                Err(_) => {
+                    debug!(
+                        "timed out collecting the Firecracker metrics of {}",
+                        vm.name_any()
+                    );
+                    continue;
+                }
+            };
+            let last_flush = last_flushes.entry(ObjectRef::from_obj(&vm)).or_default();
+            for flush in flushes {
+                let Some(timestamp) = flush["utc_timestamp_ms"].as_u64() else {
+                    continue;
+                };
+                if timestamp > *last_flush {
+                    state.metrics.firecracker_flush(&vm, &flush);
+                    *last_flush = timestamp;
+                }
+            }
+        }
+    }
+}
+
+// Republish the Warning events of VM pods, like FailedScheduling or image pull back-offs, on their
+// VM so describing the VM tells why it does not start. Events listed when a watch (re)starts were
+// already mirrored or are stale, only the ones arriving afterwards are.
+async fn mirror_pod_events(state: &AppState, ctx: Arc<Context>, vms: Store<VirtualMachine>) {
+    let config = Config::default().fields("involvedObject.kind=Pod,type=Warning");
+    let watchers = watched_apis::<CoreEvent>(state)
+        .into_iter()
+        .map(|api| watcher(api, config.clone()).default_backoff().boxed());
+    futures::stream::select_all(watchers)
+        .for_each(|event| {
+            let ctx = ctx.clone();
+            let vms = vms.clone();
+            async move {
+                let event = match event {
+                    Ok(watcher::Event::Applied(event)) => event,
+                    Ok(_) => return,
+                    Err(e) => {
+                        warn!("pod event watch failed: {e}");
+                        return;
+                    }
+                };
+                // The VM pod is named after its VM
+                let pod = &event.involved_object;
+                let (Some(name), Some(ns)) = (&pod.name, &pod.namespace) else {
+                    return;
+                };
+                if let Some(vm) = vms.get(&ObjectRef::new(name).within(ns)) {
+                    vm.mirror_pod_event(ctx, &event).await;
+                }
+            }
+        })
         .await;
 }
@@ -1,27 +1,127 @@
 #![allow(unused_imports)]
 
-use crate::{controller::Context, errors::Error, utils::Result};
+use crate::{
+    agent,
+    config::ActivatorConfig,
+    controller::{
+        virtualmachinesnapshot::VirtualMachineSnapshot,
+        virtualmachinetemplate::VirtualMachineTemplateRef, Context,
+    },
+    errors::Error,
+    firecracker,
+    notifications::{Notification, NotificationEvent},
+    utils::Result,
+};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
 use std::{sync::Arc, time::Duration};
 
 use k8s_openapi::api::core::v1::{
-    Container, Pod, PodSpec, PodStatus, Service, ServicePort, ServiceSpec,
+    Affinity, Capabilities, ConfigMap, ConfigMapVolumeSource, Container, ContainerPort,
+    ContainerStateTerminated, EmptyDirVolumeSource, Endpoints, EnvVar, Event as CoreEvent,
+    ExecAction, HTTPGetAction, HostPathVolumeSource, KeyToPath, LocalObjectReference, Node,
+    PersistentVolumeClaim, PersistentVolumeClaimSpec, PersistentVolumeClaimVolumeSource, Pod,
+    PodCondition, PodSpec, PodStatus, Probe, ResourceRequirements, Secret, SecretKeySelector,
+    SecretVolumeSource, SecurityContext, Service, ServicePort, ServiceSpec, TCPSocketAction,
+    Toleration, TypedLocalObjectReference, Volume, VolumeMount, VolumeResourceRequirements,
+};
+use k8s_openapi::api::discovery::v1::{Endpoint, EndpointPort, EndpointSlice};
+use k8s_openapi::api::networking::v1::{
+    HTTPIngressPath, HTTPIngressRuleValue, IPBlock, Ingress, IngressBackend, IngressRule,
+    IngressServiceBackend, IngressSpec, IngressTLS, NetworkPolicy, NetworkPolicyIngressRule,
+    NetworkPolicyPeer, NetworkPolicyPort, NetworkPolicySpec, ServiceBackendPort,
 };
-use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use k8s_openapi::api::policy::v1::{PodDisruptionBudget, PodDisruptionBudgetSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{
+    LabelSelector, LabelSelectorRequirement, OwnerReference,
+};
+use k8s_openapi::apimachinery::pkg::{api::resource::Quantity, util::intstr::IntOrString};
+use k8s_openapi::ByteString;
 use kube::error::ErrorResponse;
 use kube::{
-    api::{Api, Patch, PatchParams, PostParams, ResourceExt},
+    api::{
+        Api, ApiResource, AttachParams, DeleteParams, DynamicObject, GroupVersionKind, ListParams,
+        Patch, PatchParams, PostParams, ResourceExt,
+    },
     client::Client,
     core::ObjectMeta,
-    runtime::controller::Action,
+    runtime::{
+        controller::Action,
+        events::{Event, EventType},
+    },
     CustomResource, Resource,
 };
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, fmt::Debug};
+use tokio::io::AsyncReadExt;
 use tracing::*;
 
 pub static VIRTUAL_MACHINE_FINALIZER: &str = "vm.codesandbox.io";
 
-#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+pub(crate) static VM_CONTAINER_NAME: &str = "vm-container";
+
+/// Label linking the child resources to their VM
+static VM_NAME_LABEL: &str = "vms.codesandbox.io/name";
+
+/// Field manager of the child resources applied by the controller
+static FIELD_MANAGER: &str = "fink";
+
+/// Pod annotations recording the size the VM was booted with
+static CPUS_ANNOTATION: &str = "vms.codesandbox.io/cpus";
+static MEMORY_MIB_ANNOTATION: &str = "vms.codesandbox.io/memory-mib";
+
+/// Annotation requesting a restart of the VM pod, copied to the pod once it is restarted
+static RESTART_AT_ANNOTATION: &str = "vm.codesandbox.io/restart-at";
+
+/// Annotation of the VM holding the RFC 3339 time it was last used, set by guest agent heartbeats,
+/// the ingress or the HTTP API
+pub(crate) static LAST_ACTIVITY_ANNOTATION: &str = "vm.codesandbox.io/last-activity";
+
+/// Annotation of the generated Service holding the hash of its rendered spec
+static SPEC_HASH_ANNOTATION: &str = "vms.codesandbox.io/spec-hash";
+
+/// Annotation of the generated Ingress listing the keys of the annotations set from the spec, so
+/// the ones dropped from the spec are removed
+static MANAGED_ANNOTATIONS_ANNOTATION: &str = "vms.codesandbox.io/managed-annotations";
+
+/// Annotation of the VM pod holding the hash of the spec fields it is rendered from
+static POD_INPUTS_HASH_ANNOTATION: &str = "vms.codesandbox.io/pod-inputs-hash";
+
+/// Label of the ConfigMaps keeping the usage of deleted VMs
+pub(crate) static USAGE_RECORD_LABEL: &str = "vms.codesandbox.io/usage-record";
+
+/// Mount path of the per-VM snapshot PVC inside the vm-container
+pub(crate) static SNAPSHOT_MOUNT_PATH: &str = "/snapshot";
+
+/// Mount path of the root disk PVC inside the vm-container
+static ROOT_DISK_MOUNT_PATH: &str = "/rootfs";
+
+/// Mount path of the cloud-init files inside the vm-container
+static CLOUD_INIT_MOUNT_PATH: &str = "/cloud-init";
+
+/// Mount path of the authorized SSH keys inside the vm-container
+static SSH_KEYS_MOUNT_PATH: &str = "/ssh";
+
+/// Mount path of the guest kernel copied out of `bootSource.kernelImage`
+static KERNEL_MOUNT_PATH: &str = "/kernel";
+
+/// Directory holding one mount per data drive inside the vm-container
+static DRIVES_MOUNT_PATH: &str = "/drives";
+
+/// Where the runtime image keeps its default kernel and builds the rootfs
+static RUNTIME_DIR: &str = "/var/lib/firecracker";
+
+// Longest restart backoff, a VM that ran for longer restarts right away and is not crash-looping
+const MAX_RESTART_BACKOFF_SECS: i64 = 5 * 60;
+
+// Time the guest agent gets to acknowledge a shutdown before the pod is deleted anyway
+const GUEST_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
 pub enum VirtualMachineDesiredState {
     #[default]
     STOPPED,
@@ -29,7 +129,7 @@ pub enum VirtualMachineDesiredState {
     HIBERNATED,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
 pub enum VirtualMachineCurrentState {
     #[default]
     STOPPED,
@@ -38,6 +138,8 @@ pub enum VirtualMachineCurrentState {
     STARTING,
     HIBERNATING,
     HIBERNATED,
+    /// Reconciling failed too many times in a row, retried on the next spec change
+    FAILED,
 }
 
 #[derive(CustomResource, Debug, Serialize, Deserialize, Default, Clone, JsonSchema)]
@@ -51,198 +153,5488 @@ pub enum VirtualMachineCurrentState {
     plural = "virtualmachines",
     shortname = "vm",
     status = "VirtualMachineStatus",
-    printcolumn = r#"{"name":"Image", "type":"string", "description":"VM rootfs image", "jsonPath":".spec.image"}"#
+    printcolumn = r#"{"name":"Image", "type":"string", "description":"VM rootfs image", "jsonPath":".spec.bootSource.rootfsImage"}"#,
+    printcolumn = r#"{"name":"CPUs", "type":"integer", "description":"Number of vCPUs", "jsonPath":".spec.cpus"}"#,
+    printcolumn = r#"{"name":"Memory", "type":"integer", "description":"Memory in MiB", "jsonPath":".spec.memoryMib"}"#
 )]
+#[serde(rename_all = "camelCase")]
 pub struct VirtualMachineSpec {
-    pub image: String,
+    /// VirtualMachineTemplate providing the fields this spec leaves at their default
+    pub template_ref: Option<VirtualMachineTemplateRef>,
+    /// VirtualMachineClass sizing the VM, replacing `cpus`, `memoryMib`, `overcommit` and the
+    /// size of the root disk
+    pub class_name: Option<String>,
+    /// Deprecated: rootfs image, use `bootSource.rootfsImage` instead
+    pub image: Option<String>,
+    /// Kernel and rootfs the VM boots from
+    pub boot_source: Option<VirtualMachineBootSource>,
     pub state: VirtualMachineDesiredState,
+    /// Allow the VM to be HIBERNATED. Its pod then mounts a PVC sized after the guest memory,
+    /// which the snapshot is written to.
+    #[serde(default)]
+    pub hibernation: bool,
+    /// Time the VM pod gets to shut down when the VM is stopped, defaults to the pod's
+    /// termination grace period
+    pub stop_grace_period_seconds: Option<u32>,
+    /// Number of vCPUs given to the VM
+    #[serde(default = "default_cpus")]
+    #[schemars(range(min = 1))]
+    pub cpus: u32,
+    /// Memory given to the VM in MiB
+    #[serde(default = "default_memory_mib")]
+    #[schemars(range(min = 128))]
+    pub memory_mib: u32,
+    /// Share of the vCPUs and memory reserved for the VM pod, set from `className` only, the pod
+    /// is guaranteed its full size when unset
+    pub overcommit: Option<VirtualMachineOvercommit>,
+    /// Ports exposed by the VM through its Service
+    #[serde(default = "default_ports")]
+    #[schemars(length(min = 1))]
+    pub ports: Vec<VirtualMachinePort>,
+    /// Where the VM is booted from instead of a fresh image
+    pub source: Option<VirtualMachineSource>,
+    /// Root disk backed by a PVC instead of the container filesystem
+    pub storage: Option<VirtualMachineStorage>,
+    /// Cloud-init configuration applied on first boot
+    pub cloud_init: Option<VirtualMachineCloudInit>,
+    /// SSH public keys authorized to log into the VM
+    pub ssh_keys: Option<VirtualMachineSshKeys>,
+    /// Constraints on which nodes the VM pod can run
+    pub scheduling: Option<VirtualMachineScheduling>,
+    /// Additional block devices attached to the VM
+    #[serde(default)]
+    #[schemars(length(max = 16))]
+    pub drives: Vec<VirtualMachineDrive>,
+    /// Network interfaces of the VM, defaults to a single primary interface
+    #[serde(default)]
+    pub network_interfaces: Vec<VirtualMachineNetworkInterface>,
+    /// JSON metadata served to the guest through MMDS
+    pub metadata: Option<VirtualMachineMetadata>,
+    /// vsock device used for host to guest communication
+    pub vsock: Option<VirtualMachineVsock>,
+    /// Agent in the guest the controller talks to over `vsock`, for OS info, heartbeats, clean
+    /// shutdowns and running commands
+    pub guest_agent: Option<VirtualMachineGuestAgent>,
+    /// Balloon device reclaiming guest memory at runtime
+    pub balloon: Option<VirtualMachineBalloon>,
+    /// How changes to `cpus` and `memoryMib` are applied to a running VM
+    #[serde(default)]
+    pub resize_policy: ResizePolicy,
+    /// How changes to the image and other fields baked into the VM pod are applied
+    #[serde(default)]
+    pub update_strategy: UpdateStrategy,
+    /// How the children of the VM are deleted when the VM is removed
+    #[serde(default)]
+    pub deletion_policy: DeletionPolicy,
+    /// Whether the VM pod is recreated after the VM exits
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    /// Cron schedule flipping `state` between STARTED and STOPPED
+    pub schedule: Option<VirtualMachineSchedule>,
+    /// Time without activity after which a started VM is hibernated or stopped
+    pub idle_timeout_seconds: Option<u32>,
+    /// Time the VM gets to go from STARTING to STARTED before it is marked FAILED, e.g. when its
+    /// images cannot be pulled or it keeps crashing. Unlimited when unset.
+    #[schemars(range(min = 1))]
+    pub boot_timeout_seconds: Option<u32>,
+    /// What happens to the VM once it has been idle for `idleTimeoutSeconds`
+    #[serde(default)]
+    pub idle_action: IdleAction,
+    /// RuntimeClass of the VM pod, defaults to the namespace or cluster default
+    pub runtime_class_name: Option<String>,
+    /// ServiceAccount the VM pod runs as
+    pub service_account_name: Option<String>,
+    /// Secrets used to pull the rootfs, kernel and drive images from private registries
+    #[serde(default)]
+    pub image_pull_secrets: Vec<LocalObjectReference>,
+    /// How the VM is exposed on the network
+    #[serde(default)]
+    pub networking: VirtualMachineNetworking,
+    /// PodDisruptionBudget protecting the VM pod from voluntary evictions
+    #[serde(default)]
+    pub disruption_budget: VirtualMachineDisruptionBudget,
+    /// Check the guest has to pass before the VM is STARTED and Ready
+    pub readiness_probe: Option<VirtualMachineProbe>,
+    /// Collect the Firecracker metrics of the VM, like vCPU exits and block and network traffic,
+    /// and export them on the /metrics endpoint of the controller
+    #[serde(default)]
+    pub firecracker_metrics: bool,
+    /// Check telling when the guest has booted, the VM stays STARTING until it passes. The VM pod
+    /// exits once it fails `failureThreshold` times and is restarted by `restartPolicy`. Readiness
+    /// is only probed after it passed.
+    pub startup_probe: Option<VirtualMachineProbe>,
+    /// Firecracker CPU template masking the CPU features exposed to the guest, for guests that
+    /// must see the same CPU across hosts. Applied when the VM boots.
+    pub cpu_template: Option<VirtualMachineCpuTemplate>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
-pub struct VirtualMachineStatus {
-    pub state: VirtualMachineCurrentState,
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineDisruptionBudget {
+    #[serde(default = "default_disruption_budget_enabled")]
+    pub enabled: bool,
+    /// The default of 0 blocks node drains until the VM is stopped or hibernated
+    #[serde(default)]
+    #[schemars(range(min = 0))]
+    pub max_unavailable: i32,
 }
 
-impl VirtualMachine {
-    // Reconcile (for non-finalizer related changes)
-    pub async fn reconcile(&self, ctx: Arc<Context>) -> Result<Action> {
-        // let vms: Api<VirtualMachine> = Api::namespaced(client, &ns);
-
-        match self.spec.state {
-            VirtualMachineDesiredState::STOPPED => self.stop(ctx).await?,
-            VirtualMachineDesiredState::STARTED => self.start(ctx).await?,
-            VirtualMachineDesiredState::HIBERNATED => self.hibernate(ctx).await?,
+impl Default for VirtualMachineDisruptionBudget {
+    fn default() -> Self {
+        Self {
+            enabled: default_disruption_budget_enabled(),
+            max_unavailable: 0,
         }
-
-        // If no events were received, check back every 5 minutes
-        Ok(Action::requeue(Duration::from_secs(5 * 60)))
     }
+}
 
-    // Finalizer cleanup (the object was deleted, ensure nothing is orphaned)
-    pub async fn cleanup(&self, _ctx: Arc<Context>) -> Result<Action> {
-        info!("Cleaning up VirtualMachine");
+fn default_disruption_budget_enabled() -> bool {
+    true
+}
 
-        // ==============================
-        // Publish event about cleanup
-        // Do any cleanup if needed
-        // ==============================
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineNetworking {
+    /// Type of the Service exposing `spec.ports`, `None` creates no Service
+    #[serde(default)]
+    pub service_type: VirtualMachineServiceType,
+    /// Ingress routing a hostname to the VM Service
+    pub ingress: Option<VirtualMachineIngress>,
+    /// DNS name of the VM, published by external-dns from the Service
+    pub hostname: Option<String>,
+    /// Annotations added to the generated Service and Ingress
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    /// Create the Service without a cluster IP so the VM pod resolves directly in DNS, only
+    /// valid with the ClusterIP service type
+    #[serde(default)]
+    pub headless: bool,
+    /// Gateway API HTTPRoute routing to the VM Service, an alternative to `ingress`
+    pub http_route: Option<VirtualMachineHttpRoute>,
+    /// Restrict traffic to the VM pod with a NetworkPolicy
+    pub isolation: Option<VirtualMachineIsolation>,
+    /// Keep the Service and routes of the stopped or hibernated VM pointed at the activator, which
+    /// starts the VM on the first request
+    #[serde(default)]
+    pub wake_on_request: bool,
+}
 
-        Ok(Action::await_change())
-    }
+// With isolation the VM pod only accepts traffic on the ports of the Service, from the activator and
+// from the listed CIDRs and namespaces. Pods in its own namespace are only let through when the
+// namespace is listed.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineIsolation {
+    #[serde(default)]
+    pub allowed_cidrs: Vec<String>,
+    #[serde(default)]
+    pub allowed_namespaces: Vec<String>,
+}
 
-    async fn update_status(&self, ctx: Arc<Context>, status: VirtualMachineStatus) -> Result<()> {
-        let ns = self.namespace().unwrap();
-        let vm_name = self.metadata.name.as_ref().unwrap();
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineHttpRoute {
+    /// Gateway the route attaches to
+    pub gateway_ref: GatewayReference,
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+    #[serde(default = "default_ingress_path")]
+    pub path: String,
+    /// Name of the entry in `spec.ports` to route to, defaults to the first port
+    pub port: Option<String>,
+}
 
-        let vms: Api<VirtualMachine> = Api::namespaced(ctx.client.clone(), &ns);
-        let patch = Patch::Merge(status);
-        let _o = vms
-            .patch_status(vm_name, &PatchParams::default(), &patch)
-            .await
-            .map_err(Error::KubeError)?;
-        Ok(())
-    }
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayReference {
+    pub name: String,
+    /// Defaults to the namespace of the VM
+    pub namespace: Option<String>,
+    /// Listener of the Gateway to attach to
+    pub section_name: Option<String>,
+}
 
-    async fn start(&self, ctx: Arc<Context>) -> Result<()> {
-        let client: Client = ctx.client.clone();
-        let ns = self.namespace().unwrap();
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineIngress {
+    pub host: String,
+    #[serde(default = "default_ingress_path")]
+    pub path: String,
+    pub ingress_class_name: Option<String>,
+    /// Secret holding the TLS certificate for `host`
+    pub tls_secret_ref: Option<LocalObjectReference>,
+    /// Name of the entry in `spec.ports` to route to, defaults to the first port
+    pub port: Option<String>,
+}
 
-        let owner_reference = self.controller_owner_ref(&()).unwrap();
+fn default_ingress_path() -> String {
+    "/".to_string()
+}
 
-        let vm_name = self.metadata.name.as_ref().unwrap();
-        let image = self.spec.image.clone();
-        let vm_label_key = "vms.codesandbox.io/name".to_string();
+// Gateway API HTTPRoute, used untyped as its CRDs are optional in the cluster
+fn http_route_resource() -> ApiResource {
+    ApiResource::from_gvk_with_plural(
+        &GroupVersionKind::gvk("gateway.networking.k8s.io", "v1", "HTTPRoute"),
+        "httproutes",
+    )
+}
 
-        let mut labels = self.metadata.labels.clone().unwrap_or_default();
-        labels.insert(vm_label_key, vm_name.to_string());
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum VirtualMachineServiceType {
+    #[default]
+    ClusterIP,
+    NodePort,
+    LoadBalancer,
+    None,
+}
 
-        // Create a pod in the ns
-        let pod = Pod {
-            metadata: ObjectMeta {
-                name: Some(vm_name.to_string()),
-                owner_references: Some(vec![owner_reference.clone()]),
-                labels: Some(labels.clone()),
-                ..ObjectMeta::default()
-            },
-            spec: Some(PodSpec {
-                containers: vec![Container {
-                    name: "vm-container".to_string(),
-                    image: Some(image),
-                    ..Container::default()
-                }],
-                ..PodSpec::default()
-            }),
-            ..Pod::default()
-        };
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum ResizePolicy {
+    /// Recreate the VM pod with the new size
+    #[default]
+    Restart,
+    /// Shrink memory through the balloon device when only memory decreases, restart otherwise
+    LiveIfPossible,
+}
 
-        let service = Service {
-            metadata: ObjectMeta {
-                name: Some(vm_name.to_string()),
-                owner_references: Some(vec![owner_reference]),
-                labels: Some(labels.clone()),
-                ..ObjectMeta::default()
-            },
-            spec: Some(k8s_openapi::api::core::v1::ServiceSpec {
-                selector: Some(labels.clone()),
-                ports: Some(vec![k8s_openapi::api::core::v1::ServicePort {
-                    protocol: Some("TCP".to_string()),
-                    port: 80,
-                    target_port: Some(IntOrString::Int(80)),
-                    ..ServicePort::default()
-                }]),
-                ..ServiceSpec::default()
-            }),
-            ..Service::default()
-        };
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum UpdateStrategy {
+    /// Recreate the VM pod as soon as it no longer matches the spec
+    #[default]
+    Recreate,
+    /// Keep the running VM pod until it is deleted, reporting the pending update
+    OnDelete,
+}
 
-        let services: Api<Service> = Api::namespaced(client.clone(), &ns);
-        let existing_service = services.get(vm_name).await;
-        if let Err(kube::Error::Api(ErrorResponse { code, .. })) = existing_service {
-            if code == 404 {
-                let _o = services
-                    .create(&PostParams::default(), &service)
-                    .await
-                    .map_err(Error::KubeError)?;
-            }
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineSchedule {
+    /// Cron expression (minute hour day-of-month month day-of-week) at which the VM is started
+    pub start: Option<String>,
+    /// Cron expression at which the VM is stopped
+    pub stop: Option<String>,
+    /// IANA timezone the expressions are evaluated in, defaults to UTC
+    pub timezone: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum IdleAction {
+    /// Snapshot the VM so it resumes where it left off, VMs without `hibernation` are stopped
+    #[default]
+    Hibernate,
+    /// Shut the VM down, its memory is lost
+    Stop,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum RestartPolicy {
+    /// Recreate the VM pod whenever it exits
+    #[default]
+    Always,
+    /// Recreate the VM pod when it fails, a clean shutdown leaves the VM STOPPED
+    OnFailure,
+    /// Leave the VM STOPPED after a clean shutdown and FAILED after a failure
+    Never,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum DeletionPolicy {
+    /// Delete the children and keep the VM until they are gone
+    #[default]
+    Foreground,
+    /// Delete the children and remove the VM right away, the garbage collector finishes
+    Background,
+    /// Delete the pod and networking but keep the disk and snapshot PVCs, which a VM recreated
+    /// under the same name adopts
+    Orphan,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineBalloon {
+    #[serde(default = "default_balloon_enabled")]
+    pub enabled: bool,
+    /// Memory to reclaim from the guest, changes are applied without a restart
+    #[serde(default)]
+    pub target_mib: u32,
+    /// Interval in seconds the balloon statistics are refreshed at, 0 disables them
+    #[serde(default)]
+    pub stats_polling_interval: u32,
+}
+
+fn default_balloon_enabled() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineVsock {
+    #[serde(default = "default_vsock_enabled")]
+    pub enabled: bool,
+    /// Context id of the guest, 0 to 2 are reserved
+    #[serde(default = "default_guest_cid")]
+    #[schemars(range(min = 3))]
+    pub guest_cid: u32,
+}
+
+fn default_vsock_enabled() -> bool {
+    true
+}
+
+fn default_guest_cid() -> u32 {
+    3
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineGuestAgent {
+    /// vsock port the agent listens on
+    #[serde(default = "default_guest_agent_port")]
+    pub port: u32,
+}
+
+fn default_guest_agent_port() -> u32 {
+    1024
+}
+
+/// Either a static template shipped with Firecracker or a custom one
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineCpuTemplate {
+    /// Static template, it has to match the CPU vendor of the nodes
+    pub name: Option<VirtualMachineStaticCpuTemplate>,
+    /// Custom template in the Firecracker format, takes precedence over `configMapRef`
+    #[serde(default)]
+    #[schemars(schema_with = "preserve_unknown_fields")]
+    pub inline: Option<serde_json::Value>,
+    /// ConfigMap or Secret key holding a custom template as JSON
+    pub config_map_ref: Option<KeySelector>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+pub enum VirtualMachineStaticCpuTemplate {
+    /// Intel Skylake, Cascade Lake and Ice Lake
+    C3,
+    /// Intel Skylake, Cascade Lake and Ice Lake
+    T2,
+    /// T2 allowing snapshots to be restored across Intel Skylake and Cascade Lake
+    T2S,
+    /// Intel Cascade Lake and Ice Lake
+    T2CL,
+    /// AMD Milan
+    T2A,
+    /// ARM Neoverse V1 exposed as a Neoverse N1
+    V1N1,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineMetadata {
+    /// Metadata document, takes precedence over `configMapRef`
+    #[serde(default)]
+    #[schemars(schema_with = "preserve_unknown_fields")]
+    pub inline: Option<serde_json::Value>,
+    /// ConfigMap or Secret key holding the metadata as JSON
+    pub config_map_ref: Option<KeySelector>,
+}
+
+pub(crate) fn preserve_unknown_fields(
+    _: &mut schemars::gen::SchemaGenerator,
+) -> schemars::schema::Schema {
+    serde_json::from_value(json!({
+        "type": "object",
+        "nullable": true,
+        "x-kubernetes-preserve-unknown-fields": true,
+    }))
+    .unwrap()
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineNetworkInterface {
+    /// Interface id, unique within the VM
+    pub name: String,
+    /// Whether the interface is connected to the pod network. Only one interface can be primary,
+    /// if none is marked the first one is.
+    #[serde(default)]
+    pub primary: bool,
+    pub guest_mac: Option<String>,
+    /// Limits traffic received by the guest
+    pub ingress_rate_limiter: Option<RateLimiter>,
+    /// Limits traffic sent by the guest
+    pub egress_rate_limiter: Option<RateLimiter>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineDrive {
+    /// Drive id, unique within the VM. A DNS-1123 label, as it also names the pod volume of the
+    /// drive.
+    #[schemars(
+        length(min = 1, max = 57),
+        regex(pattern = r"^[a-z0-9]([-a-z0-9]*[a-z0-9])?$")
+    )]
+    pub name: String,
+    /// Image containing the disk at `/disk.img`
+    pub image: Option<String>,
+    /// PVC containing the disk at `disk.img`
+    pub persistent_volume_claim: Option<String>,
+    #[serde(default)]
+    pub read_only: bool,
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+/// Firecracker rate limiter made of token buckets
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimiter {
+    /// Limits bytes per refill period
+    pub bandwidth: Option<TokenBucket>,
+    /// Limits operations per refill period
+    pub ops: Option<TokenBucket>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenBucket {
+    pub size: u64,
+    pub one_time_burst: Option<u64>,
+    pub refill_time_ms: u64,
+}
+
+impl From<&RateLimiter> for firecracker::RateLimiter {
+    fn from(value: &RateLimiter) -> Self {
+        firecracker::RateLimiter {
+            bandwidth: value.bandwidth.as_ref().map(Into::into),
+            ops: value.ops.as_ref().map(Into::into),
         }
+    }
+}
 
-        let pods: Api<Pod> = Api::namespaced(client.clone(), &ns);
-        let existing_pod = pods.get(vm_name).await;
-        if let Err(kube::Error::Api(ErrorResponse { code, .. })) = existing_pod {
-            if code == 404 {
-                let _o = pods
-                    .create(&PostParams::default(), &pod)
-                    .await
-                    .map_err(Error::KubeError)?;
-            }
+impl From<&TokenBucket> for firecracker::TokenBucket {
+    fn from(value: &TokenBucket) -> Self {
+        firecracker::TokenBucket {
+            size: value.size,
+            one_time_burst: value.one_time_burst,
+            refill_time: value.refill_time_ms,
         }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineScheduling {
+    pub node_selector: Option<BTreeMap<String, String>>,
+    pub tolerations: Option<Vec<Toleration>>,
+    pub affinity: Option<Affinity>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineSshKeys {
+    /// Public keys in authorized_keys format
+    #[serde(default)]
+    pub inline: Vec<String>,
+    /// Secret keys holding one or more public keys in authorized_keys format
+    #[serde(default)]
+    pub secret_refs: Vec<SecretKeySelector>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineCloudInit {
+    /// ConfigMap or Secret key holding the cloud-init user-data
+    pub user_data_ref: KeySelector,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum KeySelectorKind {
+    #[default]
+    ConfigMap,
+    Secret,
+}
+
+/// Selects a key of a ConfigMap or Secret in the VM namespace
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+pub struct KeySelector {
+    #[serde(default)]
+    pub kind: KeySelectorKind,
+    pub name: String,
+    pub key: String,
+}
 
-        if let Ok(Pod {
-            status:
-                Some(PodStatus {
-                    container_statuses: Some(container_statuses),
-                    ..
+impl KeySelector {
+    // Volume projecting the selected key to `path`
+    fn volume(&self, volume_name: &str, path: &str) -> Volume {
+        let items = Some(vec![KeyToPath {
+            key: self.key.clone(),
+            path: path.to_string(),
+            ..KeyToPath::default()
+        }]);
+        match self.kind {
+            KeySelectorKind::ConfigMap => Volume {
+                name: volume_name.to_string(),
+                config_map: Some(ConfigMapVolumeSource {
+                    name: Some(self.name.clone()),
+                    items,
+                    ..ConfigMapVolumeSource::default()
                 }),
-            ..
-        }) = existing_pod
-        {
-            let all_started = container_statuses
-                .into_iter()
-                .all(|cs| cs.started.unwrap_or(false));
-
-            if all_started {
-                self.update_status(
-                    ctx.clone(),
-                    VirtualMachineStatus {
-                        state: VirtualMachineCurrentState::STARTED,
-                    },
-                )
-                .await?;
-            }
-        } else {
-            self.update_status(
-                ctx.clone(),
-                VirtualMachineStatus {
-                    state: VirtualMachineCurrentState::STARTING,
-                },
-            )
-            .await?;
+                ..Volume::default()
+            },
+            KeySelectorKind::Secret => Volume {
+                name: volume_name.to_string(),
+                secret: Some(SecretVolumeSource {
+                    secret_name: Some(self.name.clone()),
+                    items,
+                    ..SecretVolumeSource::default()
+                }),
+                ..Volume::default()
+            },
         }
+    }
 
-        Ok(())
+    // Fetch the selected value
+    async fn resolve(&self, client: Client, ns: &str) -> Result<String> {
+        let value = match self.kind {
+            KeySelectorKind::ConfigMap => {
+                let config_maps: Api<ConfigMap> = Api::namespaced(client, ns);
+                config_maps
+                    .get_opt(&self.name)
+                    .await
+                    .map_err(Error::KubeError)?
+                    .and_then(|cm| cm.data)
+                    .and_then(|mut data| data.remove(&self.key))
+            }
+            KeySelectorKind::Secret => {
+                let secrets: Api<Secret> = Api::namespaced(client, ns);
+                secrets
+                    .get_opt(&self.name)
+                    .await
+                    .map_err(Error::KubeError)?
+                    .and_then(|secret| secret.data)
+                    .and_then(|mut data| data.remove(&self.key))
+                    .map(|bytes| String::from_utf8_lossy(&bytes.0).into_owned())
+            }
+        };
+        value.ok_or_else(|| {
+            Error::InvalidSpec(format!(
+                "{:?} {} has no key {}",
+                self.kind, self.name, self.key
+            ))
+        })
     }
+}
 
-    async fn stop(&self, ctx: Arc<Context>) -> Result<()> {
-        let client: Client = ctx.client.clone();
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineStorage {
+    /// Size of the root disk, e.g. `10Gi`
+    pub size: String,
+    pub storage_class_name: Option<String>,
+    /// Keep the root disk when the VM is stopped
+    #[serde(default = "default_persistent")]
+    pub persistent: bool,
+}
 
-        let ns = self.namespace().unwrap();
-        let name = self.name_any();
-        info!("Creating VirtualMachine {} in {}", name, ns);
-        let vm_name = self.metadata.name.as_ref().unwrap();
+fn default_persistent() -> bool {
+    true
+}
 
-        let pods: Api<Pod> = Api::namespaced(client.clone(), &ns);
-        let existing_pod = pods.get(vm_name).await;
-        if existing_pod.is_ok() {
-            let _o = pods
-                .delete(vm_name, &Default::default())
-                .await
-                .map_err(Error::KubeError)?;
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineOvercommit {
+    /// Percentage of the vCPUs requested by the pod, the limit stays at the full count
+    #[serde(default = "default_request_percent")]
+    #[schemars(range(min = 1, max = 100))]
+    pub cpu_request_percent: u32,
+    /// Percentage of the memory requested by the pod, the limit stays at the full size
+    #[serde(default = "default_request_percent")]
+    #[schemars(range(min = 1, max = 100))]
+    pub memory_request_percent: u32,
+}
+
+fn default_request_percent() -> u32 {
+    100
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineSource {
+    /// VirtualMachineSnapshot in the same namespace to restore on first boot
+    pub snapshot_ref: Option<SnapshotReference>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+pub struct SnapshotReference {
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineBootSource {
+    /// Image containing the guest kernel at `/vmlinux`, defaults to the kernel of the runtime
+    pub kernel_image: Option<String>,
+    /// Kernel command line
+    #[serde(default = "default_kernel_args")]
+    pub kernel_args: String,
+    /// Image containing the guest root filesystem
+    #[schemars(length(min = 1))]
+    pub rootfs_image: String,
+}
+
+fn default_kernel_args() -> String {
+    "console=ttyS0 reboot=k panic=1 pci=off".to_string()
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+pub enum VirtualMachinePortProtocol {
+    #[default]
+    TCP,
+    UDP,
+    SCTP,
+}
+
+impl VirtualMachineServiceType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            VirtualMachineServiceType::ClusterIP => "ClusterIP",
+            VirtualMachineServiceType::NodePort => "NodePort",
+            VirtualMachineServiceType::LoadBalancer => "LoadBalancer",
+            VirtualMachineServiceType::None => "None",
         }
+    }
+}
 
-        let services: Api<Service> = Api::namespaced(client, &ns);
-        let existing_service = services.get(vm_name).await;
-        if existing_service.is_ok() {
-            let _o = services
-                .delete(vm_name, &Default::default())
-                .await
-                .map_err(Error::KubeError)?;
+impl VirtualMachinePortProtocol {
+    fn as_str(&self) -> &'static str {
+        match self {
+            VirtualMachinePortProtocol::TCP => "TCP",
+            VirtualMachinePortProtocol::UDP => "UDP",
+            VirtualMachinePortProtocol::SCTP => "SCTP",
         }
+    }
+}
 
-        info!("Stopping VirtualMachine {}", self.name_any());
-        Ok(())
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachinePort {
+    pub name: String,
+    /// Port exposed on the Service
+    pub port: i32,
+    /// Port inside the VM, defaults to `port`
+    pub target_port: Option<i32>,
+    #[serde(default)]
+    pub protocol: VirtualMachinePortProtocol,
+}
+
+/// Check of the guest run by the kubelet, exactly one of `tcpSocket`, `httpGet` and `exec` must be
+/// set
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineProbe {
+    /// Port inside the VM that must accept TCP connections
+    pub tcp_socket: Option<VirtualMachineTcpSocketProbe>,
+    /// HTTP endpoint inside the VM that must answer with a 2xx or 3xx status
+    pub http_get: Option<VirtualMachineHttpGetProbe>,
+    /// Command run in the vm-container that must exit with 0
+    pub exec: Option<VirtualMachineExecProbe>,
+    #[schemars(range(min = 0))]
+    pub initial_delay_seconds: Option<i32>,
+    #[schemars(range(min = 1))]
+    pub period_seconds: Option<i32>,
+    #[schemars(range(min = 1))]
+    pub timeout_seconds: Option<i32>,
+    /// Failed checks in a row after which the probe fails
+    #[schemars(range(min = 1))]
+    pub failure_threshold: Option<i32>,
+    /// Successful checks in a row after which the probe succeeds again
+    #[schemars(range(min = 1))]
+    pub success_threshold: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineTcpSocketProbe {
+    pub port: i32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineHttpGetProbe {
+    pub port: i32,
+    #[serde(default = "default_probe_path")]
+    pub path: String,
+    #[serde(default)]
+    pub scheme: VirtualMachineProbeScheme,
+}
+
+fn default_probe_path() -> String {
+    "/".to_string()
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+pub enum VirtualMachineProbeScheme {
+    #[default]
+    HTTP,
+    HTTPS,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineExecProbe {
+    #[schemars(length(min = 1))]
+    pub command: Vec<String>,
+}
+
+impl VirtualMachineProbe {
+    // Container probe of the check, `field` names it in the error of an invalid spec
+    fn probe(&self, field: &str) -> Result<Probe> {
+        let handlers = [
+            self.tcp_socket.is_some(),
+            self.http_get.is_some(),
+            self.exec.is_some(),
+        ];
+        if handlers.into_iter().filter(|set| *set).count() != 1 {
+            return Err(Error::InvalidSpec(format!(
+                "{field} must set exactly one of tcpSocket, httpGet or exec"
+            )));
+        }
+        Ok(Probe {
+            tcp_socket: self.tcp_socket.as_ref().map(|t| TCPSocketAction {
+                port: IntOrString::Int(t.port),
+                ..TCPSocketAction::default()
+            }),
+            http_get: self.http_get.as_ref().map(|h| HTTPGetAction {
+                port: IntOrString::Int(h.port),
+                path: Some(h.path.clone()),
+                scheme: Some(
+                    match h.scheme {
+                        VirtualMachineProbeScheme::HTTP => "HTTP",
+                        VirtualMachineProbeScheme::HTTPS => "HTTPS",
+                    }
+                    .to_string(),
+                ),
+                ..HTTPGetAction::default()
+            }),
+            exec: self.exec.as_ref().map(|e| ExecAction {
+                command: Some(e.command.clone()),
+            }),
+            initial_delay_seconds: self.initial_delay_seconds,
+            period_seconds: self.period_seconds,
+            timeout_seconds: self.timeout_seconds,
+            failure_threshold: self.failure_threshold,
+            success_threshold: self.success_threshold,
+            ..Probe::default()
+        })
     }
-    async fn hibernate(&self, _ctx: Arc<Context>) -> Result<()> {
-        info!("Hibernating VirtualMachine {}", self.name_any());
-        Ok(())
+}
+
+/// Controller-wide policy for how VM pods get access to KVM and are sized
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PodPolicy {
+    /// Extended resource of a KVM device plugin, /dev/kvm is mounted from the host when unset
+    pub kvm_device_resource: Option<String>,
+    /// Run the vm-container privileged instead of with a minimal set of capabilities
+    pub privileged: bool,
+    /// Memory requested for the VMM on top of the guest memory
+    pub memory_overhead_mib: u32,
+}
+
+// Size the VM pod was booted with, read from its annotations
+fn boot_sizing(pod: &Pod) -> Option<(u32, u32)> {
+    let annotations = pod.metadata.annotations.as_ref()?;
+    let cpus = annotations.get(CPUS_ANNOTATION)?.parse().ok()?;
+    let memory_mib = annotations.get(MEMORY_MIB_ANNOTATION)?.parse().ok()?;
+    Some((cpus, memory_mib))
+}
+
+// Storage quantity in MiB rounded up, None for fractions or units the controller does not write
+fn quantity_mib(quantity: &Quantity) -> Option<u64> {
+    let split = quantity
+        .0
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(quantity.0.len());
+    let (value, unit) = quantity.0.split_at(split);
+    let bytes: u64 = match unit {
+        "" => 1,
+        "k" => 1_000,
+        "Ki" => 1 << 10,
+        "M" => 1_000_000,
+        "Mi" => 1 << 20,
+        "G" => 1_000_000_000,
+        "Gi" => 1 << 30,
+        "T" => 1_000_000_000_000,
+        "Ti" => 1 << 40,
+        _ => return None,
+    };
+    Some((value.parse::<u64>().ok()? * bytes).div_ceil(1 << 20))
+}
+
+// Stable hash of a rendered child spec or of the inputs it is rendered from
+pub(crate) fn spec_hash<T: Serialize>(spec: &T) -> String {
+    let json = serde_json::to_vec(spec).unwrap_or_default();
+    format!("{:x}", Sha256::digest(json))
+}
+
+// Whether the Service was edited or rendered from an older spec. Only fields set by the
+// controller are compared, as the API server allocates ports and cluster IPs.
+fn service_drift(desired: &Service, existing: &Service) -> bool {
+    if existing.annotations().get(SPEC_HASH_ANNOTATION)
+        != desired.annotations().get(SPEC_HASH_ANNOTATION)
+    {
+        return true;
+    }
+    if desired
+        .labels()
+        .iter()
+        .any(|(k, v)| existing.labels().get(k) != Some(v))
+        || desired
+            .annotations()
+            .iter()
+            .any(|(k, v)| existing.annotations().get(k) != Some(v))
+    {
+        return true;
+    }
+    let (Some(desired), Some(existing)) = (&desired.spec, &existing.spec) else {
+        return true;
+    };
+    let ports = |spec: &ServiceSpec| {
+        spec.ports
+            .iter()
+            .flatten()
+            .map(|p| {
+                (
+                    p.name.clone(),
+                    p.protocol.clone(),
+                    p.port,
+                    p.target_port.clone(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    desired.type_ != existing.type_
+        || desired.selector != existing.selector
+        || ports(desired) != ports(existing)
+}
+
+// First field of the running pod that differs from the desired one. Only fields set by the
+// controller are compared, as the API server and admission fill in the rest.
+fn pod_drift(desired: &Pod, existing: &Pod) -> Option<String> {
+    let (Some(desired), Some(existing)) = (&desired.spec, &existing.spec) else {
+        return None;
+    };
+    let containers = |spec: &PodSpec| {
+        spec.init_containers
+            .iter()
+            .flatten()
+            .chain(&spec.containers)
+            .map(|c| {
+                (
+                    c.name.clone(),
+                    c.image.clone(),
+                    c.command.clone(),
+                    c.env.clone(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let (desired_containers, existing_containers) = (containers(desired), containers(existing));
+    if desired_containers.len() != existing_containers.len() {
+        return Some("containers".to_string());
+    }
+    for (d, e) in desired_containers.iter().zip(&existing_containers) {
+        if d.0 != e.0 {
+            return Some("containers".to_string());
+        }
+        if d.1 != e.1 {
+            return Some(format!("image of container {}", d.0));
+        }
+        if d.2 != e.2 {
+            return Some(format!("command of container {}", d.0));
+        }
+        if d.3 != e.3 {
+            return Some(format!("env of container {}", d.0));
+        }
+    }
+
+    let volume_names = |spec: &PodSpec| {
+        spec.volumes
+            .iter()
+            .flatten()
+            .map(|v| v.name.clone())
+            .collect::<Vec<_>>()
+    };
+    if volume_names(desired) != volume_names(existing) {
+        return Some("volumes".to_string());
+    }
+    if desired.runtime_class_name.is_some()
+        && desired.runtime_class_name != existing.runtime_class_name
+    {
+        return Some("runtimeClassName".to_string());
+    }
+    if desired.service_account_name.is_some()
+        && desired.service_account_name != existing.service_account_name
+    {
+        return Some("serviceAccountName".to_string());
+    }
+    if desired.image_pull_secrets != existing.image_pull_secrets {
+        return Some("imagePullSecrets".to_string());
+    }
+    if desired.node_selector.is_some() && desired.node_selector != existing.node_selector {
+        return Some("nodeSelector".to_string());
+    }
+    None
+}
+
+// Why the containers of a pod are not running, e.g. `ErrImagePull: <message>`, or why it is not
+// scheduled
+fn pod_failure_reason(pod: &Pod) -> Option<String> {
+    let status = pod.status.as_ref()?;
+    let waiting = status
+        .init_container_statuses
+        .iter()
+        .chain(status.container_statuses.iter())
+        .flatten()
+        .filter_map(|cs| cs.state.as_ref()?.waiting.as_ref())
+        .find(|w| w.reason.as_deref().is_some_and(|r| r != "PodInitializing"));
+    if let Some(waiting) = waiting {
+        let reason = waiting.reason.clone().unwrap_or_default();
+        return Some(match &waiting.message {
+            Some(message) => format!("{reason}: {message}"),
+            None => reason,
+        });
+    }
+    status
+        .conditions
+        .iter()
+        .flatten()
+        .find(|c| c.type_ == "PodScheduled" && c.status == "False")
+        .and_then(|c| c.message.clone().or_else(|| c.reason.clone()))
+}
+
+// Container of the pod the kubelet backs off restarting, with its restarts and last termination
+fn crash_looping_container(pod: &Pod) -> Option<String> {
+    let status = pod.status.as_ref()?;
+    let cs = status
+        .init_container_statuses
+        .iter()
+        .chain(status.container_statuses.iter())
+        .flatten()
+        .find(|cs| {
+            cs.state
+                .as_ref()
+                .and_then(|s| s.waiting.as_ref())
+                .and_then(|w| w.reason.as_deref())
+                == Some("CrashLoopBackOff")
+        })?;
+    let last_exit = cs
+        .last_state
+        .as_ref()
+        .and_then(|s| s.terminated.as_ref())
+        .map(|t| {
+            format!(
+                ", last exited with code {}{}",
+                t.exit_code,
+                termination_detail(t)
+            )
+        })
+        .unwrap_or_default();
+    Some(format!(
+        "Container {} restarted {} times{last_exit}",
+        cs.name, cs.restart_count
+    ))
+}
+
+// Reason and message of a terminated container, e.g. ` (Error): kernel panic`
+fn termination_detail(terminated: &ContainerStateTerminated) -> String {
+    let reason = terminated
+        .reason
+        .as_ref()
+        .map(|r| format!(" ({r})"))
+        .unwrap_or_default();
+    match terminated.message.as_deref().map(str::trim) {
+        Some(message) if !message.is_empty() => format!("{reason}: {message}"),
+        _ => reason,
+    }
+}
+
+fn default_cpus() -> u32 {
+    1
+}
+
+fn default_memory_mib() -> u32 {
+    512
+}
+
+fn default_ports() -> Vec<VirtualMachinePort> {
+    vec![VirtualMachinePort {
+        name: "http".to_string(),
+        port: 80,
+        target_port: None,
+        protocol: VirtualMachinePortProtocol::TCP,
+    }]
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineStatus {
+    pub state: VirtualMachineCurrentState,
+    /// The metadata.generation last processed by the controller
+    pub observed_generation: Option<i64>,
+    /// Number of reconciles in a row that failed
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    /// Number of times the VM pod was recreated after exiting since the VM was started
+    #[serde(default)]
+    pub restart_count: u32,
+    /// When the exited VM pod is recreated, after a backoff growing with `restartCount`
+    pub next_restart_time: Option<DateTime<Utc>>,
+    /// Time of the last start or stop of `spec.schedule` that was applied
+    pub last_schedule_time: Option<DateTime<Utc>>,
+    /// Latest activity reported for the VM
+    pub last_activity_time: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub conditions: Vec<VirtualMachineCondition>,
+    /// IP of the pod the VM is running in
+    #[serde(rename = "podIP")]
+    pub pod_ip: Option<String>,
+    /// Node the VM pod is scheduled on
+    #[serde(rename = "nodeName")]
+    pub node_name: Option<String>,
+    /// Cluster IP of the Service exposing the VM
+    #[serde(rename = "serviceClusterIP")]
+    pub service_cluster_ip: Option<String>,
+    /// Node ports allocated for `spec.ports` when the Service is a NodePort or LoadBalancer
+    #[serde(default)]
+    pub node_ports: Vec<NodePortStatus>,
+    /// IPs or hostnames of the load balancer exposing the VM
+    #[serde(default)]
+    pub load_balancer_ingress: Vec<String>,
+    /// URL the VM is reachable at from `spec.networking.hostname` or the Ingress host
+    pub url: Option<String>,
+    /// Location of the snapshot taken when the VM was hibernated
+    pub snapshot: Option<SnapshotLocation>,
+    /// Attachment results of `spec.drives`
+    #[serde(default)]
+    pub drives: Vec<DriveStatus>,
+    /// Balloon settings applied to the running VM
+    pub balloon: Option<VirtualMachineBalloon>,
+    /// Running time metered for billing
+    #[serde(default)]
+    pub usage: VirtualMachineUsage,
+    /// Time of the last change of `state`
+    pub last_transition_time: Option<DateTime<Utc>>,
+    /// Time the VM last became STARTED
+    pub started_at: Option<DateTime<Utc>>,
+    /// Time the VM last became STOPPED, HIBERNATED or FAILED
+    pub stopped_at: Option<DateTime<Utc>>,
+    /// Most recent state transitions, oldest first
+    #[serde(default)]
+    pub history: Vec<VirtualMachineStateTransition>,
+    /// What the guest agent last reported
+    pub guest_agent: Option<VirtualMachineGuestAgentStatus>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineGuestAgentStatus {
+    pub os_info: Option<agent::OsInfo>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineStateTransition {
+    pub from: VirtualMachineCurrentState,
+    pub to: VirtualMachineCurrentState,
+    pub timestamp: DateTime<Utc>,
+    /// Reason of the condition explaining the transition
+    pub reason: Option<String>,
+}
+
+// State transitions kept in the status
+const STATUS_HISTORY_LENGTH: usize = 10;
+
+/// Running time of the VM, accumulated across restarts of the VM and of the controller
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineUsage {
+    /// Seconds the VM spent STARTED
+    #[serde(default)]
+    pub running_seconds: u64,
+    /// Number of times the VM became STARTED
+    #[serde(default)]
+    pub boots: u64,
+    /// Time up to which the running time is accounted, only set while the VM is STARTED
+    pub accounted_at: Option<DateTime<Utc>>,
+    /// Usage per UTC day over the last 90 days, oldest first
+    #[serde(default)]
+    pub daily: Vec<VirtualMachineDailyUsage>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineDailyUsage {
+    pub date: NaiveDate,
+    pub running_seconds: u64,
+    pub boots: u64,
+}
+
+// Days of daily usage kept in the status
+const USAGE_RETENTION_DAYS: i64 = 90;
+
+impl VirtualMachineUsage {
+    // Add the time spent STARTED since the last accounting and count the boots, returning the
+    // seconds added. Sub-second remainders are carried over to the next accounting.
+    pub fn account(
+        &mut self,
+        previous: &VirtualMachineCurrentState,
+        current: &VirtualMachineCurrentState,
+        now: DateTime<Utc>,
+    ) -> u64 {
+        let started = VirtualMachineCurrentState::STARTED;
+        let mut added = 0;
+        match self.accounted_at.filter(|_| *previous == started) {
+            Some(accounted_at) => {
+                added = (now - accounted_at).num_seconds().max(0) as u64;
+                self.add_running(accounted_at, added);
+                self.accounted_at = Some(accounted_at + chrono::Duration::seconds(added as i64));
+            }
+            None if *current == started => {
+                self.boots += 1;
+                self.day(now.date_naive()).boots += 1;
+                self.accounted_at = Some(now);
+            }
+            None => {}
+        }
+        if *current != started {
+            self.accounted_at = None;
+        }
+        let oldest = now.date_naive() - chrono::Duration::days(USAGE_RETENTION_DAYS - 1);
+        self.daily.retain(|d| d.date >= oldest);
+        added
+    }
+
+    // Whether none of the days kept are within the retention anymore
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        let oldest = now.date_naive() - chrono::Duration::days(USAGE_RETENTION_DAYS - 1);
+        self.daily.last().is_none_or(|d| d.date < oldest)
+    }
+
+    // Add `seconds` of running time starting at `from`, split across the days it spans
+    fn add_running(&mut self, mut from: DateTime<Utc>, seconds: u64) {
+        self.running_seconds += seconds;
+        let mut remaining = seconds as i64;
+        while remaining > 0 {
+            let date = from.date_naive();
+            let midnight = date.succ_opt().unwrap().and_time(NaiveTime::MIN).and_utc();
+            let chunk = remaining.min((midnight - from).num_seconds().max(1));
+            self.day(date).running_seconds += chunk as u64;
+            from += chrono::Duration::seconds(chunk);
+            remaining -= chunk;
+        }
+    }
+
+    fn day(&mut self, date: NaiveDate) -> &mut VirtualMachineDailyUsage {
+        let index = match self.daily.binary_search_by_key(&date, |d| d.date) {
+            Ok(index) => index,
+            Err(index) => {
+                let day = VirtualMachineDailyUsage {
+                    date,
+                    running_seconds: 0,
+                    boots: 0,
+                };
+                self.daily.insert(index, day);
+                index
+            }
+        };
+        &mut self.daily[index]
+    }
+
+    // Running seconds and boots over the days from `from` to `to`, both included
+    pub fn between(&self, from: Option<NaiveDate>, to: Option<NaiveDate>) -> (u64, u64) {
+        self.daily
+            .iter()
+            .filter(|d| from.is_none_or(|from| d.date >= from) && to.is_none_or(|to| d.date <= to))
+            .fold((0, 0), |(seconds, boots), d| {
+                (seconds + d.running_seconds, boots + d.boots)
+            })
+    }
+}
+
+/// Usage of a deleted VM, kept in a ConfigMap of its namespace
+pub(crate) struct UsageRecord {
+    pub config_map: String,
+    pub namespace: String,
+    pub vm_name: String,
+    pub usage: VirtualMachineUsage,
+}
+
+// Usage records of deleted VMs, records that cannot be read are skipped
+pub(crate) async fn usage_records(configmaps: &Api<ConfigMap>) -> Result<Vec<UsageRecord>> {
+    let params = ListParams::default().labels(&format!("{USAGE_RECORD_LABEL}=true"));
+    let list = configmaps.list(&params).await.map_err(Error::KubeError)?;
+    Ok(list
+        .items
+        .into_iter()
+        .filter_map(|cm| {
+            let data = cm.data.as_ref()?;
+            Some(UsageRecord {
+                usage: serde_json::from_str(data.get("usage")?).ok()?,
+                vm_name: data.get("name")?.clone(),
+                namespace: cm.namespace()?,
+                config_map: cm.name_any(),
+            })
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NodePortStatus {
+    pub name: String,
+    pub node_port: i32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DriveStatus {
+    pub name: String,
+    pub attached: bool,
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotLocation {
+    /// PVC holding the snapshot files
+    pub pvc_name: String,
+    /// Path of the Firecracker VM state file within the PVC
+    pub snapshot_path: String,
+    /// Path of the guest memory file within the PVC
+    pub mem_file_path: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub enum VirtualMachineConditionType {
+    /// The VM is booted and reachable through its Service
+    Ready,
+    /// The VM pod has been bound to a node
+    PodScheduled,
+    /// All containers of the VM pod have started
+    BootCompleted,
+    /// The last reconcile of the VM failed
+    Failed,
+    /// The VM is being resized to match `cpus` and `memoryMib`
+    Resizing,
+    /// The VM pod is being replaced to match the spec
+    Updating,
+    /// The VM keeps exiting shortly after starting, or a container of its pod is crash-looping
+    Degraded,
+    /// The agent in the guest answered its last heartbeat
+    GuestAgentConnected,
+    /// The node of the VM is being drained and the PodDisruptionBudget refuses to evict the VM
+    EvictionBlocked,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum ConditionStatus {
+    True,
+    False,
+    #[default]
+    Unknown,
+}
+
+impl From<bool> for ConditionStatus {
+    fn from(value: bool) -> Self {
+        if value {
+            ConditionStatus::True
+        } else {
+            ConditionStatus::False
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineCondition {
+    #[serde(rename = "type")]
+    pub type_: VirtualMachineConditionType,
+    pub status: ConditionStatus,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub last_transition_time: Option<DateTime<Utc>>,
+}
+
+impl VirtualMachineStatus {
+    // Set a condition, only bumping lastTransitionTime when its status flips
+    pub fn set_condition(
+        &mut self,
+        type_: VirtualMachineConditionType,
+        status: impl Into<ConditionStatus>,
+        reason: &str,
+        message: Option<String>,
+    ) {
+        let status = status.into();
+        match self.conditions.iter_mut().find(|c| c.type_ == type_) {
+            Some(condition) => {
+                if condition.status != status {
+                    condition.last_transition_time = Some(Utc::now());
+                }
+                condition.status = status;
+                condition.reason = Some(reason.to_string());
+                condition.message = message;
+            }
+            None => self.conditions.push(VirtualMachineCondition {
+                type_,
+                status,
+                reason: Some(reason.to_string()),
+                message,
+                last_transition_time: Some(Utc::now()),
+            }),
+        }
+    }
+
+    pub fn condition(
+        &self,
+        type_: VirtualMachineConditionType,
+    ) -> Option<&VirtualMachineCondition> {
+        self.conditions.iter().find(|c| c.type_ == type_)
+    }
+
+    // Condition explaining the current state, the failure for a FAILED VM and readiness otherwise
+    pub fn state_condition(&self) -> Option<&VirtualMachineCondition> {
+        self.condition(VirtualMachineConditionType::Failed)
+            .filter(|c| {
+                self.state == VirtualMachineCurrentState::FAILED
+                    && c.status == ConditionStatus::True
+            })
+            .or(self.condition(VirtualMachineConditionType::Ready))
+    }
+
+    // Degraded condition of a crash-looping VM
+    pub fn degraded(&self) -> Option<&VirtualMachineCondition> {
+        self.condition(VirtualMachineConditionType::Degraded)
+            .filter(|c| c.status == ConditionStatus::True)
+    }
+
+    // Record the transition from `from` to the current state, dropping the oldest ones from the
+    // history
+    pub fn record_transition(
+        &mut self,
+        from: VirtualMachineCurrentState,
+        reason: Option<String>,
+        timestamp: DateTime<Utc>,
+    ) {
+        self.last_transition_time = Some(timestamp);
+        match self.state {
+            VirtualMachineCurrentState::STARTED => self.started_at = Some(timestamp),
+            VirtualMachineCurrentState::STOPPED
+            | VirtualMachineCurrentState::HIBERNATED
+            | VirtualMachineCurrentState::FAILED => self.stopped_at = Some(timestamp),
+            _ => {}
+        }
+        self.history.push(VirtualMachineStateTransition {
+            from,
+            to: self.state.clone(),
+            timestamp,
+            reason,
+        });
+        let excess = self.history.len().saturating_sub(STATUS_HISTORY_LENGTH);
+        self.history.drain(..excess);
+    }
+}
+
+// Latest occurrence of `cron` after `since` and up to `now`. The window searched grows back from
+// `now`, so a schedule that last fired long ago is not walked occurrence by occurrence.
+fn latest_occurrence(cron: &Cron, since: DateTime<Tz>, now: DateTime<Tz>) -> Option<DateTime<Tz>> {
+    let mut window = chrono::Duration::minutes(1);
+    loop {
+        let start = (now - window).max(since);
+        if let Some(latest) = cron.iter_after(start).take_while(|t| *t <= now).last() {
+            return Some(latest);
+        }
+        if start == since {
+            return None;
+        }
+        window = window * 2;
+    }
+}
+
+// Addresses of the ready activator pods, from the Endpoints of the activator Service
+pub(crate) fn activator_addresses(endpoints: Option<Endpoints>) -> Vec<String> {
+    endpoints
+        .and_then(|e| e.subsets)
+        .into_iter()
+        .flatten()
+        .flat_map(|s| s.addresses.unwrap_or_default())
+        .map(|a| a.ip)
+        .collect()
+}
+
+impl VirtualMachine {
+    // Reconcile (for non-finalizer related changes)
+    pub async fn reconcile(&self, ctx: Arc<Context>) -> Result<Action> {
+        let now = Utc::now();
+        if let Some((fired_at, state)) = self.due_schedule(now)? {
+            self.apply_schedule(ctx.clone(), fired_at, state).await?;
+            // Continue once the change has reached the cache
+            return Ok(Action::requeue(Duration::from_secs(1)));
+        }
+        if let Some(idle_deadline) = self.idle_deadline().filter(|d| *d <= now) {
+            self.apply_idle_action(ctx.clone(), idle_deadline).await?;
+            return Ok(Action::requeue(Duration::from_secs(1)));
+        }
+        // Sleeping VMs are woken up by the next start or stop of the schedule, and idle ones are
+        // checked again once their timeout passes
+        let next_scheduled = [
+            self.next_schedule(now)?,
+            self.idle_deadline(),
+            self.boot_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .and_then(|t| (t - now).to_std().ok());
+        let requeue_interval = next_scheduled
+            .map_or(ctx.config.controller.requeue_interval(), |d| {
+                d.min(ctx.config.controller.requeue_interval())
+            });
+
+        if self.is_settled() {
+            debug!("VirtualMachine {} is settled, skipping", self.name_any());
+            return Ok(Action::requeue(requeue_interval));
+        }
+        if self.has_given_up() {
+            debug!(
+                "VirtualMachine {} has failed, waiting for a spec change",
+                self.name_any()
+            );
+            return Ok(next_scheduled.map_or(Action::await_change(), Action::requeue));
+        }
+
+        let mut status = self.status.clone().unwrap_or_default();
+        let previous_state = status.state.clone();
+        status.last_activity_time = self.last_reported_activity();
+        // A spec change after giving up gets a fresh set of attempts
+        if status.observed_generation != self.metadata.generation {
+            status.consecutive_failures = 0;
+        }
+
+        let result = match self.spec.state {
+            VirtualMachineDesiredState::STOPPED => self.stop(ctx.clone(), &mut status).await,
+            VirtualMachineDesiredState::STARTED => self.start(ctx.clone(), &mut status).await,
+            VirtualMachineDesiredState::HIBERNATED => {
+                self.hibernate(ctx.clone(), &mut status).await
+            }
+        };
+
+        match &result {
+            Ok(()) => {
+                status.consecutive_failures = 0;
+                status.set_condition(
+                    VirtualMachineConditionType::Failed,
+                    false,
+                    "ReconcileSucceeded",
+                    None,
+                )
+            }
+            Err(e) => self.record_failure(ctx.clone(), &mut status, e).await,
+        }
+        // A VM still STARTING past its boot timeout is given up on, like one that keeps failing
+        let boot_timed_out = self.boot_deadline().is_some_and(|d| d <= Utc::now());
+        if result.is_ok() && status.state == VirtualMachineCurrentState::STARTING && boot_timed_out
+        {
+            self.fail_boot(ctx.clone(), &mut status).await?;
+        }
+        if status.state != previous_state {
+            let reason = status.state_condition().and_then(|c| c.reason.clone());
+            status.record_transition(previous_state.clone(), reason, Utc::now());
+        }
+        let running_seconds = status
+            .usage
+            .account(&previous_state, &status.state, Utc::now());
+        let state = status.state.clone();
+        // Notified once the status is written, so a failed write does not page twice
+        let failed_message = (status.state == VirtualMachineCurrentState::FAILED
+            && previous_state != VirtualMachineCurrentState::FAILED)
+            .then(|| status.state_condition().and_then(|c| c.message.clone()));
+        let crash_loop_message = match (
+            status.degraded(),
+            self.status.as_ref().and_then(|s| s.degraded()),
+        ) {
+            (Some(degraded), None) => Some(degraded.message.clone()),
+            _ => None,
+        };
+        let next_restart = status.next_restart_time;
+        // Transitional states are checked often so the status converges quickly, except while
+        // waiting out the restart backoff
+        let transitioning = matches!(
+            status.state,
+            VirtualMachineCurrentState::STARTING
+                | VirtualMachineCurrentState::STOPPING
+                | VirtualMachineCurrentState::HIBERNATING
+        );
+        let requeue_interval = if transitioning && next_restart.is_none() {
+            requeue_interval.min(ctx.config.controller.transition_requeue_interval())
+        } else {
+            requeue_interval
+        };
+        self.update_status(ctx.clone(), status).await?;
+        // Seconds accounted by a failed write are accounted again from the previous status
+        ctx.metrics.vm_running(self, running_seconds);
+        self.publish_transition(ctx.clone(), &previous_state, &state)
+            .await;
+        self.publish_cloud_event(&ctx, &previous_state, &state);
+        if let Some(message) = failed_message {
+            self.notify(&ctx, NotificationEvent::Failed, message);
+        }
+        if let Some(message) = crash_loop_message {
+            self.notify(&ctx, NotificationEvent::CrashLoop, message);
+        }
+        result?;
+
+        // If no events were received, check back after the requeue interval, or once the backoff
+        // of an exited pod has passed
+        let requeue = next_restart
+            .and_then(|t| (t - Utc::now()).to_std().ok())
+            .map_or(requeue_interval, |d| d.min(requeue_interval));
+        Ok(Action::requeue(requeue))
+    }
+
+    // Count a failed reconcile in the status, and give up on the VM once it failed too many times
+    // in a row
+    async fn record_failure(
+        &self,
+        ctx: Arc<Context>,
+        status: &mut VirtualMachineStatus,
+        e: &Error,
+    ) {
+        status.consecutive_failures += 1;
+        self.publish_event(
+            ctx.clone(),
+            EventType::Warning,
+            "ReconcileFailed",
+            "Reconcile",
+            Some(e.to_string()),
+        )
+        .await;
+        let reason = if e.is_retryable() {
+            "TransientError"
+        } else {
+            "TerminalError"
+        };
+        status.set_condition(
+            VirtualMachineConditionType::Failed,
+            true,
+            reason,
+            Some(e.to_string()),
+        );
+
+        let max_failures = ctx.config.controller.max_consecutive_failures;
+        if max_failures > 0 && status.consecutive_failures >= max_failures {
+            let message = format!(
+                "Giving up after {} failed reconciles: {}",
+                status.consecutive_failures, e
+            );
+            status.state = VirtualMachineCurrentState::FAILED;
+            status.set_condition(
+                VirtualMachineConditionType::Failed,
+                true,
+                "RetriesExhausted",
+                Some(message.clone()),
+            );
+            self.publish_event(
+                ctx.clone(),
+                EventType::Warning,
+                "RetriesExhausted",
+                "Reconcile",
+                Some(message),
+            )
+            .await;
+        }
+    }
+
+    // Record a template or class that cannot be resolved like a failed reconcile, so it is
+    // reported in the status and the VM is given up on the same way
+    pub async fn fail_resolution(&self, ctx: Arc<Context>, error: Error) -> Result<Action> {
+        if self.has_given_up() {
+            return Ok(Action::await_change());
+        }
+        let mut status = self.status.clone().unwrap_or_default();
+        let previous_state = status.state.clone();
+        if status.observed_generation != self.metadata.generation {
+            status.consecutive_failures = 0;
+        }
+        self.record_failure(ctx.clone(), &mut status, &error).await;
+        let state = status.state.clone();
+        let gave_up = state != previous_state;
+        if gave_up {
+            let reason = status.state_condition().and_then(|c| c.reason.clone());
+            status.record_transition(previous_state.clone(), reason, Utc::now());
+        }
+        let running_seconds = status.usage.account(&previous_state, &state, Utc::now());
+        let message = status.state_condition().and_then(|c| c.message.clone());
+        self.update_status(ctx.clone(), status).await?;
+        ctx.metrics.vm_running(self, running_seconds);
+        if gave_up {
+            self.publish_transition(ctx.clone(), &previous_state, &state)
+                .await;
+            self.publish_cloud_event(&ctx, &previous_state, &state);
+            self.notify(&ctx, NotificationEvent::Failed, message);
+        }
+        Err(error)
+    }
+
+    // Finalizer cleanup (the object was deleted, ensure nothing is orphaned)
+    // With the Foreground deletion policy the finalizer is only removed once the pod, the Service
+    // and the disks are gone, so a failing deletion holds the VM instead of leaving half of it
+    // behind. Until then the requeue is returned, None once the finalizer can be removed.
+    #[instrument(skip_all)]
+    pub async fn cleanup(&self, ctx: Arc<Context>) -> Result<Option<Action>> {
+        let ns = self.namespace().unwrap();
+        let vm_name = self.name_any();
+        let policy = &self.spec.deletion_policy;
+        info!(
+            "Cleaning up VirtualMachine {} in {} ({:?})",
+            vm_name, ns, policy
+        );
+        self.record_usage(ctx.clone()).await?;
+
+        let params = match policy {
+            DeletionPolicy::Foreground => DeleteParams::foreground(),
+            DeletionPolicy::Background | DeletionPolicy::Orphan => DeleteParams::background(),
+        };
+        self.delete_children(ctx.clone(), &params, false).await?;
+        ctx.metrics.forget_vm(self);
+        // Deleting the snapshot PVC releases the hibernation snapshot storage
+        let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(ctx.client.clone(), &ns);
+        let pvc_names = [self.root_disk_pvc_name(), self.snapshot_pvc_name()];
+        for pvc_name in &pvc_names {
+            let Some(pvc) = pvcs.get_opt(pvc_name).await.map_err(Error::KubeError)? else {
+                continue;
+            };
+            if *policy == DeletionPolicy::Orphan {
+                self.release(&pvcs, &pvc).await?;
+            } else {
+                let _o = pvcs
+                    .delete(pvc_name, &params)
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+        }
+        if *policy != DeletionPolicy::Foreground {
+            return Ok(None);
+        }
+
+        let pods: Api<Pod> = Api::namespaced(ctx.client.clone(), &ns);
+        let services: Api<Service> = Api::namespaced(ctx.client.clone(), &ns);
+        let mut remaining = vec![];
+        if pods
+            .get_opt(&vm_name)
+            .await
+            .map_err(Error::KubeError)?
+            .is_some()
+        {
+            remaining.push(format!("Pod {vm_name}"));
+        }
+        if services
+            .get_opt(&vm_name)
+            .await
+            .map_err(Error::KubeError)?
+            .is_some()
+        {
+            remaining.push(format!("Service {vm_name}"));
+        }
+        for pvc_name in pvc_names {
+            if pvcs
+                .get_opt(&pvc_name)
+                .await
+                .map_err(Error::KubeError)?
+                .is_some()
+            {
+                remaining.push(format!("PersistentVolumeClaim {pvc_name}"));
+            }
+        }
+        if !remaining.is_empty() {
+            info!(
+                "Waiting for {} of VirtualMachine {} to be deleted",
+                remaining.join(", "),
+                vm_name
+            );
+            return Ok(Some(Action::requeue(
+                ctx.config.controller.retry_interval(),
+            )));
+        }
+
+        Ok(None)
+    }
+
+    // Keep the usage of the deleted VM in a ConfigMap named after its UID, as the status goes away
+    // with the VM, and drop the records of the namespace past the usage retention
+    async fn record_usage(&self, ctx: Arc<Context>) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let now = Utc::now();
+        let status = self.status.clone().unwrap_or_default();
+        let mut usage = status.usage;
+        usage.account(&status.state, &VirtualMachineCurrentState::STOPPED, now);
+
+        let configmaps: Api<ConfigMap> = Api::namespaced(ctx.client.clone(), &ns);
+        if !usage.expired(now) {
+            let record = ConfigMap {
+                metadata: ObjectMeta {
+                    name: Some(format!("fink-usage-{}", self.uid().unwrap())),
+                    labels: Some(BTreeMap::from([(
+                        USAGE_RECORD_LABEL.to_string(),
+                        "true".to_string(),
+                    )])),
+                    ..ObjectMeta::default()
+                },
+                data: Some(BTreeMap::from([
+                    ("name".to_string(), self.name_any()),
+                    (
+                        "usage".to_string(),
+                        serde_json::to_string(&usage).map_err(Error::SerializationError)?,
+                    ),
+                ])),
+                ..ConfigMap::default()
+            };
+            // A retried cleanup keeps the record of its first attempt
+            match configmaps.create(&PostParams::default(), &record).await {
+                Ok(_) | Err(kube::Error::Api(ErrorResponse { code: 409, .. })) => {}
+                Err(e) => return Err(Error::KubeError(e)),
+            }
+        }
+
+        for record in usage_records(&configmaps).await? {
+            if record.usage.expired(now) {
+                let _o = configmaps
+                    .delete(&record.config_map, &DeleteParams::default())
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Start and stop expressions of spec.schedule with the state they switch the VM to
+    fn schedule_crons(&self) -> Result<Vec<(Cron, VirtualMachineDesiredState)>> {
+        let Some(schedule) = &self.spec.schedule else {
+            return Ok(vec![]);
+        };
+        [
+            (
+                &schedule.start,
+                "start",
+                VirtualMachineDesiredState::STARTED,
+            ),
+            (&schedule.stop, "stop", VirtualMachineDesiredState::STOPPED),
+        ]
+        .into_iter()
+        .filter_map(|(expression, field, state)| Some((expression.as_ref()?, field, state)))
+        .map(|(expression, field, state)| {
+            let cron = Cron::new(expression).parse().map_err(|e| {
+                Error::InvalidSpec(format!("spec.schedule.{field} {expression:?}: {e}"))
+            })?;
+            Ok((cron, state))
+        })
+        .collect()
+    }
+
+    fn schedule_timezone(&self) -> Result<Tz> {
+        match self
+            .spec
+            .schedule
+            .as_ref()
+            .and_then(|s| s.timezone.as_ref())
+        {
+            Some(timezone) => timezone.parse().map_err(|_| {
+                Error::InvalidSpec(format!("unknown spec.schedule.timezone {timezone:?}"))
+            }),
+            None => Ok(Tz::UTC),
+        }
+    }
+
+    // Latest start or stop of spec.schedule that fired after the last one applied, or after the
+    // VM was created
+    fn due_schedule(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Option<(DateTime<Utc>, VirtualMachineDesiredState)>> {
+        let timezone = self.schedule_timezone()?;
+        let since = self
+            .status
+            .as_ref()
+            .and_then(|s| s.last_schedule_time)
+            .or(self.metadata.creation_timestamp.as_ref().map(|t| t.0))
+            .unwrap_or(now)
+            .with_timezone(&timezone);
+        let now_local = now.with_timezone(&timezone);
+
+        Ok(self
+            .schedule_crons()?
+            .into_iter()
+            .filter_map(|(cron, state)| {
+                let fired_at = latest_occurrence(&cron, since, now_local)?;
+                Some((fired_at.with_timezone(&Utc), state))
+            })
+            .max_by_key(|(fired_at, _)| *fired_at))
+    }
+
+    // Next start or stop of spec.schedule
+    fn next_schedule(&self, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+        let now_local = now.with_timezone(&self.schedule_timezone()?);
+        Ok(self
+            .schedule_crons()?
+            .into_iter()
+            .filter_map(|(cron, _)| cron.find_next_occurrence(&now_local, false).ok())
+            .map(|t| t.with_timezone(&Utc))
+            .min())
+    }
+
+    // Switch spec.state as the schedule fired, a later manual change holds until the next start or
+    // stop of the schedule
+    async fn apply_schedule(
+        &self,
+        ctx: Arc<Context>,
+        fired_at: DateTime<Utc>,
+        state: VirtualMachineDesiredState,
+    ) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let vm_name = self.name_any();
+        let vms: Api<VirtualMachine> = Api::namespaced(ctx.client.clone(), &ns);
+
+        if self.spec.state != state {
+            info!(
+                "Switching VirtualMachine {} to {:?} on schedule",
+                vm_name, state
+            );
+            let patch = Patch::Merge(json!({ "spec": { "state": state } }));
+            let _o = vms
+                .patch(&vm_name, &PatchParams::default(), &patch)
+                .await
+                .map_err(Error::KubeError)?;
+            let reason = match state {
+                VirtualMachineDesiredState::STARTED => "ScheduledStart",
+                _ => "ScheduledStop",
+            };
+            self.publish_event(
+                ctx.clone(),
+                EventType::Normal,
+                reason,
+                "Schedule",
+                Some(format!("Scheduled at {fired_at}")),
+            )
+            .await;
+        }
+
+        let patch = Patch::Merge(json!({ "status": { "lastScheduleTime": fired_at } }));
+        let _o = vms
+            .patch_status(&vm_name, &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+
+    // Latest activity reported through the annotation or already recorded in the status
+    fn last_reported_activity(&self) -> Option<DateTime<Utc>> {
+        let annotated = self
+            .annotations()
+            .get(LAST_ACTIVITY_ANNOTATION)
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc));
+        let recorded = self.status.as_ref().and_then(|s| s.last_activity_time);
+        annotated.max(recorded)
+    }
+
+    // Latest sign of use of the VM, the reported activity or the time it became ready
+    fn last_activity(&self) -> Option<DateTime<Utc>> {
+        let ready = self
+            .status
+            .as_ref()?
+            .condition(VirtualMachineConditionType::Ready)
+            .filter(|c| c.status == ConditionStatus::True)
+            .and_then(|c| c.last_transition_time);
+        self.last_reported_activity().max(ready)
+    }
+
+    // Time at which the started VM counts as idle, None without an idle timeout
+    fn idle_deadline(&self) -> Option<DateTime<Utc>> {
+        let timeout = self.spec.idle_timeout_seconds?;
+        let started = self.spec.state == VirtualMachineDesiredState::STARTED
+            && self.status.as_ref()?.state == VirtualMachineCurrentState::STARTED;
+        if !started {
+            return None;
+        }
+        Some(self.last_activity()? + chrono::Duration::seconds(timeout.into()))
+    }
+
+    // Time a VM that was STARTING at the last reconcile has to become STARTED
+    fn boot_deadline(&self) -> Option<DateTime<Utc>> {
+        let timeout = self.spec.boot_timeout_seconds?;
+        let status = self.status.as_ref()?;
+        let starting = self.spec.state == VirtualMachineDesiredState::STARTED
+            && status.state == VirtualMachineCurrentState::STARTING;
+        if !starting {
+            return None;
+        }
+        Some(status.last_transition_time? + chrono::Duration::seconds(timeout.into()))
+    }
+
+    // Mark the VM FAILED with why its pod did not start, and delete the pod so it does not hold the
+    // node and keep booting until the spec changes
+    async fn fail_boot(&self, ctx: Arc<Context>, status: &mut VirtualMachineStatus) -> Result<()> {
+        let pods: Api<Pod> = Api::namespaced(ctx.client.clone(), &self.namespace().unwrap());
+        let pod = pods
+            .get_opt(&self.name_any())
+            .await
+            .map_err(Error::KubeError)?;
+        let reason = pod.as_ref().and_then(pod_failure_reason).or_else(|| {
+            status
+                .condition(VirtualMachineConditionType::Ready)
+                .and_then(|c| c.message.clone())
+        });
+        let message = format!(
+            "The VM did not boot within {}s{}",
+            self.spec.boot_timeout_seconds.unwrap_or_default(),
+            reason.map(|r| format!(": {r}")).unwrap_or_default()
+        );
+        if let Some(pod) = pod {
+            let _o = pods
+                .delete(&pod.name_any(), &Default::default())
+                .await
+                .map_err(Error::KubeError)?;
+        }
+        status.node_name = None;
+        status.pod_ip = None;
+        status.state = VirtualMachineCurrentState::FAILED;
+        status.set_condition(
+            VirtualMachineConditionType::Failed,
+            true,
+            "BootTimeout",
+            Some(message.clone()),
+        );
+        self.publish_event(
+            ctx,
+            EventType::Warning,
+            "BootTimeout",
+            "Start",
+            Some(message),
+        )
+        .await;
+        Ok(())
+    }
+
+    async fn apply_idle_action(
+        &self,
+        ctx: Arc<Context>,
+        idle_deadline: DateTime<Utc>,
+    ) -> Result<()> {
+        let state = match self.spec.idle_action {
+            IdleAction::Hibernate if self.spec.hibernation => {
+                VirtualMachineDesiredState::HIBERNATED
+            }
+            IdleAction::Hibernate | IdleAction::Stop => VirtualMachineDesiredState::STOPPED,
+        };
+        info!(
+            "Switching idle VirtualMachine {} to {:?}",
+            self.name_any(),
+            state
+        );
+        let ns = self.namespace().unwrap();
+        let vms: Api<VirtualMachine> = Api::namespaced(ctx.client.clone(), &ns);
+        let patch = Patch::Merge(json!({ "spec": { "state": state } }));
+        let _o = vms
+            .patch(&self.name_any(), &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        let idle_since = idle_deadline
+            - chrono::Duration::seconds(self.spec.idle_timeout_seconds.unwrap_or(0).into());
+        self.publish_event(
+            ctx,
+            EventType::Normal,
+            "IdleTimeout",
+            "Idle",
+            Some(format!("No activity since {idle_since}")),
+        )
+        .await;
+        Ok(())
+    }
+
+    // The controller gave up on the current generation of the spec
+    fn has_given_up(&self) -> bool {
+        self.status.as_ref().is_some_and(|s| {
+            s.state == VirtualMachineCurrentState::FAILED
+                && s.observed_generation == self.metadata.generation
+        })
+    }
+
+    // Whether the latest spec was already applied and the VM reached a state that needs no
+    // child resources to be watched (STARTED VMs still track their pod)
+    fn is_settled(&self) -> bool {
+        let Some(status) = &self.status else {
+            return false;
+        };
+        if status.observed_generation != self.metadata.generation {
+            return false;
+        }
+        let failed = status
+            .condition(VirtualMachineConditionType::Failed)
+            .is_some_and(|c| c.status == ConditionStatus::True);
+
+        !failed
+            && matches!(
+                (&self.spec.state, &status.state),
+                (
+                    VirtualMachineDesiredState::STOPPED,
+                    VirtualMachineCurrentState::STOPPED
+                ) | (
+                    VirtualMachineDesiredState::HIBERNATED,
+                    VirtualMachineCurrentState::HIBERNATED
+                )
+            )
+    }
+
+    async fn update_status(
+        &self,
+        ctx: Arc<Context>,
+        mut status: VirtualMachineStatus,
+    ) -> Result<()> {
+        status.observed_generation = self.metadata.generation;
+        if self.status.as_ref() == Some(&status) {
+            return Ok(());
+        }
+
+        let ns = self.namespace().unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+
+        let vms: Api<VirtualMachine> = Api::namespaced(ctx.client.clone(), &ns);
+        let patch = Patch::Merge(json!({ "status": status }));
+        let _o = vms
+            .patch_status(vm_name, &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn start(&self, ctx: Arc<Context>, status: &mut VirtualMachineStatus) -> Result<()> {
+        if self.spec.ports.is_empty() {
+            return Err(Error::InvalidSpec(
+                "spec.ports must contain at least one port".to_string(),
+            ));
+        }
+
+        let client: Client = ctx.client.clone();
+        let ns = self.namespace().unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+
+        // Fail early instead of leaving the pod stuck in ContainerCreating
+        if let Some(cloud_init) = &self.spec.cloud_init {
+            cloud_init
+                .user_data_ref
+                .resolve(client.clone(), &ns)
+                .await?;
+        }
+
+        if let Some(restored) = self.ensure_snapshot_pvc(ctx.clone()).await? {
+            info!("Restoring VirtualMachine {} from snapshot", vm_name);
+            status.snapshot = Some(restored);
+        }
+        self.ensure_root_disk_pvc(ctx.clone()).await?;
+        self.ensure_ssh_keys_secret(ctx.clone()).await?;
+
+        let pods: Api<Pod> = Api::namespaced(client.clone(), &ns);
+        let existing_pod = pods.get_opt(vm_name).await.map_err(Error::KubeError)?;
+        // A live resize keeps the boot memory in the config until the balloon reclaimed the rest
+        let live_resizing = existing_pod
+            .as_ref()
+            .and_then(|pod| self.pending_live_resize(pod, status));
+        let config_memory_mib = live_resizing.unwrap_or(self.spec.memory_mib);
+        let changed_metadata = self
+            .ensure_firecracker_config(ctx.clone(), config_memory_mib)
+            .await?;
+
+        let desired_pod = self.pod(status, &ctx.config.pod)?;
+        self.ensure_service(ctx.clone(), status).await?;
+        self.ensure_ingress(ctx.clone()).await?;
+        status.url = self.url();
+        self.ensure_http_route(ctx.clone()).await?;
+        self.ensure_network_policy(ctx.clone()).await?;
+        self.ensure_pod_disruption_budget(ctx.clone()).await?;
+
+        if let Some(pod) = &existing_pod {
+            self.adopt(ctx.clone(), &pods, pod).await?;
+        }
+        let boot_memory_mib = match &existing_pod {
+            Some(pod) => {
+                if self.resize(ctx.clone(), pod, status).await? {
+                    return Ok(());
+                }
+                if self.update(ctx.clone(), pod, &desired_pod, status).await? {
+                    return Ok(());
+                }
+                if self.handle_exit(ctx.clone(), pod, status).await? {
+                    return Ok(());
+                }
+                if self.restart_if_requested(ctx.clone(), pod, status).await? {
+                    return Ok(());
+                }
+                boot_sizing(pod).map_or(self.spec.memory_mib, |(_, memory_mib)| memory_mib)
+            }
+            None => self.spec.memory_mib,
+        };
+        // The pod spec is immutable, so only a missing pod is applied. Applying instead of
+        // creating makes a concurrent reconcile a no-op rather than a conflict.
+        if existing_pod.is_none() {
+            let _o = pods
+                .patch(
+                    vm_name,
+                    &PatchParams::apply(FIELD_MANAGER).force(),
+                    &Patch::Apply(&desired_pod),
+                )
+                .await
+                .map_err(Error::KubeError)?;
+        }
+
+        status.drives = self
+            .drive_statuses(ctx.clone(), existing_pod.as_ref())
+            .await?;
+
+        // A booted VM only reads the metadata file on startup, so changes are pushed to MMDS
+        let running = existing_pod
+            .as_ref()
+            .and_then(|p| p.status.as_ref())
+            .and_then(|s| s.phase.as_deref())
+            == Some("Running");
+        // Once the guest resumed from the snapshot, later pods cold boot instead of restoring the
+        // same memory image again
+        if running && status.snapshot.is_some() {
+            info!("VirtualMachine {} resumed from its snapshot", vm_name);
+            status.snapshot = None;
+        }
+        if let (Some(metadata), true) = (changed_metadata, running) {
+            self.firecracker(ctx.client.clone())
+                .put_mmds(&metadata)
+                .await?;
+        }
+        if running {
+            self.apply_balloon(ctx.clone(), status, boot_memory_mib)
+                .await?;
+        } else {
+            status.balloon = None;
+        }
+        let live_resized = existing_pod
+            .as_ref()
+            .is_some_and(|pod| self.pending_live_resize(pod, status).is_none());
+        if live_resizing.is_some() && live_resized {
+            self.ensure_firecracker_config(ctx.clone(), self.spec.memory_mib)
+                .await?;
+            let message = status
+                .condition(VirtualMachineConditionType::Resizing)
+                .and_then(|c| c.message.clone());
+            status.set_condition(
+                VirtualMachineConditionType::Resizing,
+                false,
+                "LiveResized",
+                message,
+            );
+        }
+
+        self.report_blocked_eviction(ctx.clone(), existing_pod.as_ref(), status)
+            .await?;
+        if let Some(pod) = &existing_pod {
+            status.node_name = pod.spec.as_ref().and_then(|s| s.node_name.clone());
+            status.pod_ip = pod.status.as_ref().and_then(|s| s.pod_ip.clone());
+        } else {
+            status.node_name = None;
+            status.pod_ip = None;
+        }
+
+        // Crash loops are reported until the VM stays up for longer than the restart backoff
+        let crash_looping = existing_pod.as_ref().and_then(crash_looping_container);
+        let running_since = existing_pod
+            .as_ref()
+            .and_then(|p| p.status.as_ref()?.container_statuses.as_ref())
+            .into_iter()
+            .flatten()
+            .find(|cs| cs.name == VM_CONTAINER_NAME)
+            .and_then(|cs| cs.state.as_ref()?.running.as_ref()?.started_at.as_ref())
+            .map(|t| t.0);
+        if let Some(message) = &crash_looping {
+            status.set_condition(
+                VirtualMachineConditionType::Degraded,
+                true,
+                "CrashLoopBackOff",
+                Some(message.clone()),
+            );
+        } else if status.degraded().is_some()
+            && running_since.is_some_and(|t| {
+                Utc::now() - t > chrono::Duration::seconds(MAX_RESTART_BACKOFF_SECS)
+            })
+        {
+            status.set_condition(VirtualMachineConditionType::Degraded, false, "Stable", None);
+        }
+
+        match existing_pod {
+            Some(Pod {
+                status: Some(pod_status),
+                ..
+            }) => {
+                let scheduled = pod_status
+                    .conditions
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|c| c.type_ == "PodScheduled");
+                match scheduled {
+                    Some(PodCondition {
+                        status: s,
+                        reason,
+                        message,
+                        ..
+                    }) => status.set_condition(
+                        VirtualMachineConditionType::PodScheduled,
+                        s == "True",
+                        reason.as_deref().unwrap_or("Scheduled"),
+                        message,
+                    ),
+                    None => status.set_condition(
+                        VirtualMachineConditionType::PodScheduled,
+                        false,
+                        "Pending",
+                        None,
+                    ),
+                }
+
+                let container_statuses = pod_status.container_statuses.unwrap_or_default();
+                let all_started = !container_statuses.is_empty()
+                    && container_statuses
+                        .iter()
+                        .all(|cs| cs.started.unwrap_or(false));
+                // With a readiness probe the guest has to pass it before the VM is STARTED, a
+                // STARTED VM failing it only loses its Ready condition
+                let serving = self.spec.readiness_probe.is_none()
+                    || container_statuses
+                        .iter()
+                        .any(|cs| cs.name == VM_CONTAINER_NAME && cs.ready);
+
+                // Containers only count as started once the startup probe passed
+                let boot_completed = if self.spec.startup_probe.is_some() {
+                    "StartupProbeSucceeded"
+                } else {
+                    "ContainersStarted"
+                };
+
+                if all_started && serving {
+                    status.state = VirtualMachineCurrentState::STARTED;
+                    status.set_condition(
+                        VirtualMachineConditionType::BootCompleted,
+                        true,
+                        boot_completed,
+                        None,
+                    );
+                    status.set_condition(VirtualMachineConditionType::Ready, true, "Started", None);
+                } else if all_started {
+                    status.set_condition(
+                        VirtualMachineConditionType::BootCompleted,
+                        true,
+                        boot_completed,
+                        None,
+                    );
+                    if status.state == VirtualMachineCurrentState::STARTED {
+                        status.set_condition(
+                            VirtualMachineConditionType::Ready,
+                            false,
+                            "ReadinessProbeFailed",
+                            Some("The guest is failing its readiness probe".to_string()),
+                        );
+                    } else {
+                        status.state = VirtualMachineCurrentState::STARTING;
+                        status.set_condition(
+                            VirtualMachineConditionType::Ready,
+                            false,
+                            "WaitingForReadiness",
+                            Some("Waiting for the guest to pass its readiness probe".to_string()),
+                        );
+                    }
+                } else {
+                    // A running vm-container that has not started is waiting on its startup probe
+                    let probing = self.spec.startup_probe.is_some()
+                        && container_statuses.iter().any(|cs| {
+                            cs.name == VM_CONTAINER_NAME
+                                && cs.state.as_ref().is_some_and(|s| s.running.is_some())
+                        });
+                    status.state = VirtualMachineCurrentState::STARTING;
+                    status.set_condition(
+                        VirtualMachineConditionType::BootCompleted,
+                        false,
+                        "Booting",
+                        probing
+                            .then(|| "Waiting for the guest to pass its startup probe".to_string()),
+                    );
+                    match crash_looping {
+                        Some(message) => status.set_condition(
+                            VirtualMachineConditionType::Ready,
+                            false,
+                            "CrashLoopBackOff",
+                            Some(message),
+                        ),
+                        None => status.set_condition(
+                            VirtualMachineConditionType::Ready,
+                            false,
+                            "Starting",
+                            None,
+                        ),
+                    }
+                }
+            }
+            _ => {
+                status.state = VirtualMachineCurrentState::STARTING;
+                status.set_condition(
+                    VirtualMachineConditionType::PodScheduled,
+                    false,
+                    "PodCreated",
+                    None,
+                );
+                status.set_condition(
+                    VirtualMachineConditionType::BootCompleted,
+                    false,
+                    "Booting",
+                    None,
+                );
+                status.set_condition(VirtualMachineConditionType::Ready, false, "Starting", None);
+            }
+        }
+        // A configured agent is checked in the background, see check_guest_agent()
+        if self.spec.guest_agent.is_none() {
+            status.guest_agent = None;
+            status
+                .conditions
+                .retain(|c| c.type_ != VirtualMachineConditionType::GuestAgentConnected);
+        }
+
+        Ok(())
+    }
+
+    // Query the OS info from the guest agent, which doubles as its heartbeat, and record whether
+    // it answered. The status is only written when the agent connects, disconnects or reports
+    // another OS, guarded by the resourceVersion so a concurrent reconcile wins.
+    #[instrument(skip_all)]
+    pub(crate) async fn check_guest_agent(&self, client: Client) -> Result<()> {
+        let (Some(guest_agent), Some(current)) = (&self.spec.guest_agent, &self.status) else {
+            return Ok(());
+        };
+        let ns = self.namespace().unwrap();
+        let pods: Api<Pod> = Api::namespaced(client.clone(), &ns);
+        let request = agent::Request::Info;
+        let mut status = current.clone();
+        match agent::call::<agent::OsInfo>(&pods, &self.name_any(), guest_agent.port, &request)
+            .await
+        {
+            Ok(os_info) => {
+                status.guest_agent = Some(VirtualMachineGuestAgentStatus {
+                    os_info: Some(os_info),
+                });
+                status.set_condition(
+                    VirtualMachineConditionType::GuestAgentConnected,
+                    true,
+                    "Connected",
+                    None,
+                );
+            }
+            Err(e) => {
+                debug!("guest agent of {} did not answer: {e}", self.name_any());
+                status.set_condition(
+                    VirtualMachineConditionType::GuestAgentConnected,
+                    false,
+                    "Unreachable",
+                    Some(e.to_string()),
+                );
+            }
+        }
+
+        let connected = |s: &VirtualMachineStatus| {
+            s.condition(VirtualMachineConditionType::GuestAgentConnected)
+                .map(|c| (c.status.clone(), c.reason.clone()))
+        };
+        if status.guest_agent == current.guest_agent && connected(&status) == connected(current) {
+            return Ok(());
+        }
+        let vms: Api<VirtualMachine> = Api::namespaced(client, &ns);
+        let patch = Patch::Merge(json!({
+            "metadata": { "resourceVersion": self.resource_version() },
+            "status": {
+                "guestAgent": status.guest_agent,
+                "conditions": status.conditions,
+            },
+        }));
+        let _o = vms
+            .patch_status(&self.name_any(), &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+
+    // Run a command in the guest through its agent
+    pub async fn guest_exec(
+        &self,
+        client: Client,
+        request: agent::ExecRequest,
+    ) -> Result<agent::ExecOutput> {
+        let Some(guest_agent) = &self.spec.guest_agent else {
+            return Err(Error::InvalidSpec(
+                "spec.guestAgent must be set to run commands in the guest".to_string(),
+            ));
+        };
+        let pods: Api<Pod> = Api::namespaced(client, &self.namespace().unwrap());
+        agent::call(
+            &pods,
+            &self.name_any(),
+            guest_agent.port,
+            &agent::Request::Exec(request),
+        )
+        .await
+    }
+
+    // Effective boot source, falling back to the deprecated `spec.image` as rootfs
+    pub fn boot_source(&self) -> Result<VirtualMachineBootSource> {
+        match (&self.spec.boot_source, &self.spec.image) {
+            (Some(boot_source), _) => Ok(boot_source.clone()),
+            (None, Some(image)) => Ok(VirtualMachineBootSource {
+                kernel_image: None,
+                kernel_args: default_kernel_args(),
+                rootfs_image: image.clone(),
+            }),
+            (None, None) => Err(Error::InvalidSpec(
+                "one of spec.bootSource or spec.image must be set".to_string(),
+            )),
+        }
+    }
+
+    fn labels(&self) -> BTreeMap<String, String> {
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let mut labels = self.metadata.labels.clone().unwrap_or_default();
+        labels.insert(VM_NAME_LABEL.to_string(), vm_name.to_string());
+        labels
+    }
+
+    pub(crate) fn snapshot_pvc_name(&self) -> String {
+        format!("{}-snapshot", self.name_any())
+    }
+
+    fn firecracker_config_name(&self) -> String {
+        format!("{}-firecracker", self.name_any())
+    }
+
+    // Render the Firecracker config file the runtime boots the VM with, giving it `memory_mib`
+    fn firecracker_config(&self, memory_mib: u32) -> Result<firecracker::Config> {
+        let boot_source = self.boot_source()?;
+        // Also checked by the CRD, except for VMs sized by their template or class
+        if let Some(balloon) = &self.spec.balloon {
+            if balloon.target_mib >= self.spec.memory_mib {
+                return Err(Error::InvalidSpec(
+                    "spec.balloon.targetMib must be below spec.memoryMib".to_string(),
+                ));
+            }
+        }
+        let cpu_template = self.spec.cpu_template.as_ref();
+        if let Some(template) = cpu_template {
+            let custom = template.inline.is_some() || template.config_map_ref.is_some();
+            if template.name.is_some() == custom {
+                return Err(Error::InvalidSpec(
+                    "spec.cpuTemplate needs either a name or a custom template".to_string(),
+                ));
+            }
+        }
+
+        let kernel_image_path = match boot_source.kernel_image {
+            Some(_) => format!("{KERNEL_MOUNT_PATH}/vmlinux"),
+            None => format!("{RUNTIME_DIR}/vmlinux"),
+        };
+        let rootfs_path = match self.spec.storage {
+            Some(_) => format!("{ROOT_DISK_MOUNT_PATH}/rootfs.ext4"),
+            None => format!("{RUNTIME_DIR}/rootfs.ext4"),
+        };
+
+        let mut drives = vec![firecracker::Drive {
+            drive_id: "rootfs".to_string(),
+            path_on_host: rootfs_path,
+            is_root_device: true,
+            is_read_only: false,
+            rate_limiter: None,
+        }];
+        drives.extend(self.spec.drives.iter().map(|drive| firecracker::Drive {
+            drive_id: drive.name.clone(),
+            path_on_host: format!("{DRIVES_MOUNT_PATH}/{}/disk.img", drive.name),
+            is_root_device: false,
+            is_read_only: drive.read_only,
+            rate_limiter: drive.rate_limiter.as_ref().map(Into::into),
+        }));
+
+        let network_interfaces: Vec<firecracker::NetworkInterface> = self
+            .network_interfaces()?
+            .iter()
+            .enumerate()
+            .map(|(i, iface)| firecracker::NetworkInterface {
+                iface_id: iface.name.clone(),
+                host_dev_name: format!("tap{i}"),
+                guest_mac: iface.guest_mac.clone(),
+                rx_rate_limiter: iface.ingress_rate_limiter.as_ref().map(Into::into),
+                tx_rate_limiter: iface.egress_rate_limiter.as_ref().map(Into::into),
+            })
+            .collect();
+
+        Ok(firecracker::Config {
+            boot_source: firecracker::BootSource {
+                kernel_image_path,
+                boot_args: Some(boot_source.kernel_args),
+            },
+            drives,
+            machine_config: firecracker::MachineConfig {
+                vcpu_count: self.spec.cpus,
+                mem_size_mib: memory_mib,
+                cpu_template: cpu_template
+                    .and_then(|t| t.name.as_ref())
+                    .map(|name| format!("{name:?}")),
+            },
+            // The primary interface always comes first
+            mmds_config: self
+                .spec
+                .metadata
+                .as_ref()
+                .map(|_| firecracker::MmdsConfig {
+                    network_interfaces: network_interfaces
+                        .first()
+                        .map(|i| vec![i.iface_id.clone()])
+                        .unwrap_or_default(),
+                    version: "V2".to_string(),
+                }),
+            network_interfaces,
+            balloon: self.spec.balloon.as_ref().filter(|b| b.enabled).map(|b| {
+                firecracker::Balloon {
+                    amount_mib: b.target_mib,
+                    deflate_on_oom: true,
+                    stats_polling_interval_s: b.stats_polling_interval,
+                }
+            }),
+            vsock: self
+                .spec
+                .vsock
+                .as_ref()
+                .filter(|v| v.enabled)
+                .map(|v| firecracker::Vsock {
+                    guest_cid: v.guest_cid,
+                    uds_path: firecracker::VSOCK_UDS_PATH.to_string(),
+                }),
+            metrics: self.spec.firecracker_metrics.then(|| firecracker::Metrics {
+                metrics_path: firecracker::METRICS_PATH.to_string(),
+            }),
+            cpu_config: cpu_template
+                .filter(|t| t.name.is_none())
+                .map(|_| firecracker::CPU_TEMPLATE_PATH.to_string()),
+        })
+    }
+
+    // Network interfaces with the primary one first, so it is always backed by `tap0`
+    fn network_interfaces(&self) -> Result<Vec<VirtualMachineNetworkInterface>> {
+        let mut interfaces = self.spec.network_interfaces.clone();
+        if interfaces.is_empty() {
+            interfaces.push(VirtualMachineNetworkInterface {
+                name: "eth0".to_string(),
+                primary: true,
+                ..VirtualMachineNetworkInterface::default()
+            });
+        }
+
+        match interfaces.iter().filter(|i| i.primary).count() {
+            0 => interfaces[0].primary = true,
+            1 => {}
+            _ => {
+                return Err(Error::InvalidSpec(
+                    "only one network interface can be primary".to_string(),
+                ))
+            }
+        }
+        interfaces.sort_by_key(|i| !i.primary);
+        Ok(interfaces)
+    }
+
+    async fn mmds_metadata(&self, ctx: Arc<Context>) -> Result<Option<serde_json::Value>> {
+        let Some(metadata) = &self.spec.metadata else {
+            return Ok(None);
+        };
+        if let Some(inline) = &metadata.inline {
+            return Ok(Some(inline.clone()));
+        }
+        let Some(config_map_ref) = &metadata.config_map_ref else {
+            return Ok(None);
+        };
+        let ns = self.namespace().unwrap();
+        let raw = config_map_ref.resolve(ctx.client.clone(), &ns).await?;
+        serde_json::from_str(&raw)
+            .map(Some)
+            .map_err(Error::SerializationError)
+    }
+
+    // Custom CPU template of the VM, None for a static one
+    async fn custom_cpu_template(&self, ctx: Arc<Context>) -> Result<Option<serde_json::Value>> {
+        let Some(template) = &self.spec.cpu_template else {
+            return Ok(None);
+        };
+        if let Some(inline) = &template.inline {
+            return Ok(Some(inline.clone()));
+        }
+        let Some(config_map_ref) = &template.config_map_ref else {
+            return Ok(None);
+        };
+        let ns = self.namespace().unwrap();
+        let raw = config_map_ref.resolve(ctx.client.clone(), &ns).await?;
+        serde_json::from_str(&raw)
+            .map(Some)
+            .map_err(Error::SerializationError)
+    }
+
+    // Write the Firecracker config and MMDS contents, returning the metadata if it changed
+    #[instrument(skip_all)]
+    async fn ensure_firecracker_config(
+        &self,
+        ctx: Arc<Context>,
+        memory_mib: u32,
+    ) -> Result<Option<serde_json::Value>> {
+        let ns = self.namespace().unwrap();
+        let config_name = self.firecracker_config_name();
+
+        let config = serde_json::to_string_pretty(&self.firecracker_config(memory_mib)?)
+            .map_err(Error::SerializationError)?;
+        let metadata = self.mmds_metadata(ctx.clone()).await?;
+        let mut data = BTreeMap::from([("config.json".to_string(), config)]);
+        if let Some(metadata) = &metadata {
+            data.insert("metadata.json".to_string(), metadata.to_string());
+        }
+        if let Some(template) = self.custom_cpu_template(ctx.clone()).await? {
+            data.insert("cpu-template.json".to_string(), template.to_string());
+        }
+
+        let config_maps: Api<ConfigMap> = Api::namespaced(ctx.client.clone(), &ns);
+        let existing = config_maps
+            .get_opt(&config_name)
+            .await
+            .map_err(Error::KubeError)?;
+        let metadata_changed = existing
+            .as_ref()
+            .and_then(|cm| cm.data.as_ref())
+            .and_then(|d| d.get("metadata.json"))
+            != data.get("metadata.json");
+
+        if existing.and_then(|cm| cm.data).as_ref() != Some(&data) {
+            let config_map = ConfigMap {
+                metadata: ObjectMeta {
+                    name: Some(config_name.clone()),
+                    owner_references: Some(vec![self.controller_owner_ref(&()).unwrap()]),
+                    labels: Some(self.labels()),
+                    ..ObjectMeta::default()
+                },
+                data: Some(data),
+                ..ConfigMap::default()
+            };
+            let _o = config_maps
+                .patch(
+                    &config_name,
+                    &PatchParams::apply(FIELD_MANAGER).force(),
+                    &Patch::Apply(&config_map),
+                )
+                .await
+                .map_err(Error::KubeError)?;
+        }
+        Ok(metadata.filter(|_| metadata_changed))
+    }
+
+    // Adjust the balloon of the running VM to match the spec, inflating it further by however much
+    // memory was shrunk since boot. The device itself can only be added at boot, so enabling it on
+    // a running VM recreates its pod.
+    #[instrument(skip_all)]
+    async fn apply_balloon(
+        &self,
+        ctx: Arc<Context>,
+        status: &mut VirtualMachineStatus,
+        boot_memory_mib: u32,
+    ) -> Result<()> {
+        let Some(spec_balloon) = self.spec.balloon.as_ref().filter(|b| b.enabled) else {
+            return Ok(());
+        };
+        let balloon = &VirtualMachineBalloon {
+            target_mib: spec_balloon.target_mib
+                + boot_memory_mib.saturating_sub(self.spec.memory_mib),
+            ..spec_balloon.clone()
+        };
+        let applied = status.balloon.clone();
+
+        if applied.as_ref().map(|b| b.target_mib) != Some(balloon.target_mib) {
+            info!(
+                "Setting balloon of VirtualMachine {} to {} MiB",
+                self.name_any(),
+                balloon.target_mib
+            );
+            let result = self
+                .firecracker(ctx.client.clone())
+                .set_balloon(balloon.target_mib)
+                .await;
+            if let (Err(e), None) = (&result, &applied) {
+                warn!(
+                    "VirtualMachine {} has no balloon device yet: {}",
+                    self.name_any(),
+                    e
+                );
+                return Ok(());
+            }
+            result?;
+        }
+        if applied.as_ref().map(|b| b.stats_polling_interval)
+            != Some(balloon.stats_polling_interval)
+        {
+            self.firecracker(ctx.client.clone())
+                .set_balloon_stats_interval(balloon.stats_polling_interval)
+                .await?;
+        }
+
+        status.balloon = Some(balloon.clone());
+        Ok(())
+    }
+
+    // Whether a pod booted with `boot_cpus` and `boot_memory_mib` shrinks to the spec without a
+    // restart, by inflating the balloon
+    fn resizes_live(&self, boot_cpus: u32, boot_memory_mib: u32) -> bool {
+        self.spec.resize_policy == ResizePolicy::LiveIfPossible
+            && boot_cpus == self.spec.cpus
+            && self.spec.memory_mib < boot_memory_mib
+            && self.spec.balloon.as_ref().is_some_and(|b| b.enabled)
+    }
+
+    // Boot memory of the pod while it is resized live and the balloon has not reclaimed the
+    // difference to the spec yet
+    fn pending_live_resize(&self, pod: &Pod, status: &VirtualMachineStatus) -> Option<u32> {
+        let (boot_cpus, boot_memory_mib) = boot_sizing(pod)?;
+        let balloon = self.spec.balloon.as_ref()?;
+        let target_mib = balloon.target_mib + boot_memory_mib.saturating_sub(self.spec.memory_mib);
+        let inflated = status.balloon.as_ref().map(|b| b.target_mib) == Some(target_mib);
+        (self.resizes_live(boot_cpus, boot_memory_mib) && !inflated).then_some(boot_memory_mib)
+    }
+
+    // Compare the size the pod was booted with against the spec. Returns true when the pod was
+    // deleted so it gets recreated with the new size.
+    #[instrument(skip_all)]
+    async fn resize(
+        &self,
+        ctx: Arc<Context>,
+        pod: &Pod,
+        status: &mut VirtualMachineStatus,
+    ) -> Result<bool> {
+        let Some((boot_cpus, boot_memory_mib)) = boot_sizing(pod) else {
+            return Ok(false);
+        };
+        if boot_cpus == self.spec.cpus && boot_memory_mib == self.spec.memory_mib {
+            if status
+                .condition(VirtualMachineConditionType::Resizing)
+                .is_some_and(|c| c.status == ConditionStatus::True)
+            {
+                status.set_condition(
+                    VirtualMachineConditionType::Resizing,
+                    false,
+                    "Resized",
+                    None,
+                );
+            }
+            return Ok(false);
+        }
+
+        let message = Some(format!(
+            "{boot_cpus} vCPUs/{boot_memory_mib} MiB to {} vCPUs/{} MiB",
+            self.spec.cpus, self.spec.memory_mib
+        ));
+        if self.resizes_live(boot_cpus, boot_memory_mib) {
+            // Memory is reclaimed by the balloon, the resize is done once it is inflated
+            if self.pending_live_resize(pod, status).is_some() {
+                status.set_condition(
+                    VirtualMachineConditionType::Resizing,
+                    true,
+                    "LiveResizing",
+                    message,
+                );
+            }
+            return Ok(false);
+        }
+
+        info!("Restarting VirtualMachine {} to resize it", self.name_any());
+        let ns = self.namespace().unwrap();
+        let pods: Api<Pod> = Api::namespaced(ctx.client.clone(), &ns);
+        let _o = pods
+            .delete(&pod.name_any(), &Default::default())
+            .await
+            .map_err(Error::KubeError)?;
+
+        status.state = VirtualMachineCurrentState::STARTING;
+        status.pod_ip = None;
+        status.node_name = None;
+        status.balloon = None;
+        status.set_condition(
+            VirtualMachineConditionType::Resizing,
+            true,
+            "Restarting",
+            message,
+        );
+        status.set_condition(VirtualMachineConditionType::Ready, false, "Resizing", None);
+        Ok(true)
+    }
+
+    // Recreate the pod when it no longer matches the spec, or only report the pending update with
+    // the OnDelete strategy. Returns whether the pod was deleted.
+    #[instrument(skip_all)]
+    async fn update(
+        &self,
+        ctx: Arc<Context>,
+        pod: &Pod,
+        desired: &Pod,
+        status: &mut VirtualMachineStatus,
+    ) -> Result<bool> {
+        // Pods created before the pod-inputs-hash annotation are only compared field by field
+        let drift = match pod.annotations().get(POD_INPUTS_HASH_ANNOTATION) {
+            Some(hash) => (desired.annotations().get(POD_INPUTS_HASH_ANNOTATION) != Some(hash))
+                .then(|| "spec".to_string()),
+            None => pod_drift(desired, pod),
+        };
+        let Some(drift) = drift else {
+            if status
+                .condition(VirtualMachineConditionType::Updating)
+                .is_some_and(|c| c.status == ConditionStatus::True)
+            {
+                status.set_condition(
+                    VirtualMachineConditionType::Updating,
+                    false,
+                    "Updated",
+                    None,
+                );
+            }
+            return Ok(false);
+        };
+
+        let message = Some(format!("The VM pod has an outdated {drift}"));
+        if self.spec.update_strategy == UpdateStrategy::OnDelete {
+            status.set_condition(
+                VirtualMachineConditionType::Updating,
+                true,
+                "WaitingForPodDeletion",
+                message,
+            );
+            return Ok(false);
+        }
+
+        info!(
+            "Recreating the pod of VirtualMachine {} for its outdated {}",
+            self.name_any(),
+            drift
+        );
+        let ns = self.namespace().unwrap();
+        let pods: Api<Pod> = Api::namespaced(ctx.client.clone(), &ns);
+        let _o = pods
+            .delete(&pod.name_any(), &Default::default())
+            .await
+            .map_err(Error::KubeError)?;
+
+        status.state = VirtualMachineCurrentState::STARTING;
+        status.pod_ip = None;
+        status.node_name = None;
+        status.balloon = None;
+        status.set_condition(
+            VirtualMachineConditionType::Updating,
+            true,
+            "Recreating",
+            message,
+        );
+        status.set_condition(VirtualMachineConditionType::Ready, false, "Updating", None);
+        Ok(true)
+    }
+
+    // Gracefully recreate the pod once when the restart-at annotation of the VM changes, like
+    // `kubectl rollout restart`. Returns whether the pod was deleted.
+    #[instrument(skip_all)]
+    async fn restart_if_requested(
+        &self,
+        ctx: Arc<Context>,
+        pod: &Pod,
+        status: &mut VirtualMachineStatus,
+    ) -> Result<bool> {
+        let Some(restart_at) = self.annotations().get(RESTART_AT_ANNOTATION) else {
+            return Ok(false);
+        };
+        if pod.annotations().get(RESTART_AT_ANNOTATION) == Some(restart_at) {
+            return Ok(false);
+        }
+        // Still draining from the restart
+        if pod.metadata.deletion_timestamp.is_some() {
+            return Ok(true);
+        }
+
+        info!(
+            "Restarting VirtualMachine {} as requested at {}",
+            self.name_any(),
+            restart_at
+        );
+        let ns = self.namespace().unwrap();
+        let pods: Api<Pod> = Api::namespaced(ctx.client.clone(), &ns);
+        let params = DeleteParams {
+            grace_period_seconds: self.spec.stop_grace_period_seconds,
+            ..DeleteParams::default()
+        };
+        let _o = pods
+            .delete(&pod.name_any(), &params)
+            .await
+            .map_err(Error::KubeError)?;
+
+        status.state = VirtualMachineCurrentState::STARTING;
+        status.pod_ip = None;
+        status.node_name = None;
+        status.balloon = None;
+        status.set_condition(
+            VirtualMachineConditionType::Ready,
+            false,
+            "Restarting",
+            Some(format!("Restart requested at {restart_at}")),
+        );
+        self.publish_event(
+            ctx,
+            EventType::Normal,
+            "RestartRequested",
+            "Restart",
+            Some(format!("Restart requested at {restart_at}")),
+        )
+        .await;
+        Ok(true)
+    }
+
+    // Apply the restart policy to a pod whose VM exited. Returns whether the exit was handled, in
+    // which case the pod is not reconciled further.
+    #[instrument(skip_all)]
+    async fn handle_exit(
+        &self,
+        ctx: Arc<Context>,
+        pod: &Pod,
+        status: &mut VirtualMachineStatus,
+    ) -> Result<bool> {
+        let failed = match pod.status.as_ref().and_then(|s| s.phase.as_deref()) {
+            Some("Failed") => true,
+            Some("Succeeded") => false,
+            _ => {
+                status.next_restart_time = None;
+                return Ok(false);
+            }
+        };
+        let terminated = pod
+            .status
+            .as_ref()
+            .and_then(|s| s.container_statuses.as_ref())
+            .into_iter()
+            .flatten()
+            .find(|cs| cs.name == VM_CONTAINER_NAME)
+            .and_then(|cs| cs.state.as_ref())
+            .and_then(|s| s.terminated.as_ref());
+        let message = match terminated {
+            Some(t) => format!(
+                "The VM pod exited with code {}{}",
+                t.exit_code,
+                termination_detail(t)
+            ),
+            None => "The VM pod failed".to_string(),
+        };
+        status.pod_ip = None;
+        status.balloon = None;
+
+        let restart = match self.spec.restart_policy {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Never => false,
+        };
+        if !restart {
+            status.next_restart_time = None;
+            if failed {
+                status.state = VirtualMachineCurrentState::FAILED;
+                status.set_condition(
+                    VirtualMachineConditionType::Ready,
+                    false,
+                    "PodFailed",
+                    Some(message.clone()),
+                );
+                self.publish_event(
+                    ctx,
+                    EventType::Warning,
+                    "PodFailed",
+                    "Restart",
+                    Some(message),
+                )
+                .await;
+            } else {
+                status.state = VirtualMachineCurrentState::STOPPED;
+                status.set_condition(
+                    VirtualMachineConditionType::Ready,
+                    false,
+                    "Exited",
+                    Some(message),
+                );
+            }
+            return Ok(true);
+        }
+
+        // Like the kubelet, a VM that ran longer than the maximum backoff restarts right away
+        let now = Utc::now();
+        let finished_at = terminated
+            .and_then(|t| t.finished_at.as_ref())
+            .map_or(now, |t| t.0);
+        let ran_for = terminated
+            .and_then(|t| Some(finished_at - t.started_at.as_ref()?.0))
+            .unwrap_or_default();
+        let max_backoff = chrono::Duration::seconds(MAX_RESTART_BACKOFF_SECS);
+        // Exiting shortly after starting once too often is a crash loop, instead of just another
+        // restart
+        if ran_for <= max_backoff
+            && status.restart_count >= ctx.config.controller.crash_loop_restarts
+        {
+            status.set_condition(
+                VirtualMachineConditionType::Degraded,
+                true,
+                "CrashLoop",
+                Some(format!(
+                    "Restarted {} times shortly after starting. {message}",
+                    status.restart_count
+                )),
+            );
+        }
+        let backoff = if ran_for > max_backoff {
+            chrono::Duration::zero()
+        } else {
+            (chrono::Duration::seconds(10) * 2i32.pow(status.restart_count.min(5))).min(max_backoff)
+        };
+        status.state = VirtualMachineCurrentState::STARTING;
+        if now < finished_at + backoff {
+            status.next_restart_time = Some(finished_at + backoff);
+            status.set_condition(
+                VirtualMachineConditionType::Ready,
+                false,
+                "BackOff",
+                Some(message),
+            );
+            return Ok(true);
+        }
+
+        info!(
+            "Restarting VirtualMachine {} after it exited",
+            self.name_any()
+        );
+        let ns = self.namespace().unwrap();
+        let pods: Api<Pod> = Api::namespaced(ctx.client.clone(), &ns);
+        let _o = pods
+            .delete(&pod.name_any(), &Default::default())
+            .await
+            .map_err(Error::KubeError)?;
+        status.restart_count += 1;
+        status.next_restart_time = None;
+        status.node_name = None;
+        status.set_condition(
+            VirtualMachineConditionType::Ready,
+            false,
+            "Restarting",
+            Some(message.clone()),
+        );
+        self.publish_event(
+            ctx,
+            EventType::Warning,
+            "Restarted",
+            "Restart",
+            Some(message),
+        )
+        .await;
+        Ok(true)
+    }
+
+    // A drive is attached once its backing volume is available and the VM pod is running
+    async fn drive_statuses(
+        &self,
+        ctx: Arc<Context>,
+        pod: Option<&Pod>,
+    ) -> Result<Vec<DriveStatus>> {
+        let ns = self.namespace().unwrap();
+        let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(ctx.client.clone(), &ns);
+        let pod_status = pod.and_then(|p| p.status.as_ref());
+        let running = pod_status.and_then(|s| s.phase.as_deref()) == Some("Running");
+
+        let mut statuses = vec![];
+        for drive in &self.spec.drives {
+            let message = if let Some(claim_name) = &drive.persistent_volume_claim {
+                let phase = pvcs
+                    .get_opt(claim_name)
+                    .await
+                    .map_err(Error::KubeError)?
+                    .and_then(|pvc| pvc.status)
+                    .and_then(|s| s.phase);
+                match phase.as_deref() {
+                    Some("Bound") => None,
+                    Some(phase) => Some(format!("PersistentVolumeClaim {claim_name} is {phase}")),
+                    None => Some(format!("PersistentVolumeClaim {claim_name} not found")),
+                }
+            } else {
+                let init_status = pod_status
+                    .and_then(|s| s.init_container_statuses.as_ref())
+                    .and_then(|css| {
+                        css.iter()
+                            .find(|cs| cs.name == format!("drive-{}", drive.name))
+                    });
+                let waiting = init_status
+                    .and_then(|cs| cs.state.as_ref())
+                    .and_then(|s| s.waiting.as_ref());
+                waiting.map(|w| {
+                    format!(
+                        "copying disk image: {}",
+                        w.reason.as_deref().unwrap_or("waiting")
+                    )
+                })
+            };
+            statuses.push(DriveStatus {
+                name: drive.name.clone(),
+                attached: running && message.is_none(),
+                message: message.or_else(|| (!running).then(|| "VM is not running".to_string())),
+            });
+        }
+        Ok(statuses)
+    }
+
+    fn ssh_keys_secret_name(&self) -> String {
+        format!("{}-ssh-keys", self.name_any())
+    }
+
+    fn root_disk_pvc_name(&self) -> String {
+        format!("{}-rootfs", self.name_any())
+    }
+
+    // Render the pod running the VM, restoring from the hibernation snapshot if there is one
+    fn pod(&self, status: &VirtualMachineStatus, policy: &PodPolicy) -> Result<Pod> {
+        let owner_reference = self.controller_owner_ref(&()).unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let boot_source = self.boot_source()?;
+        let firecracker_config = self.firecracker_config(self.spec.memory_mib)?;
+        if self.spec.guest_agent.is_some() && !self.spec.vsock.as_ref().is_some_and(|v| v.enabled) {
+            return Err(Error::InvalidSpec(
+                "spec.guestAgent requires spec.vsock to be enabled".to_string(),
+            ));
+        }
+
+        // Requests and limits are equal so the VM gets a guaranteed QoS class, unless part of it
+        // is overcommitted
+        let memory_mib = self.spec.memory_mib + policy.memory_overhead_mib;
+        let mut resources = BTreeMap::from([
+            ("cpu".to_string(), Quantity(self.spec.cpus.to_string())),
+            ("memory".to_string(), Quantity(format!("{memory_mib}Mi"))),
+        ]);
+        if let Some(kvm_device_resource) = &policy.kvm_device_resource {
+            resources.insert(kvm_device_resource.clone(), Quantity("1".to_string()));
+        }
+        let mut requests = resources.clone();
+        if let Some(overcommit) = &self.spec.overcommit {
+            requests.insert(
+                "cpu".to_string(),
+                Quantity(format!(
+                    "{}m",
+                    self.spec.cpus * 10 * overcommit.cpu_request_percent
+                )),
+            );
+            requests.insert(
+                "memory".to_string(),
+                Quantity(format!(
+                    "{}Mi",
+                    memory_mib * overcommit.memory_request_percent / 100
+                )),
+            );
+        }
+
+        let mut env = vec![
+            EnvVar {
+                name: "FIRECRACKER_API_SOCKET".to_string(),
+                value: Some(firecracker::API_SOCKET_PATH.to_string()),
+                ..EnvVar::default()
+            },
+            EnvVar {
+                name: "FIRECRACKER_KERNEL_ARGS".to_string(),
+                value: Some(boot_source.kernel_args.clone()),
+                ..EnvVar::default()
+            },
+        ];
+        if let Some(snapshot) = &status.snapshot {
+            env.push(EnvVar {
+                name: "FIRECRACKER_RESTORE_SNAPSHOT_PATH".to_string(),
+                value: Some(format!("{SNAPSHOT_MOUNT_PATH}/{}", snapshot.snapshot_path)),
+                ..EnvVar::default()
+            });
+            env.push(EnvVar {
+                name: "FIRECRACKER_RESTORE_MEM_FILE_PATH".to_string(),
+                value: Some(format!("{SNAPSHOT_MOUNT_PATH}/{}", snapshot.mem_file_path)),
+                ..EnvVar::default()
+            });
+        }
+
+        let mut volume_mounts = vec![];
+        let mut volumes = vec![];
+        if self.spec.hibernation || status.snapshot.is_some() {
+            volume_mounts.push(VolumeMount {
+                name: "snapshot".to_string(),
+                mount_path: SNAPSHOT_MOUNT_PATH.to_string(),
+                ..VolumeMount::default()
+            });
+            volumes.push(Volume {
+                name: "snapshot".to_string(),
+                persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+                    claim_name: self.snapshot_pvc_name(),
+                    ..PersistentVolumeClaimVolumeSource::default()
+                }),
+                ..Volume::default()
+            });
+        }
+
+        // Firecracker needs /dev/kvm, and /dev/net/tun for the tap devices of the interfaces
+        let mut host_devices = vec![("tun", "/dev/net/tun")];
+        if policy.kvm_device_resource.is_none() {
+            host_devices.push(("kvm", "/dev/kvm"));
+        }
+        for (name, path) in host_devices {
+            volume_mounts.push(VolumeMount {
+                name: format!("dev-{name}"),
+                mount_path: path.to_string(),
+                ..VolumeMount::default()
+            });
+            volumes.push(Volume {
+                name: format!("dev-{name}"),
+                host_path: Some(HostPathVolumeSource {
+                    path: path.to_string(),
+                    type_: Some("CharDevice".to_string()),
+                }),
+                ..Volume::default()
+            });
+        }
+        let security_context = if policy.privileged {
+            SecurityContext {
+                privileged: Some(true),
+                ..SecurityContext::default()
+            }
+        } else {
+            SecurityContext {
+                capabilities: Some(Capabilities {
+                    add: Some(vec!["NET_ADMIN".to_string(), "NET_RAW".to_string()]),
+                    ..Capabilities::default()
+                }),
+                ..SecurityContext::default()
+            }
+        };
+        if self.spec.storage.is_some() {
+            env.push(EnvVar {
+                name: "FIRECRACKER_ROOT_DISK_PATH".to_string(),
+                value: Some(ROOT_DISK_MOUNT_PATH.to_string()),
+                ..EnvVar::default()
+            });
+            volume_mounts.push(VolumeMount {
+                name: "rootfs".to_string(),
+                mount_path: ROOT_DISK_MOUNT_PATH.to_string(),
+                ..VolumeMount::default()
+            });
+            volumes.push(Volume {
+                name: "rootfs".to_string(),
+                persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+                    claim_name: self.root_disk_pvc_name(),
+                    ..PersistentVolumeClaimVolumeSource::default()
+                }),
+                ..Volume::default()
+            });
+        }
+        if let Some(cloud_init) = &self.spec.cloud_init {
+            env.push(EnvVar {
+                name: "FIRECRACKER_CLOUD_INIT_USER_DATA".to_string(),
+                value: Some(format!("{CLOUD_INIT_MOUNT_PATH}/user-data")),
+                ..EnvVar::default()
+            });
+            volume_mounts.push(VolumeMount {
+                name: "cloud-init".to_string(),
+                mount_path: CLOUD_INIT_MOUNT_PATH.to_string(),
+                read_only: Some(true),
+                ..VolumeMount::default()
+            });
+            volumes.push(cloud_init.user_data_ref.volume("cloud-init", "user-data"));
+        }
+        if self.spec.ssh_keys.is_some() {
+            env.push(EnvVar {
+                name: "FIRECRACKER_SSH_AUTHORIZED_KEYS".to_string(),
+                value: Some(format!("{SSH_KEYS_MOUNT_PATH}/authorized_keys")),
+                ..EnvVar::default()
+            });
+            volume_mounts.push(VolumeMount {
+                name: "ssh-keys".to_string(),
+                mount_path: SSH_KEYS_MOUNT_PATH.to_string(),
+                read_only: Some(true),
+                ..VolumeMount::default()
+            });
+            volumes.push(Volume {
+                name: "ssh-keys".to_string(),
+                secret: Some(SecretVolumeSource {
+                    secret_name: Some(self.ssh_keys_secret_name()),
+                    ..SecretVolumeSource::default()
+                }),
+                ..Volume::default()
+            });
+        }
+
+        // The kernel image only ships the kernel, so an init container copies it next to the rootfs
+        let mut init_containers = vec![];
+        if let Some(kernel_image) = &boot_source.kernel_image {
+            env.push(EnvVar {
+                name: "FIRECRACKER_KERNEL_PATH".to_string(),
+                value: Some(format!("{KERNEL_MOUNT_PATH}/vmlinux")),
+                ..EnvVar::default()
+            });
+            let kernel_mount = VolumeMount {
+                name: "kernel".to_string(),
+                mount_path: KERNEL_MOUNT_PATH.to_string(),
+                ..VolumeMount::default()
+            };
+            init_containers.push(Container {
+                name: "kernel".to_string(),
+                image: Some(kernel_image.clone()),
+                command: Some(vec![
+                    "cp".to_string(),
+                    "/vmlinux".to_string(),
+                    format!("{KERNEL_MOUNT_PATH}/vmlinux"),
+                ]),
+                volume_mounts: Some(vec![kernel_mount.clone()]),
+                ..Container::default()
+            });
+            volume_mounts.push(kernel_mount);
+            volumes.push(Volume {
+                name: "kernel".to_string(),
+                empty_dir: Some(EmptyDirVolumeSource::default()),
+                ..Volume::default()
+            });
+        }
+
+        for drive in &self.spec.drives {
+            let volume_name = format!("drive-{}", drive.name);
+            let drive_mount = VolumeMount {
+                name: volume_name.clone(),
+                mount_path: format!("{DRIVES_MOUNT_PATH}/{}", drive.name),
+                ..VolumeMount::default()
+            };
+            match (&drive.image, &drive.persistent_volume_claim) {
+                (Some(image), None) => {
+                    init_containers.push(Container {
+                        name: volume_name.clone(),
+                        image: Some(image.clone()),
+                        command: Some(vec![
+                            "cp".to_string(),
+                            "/disk.img".to_string(),
+                            format!("{}/disk.img", drive_mount.mount_path),
+                        ]),
+                        volume_mounts: Some(vec![drive_mount.clone()]),
+                        ..Container::default()
+                    });
+                    volumes.push(Volume {
+                        name: volume_name,
+                        empty_dir: Some(EmptyDirVolumeSource::default()),
+                        ..Volume::default()
+                    });
+                }
+                (None, Some(claim_name)) => volumes.push(Volume {
+                    name: volume_name,
+                    persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+                        claim_name: claim_name.clone(),
+                        read_only: Some(drive.read_only),
+                    }),
+                    ..Volume::default()
+                }),
+                _ => {
+                    return Err(Error::InvalidSpec(format!(
+                        "drive {} must set exactly one of image or persistentVolumeClaim",
+                        drive.name
+                    )))
+                }
+            }
+            volume_mounts.push(drive_mount);
+        }
+
+        env.push(EnvVar {
+            name: "FIRECRACKER_CONFIG_FILE".to_string(),
+            value: Some(firecracker::CONFIG_PATH.to_string()),
+            ..EnvVar::default()
+        });
+        if self.spec.firecracker_metrics {
+            env.push(EnvVar {
+                name: "FIRECRACKER_METRICS_PATH".to_string(),
+                value: Some(firecracker::METRICS_PATH.to_string()),
+                ..EnvVar::default()
+            });
+        }
+        if self.spec.metadata.is_some() {
+            env.push(EnvVar {
+                name: "FIRECRACKER_METADATA_FILE".to_string(),
+                value: Some(firecracker::METADATA_PATH.to_string()),
+                ..EnvVar::default()
+            });
+        }
+        volume_mounts.push(VolumeMount {
+            name: "firecracker-config".to_string(),
+            mount_path: "/etc/firecracker".to_string(),
+            read_only: Some(true),
+            ..VolumeMount::default()
+        });
+        volumes.push(Volume {
+            name: "firecracker-config".to_string(),
+            config_map: Some(ConfigMapVolumeSource {
+                name: Some(self.firecracker_config_name()),
+                ..ConfigMapVolumeSource::default()
+            }),
+            ..Volume::default()
+        });
+
+        let scheduling = self.spec.scheduling.clone().unwrap_or_default();
+
+        let spec = PodSpec {
+            containers: vec![Container {
+                name: VM_CONTAINER_NAME.to_string(),
+                image: Some(boot_source.rootfs_image.clone()),
+                env: Some(env),
+                ports: Some(
+                    self.spec
+                        .ports
+                        .iter()
+                        .map(|p| ContainerPort {
+                            name: Some(p.name.clone()),
+                            container_port: p.target_port.unwrap_or(p.port),
+                            protocol: Some(p.protocol.as_str().to_string()),
+                            ..ContainerPort::default()
+                        })
+                        .collect(),
+                ),
+                resources: Some(ResourceRequirements {
+                    requests: Some(requests),
+                    limits: Some(resources),
+                    ..ResourceRequirements::default()
+                }),
+                volume_mounts: Some(volume_mounts),
+                security_context: Some(security_context),
+                readiness_probe: self
+                    .spec
+                    .readiness_probe
+                    .as_ref()
+                    .map(|p| p.probe("spec.readinessProbe"))
+                    .transpose()?,
+                startup_probe: self
+                    .spec
+                    .startup_probe
+                    .as_ref()
+                    .map(|p| p.probe("spec.startupProbe"))
+                    .transpose()?,
+                ..Container::default()
+            }],
+            init_containers: Some(init_containers),
+            volumes: Some(volumes),
+            runtime_class_name: self.spec.runtime_class_name.clone(),
+            service_account_name: self.spec.service_account_name.clone(),
+            image_pull_secrets: Some(self.spec.image_pull_secrets.clone())
+                .filter(|s| !s.is_empty()),
+            node_selector: scheduling.node_selector,
+            tolerations: scheduling.tolerations,
+            affinity: scheduling.affinity,
+            // Exits are handled by the controller according to spec.restartPolicy
+            restart_policy: Some("Never".to_string()),
+            ..PodSpec::default()
+        };
+        Ok(Pod {
+            metadata: ObjectMeta {
+                name: Some(vm_name.to_string()),
+                owner_references: Some(vec![owner_reference]),
+                labels: Some(self.labels()),
+                annotations: Some(
+                    BTreeMap::from([
+                        (CPUS_ANNOTATION.to_string(), self.spec.cpus.to_string()),
+                        (
+                            MEMORY_MIB_ANNOTATION.to_string(),
+                            self.spec.memory_mib.to_string(),
+                        ),
+                        (
+                            POD_INPUTS_HASH_ANNOTATION.to_string(),
+                            self.pod_inputs_hash(&boot_source, &firecracker_config),
+                        ),
+                    ])
+                    .into_iter()
+                    .chain(
+                        self.annotations()
+                            .get(RESTART_AT_ANNOTATION)
+                            .map(|v| (RESTART_AT_ANNOTATION.to_string(), v.clone())),
+                    )
+                    .collect(),
+                ),
+                ..ObjectMeta::default()
+            },
+            spec: Some(spec),
+            ..Pod::default()
+        })
+    }
+
+    // Hash of the spec fields the VM pod is rendered from, telling whether the pod is outdated.
+    // Sizing is left out as resize() decides how it is applied. The pod rendering itself is not
+    // hashed, so upgrading the controller or restoring a snapshot does not recreate running VMs.
+    // The Firecracker config is, as it is only read at boot, without the parts applied live.
+    fn pod_inputs_hash(
+        &self,
+        boot_source: &VirtualMachineBootSource,
+        firecracker_config: &firecracker::Config,
+    ) -> String {
+        let spec = &self.spec;
+        let mut firecracker_config = firecracker_config.clone();
+        firecracker_config.machine_config.vcpu_count = 0;
+        firecracker_config.machine_config.mem_size_mib = 0;
+        if let Some(balloon) = firecracker_config.balloon.as_mut() {
+            balloon.amount_mib = 0;
+            balloon.stats_polling_interval_s = 0;
+        }
+        spec_hash(&json!({
+            "bootSource": boot_source,
+            "hibernation": spec.hibernation,
+            "ports": spec
+                .ports
+                .iter()
+                .map(|p| (&p.name, p.target_port.unwrap_or(p.port), p.protocol.as_str()))
+                .collect::<Vec<_>>(),
+            "storage": spec.storage.is_some(),
+            "cloudInit": spec.cloud_init.as_ref().map(|c| &c.user_data_ref),
+            "sshKeys": spec.ssh_keys.is_some(),
+            "drives": spec
+                .drives
+                .iter()
+                .map(|d| (&d.name, &d.image, &d.persistent_volume_claim, d.read_only))
+                .collect::<Vec<_>>(),
+            "metadata": spec.metadata.is_some(),
+            "scheduling": spec.scheduling,
+            "runtimeClassName": spec.runtime_class_name,
+            "serviceAccountName": spec.service_account_name,
+            "imagePullSecrets": spec.image_pull_secrets,
+            // Resizing only compares the size, the requests follow the overcommit of the class
+            "overcommit": spec.overcommit,
+            "readinessProbe": spec.readiness_probe,
+            "startupProbe": spec.startup_probe,
+            "firecrackerMetrics": spec.firecracker_metrics,
+            "firecrackerConfig": firecracker_config,
+            // Custom CPU templates are written next to the config
+            "cpuTemplate": spec.cpu_template,
+        }))
+    }
+
+    // Apply the Service for the configured type and report its addresses
+    #[instrument(skip_all)]
+    async fn ensure_service(
+        &self,
+        ctx: Arc<Context>,
+        status: &mut VirtualMachineStatus,
+    ) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let services: Api<Service> = Api::namespaced(ctx.client.clone(), &ns);
+        let existing_service = services.get_opt(vm_name).await.map_err(Error::KubeError)?;
+        if let Some(service) = &existing_service {
+            self.adopt(ctx.clone(), &services, service).await?;
+        }
+
+        let service_type = &self.spec.networking.service_type;
+        let headless = self.spec.networking.headless;
+        if headless && *service_type != VirtualMachineServiceType::ClusterIP {
+            return Err(Error::InvalidSpec(
+                "spec.networking.headless requires the ClusterIP service type".to_string(),
+            ));
+        }
+
+        let existing_service = match existing_service {
+            _ if *service_type == VirtualMachineServiceType::None => {
+                if existing_service.is_some() {
+                    let _o = services
+                        .delete(vm_name, &Default::default())
+                        .await
+                        .map_err(Error::KubeError)?;
+                }
+                None
+            }
+            // The cluster IP is immutable, the Service is created again on the next reconcile
+            Some(existing)
+                if (existing.spec.as_ref().and_then(|s| s.cluster_ip.as_deref())
+                    == Some("None"))
+                    != headless =>
+            {
+                let _o = services
+                    .delete(vm_name, &Default::default())
+                    .await
+                    .map_err(Error::KubeError)?;
+                None
+            }
+            Some(existing) if !service_drift(&self.service(), &existing) => Some(existing),
+            // Reverts changes made to the fields fink manages
+            _ => Some(
+                services
+                    .patch(
+                        vm_name,
+                        &PatchParams::apply(FIELD_MANAGER).force(),
+                        &Patch::Apply(&self.service()),
+                    )
+                    .await
+                    .map_err(Error::KubeError)?,
+            ),
+        };
+
+        // The VM pod is selected again, so the activator no longer receives its traffic
+        self.delete_activator_endpoints(ctx.clone()).await?;
+
+        let spec = existing_service.as_ref().and_then(|s| s.spec.as_ref());
+        status.service_cluster_ip = spec.and_then(|s| s.cluster_ip.clone());
+        status.node_ports = spec
+            .and_then(|s| s.ports.as_ref())
+            .into_iter()
+            .flatten()
+            .filter_map(|p| {
+                Some(NodePortStatus {
+                    name: p.name.clone()?,
+                    node_port: p.node_port?,
+                })
+            })
+            .collect();
+        status.load_balancer_ingress = existing_service
+            .as_ref()
+            .and_then(|s| s.status.as_ref())
+            .and_then(|s| s.load_balancer.as_ref())
+            .and_then(|lb| lb.ingress.as_ref())
+            .into_iter()
+            .flatten()
+            .filter_map(|i| i.ip.clone().or_else(|| i.hostname.clone()))
+            .collect();
+        Ok(())
+    }
+
+    // Whether the Service of the stopped or hibernated VM routes to the activator
+    fn wakes_on_request(&self, ctx: &Context) -> bool {
+        self.spec.networking.wake_on_request
+            && ctx.config.activator.enabled
+            && self.spec.networking.service_type != VirtualMachineServiceType::None
+    }
+
+    // Port the activator proxies requests to, the one of the Ingress or else the first port
+    pub(crate) fn activator_target_port(&self) -> Option<i32> {
+        let ingress = self.spec.networking.ingress.as_ref();
+        let port = self
+            .route_port(ingress.and_then(|i| i.port.as_ref()), "")
+            .ok()?;
+        Some(port.target_port.unwrap_or(port.port))
+    }
+
+    // Point the Service of the sleeping VM at the activator. The selector is dropped so an
+    // EndpointSlice managed by fink can carry the addresses of the activator pods, every port of
+    // the VM forwarding to the activator port.
+    #[instrument(skip_all)]
+    async fn ensure_activator_routing(&self, ctx: Arc<Context>) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let activator = &ctx.config.activator;
+        let Some(activator_port) = activator.port() else {
+            return Err(Error::InvalidSpec(format!(
+                "activator bind address {} has no port",
+                activator.bind
+            )));
+        };
+
+        let mut service = self.service();
+        if let Some(spec) = service.spec.as_mut() {
+            spec.selector = None;
+        }
+        let hash = spec_hash(&service.spec);
+        service
+            .annotations_mut()
+            .insert(SPEC_HASH_ANNOTATION.to_string(), hash);
+        let services: Api<Service> = Api::namespaced(ctx.client.clone(), &ns);
+        let existing = services.get_opt(vm_name).await.map_err(Error::KubeError)?;
+        if existing.is_none_or(|existing| service_drift(&service, &existing)) {
+            let _o = services
+                .patch(
+                    vm_name,
+                    &PatchParams::apply(FIELD_MANAGER).force(),
+                    &Patch::Apply(&service),
+                )
+                .await
+                .map_err(Error::KubeError)?;
+        }
+
+        let activator_endpoints: Api<Endpoints> =
+            Api::namespaced(ctx.client.clone(), &activator.service_namespace);
+        let addresses = activator_addresses(
+            activator_endpoints
+                .get_opt(&activator.service_name)
+                .await
+                .map_err(Error::KubeError)?,
+        );
+        if addresses.is_empty() {
+            warn!(
+                "Activator service {}/{} has no ready endpoints",
+                activator.service_namespace, activator.service_name
+            );
+        }
+        self.apply_activator_endpoints(ctx.client.clone(), activator_port, addresses)
+            .await
+    }
+
+    // Whether the Service of the VM routes to the activator, as the VM sleeps and wakes on request
+    pub(crate) fn routes_to_activator(&self, ctx: &Context) -> bool {
+        let sleeping = self.status.as_ref().is_some_and(|s| {
+            matches!(
+                s.state,
+                VirtualMachineCurrentState::STOPPED | VirtualMachineCurrentState::HIBERNATED
+            )
+        });
+        sleeping
+            && self.spec.state != VirtualMachineDesiredState::STARTED
+            && self.wakes_on_request(ctx)
+    }
+
+    // Apply the EndpointSlice of the Service of the sleeping VM carrying the activator `addresses`
+    pub(crate) async fn apply_activator_endpoints(
+        &self,
+        client: Client,
+        activator_port: i32,
+        addresses: Vec<String>,
+    ) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let slice = EndpointSlice {
+            metadata: ObjectMeta {
+                name: Some(self.activator_endpoints_name()),
+                owner_references: Some(vec![self.controller_owner_ref(&()).unwrap()]),
+                labels: Some(
+                    self.labels()
+                        .into_iter()
+                        .chain([
+                            (
+                                "kubernetes.io/service-name".to_string(),
+                                vm_name.to_string(),
+                            ),
+                            (
+                                "endpointslice.kubernetes.io/managed-by".to_string(),
+                                "fink".to_string(),
+                            ),
+                        ])
+                        .collect(),
+                ),
+                ..ObjectMeta::default()
+            },
+            address_type: "IPv4".to_string(),
+            endpoints: addresses
+                .into_iter()
+                .map(|ip| Endpoint {
+                    addresses: vec![ip],
+                    ..Endpoint::default()
+                })
+                .collect(),
+            ports: Some(
+                self.spec
+                    .ports
+                    .iter()
+                    .map(|p| EndpointPort {
+                        name: Some(p.name.clone()),
+                        protocol: Some(p.protocol.as_str().to_string()),
+                        port: Some(activator_port),
+                        ..EndpointPort::default()
+                    })
+                    .collect(),
+            ),
+        };
+        let slices: Api<EndpointSlice> = Api::namespaced(client, &ns);
+        let _o = slices
+            .patch(
+                &self.activator_endpoints_name(),
+                &PatchParams::apply(FIELD_MANAGER).force(),
+                &Patch::Apply(&slice),
+            )
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+
+    // Remove the activator endpoints once the VM pod serves the Service again
+    async fn delete_activator_endpoints(&self, ctx: Arc<Context>) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let slices: Api<EndpointSlice> = Api::namespaced(ctx.client.clone(), &ns);
+        if slices
+            .get_opt(&self.activator_endpoints_name())
+            .await
+            .map_err(Error::KubeError)?
+            .is_some()
+        {
+            let _o = slices
+                .delete(&self.activator_endpoints_name(), &Default::default())
+                .await
+                .map_err(Error::KubeError)?;
+        }
+        Ok(())
+    }
+
+    fn activator_endpoints_name(&self) -> String {
+        format!("{}-activator", self.name_any())
+    }
+
+    // Annotations of the Service and Ingress, with the external-dns hostname when one is set
+    fn networking_annotations(&self) -> BTreeMap<String, String> {
+        let mut annotations = self.spec.networking.annotations.clone();
+        if let Some(hostname) = &self.spec.networking.hostname {
+            annotations.insert(
+                "external-dns.alpha.kubernetes.io/hostname".to_string(),
+                hostname.clone(),
+            );
+        }
+        annotations
+    }
+
+    fn url(&self) -> Option<String> {
+        let networking = &self.spec.networking;
+        let ingress = networking.ingress.as_ref();
+        let host = networking.hostname.as_ref().or(ingress.map(|i| &i.host))?;
+        let tls = ingress.is_some_and(|i| &i.host == host && i.tls_secret_ref.is_some());
+        Some(format!("{}://{}", if tls { "https" } else { "http" }, host))
+    }
+
+    // Port of spec.ports selected by name, or the first one
+    fn route_port(&self, name: Option<&String>, field: &str) -> Result<&VirtualMachinePort> {
+        match name {
+            Some(name) => self.spec.ports.iter().find(|p| &p.name == name),
+            None => self.spec.ports.first(),
+        }
+        .ok_or_else(|| Error::InvalidSpec(format!("{} does not match a port", field)))
+    }
+
+    fn ingress(&self, ingress: &VirtualMachineIngress) -> Result<Ingress> {
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let port = self.route_port(ingress.port.as_ref(), "spec.networking.ingress.port")?;
+
+        let mut annotations = self.networking_annotations();
+        let managed: Vec<&str> = annotations.keys().map(String::as_str).collect();
+        annotations.insert(
+            MANAGED_ANNOTATIONS_ANNOTATION.to_string(),
+            managed.join(","),
+        );
+        Ok(Ingress {
+            metadata: ObjectMeta {
+                name: Some(vm_name.to_string()),
+                owner_references: Some(vec![self.controller_owner_ref(&()).unwrap()]),
+                labels: Some(self.labels()),
+                annotations: Some(annotations),
+                ..ObjectMeta::default()
+            },
+            spec: Some(IngressSpec {
+                ingress_class_name: ingress.ingress_class_name.clone(),
+                rules: Some(vec![IngressRule {
+                    host: Some(ingress.host.clone()),
+                    http: Some(HTTPIngressRuleValue {
+                        paths: vec![HTTPIngressPath {
+                            path: Some(ingress.path.clone()),
+                            path_type: "Prefix".to_string(),
+                            backend: IngressBackend {
+                                service: Some(IngressServiceBackend {
+                                    name: vm_name.to_string(),
+                                    port: Some(ServiceBackendPort {
+                                        number: Some(port.port),
+                                        ..ServiceBackendPort::default()
+                                    }),
+                                }),
+                                ..IngressBackend::default()
+                            },
+                        }],
+                    }),
+                }]),
+                tls: ingress.tls_secret_ref.as_ref().map(|secret| {
+                    vec![IngressTLS {
+                        hosts: Some(vec![ingress.host.clone()]),
+                        secret_name: secret.name.clone(),
+                    }]
+                }),
+                ..IngressSpec::default()
+            }),
+            ..Ingress::default()
+        })
+    }
+
+    // Keep the Ingress in line with spec.networking.ingress, removing it when unset
+    #[instrument(skip_all)]
+    async fn ensure_ingress(&self, ctx: Arc<Context>) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let ingresses: Api<Ingress> = Api::namespaced(ctx.client.clone(), &ns);
+        let existing = ingresses.get_opt(vm_name).await.map_err(Error::KubeError)?;
+
+        match (&self.spec.networking.ingress, existing) {
+            (None, None) => {}
+            (None, Some(_)) => {
+                let _o = ingresses
+                    .delete(vm_name, &Default::default())
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+            (Some(ingress), existing) => {
+                let desired = self.ingress(ingress)?;
+                let drift = existing.is_none_or(|existing| {
+                    let desired_annotations = desired.annotations();
+                    let annotations = existing.annotations();
+                    // Annotations fink no longer sets are dropped by the apply below
+                    let stale = annotations
+                        .get(MANAGED_ANNOTATIONS_ANNOTATION)
+                        .into_iter()
+                        .flat_map(|keys| keys.split(','))
+                        .any(|k| {
+                            !desired_annotations.contains_key(k) && annotations.contains_key(k)
+                        });
+                    stale
+                        || desired_annotations
+                            .iter()
+                            .any(|(k, v)| annotations.get(k) != Some(v))
+                        || existing.spec != desired.spec
+                });
+                if drift {
+                    let _o = ingresses
+                        .patch(
+                            vm_name,
+                            &PatchParams::apply(FIELD_MANAGER).force(),
+                            &Patch::Apply(&desired),
+                        )
+                        .await
+                        .map_err(Error::KubeError)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn http_route(&self, route: &VirtualMachineHttpRoute) -> Result<DynamicObject> {
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let port = self.route_port(route.port.as_ref(), "spec.networking.httpRoute.port")?;
+
+        let mut parent_ref = json!({
+            "group": "gateway.networking.k8s.io",
+            "kind": "Gateway",
+            "name": route.gateway_ref.name,
+        });
+        if let Some(namespace) = &route.gateway_ref.namespace {
+            parent_ref["namespace"] = json!(namespace);
+        }
+        if let Some(section_name) = &route.gateway_ref.section_name {
+            parent_ref["sectionName"] = json!(section_name);
+        }
+
+        // Defaulted fields are spelled out so the spec compares equal once stored
+        let mut spec = json!({
+            "parentRefs": [parent_ref],
+            "rules": [{
+                "matches": [{"path": {"type": "PathPrefix", "value": route.path}}],
+                "backendRefs": [{
+                    "group": "",
+                    "kind": "Service",
+                    "name": vm_name,
+                    "port": port.port,
+                    "weight": 1,
+                }],
+            }],
+        });
+        if !route.hostnames.is_empty() {
+            spec["hostnames"] = json!(route.hostnames);
+        }
+
+        let mut object = DynamicObject::new(vm_name, &http_route_resource()).data(json!({
+            "spec": spec,
+        }));
+        object.metadata.owner_references = Some(vec![self.controller_owner_ref(&()).unwrap()]);
+        object.metadata.labels = Some(self.labels());
+        Ok(object)
+    }
+
+    // Keep the HTTPRoute in line with spec.networking.httpRoute, removing it when unset
+    #[instrument(skip_all)]
+    async fn ensure_http_route(&self, ctx: Arc<Context>) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let routes: Api<DynamicObject> =
+            Api::namespaced_with(ctx.client.clone(), &ns, &http_route_resource());
+
+        let Some(route) = &self.spec.networking.http_route else {
+            // Without the Gateway API CRDs installed this lookup is a 404 as well
+            if routes
+                .get_opt(vm_name)
+                .await
+                .map_err(Error::KubeError)?
+                .is_some()
+            {
+                let _o = routes
+                    .delete(vm_name, &Default::default())
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+            return Ok(());
+        };
+
+        let desired = self.http_route(route)?;
+        let existing = routes.get_opt(vm_name).await.map_err(Error::KubeError)?;
+        if existing.is_none_or(|existing| existing.data["spec"] != desired.data["spec"]) {
+            let _o = routes
+                .patch(
+                    vm_name,
+                    &PatchParams::apply(FIELD_MANAGER).force(),
+                    &Patch::Apply(&desired),
+                )
+                .await
+                .map_err(Error::KubeError)?;
+        }
+        Ok(())
+    }
+
+    fn network_policy(
+        &self,
+        isolation: &VirtualMachineIsolation,
+        activator: &ActivatorConfig,
+    ) -> NetworkPolicy {
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let namespaces = |names: Vec<String>| LabelSelector {
+            match_expressions: Some(vec![LabelSelectorRequirement {
+                key: "kubernetes.io/metadata.name".to_string(),
+                operator: "In".to_string(),
+                values: Some(names),
+            }]),
+            ..LabelSelector::default()
+        };
+
+        // The activator proxies the requests waking the VM
+        let mut from = vec![NetworkPolicyPeer {
+            namespace_selector: Some(namespaces(vec![activator.service_namespace.clone()])),
+            pod_selector: Some(LabelSelector {
+                match_labels: Some(activator.pod_labels.clone()),
+                ..LabelSelector::default()
+            }),
+            ..NetworkPolicyPeer::default()
+        }];
+        from.extend(
+            isolation
+                .allowed_cidrs
+                .iter()
+                .map(|cidr| NetworkPolicyPeer {
+                    ip_block: Some(IPBlock {
+                        cidr: cidr.clone(),
+                        except: None,
+                    }),
+                    ..NetworkPolicyPeer::default()
+                }),
+        );
+        if !isolation.allowed_namespaces.is_empty() {
+            from.push(NetworkPolicyPeer {
+                namespace_selector: Some(namespaces(isolation.allowed_namespaces.clone())),
+                ..NetworkPolicyPeer::default()
+            });
+        }
+
+        NetworkPolicy {
+            metadata: ObjectMeta {
+                name: Some(vm_name.to_string()),
+                owner_references: Some(vec![self.controller_owner_ref(&()).unwrap()]),
+                labels: Some(self.labels()),
+                ..ObjectMeta::default()
+            },
+            spec: Some(NetworkPolicySpec {
+                pod_selector: LabelSelector {
+                    match_labels: Some(self.labels()),
+                    ..LabelSelector::default()
+                },
+                policy_types: Some(vec!["Ingress".to_string()]),
+                ingress: Some(vec![NetworkPolicyIngressRule {
+                    from: Some(from),
+                    ports: Some(
+                        self.spec
+                            .ports
+                            .iter()
+                            .map(|p| NetworkPolicyPort {
+                                protocol: Some(p.protocol.as_str().to_string()),
+                                port: Some(IntOrString::Int(p.target_port.unwrap_or(p.port))),
+                                end_port: None,
+                            })
+                            .collect(),
+                    ),
+                }]),
+                ..NetworkPolicySpec::default()
+            }),
+        }
+    }
+
+    // Keep the NetworkPolicy in line with spec.networking.isolation, removing it when unset
+    #[instrument(skip_all)]
+    async fn ensure_network_policy(&self, ctx: Arc<Context>) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let policies: Api<NetworkPolicy> = Api::namespaced(ctx.client.clone(), &ns);
+        let existing = policies.get_opt(vm_name).await.map_err(Error::KubeError)?;
+
+        match (&self.spec.networking.isolation, existing) {
+            (None, None) => {}
+            (None, Some(_)) => {
+                let _o = policies
+                    .delete(vm_name, &Default::default())
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+            (Some(isolation), existing) => {
+                let desired = self.network_policy(isolation, &ctx.config.activator);
+                if existing.is_none_or(|existing| existing.spec != desired.spec) {
+                    let _o = policies
+                        .patch(
+                            vm_name,
+                            &PatchParams::apply(FIELD_MANAGER).force(),
+                            &Patch::Apply(&desired),
+                        )
+                        .await
+                        .map_err(Error::KubeError)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn pod_disruption_budget(&self) -> PodDisruptionBudget {
+        let vm_name = self.metadata.name.as_ref().unwrap();
+
+        PodDisruptionBudget {
+            metadata: ObjectMeta {
+                name: Some(vm_name.to_string()),
+                owner_references: Some(vec![self.controller_owner_ref(&()).unwrap()]),
+                labels: Some(self.labels()),
+                ..ObjectMeta::default()
+            },
+            spec: Some(PodDisruptionBudgetSpec {
+                selector: Some(LabelSelector {
+                    match_labels: Some(self.labels()),
+                    ..LabelSelector::default()
+                }),
+                max_unavailable: Some(IntOrString::Int(
+                    self.spec.disruption_budget.max_unavailable,
+                )),
+                ..PodDisruptionBudgetSpec::default()
+            }),
+            ..PodDisruptionBudget::default()
+        }
+    }
+
+    // Keep the PodDisruptionBudget in line with spec.disruptionBudget, removing it when disabled
+    #[instrument(skip_all)]
+    async fn ensure_pod_disruption_budget(&self, ctx: Arc<Context>) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let pdbs: Api<PodDisruptionBudget> = Api::namespaced(ctx.client.clone(), &ns);
+        let existing = pdbs.get_opt(vm_name).await.map_err(Error::KubeError)?;
+
+        match (self.spec.disruption_budget.enabled, existing) {
+            (false, None) => {}
+            (false, Some(_)) => {
+                let _o = pdbs
+                    .delete(vm_name, &Default::default())
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+            (true, existing) => {
+                let desired = self.pod_disruption_budget();
+                if existing.is_none_or(|existing| existing.spec != desired.spec) {
+                    let _o = pdbs
+                        .patch(
+                            vm_name,
+                            &PatchParams::apply(FIELD_MANAGER).force(),
+                            &Patch::Apply(&desired),
+                        )
+                        .await
+                        .map_err(Error::KubeError)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Evictions refused by the PDB are not recorded anywhere, so the VM is reported as blocking a
+    // drain while its node is cordoned and its PDB allows no disruption. The Warning event is only
+    // published when the block starts.
+    async fn report_blocked_eviction(
+        &self,
+        ctx: Arc<Context>,
+        pod: Option<&Pod>,
+        status: &mut VirtualMachineStatus,
+    ) -> Result<()> {
+        let blocked_node = match pod {
+            Some(pod) => self.blocked_drain(ctx.clone(), pod).await?,
+            None => None,
+        };
+        let condition = status.condition(VirtualMachineConditionType::EvictionBlocked);
+        let was_blocked = condition.is_some_and(|c| c.status == ConditionStatus::True);
+        let Some(node_name) = blocked_node else {
+            if condition.is_some() {
+                status.set_condition(
+                    VirtualMachineConditionType::EvictionBlocked,
+                    false,
+                    "NotDraining",
+                    None,
+                );
+            }
+            return Ok(());
+        };
+
+        let message = format!(
+            "Node {} is being drained, the PodDisruptionBudget blocks evicting the VM until it is stopped or hibernated",
+            node_name
+        );
+        status.set_condition(
+            VirtualMachineConditionType::EvictionBlocked,
+            true,
+            "DisruptionBudget",
+            Some(message.clone()),
+        );
+        if !was_blocked {
+            self.publish_event(
+                ctx,
+                EventType::Warning,
+                "EvictionBlocked",
+                "Evict",
+                Some(message),
+            )
+            .await;
+        }
+        Ok(())
+    }
+
+    // Node of the VM pod when it is cordoned for a drain that the PDB of the VM refuses
+    async fn blocked_drain(&self, ctx: Arc<Context>, pod: &Pod) -> Result<Option<String>> {
+        let Some(node_name) = pod.spec.as_ref().and_then(|s| s.node_name.clone()) else {
+            return Ok(None);
+        };
+        let pdbs: Api<PodDisruptionBudget> =
+            Api::namespaced(ctx.client.clone(), &self.namespace().unwrap());
+        let refusing = pdbs
+            .get_opt(&self.name_any())
+            .await
+            .map_err(Error::KubeError)?
+            .and_then(|pdb| pdb.status)
+            .is_some_and(|s| s.disruptions_allowed == 0);
+        if !refusing {
+            return Ok(None);
+        }
+
+        // Controllers restricted to a namespace may not be allowed to read nodes
+        let nodes: Api<Node> = Api::all(ctx.client.clone());
+        let node = match nodes.get_opt(&node_name).await {
+            Err(kube::Error::Api(ErrorResponse { code: 403, .. })) => return Ok(None),
+            node => node.map_err(Error::KubeError)?,
+        };
+        let cordoned = node
+            .and_then(|n| n.spec)
+            .and_then(|s| s.unschedulable)
+            .unwrap_or(false);
+        Ok(cordoned.then_some(node_name))
+    }
+
+    // Take ownership of a child carrying the VM name label but no owner reference to this VM, e.g.
+    // after a restore or a manual creation, instead of failing or creating a duplicate. References
+    // to an earlier VM of the same name are replaced.
+    async fn adopt<K>(&self, ctx: Arc<Context>, api: &Api<K>, child: &K) -> Result<()>
+    where
+        K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug,
+    {
+        let owner_reference = self.controller_owner_ref(&()).unwrap();
+        let owner_references = child.owner_references();
+        if owner_references
+            .iter()
+            .any(|r| r.uid == owner_reference.uid)
+        {
+            return Ok(());
+        }
+
+        let vm_name = self.name_any();
+        let kind = K::kind(&());
+        let child_name = child.name_any();
+        if child.labels().get(VM_NAME_LABEL) != Some(&vm_name) {
+            return Err(Error::InvalidSpec(format!(
+                "{kind} {child_name} already exists and is not labelled {VM_NAME_LABEL}={vm_name}"
+            )));
+        }
+        let previous_vm = |r: &OwnerReference| {
+            r.kind == owner_reference.kind
+                && r.api_version == owner_reference.api_version
+                && r.name == vm_name
+        };
+        if let Some(controller) = owner_references
+            .iter()
+            .find(|r| r.controller == Some(true) && !previous_vm(r))
+        {
+            return Err(Error::InvalidSpec(format!(
+                "{kind} {child_name} is controlled by {} {}",
+                controller.kind, controller.name
+            )));
+        }
+
+        info!(
+            "Adopting {} {} for VirtualMachine {}",
+            kind, child_name, vm_name
+        );
+        let mut adopted: Vec<OwnerReference> = owner_references
+            .iter()
+            .filter(|r| !previous_vm(r))
+            .cloned()
+            .collect();
+        adopted.push(owner_reference);
+        // The resource version makes the patch fail if the child changed in the meantime
+        let patch = Patch::Merge(json!({
+            "metadata": {
+                "ownerReferences": adopted,
+                "resourceVersion": child.resource_version(),
+            }
+        }));
+        let _o = api
+            .patch(&child_name, &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        self.publish_event(
+            ctx,
+            EventType::Normal,
+            "Adopted",
+            "Adopt",
+            Some(format!("Adopted orphaned {kind} {child_name}")),
+        )
+        .await;
+        Ok(())
+    }
+
+    // Drop the owner reference to this VM so the garbage collector keeps the child around
+    async fn release<K>(&self, api: &Api<K>, child: &K) -> Result<()>
+    where
+        K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug,
+    {
+        let uid = self.uid();
+        let owner_references: Vec<&OwnerReference> = child
+            .owner_references()
+            .iter()
+            .filter(|r| Some(&r.uid) != uid.as_ref())
+            .collect();
+        if owner_references.len() == child.owner_references().len() {
+            return Ok(());
+        }
+
+        info!(
+            "Orphaning {} {} of VirtualMachine {}",
+            K::kind(&()),
+            child.name_any(),
+            self.name_any()
+        );
+        let patch = Patch::Merge(json!({
+            "metadata": {
+                "ownerReferences": owner_references,
+                "resourceVersion": child.resource_version(),
+            }
+        }));
+        let _o = api
+            .patch(&child.name_any(), &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+
+    // Events are informational, failing to publish one does not fail the reconcile
+    async fn publish_event(
+        &self,
+        ctx: Arc<Context>,
+        type_: EventType,
+        reason: &str,
+        action: &str,
+        note: Option<String>,
+    ) {
+        if !ctx.config.features.events {
+            return;
+        }
+        let event = Event {
+            type_,
+            reason: reason.to_string(),
+            note,
+            action: action.to_string(),
+            secondary: None,
+        };
+        if let Err(e) = ctx.recorder(self.object_ref(&())).publish(event).await {
+            warn!(
+                "Failed to publish {} event for {}: {}",
+                reason,
+                self.name_any(),
+                e
+            );
+        }
+    }
+
+    // Republish a Warning event of the VM pod on the VM
+    pub async fn mirror_pod_event(&self, ctx: Arc<Context>, event: &CoreEvent) {
+        let reason = event.reason.as_deref().unwrap_or("PodWarning");
+        let note = event
+            .message
+            .as_ref()
+            .map(|message| format!("Pod {}: {message}", self.name_any()));
+        self.publish_event(ctx, EventType::Warning, reason, "PodEvent", note)
+            .await;
+    }
+
+    // Record the lifecycle transition made by a reconcile as an event
+    async fn publish_transition(
+        &self,
+        ctx: Arc<Context>,
+        previous: &VirtualMachineCurrentState,
+        current: &VirtualMachineCurrentState,
+    ) {
+        if previous == current {
+            return;
+        }
+        let (reason, note) = match (&self.spec.state, current) {
+            // Giving up is reported as a Warning of its own
+            (_, VirtualMachineCurrentState::FAILED) => return,
+            (_, VirtualMachineCurrentState::STARTED) => ("Started", "VirtualMachine has booted"),
+            (_, VirtualMachineCurrentState::STOPPED) => ("Stopped", "VirtualMachine has stopped"),
+            (_, VirtualMachineCurrentState::HIBERNATED) => {
+                ("Hibernated", "VirtualMachine has been hibernated")
+            }
+            (VirtualMachineDesiredState::STARTED, _) => ("Starting", "Starting VirtualMachine"),
+            (VirtualMachineDesiredState::STOPPED, _) => ("Stopping", "Stopping VirtualMachine"),
+            (VirtualMachineDesiredState::HIBERNATED, _) => (
+                "HibernateRequested",
+                "Snapshotting VirtualMachine before stopping it",
+            ),
+        };
+        self.publish_event(
+            ctx,
+            EventType::Normal,
+            reason,
+            reason,
+            Some(note.to_string()),
+        )
+        .await;
+    }
+
+    // Call the notification webhooks subscribed to `event`
+    fn notify(&self, ctx: &Context, event: NotificationEvent, message: Option<String>) {
+        let Some(notifier) = &ctx.notifier else {
+            return;
+        };
+        notifier.notify(Notification {
+            event,
+            namespace: self.namespace().unwrap(),
+            name: self.name_any(),
+            message,
+            time: Utc::now(),
+        });
+    }
+
+    // Send a CloudEvent of type `io.codesandbox.fink.virtualmachine.<state>` when the state changed
+    fn publish_cloud_event(
+        &self,
+        ctx: &Context,
+        previous: &VirtualMachineCurrentState,
+        current: &VirtualMachineCurrentState,
+    ) {
+        let Some(cloud_events) = &ctx.cloud_events else {
+            return;
+        };
+        if previous == current {
+            return;
+        }
+        let ns = self.namespace().unwrap();
+        let name = self.name_any();
+        let type_ = format!(
+            "io.codesandbox.fink.virtualmachine.{}",
+            format!("{current:?}").to_lowercase()
+        );
+        let data = json!({
+            "namespace": ns,
+            "name": name,
+            "uid": self.uid(),
+            "previousState": previous,
+            "state": current,
+        });
+        cloud_events.send(cloud_events.event(&type_, Some(format!("{ns}/{name}")), data));
+    }
+
+    fn service(&self) -> Service {
+        let owner_reference = self.controller_owner_ref(&()).unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+        let labels = self.labels();
+
+        let spec = ServiceSpec {
+            selector: Some(labels.clone()),
+            ports: Some(
+                self.spec
+                    .ports
+                    .iter()
+                    .map(|p| ServicePort {
+                        name: Some(p.name.clone()),
+                        protocol: Some(p.protocol.as_str().to_string()),
+                        port: p.port,
+                        target_port: Some(IntOrString::Int(p.target_port.unwrap_or(p.port))),
+                        ..ServicePort::default()
+                    })
+                    .collect(),
+            ),
+            type_: Some(self.spec.networking.service_type.as_str().to_string()),
+            cluster_ip: self.spec.networking.headless.then(|| "None".to_string()),
+            ..ServiceSpec::default()
+        };
+        let mut annotations = self.networking_annotations();
+        annotations.insert(SPEC_HASH_ANNOTATION.to_string(), spec_hash(&spec));
+
+        Service {
+            metadata: ObjectMeta {
+                name: Some(vm_name.to_string()),
+                owner_references: Some(vec![owner_reference]),
+                labels: Some(labels),
+                annotations: Some(annotations),
+                ..ObjectMeta::default()
+            },
+            spec: Some(spec),
+            ..Service::default()
+        }
+    }
+
+    // The snapshot PVC is sized to hold the full guest memory plus the device state. When the VM is
+    // sourced from a VirtualMachineSnapshot the PVC is cloned from the snapshot's PVC, and the
+    // location of the restored snapshot is returned the first time the PVC is created.
+    #[instrument(skip_all)]
+    async fn ensure_snapshot_pvc(&self, ctx: Arc<Context>) -> Result<Option<SnapshotLocation>> {
+        let snapshot_ref = self
+            .spec
+            .source
+            .as_ref()
+            .and_then(|s| s.snapshot_ref.as_ref());
+        if !self.spec.hibernation && snapshot_ref.is_none() {
+            return Ok(None);
+        }
+        let ns = self.namespace().unwrap();
+        let owner_reference = self.controller_owner_ref(&()).unwrap();
+        let pvc_name = self.snapshot_pvc_name();
+
+        let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(ctx.client.clone(), &ns);
+        let storage_mib = self.spec.memory_mib + 256;
+        // Kept by an earlier VM of the same name with the Orphan deletion policy
+        if let Some(pvc) = pvcs.get_opt(&pvc_name).await.map_err(Error::KubeError)? {
+            self.adopt(ctx.clone(), &pvcs, &pvc).await?;
+            // The PVC is expanded when the memory grows, so later snapshots still fit
+            let size_mib = pvc
+                .spec
+                .and_then(|s| s.resources)
+                .and_then(|r| r.requests)
+                .and_then(|r| r.get("storage").and_then(quantity_mib));
+            if size_mib.is_some_and(|size_mib| size_mib < storage_mib.into()) {
+                info!(
+                    "Expanding the snapshot PVC of VirtualMachine {} to {} MiB",
+                    self.name_any(),
+                    storage_mib
+                );
+                let patch = Patch::Merge(json!({
+                    "spec": { "resources": { "requests": { "storage": format!("{storage_mib}Mi") } } }
+                }));
+                let _o = pvcs
+                    .patch(&pvc_name, &PatchParams::default(), &patch)
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+            return Ok(None);
+        }
+
+        let mut storage = Quantity(format!("{storage_mib}Mi"));
+        let mut data_source = None;
+        let mut restored = None;
+        if let Some(snapshot_ref) = snapshot_ref {
+            let snapshots: Api<VirtualMachineSnapshot> = Api::namespaced(ctx.client.clone(), &ns);
+            let location = snapshots
+                .get(&snapshot_ref.name)
+                .await
+                .map_err(Error::KubeError)?
+                .status
+                .filter(|s| s.ready_to_use)
+                .and_then(|s| s.location)
+                .ok_or_else(|| {
+                    Error::InvalidSpec(format!(
+                        "VirtualMachineSnapshot {} is not ready to use",
+                        snapshot_ref.name
+                    ))
+                })?;
+
+            // A clone must be at least as large as its source
+            let source_pvc = pvcs
+                .get(&location.pvc_name)
+                .await
+                .map_err(Error::KubeError)?;
+            if let Some(source_storage) = source_pvc
+                .spec
+                .and_then(|s| s.resources)
+                .and_then(|r| r.requests)
+                .and_then(|r| r.get("storage").cloned())
+            {
+                storage = source_storage;
+            }
+
+            data_source = Some(TypedLocalObjectReference {
+                api_group: None,
+                kind: "PersistentVolumeClaim".to_string(),
+                name: location.pvc_name.clone(),
+            });
+            restored = Some(SnapshotLocation {
+                pvc_name: pvc_name.clone(),
+                ..location
+            });
+        }
+
+        let pvc = PersistentVolumeClaim {
+            metadata: ObjectMeta {
+                name: Some(pvc_name.clone()),
+                owner_references: Some(vec![owner_reference]),
+                labels: Some(self.labels()),
+                ..ObjectMeta::default()
+            },
+            spec: Some(PersistentVolumeClaimSpec {
+                access_modes: Some(vec!["ReadWriteOnce".to_string()]),
+                data_source,
+                resources: Some(VolumeResourceRequirements {
+                    requests: Some(BTreeMap::from([("storage".to_string(), storage)])),
+                    ..VolumeResourceRequirements::default()
+                }),
+                ..PersistentVolumeClaimSpec::default()
+            }),
+            ..PersistentVolumeClaim::default()
+        };
+
+        let _o = pvcs
+            .create(&PostParams::default(), &pvc)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(restored)
+    }
+
+    #[instrument(skip_all)]
+    async fn ensure_root_disk_pvc(&self, ctx: Arc<Context>) -> Result<()> {
+        let Some(storage) = &self.spec.storage else {
+            return Ok(());
+        };
+        let ns = self.namespace().unwrap();
+        let owner_reference = self.controller_owner_ref(&()).unwrap();
+        let pvc_name = self.root_disk_pvc_name();
+
+        let pvc = PersistentVolumeClaim {
+            metadata: ObjectMeta {
+                name: Some(pvc_name.clone()),
+                owner_references: Some(vec![owner_reference]),
+                labels: Some(self.labels()),
+                ..ObjectMeta::default()
+            },
+            spec: Some(PersistentVolumeClaimSpec {
+                access_modes: Some(vec!["ReadWriteOnce".to_string()]),
+                storage_class_name: storage.storage_class_name.clone(),
+                resources: Some(VolumeResourceRequirements {
+                    requests: Some(BTreeMap::from([(
+                        "storage".to_string(),
+                        Quantity(storage.size.clone()),
+                    )])),
+                    ..VolumeResourceRequirements::default()
+                }),
+                ..PersistentVolumeClaimSpec::default()
+            }),
+            ..PersistentVolumeClaim::default()
+        };
+
+        let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(ctx.client.clone(), &ns);
+        match pvcs.get_opt(&pvc_name).await.map_err(Error::KubeError)? {
+            Some(existing) => self.adopt(ctx.clone(), &pvcs, &existing).await?,
+            None => {
+                let _o = pvcs
+                    .create(&PostParams::default(), &pvc)
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Collect inline and referenced keys into a single authorized_keys Secret owned by the VM, which
+    // is deleted once `spec.sshKeys` is unset
+    #[instrument(skip_all)]
+    async fn ensure_ssh_keys_secret(&self, ctx: Arc<Context>) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let secret_name = self.ssh_keys_secret_name();
+        let secrets: Api<Secret> = Api::namespaced(ctx.client.clone(), &ns);
+        let Some(ssh_keys) = &self.spec.ssh_keys else {
+            if secrets
+                .get_opt(&secret_name)
+                .await
+                .map_err(Error::KubeError)?
+                .is_some()
+            {
+                let _o = secrets
+                    .delete(&secret_name, &DeleteParams::default())
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+            return Ok(());
+        };
+
+        let mut authorized_keys = ssh_keys.inline.clone();
+        for secret_ref in &ssh_keys.secret_refs {
+            let Some(name) = &secret_ref.name else {
+                return Err(Error::InvalidSpec(
+                    "spec.sshKeys.secretRefs entries must name a Secret".to_string(),
+                ));
+            };
+            let selector = KeySelector {
+                kind: KeySelectorKind::Secret,
+                name: name.clone(),
+                key: secret_ref.key.clone(),
+            };
+            match selector.resolve(ctx.client.clone(), &ns).await {
+                Ok(keys) => authorized_keys.push(keys),
+                Err(Error::InvalidSpec(_)) if secret_ref.optional == Some(true) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let data = BTreeMap::from([(
+            "authorized_keys".to_string(),
+            ByteString(format!("{}\n", authorized_keys.join("\n")).into_bytes()),
+        )]);
+
+        let existing = secrets
+            .get_opt(&secret_name)
+            .await
+            .map_err(Error::KubeError)?;
+        if existing.and_then(|s| s.data).as_ref() != Some(&data) {
+            let secret = Secret {
+                metadata: ObjectMeta {
+                    name: Some(secret_name.clone()),
+                    owner_references: Some(vec![self.controller_owner_ref(&()).unwrap()]),
+                    labels: Some(self.labels()),
+                    ..ObjectMeta::default()
+                },
+                data: Some(data),
+                ..Secret::default()
+            };
+            let _o = secrets
+                .patch(
+                    &secret_name,
+                    &PatchParams::apply(FIELD_MANAGER).force(),
+                    &Patch::Apply(&secret),
+                )
+                .await
+                .map_err(Error::KubeError)?;
+        }
+        Ok(())
+    }
+
+    // Client of the Firecracker of the running VM pod
+    pub(crate) fn firecracker(&self, client: Client) -> firecracker::ApiClient {
+        firecracker::ApiClient::new(client, &self.namespace().unwrap(), &self.name_any())
+    }
+
+    // Delete the pod and the resources routing to it, unless `keep_routing` keeps the Service,
+    // Ingress and HTTPRoute for the activator
+    #[instrument(skip_all)]
+    async fn delete_children(
+        &self,
+        ctx: Arc<Context>,
+        params: &DeleteParams,
+        keep_routing: bool,
+    ) -> Result<()> {
+        let client: Client = ctx.client.clone();
+        let ns = self.namespace().unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+
+        let pods: Api<Pod> = Api::namespaced(client.clone(), &ns);
+        let existing_pod = pods.get(vm_name).await;
+        if existing_pod.is_ok() {
+            let _o = pods
+                .delete(vm_name, params)
+                .await
+                .map_err(Error::KubeError)?;
+        }
+
+        if !keep_routing {
+            self.delete_activator_endpoints(ctx.clone()).await?;
+
+            let services: Api<Service> = Api::namespaced(client.clone(), &ns);
+            let existing_service = services.get(vm_name).await;
+            if existing_service.is_ok() {
+                let _o = services
+                    .delete(vm_name, params)
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+
+            let ingresses: Api<Ingress> = Api::namespaced(client.clone(), &ns);
+            if ingresses
+                .get_opt(vm_name)
+                .await
+                .map_err(Error::KubeError)?
+                .is_some()
+            {
+                let _o = ingresses
+                    .delete(vm_name, params)
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+        }
+
+        let pdbs: Api<PodDisruptionBudget> = Api::namespaced(client.clone(), &ns);
+        if pdbs
+            .get_opt(vm_name)
+            .await
+            .map_err(Error::KubeError)?
+            .is_some()
+        {
+            let _o = pdbs
+                .delete(vm_name, params)
+                .await
+                .map_err(Error::KubeError)?;
+        }
+
+        let policies: Api<NetworkPolicy> = Api::namespaced(client.clone(), &ns);
+        if policies
+            .get_opt(vm_name)
+            .await
+            .map_err(Error::KubeError)?
+            .is_some()
+        {
+            let _o = policies
+                .delete(vm_name, params)
+                .await
+                .map_err(Error::KubeError)?;
+        }
+
+        let routes: Api<DynamicObject> = Api::namespaced_with(client, &ns, &http_route_resource());
+        if !keep_routing
+            && routes
+                .get_opt(vm_name)
+                .await
+                .map_err(Error::KubeError)?
+                .is_some()
+        {
+            let _o = routes
+                .delete(vm_name, params)
+                .await
+                .map_err(Error::KubeError)?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn stop(&self, ctx: Arc<Context>, status: &mut VirtualMachineStatus) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let name = self.name_any();
+        info!("Stopping VirtualMachine {} in {}", name, ns);
+
+        // The guest agent is asked to shut the guest down cleanly before the pod is deleted, the
+        // grace period of the deletion leaves it time to do so
+        if let (Some(guest_agent), VirtualMachineCurrentState::STARTED) =
+            (&self.spec.guest_agent, &status.state)
+        {
+            let pods: Api<Pod> = Api::namespaced(ctx.client.clone(), &ns);
+            let shutdown = tokio::time::timeout(
+                Duration::from_secs(GUEST_SHUTDOWN_TIMEOUT_SECS),
+                agent::call::<()>(&pods, &name, guest_agent.port, &agent::Request::Shutdown),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                Err(Error::GuestAgentError(format!(
+                    "no answer after {GUEST_SHUTDOWN_TIMEOUT_SECS}s"
+                )))
+            });
+            match shutdown {
+                Ok(()) => {
+                    self.publish_event(
+                        ctx.clone(),
+                        EventType::Normal,
+                        "GuestShutdown",
+                        "Stop",
+                        Some("Guest agent is shutting the guest down".to_string()),
+                    )
+                    .await
+                }
+                Err(e) => {
+                    warn!("failed to shut down the guest of {name} through its agent: {e}");
+                    self.publish_event(
+                        ctx.clone(),
+                        EventType::Warning,
+                        "GuestShutdownFailed",
+                        "Stop",
+                        Some(format!("Guest agent did not shut the guest down: {e}")),
+                    )
+                    .await
+                }
+            }
+        }
+
+        let params = DeleteParams {
+            grace_period_seconds: self.spec.stop_grace_period_seconds,
+            ..DeleteParams::default()
+        };
+        let wakes_on_request = self.wakes_on_request(&ctx);
+        self.delete_children(ctx.clone(), &params, wakes_on_request)
+            .await?;
+
+        // The deletion of the pod triggers another reconcile once it has drained
+        let pods: Api<Pod> = Api::namespaced(ctx.client.clone(), &ns);
+        if pods
+            .get_opt(&name)
+            .await
+            .map_err(Error::KubeError)?
+            .is_some()
+        {
+            status.state = VirtualMachineCurrentState::STOPPING;
+            status.set_condition(VirtualMachineConditionType::Ready, false, "Stopping", None);
+            return Ok(());
+        }
+
+        if wakes_on_request {
+            self.ensure_activator_routing(ctx.clone()).await?;
+        }
+
+        // Non-persistent root disks only live as long as the VM runs
+        if self.spec.storage.as_ref().is_some_and(|s| !s.persistent) {
+            let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(ctx.client.clone(), &ns);
+            if pvcs
+                .get_opt(&self.root_disk_pvc_name())
+                .await
+                .map_err(Error::KubeError)?
+                .is_some()
+            {
+                let _o = pvcs
+                    .delete(&self.root_disk_pvc_name(), &Default::default())
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+        }
+
+        // A stopped VM cold boots on the next start, so the hibernation state is discarded
+        status.state = VirtualMachineCurrentState::STOPPED;
+        status.restart_count = 0;
+        status.next_restart_time = None;
+        status.snapshot = None;
+        status.pod_ip = None;
+        status.node_name = None;
+        status.service_cluster_ip = None;
+        status.node_ports.clear();
+        status.load_balancer_ingress.clear();
+        status.url = None;
+        status.drives.clear();
+        status.balloon = None;
+        status.set_condition(
+            VirtualMachineConditionType::PodScheduled,
+            false,
+            "Stopped",
+            None,
+        );
+        status.set_condition(
+            VirtualMachineConditionType::BootCompleted,
+            false,
+            "Stopped",
+            None,
+        );
+        status.set_condition(VirtualMachineConditionType::Ready, false, "Stopped", None);
+        for type_ in [
+            VirtualMachineConditionType::Degraded,
+            VirtualMachineConditionType::GuestAgentConnected,
+        ] {
+            if status.condition(type_.clone()).is_some() {
+                status.set_condition(type_, false, "Stopped", None);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Pause the VM, snapshot memory and device state to the snapshot PVC, then tear down the pod
+    #[instrument(skip_all)]
+    async fn hibernate(&self, ctx: Arc<Context>, status: &mut VirtualMachineStatus) -> Result<()> {
+        info!("Hibernating VirtualMachine {}", self.name_any());
+        let ns = self.namespace().unwrap();
+        let vm_name = self.metadata.name.as_ref().unwrap();
+
+        let pods: Api<Pod> = Api::namespaced(ctx.client.clone(), &ns);
+        let pod = pods.get_opt(vm_name).await.map_err(Error::KubeError)?;
+        // A pod already being deleted was snapshotted before
+        let running = pod
+            .as_ref()
+            .filter(|p| p.metadata.deletion_timestamp.is_none())
+            .and_then(|p| p.status.as_ref())
+            .and_then(|s| s.phase.as_deref())
+            == Some("Running");
+
+        if running {
+            let mounts_snapshot_pvc = pod
+                .as_ref()
+                .and_then(|p| p.spec.as_ref()?.volumes.as_ref())
+                .is_some_and(|volumes| volumes.iter().any(|v| v.name == "snapshot"));
+            if !mounts_snapshot_pvc {
+                return Err(Error::InvalidSpec(
+                    "spec.hibernation must be enabled, and the VM pod recreated, to hibernate the VM"
+                        .to_string(),
+                ));
+            }
+            status.state = VirtualMachineCurrentState::HIBERNATING;
+
+            let snapshot = SnapshotLocation {
+                pvc_name: self.snapshot_pvc_name(),
+                snapshot_path: "vmstate".to_string(),
+                mem_file_path: "memory".to_string(),
+                created_at: Some(Utc::now()),
+            };
+            let firecracker = self.firecracker(ctx.client.clone());
+            firecracker.pause().await?;
+            let result = firecracker
+                .create_snapshot(
+                    &format!("{SNAPSHOT_MOUNT_PATH}/{}", snapshot.snapshot_path),
+                    &format!("{SNAPSHOT_MOUNT_PATH}/{}", snapshot.mem_file_path),
+                )
+                .await;
+            // Resume the guest when the snapshot failed, it would stay frozen otherwise
+            if let Err(e) = result {
+                firecracker.resume().await?;
+                return Err(e);
+            }
+            status.snapshot = Some(snapshot);
+        } else if !matches!(
+            status.state,
+            VirtualMachineCurrentState::HIBERNATING | VirtualMachineCurrentState::HIBERNATED
+        ) && status
+            .condition(VirtualMachineConditionType::BootCompleted)
+            .is_some_and(|c| c.status == ConditionStatus::True)
+        {
+            // Only a snapshot written during this hibernation, or one the guest has not resumed
+            // from yet, holds its current state
+            status.snapshot = None;
+        }
+
+        let wakes_on_request = self.wakes_on_request(&ctx);
+        self.delete_children(ctx.clone(), &DeleteParams::default(), wakes_on_request)
+            .await?;
+
+        // The deletion of the pod triggers another reconcile once it is gone
+        if pods
+            .get_opt(vm_name)
+            .await
+            .map_err(Error::KubeError)?
+            .is_some()
+        {
+            status.state = if status.snapshot.is_some() {
+                VirtualMachineCurrentState::HIBERNATING
+            } else {
+                VirtualMachineCurrentState::STOPPING
+            };
+            status.set_condition(
+                VirtualMachineConditionType::Ready,
+                false,
+                "Hibernating",
+                None,
+            );
+            return Ok(());
+        }
+
+        if wakes_on_request {
+            self.ensure_activator_routing(ctx).await?;
+        }
+
+        // Without a snapshot there is no state to resume from, which is the same as stopped
+        status.state = if status.snapshot.is_some() {
+            VirtualMachineCurrentState::HIBERNATED
+        } else {
+            VirtualMachineCurrentState::STOPPED
+        };
+        status.pod_ip = None;
+        status.node_name = None;
+        status.service_cluster_ip = None;
+        status.node_ports.clear();
+        status.load_balancer_ingress.clear();
+        status.url = None;
+        status.drives.clear();
+        status.balloon = None;
+        if status
+            .condition(VirtualMachineConditionType::GuestAgentConnected)
+            .is_some()
+        {
+            status.set_condition(
+                VirtualMachineConditionType::GuestAgentConnected,
+                false,
+                "Hibernated",
+                None,
+            );
+        }
+        status.set_condition(
+            VirtualMachineConditionType::PodScheduled,
+            false,
+            "Hibernated",
+            None,
+        );
+        status.set_condition(
+            VirtualMachineConditionType::BootCompleted,
+            false,
+            "Hibernated",
+            None,
+        );
+        status.set_condition(
+            VirtualMachineConditionType::Ready,
+            false,
+            "Hibernated",
+            None,
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+    fn inputs_hash(mut spec: serde_json::Value) -> String {
+        spec["image"] = json!("rootfs");
+        let vm = VirtualMachine::new("vm", serde_json::from_value(spec).unwrap());
+        let boot_source = vm.boot_source().unwrap();
+        let firecracker_config = vm.firecracker_config(vm.spec.memory_mib).unwrap();
+        vm.pod_inputs_hash(&boot_source, &firecracker_config)
+    }
+
+    #[test]
+    fn pod_inputs_hash_ignores_sizing_and_state() {
+        let base = inputs_hash(json!({
+            "state": "STARTED",
+            "ports": [{ "name": "http", "port": 80, "targetPort": 8080 }],
+        }));
+        for spec in [
+            json!({ "state": "STOPPED", "ports": [{ "name": "http", "port": 80, "targetPort": 8080 }] }),
+            json!({ "state": "STARTED", "cpus": 4, "memoryMib": 4096, "ports": [{ "name": "http", "port": 80, "targetPort": 8080 }] }),
+            // Only the Service exposes the port
+            json!({ "state": "STARTED", "ports": [{ "name": "http", "port": 443, "targetPort": 8080 }] }),
+        ] {
+            assert_eq!(inputs_hash(spec), base);
+        }
+        // The balloon is adjusted on the running VM
+        assert_eq!(
+            inputs_hash(json!({ "state": "STARTED", "balloon": { "targetMib": 128 } })),
+            inputs_hash(json!({ "state": "STARTED", "balloon": { "targetMib": 256 } }))
+        );
+    }
+
+    #[test]
+    fn pod_inputs_hash_changes_with_pod_fields() {
+        let base = inputs_hash(json!({ "state": "STARTED" }));
+        for spec in [
+            json!({ "state": "STARTED", "hibernation": true }),
+            json!({ "state": "STARTED", "runtimeClassName": "kata" }),
+            json!({ "state": "STARTED", "serviceAccountName": "guest" }),
+            json!({ "state": "STARTED", "ports": [{ "name": "http", "port": 80, "targetPort": 8080 }] }),
+            json!({ "state": "STARTED", "overcommit": { "memoryRequestPercent": 50 } }),
+            json!({ "state": "STARTED", "readinessProbe": { "tcpSocket": { "port": 8080 } } }),
+            json!({ "state": "STARTED", "startupProbe": { "tcpSocket": { "port": 22 } } }),
+            json!({ "state": "STARTED", "firecrackerMetrics": true }),
+            json!({ "state": "STARTED", "networkInterfaces": [{ "name": "eth0", "guestMac": "06:00:ac:10:00:02" }] }),
+            json!({ "state": "STARTED", "vsock": { "guestCid": 3 } }),
+            json!({ "state": "STARTED", "cpuTemplate": { "name": "T2" } }),
+        ] {
+            assert_ne!(inputs_hash(spec), base);
+        }
+    }
+
+    fn drift_pod(image: &str, volumes: &[&str]) -> Pod {
+        Pod {
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    name: "vm".to_string(),
+                    image: Some(image.to_string()),
+                    ..Container::default()
+                }],
+                volumes: Some(
+                    volumes
+                        .iter()
+                        .map(|name| Volume {
+                            name: name.to_string(),
+                            ..Volume::default()
+                        })
+                        .collect(),
+                ),
+                ..PodSpec::default()
+            }),
+            ..Pod::default()
+        }
+    }
+
+    #[test]
+    fn pod_drift_ignores_fields_filled_in_by_the_cluster() {
+        let desired = drift_pod("firecracker:1", &["config"]);
+        let mut existing = desired.clone();
+        let spec = existing.spec.as_mut().unwrap();
+        spec.node_name = Some("node-1".to_string());
+        spec.runtime_class_name = Some("kata".to_string());
+        spec.containers[0].termination_message_path = Some("/dev/termination-log".to_string());
+        assert_eq!(pod_drift(&desired, &existing), None);
+    }
+
+    #[test]
+    fn pod_drift_reports_changed_fields() {
+        let desired = drift_pod("firecracker:2", &["config", "snapshot"]);
+        assert_eq!(
+            pod_drift(
+                &desired,
+                &drift_pod("firecracker:1", &["config", "snapshot"])
+            )
+            .as_deref(),
+            Some("image of container vm")
+        );
+        assert_eq!(
+            pod_drift(&desired, &drift_pod("firecracker:2", &["config"])).as_deref(),
+            Some("volumes")
+        );
+        let mut runtime_class = desired.clone();
+        runtime_class.spec.as_mut().unwrap().runtime_class_name = Some("kata".to_string());
+        assert_eq!(
+            pod_drift(&runtime_class, &desired).as_deref(),
+            Some("runtimeClassName")
+        );
+    }
+
+    fn scheduled_vm(schedule: serde_json::Value, created: DateTime<Utc>) -> VirtualMachine {
+        let spec =
+            serde_json::from_value(json!({ "state": "STOPPED", "schedule": schedule })).unwrap();
+        let mut vm = VirtualMachine::new("vm", spec);
+        vm.metadata.creation_timestamp = Some(Time(created));
+        vm
+    }
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn due_schedule_picks_latest_transition() {
+        let vm = scheduled_vm(
+            json!({ "start": "0 8 * * 1-5", "stop": "0 18 * * 1-5" }),
+            utc(2024, 1, 1, 0, 0),
+        );
+        // Tuesday evening, both the start and the stop of the day fired
+        let due = vm.due_schedule(utc(2024, 1, 2, 19, 0)).unwrap();
+        assert_eq!(
+            due,
+            Some((utc(2024, 1, 2, 18, 0), VirtualMachineDesiredState::STOPPED))
+        );
+    }
+
+    #[test]
+    fn due_schedule_skips_applied_runs() {
+        let mut vm = scheduled_vm(
+            json!({ "start": "0 8 * * *", "stop": "0 18 * * *" }),
+            utc(2024, 1, 1, 0, 0),
+        );
+        vm.status = Some(VirtualMachineStatus {
+            last_schedule_time: Some(utc(2024, 1, 2, 8, 0)),
+            ..Default::default()
+        });
+        assert_eq!(vm.due_schedule(utc(2024, 1, 2, 12, 0)).unwrap(), None);
+        assert_eq!(
+            vm.due_schedule(utc(2024, 1, 2, 18, 0)).unwrap(),
+            Some((utc(2024, 1, 2, 18, 0), VirtualMachineDesiredState::STOPPED))
+        );
+    }
+
+    #[test]
+    fn due_schedule_ignores_runs_before_creation() {
+        let vm = scheduled_vm(json!({ "start": "0 8 * * *" }), utc(2024, 1, 2, 9, 0));
+        assert_eq!(vm.due_schedule(utc(2024, 1, 2, 12, 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn due_schedule_uses_timezone() {
+        let vm = scheduled_vm(
+            json!({ "start": "0 8 * * *", "timezone": "Europe/Amsterdam" }),
+            utc(2024, 7, 1, 0, 0),
+        );
+        assert_eq!(
+            vm.due_schedule(utc(2024, 7, 1, 7, 0)).unwrap(),
+            Some((utc(2024, 7, 1, 6, 0), VirtualMachineDesiredState::STARTED))
+        );
+    }
+
+    #[test]
+    fn due_schedule_rejects_invalid_schedule() {
+        let vm = scheduled_vm(json!({ "start": "every day" }), utc(2024, 1, 1, 0, 0));
+        assert!(matches!(
+            vm.due_schedule(utc(2024, 1, 2, 0, 0)),
+            Err(Error::InvalidSpec(_))
+        ));
+        let vm = scheduled_vm(
+            json!({ "start": "0 8 * * *", "timezone": "Mars/Olympus" }),
+            utc(2024, 1, 1, 0, 0),
+        );
+        assert!(matches!(
+            vm.due_schedule(utc(2024, 1, 2, 0, 0)),
+            Err(Error::InvalidSpec(_))
+        ));
+    }
+
+    #[test]
+    fn latest_occurrence_of_rare_schedule() {
+        let cron = Cron::new("0 0 1 1 *").parse().unwrap();
+        let since = utc(2000, 1, 1, 0, 0).with_timezone(&Tz::UTC);
+        let now = utc(2024, 6, 1, 0, 0).with_timezone(&Tz::UTC);
+        assert_eq!(
+            latest_occurrence(&cron, since, now),
+            Some(utc(2024, 1, 1, 0, 0).with_timezone(&Tz::UTC))
+        );
+        assert_eq!(latest_occurrence(&cron, now, now), None);
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn usage_accounts_running_time_across_days() {
+        use VirtualMachineCurrentState::{STARTED, STOPPED};
+        let mut usage = VirtualMachineUsage::default();
+        let booted = utc(2024, 1, 1, 23, 59) + chrono::Duration::seconds(30);
+        assert_eq!(usage.account(&STOPPED, &STARTED, booted), 0);
+        assert_eq!(usage.boots, 1);
+        assert_eq!(usage.accounted_at, Some(booted));
+
+        let now = utc(2024, 1, 2, 0, 0) + chrono::Duration::milliseconds(45_500);
+        assert_eq!(usage.account(&STARTED, &STARTED, now), 75);
+        // The half second left is accounted next time
+        assert_eq!(
+            usage.accounted_at,
+            Some(utc(2024, 1, 2, 0, 0) + chrono::Duration::seconds(45))
+        );
+        assert_eq!(usage.account(&STARTED, &STOPPED, utc(2024, 1, 2, 0, 1)), 15);
+        assert_eq!(usage.accounted_at, None);
+
+        assert_eq!(usage.running_seconds, 90);
+        assert_eq!(
+            usage.daily,
+            vec![
+                VirtualMachineDailyUsage {
+                    date: date(2024, 1, 1),
+                    running_seconds: 30,
+                    boots: 1,
+                },
+                VirtualMachineDailyUsage {
+                    date: date(2024, 1, 2),
+                    running_seconds: 60,
+                    boots: 0,
+                },
+            ]
+        );
+        assert_eq!(usage.between(Some(date(2024, 1, 2)), None), (60, 0));
+        assert_eq!(usage.between(None, Some(date(2024, 1, 1))), (30, 1));
+        assert_eq!(usage.between(None, None), (90, 1));
+    }
+
+    #[test]
+    fn usage_is_not_accounted_while_stopped() {
+        use VirtualMachineCurrentState::{STARTED, STOPPED};
+        let mut usage = VirtualMachineUsage {
+            accounted_at: Some(utc(2024, 1, 1, 0, 0)),
+            ..Default::default()
+        };
+        // A stale accounting time left from a STARTED VM is not counted
+        assert_eq!(usage.account(&STOPPED, &STOPPED, utc(2024, 1, 1, 1, 0)), 0);
+        assert_eq!(usage.accounted_at, None);
+        assert_eq!(usage.account(&STARTED, &STARTED, utc(2024, 1, 1, 2, 0)), 0);
+        assert_eq!(usage.running_seconds, 0);
+    }
+
+    #[test]
+    fn usage_keeps_retention_days() {
+        use VirtualMachineCurrentState::{STARTED, STOPPED};
+        let mut usage = VirtualMachineUsage::default();
+        usage.account(&STOPPED, &STARTED, utc(2024, 1, 1, 12, 0));
+        usage.account(&STARTED, &STOPPED, utc(2024, 1, 1, 13, 0));
+        assert!(!usage.expired(utc(2024, 3, 30, 0, 0)));
+        assert!(usage.expired(utc(2024, 3, 31, 0, 0)));
+
+        usage.account(&STOPPED, &STARTED, utc(2024, 3, 31, 0, 0));
+        assert_eq!(usage.daily.len(), 1);
+        assert_eq!(usage.daily[0].date, date(2024, 3, 31));
+        // Lifetime totals are kept
+        assert_eq!((usage.running_seconds, usage.boots), (3600, 2));
+        assert!(VirtualMachineUsage::default().expired(utc(2024, 1, 1, 0, 0)));
+    }
+
+    #[test]
+    fn balloon_target_must_be_below_memory() {
+        let vm = |target_mib: u32| {
+            let spec = serde_json::from_value(json!({
+                "state": "STARTED",
+                "image": "rootfs",
+                "memoryMib": 1024,
+                "balloon": { "targetMib": target_mib },
+            }))
+            .unwrap();
+            VirtualMachine::new("vm", spec)
+        };
+        assert!(vm(512).firecracker_config(1024).is_ok());
+        assert!(matches!(
+            vm(1024).firecracker_config(1024),
+            Err(Error::InvalidSpec(_))
+        ));
+    }
+
+    fn cpu_template_vm(cpu_template: serde_json::Value) -> VirtualMachine {
+        let spec = serde_json::from_value(json!({
+            "state": "STARTED",
+            "image": "rootfs",
+            "cpuTemplate": cpu_template,
+        }))
+        .unwrap();
+        VirtualMachine::new("vm", spec)
+    }
+
+    #[test]
+    fn static_cpu_template_is_set_by_name() {
+        let config = cpu_template_vm(json!({ "name": "T2S" }))
+            .firecracker_config(1024)
+            .unwrap();
+        assert_eq!(config.machine_config.cpu_template.as_deref(), Some("T2S"));
+        assert_eq!(config.cpu_config, None);
+    }
+
+    #[test]
+    fn custom_cpu_template_is_loaded_from_file() {
+        for template in [
+            json!({ "inline": { "cpuid_modifiers": [] } }),
+            json!({ "configMapRef": { "name": "templates", "key": "t2.json" } }),
+        ] {
+            let config = cpu_template_vm(template).firecracker_config(1024).unwrap();
+            assert_eq!(config.machine_config.cpu_template, None);
+            assert_eq!(
+                config.cpu_config.as_deref(),
+                Some(firecracker::CPU_TEMPLATE_PATH)
+            );
+        }
+    }
+
+    #[test]
+    fn cpu_template_needs_either_name_or_custom() {
+        for template in [
+            json!({}),
+            json!({ "name": "T2", "inline": { "cpuid_modifiers": [] } }),
+        ] {
+            assert!(matches!(
+                cpu_template_vm(template).firecracker_config(1024),
+                Err(Error::InvalidSpec(_))
+            ));
+        }
     }
 }
@@ -0,0 +1,277 @@
+use crate::{
+    controller::{
+        virtualmachine::VirtualMachine,
+        virtualmachinepool::{is_ready, POOL_LABEL},
+        Context,
+    },
+    errors::Error,
+    utils::Result,
+};
+use chrono::{DateTime, Utc};
+use kube::{
+    api::{Api, DeleteParams, ListParams, Patch, PatchParams, ResourceExt},
+    runtime::controller::Action,
+    CustomResource, Resource,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::*;
+
+pub static VIRTUAL_MACHINE_CLAIM_FINALIZER: &str = "vmclaim.codesandbox.io";
+
+/// Label of a VM handed out from a pool, naming the claim it is bound to
+pub(crate) static CLAIM_LABEL: &str = "vms.codesandbox.io/claim";
+
+#[derive(CustomResource, Debug, Serialize, Deserialize, Default, Clone, JsonSchema)]
+#[kube(
+    group = "codesandbox.io",
+    version = "v1alpha1",
+    kind = "VirtualMachineClaim",
+    namespaced,
+    doc = "A lease of a booted FinK VirtualMachine from a VirtualMachinePool",
+    singular = "virtualmachineclaim",
+    plural = "virtualmachineclaims",
+    shortname = "vmclaim",
+    status = "VirtualMachineClaimStatus",
+    printcolumn = r#"{"name":"Pool", "type":"string", "description":"Pool the VM is leased from", "jsonPath":".spec.poolName"}"#,
+    printcolumn = r#"{"name":"VM", "type":"string", "description":"Bound VirtualMachine", "jsonPath":".status.virtualMachineName"}"#,
+    printcolumn = r#"{"name":"Phase", "type":"string", "description":"Binding phase", "jsonPath":".status.phase"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineClaimSpec {
+    /// Name of the VirtualMachinePool in the same namespace to lease a VM from
+    pub pool_name: String,
+    /// Labels added to the VM once it is bound
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// What happens to the bound VM when the claim is deleted
+    #[serde(default)]
+    pub reclaim_policy: ReclaimPolicy,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub enum ReclaimPolicy {
+    /// Delete the VM with the claim
+    #[default]
+    Delete,
+    /// Keep the VM as a standalone VirtualMachine
+    Retain,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub enum ClaimPhase {
+    /// Waiting for a booted VM in the pool
+    #[default]
+    Pending,
+    Bound,
+    /// The bound VM was deleted
+    Lost,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineClaimStatus {
+    pub phase: ClaimPhase,
+    /// VirtualMachine leased by the claim
+    pub virtual_machine_name: Option<String>,
+    pub bound_time: Option<DateTime<Utc>>,
+}
+
+impl VirtualMachineClaim {
+    // Bind the claim to a booted VM of its pool, or check on the VM it is bound to
+    pub async fn reconcile(&self, ctx: Arc<Context>) -> Result<Action> {
+        let ns = self.namespace().unwrap();
+        let vms: Api<VirtualMachine> = Api::namespaced(ctx.client.clone(), &ns);
+        let mut status = self.status.clone().unwrap_or_default();
+
+        if let Some(vm_name) = &status.virtual_machine_name {
+            let bound = vms.get_opt(vm_name).await.map_err(Error::KubeError)?;
+            status.phase = match bound {
+                Some(vm) if vm.labels().get(CLAIM_LABEL) == Some(&self.name_any()) => {
+                    ClaimPhase::Bound
+                }
+                _ => ClaimPhase::Lost,
+            };
+            self.update_status(ctx, status).await?;
+            return Ok(Action::await_change());
+        }
+
+        // Bound before the status could be written
+        let claimed = vms
+            .list(&ListParams::default().labels(&format!("{CLAIM_LABEL}={}", self.name_any())))
+            .await
+            .map_err(Error::KubeError)?
+            .items;
+        if let Some(vm) = claimed.first() {
+            status.phase = ClaimPhase::Bound;
+            status.virtual_machine_name = Some(vm.name_any());
+            status.bound_time = Some(Utc::now());
+            self.update_status(ctx, status).await?;
+            return Ok(Action::await_change());
+        }
+
+        // The oldest VMs have been booted the longest
+        let mut free: Vec<VirtualMachine> = vms
+            .list(&ListParams::default().labels(&format!(
+                "{POOL_LABEL}={},!{CLAIM_LABEL}",
+                self.spec.pool_name
+            )))
+            .await
+            .map_err(Error::KubeError)?
+            .items
+            .into_iter()
+            .filter(|vm| is_ready(vm) && vm.metadata.deletion_timestamp.is_none())
+            .collect();
+        free.sort_by_key(|vm| vm.metadata.creation_timestamp.clone());
+
+        let Some(vm) = free.first() else {
+            status.phase = ClaimPhase::Pending;
+            self.update_status(ctx.clone(), status).await?;
+            return Ok(Action::requeue(ctx.config.controller.retry_interval()));
+        };
+        self.bind(&vms, vm).await?;
+        info!(
+            "Bound claim {} to VirtualMachine {}",
+            self.name_any(),
+            vm.name_any()
+        );
+
+        status.phase = ClaimPhase::Bound;
+        status.virtual_machine_name = Some(vm.name_any());
+        status.bound_time = Some(Utc::now());
+        self.update_status(ctx, status).await?;
+        Ok(Action::await_change())
+    }
+
+    // Take over the VM from its pool. The patch is conditional on the resource version, so a VM
+    // bound by another claim in the meantime fails with a conflict and is not handed out twice.
+    async fn bind(&self, vms: &Api<VirtualMachine>, vm: &VirtualMachine) -> Result<()> {
+        let patch = Patch::Merge(self.bind_patch(vm));
+        let _o = vms
+            .patch(&vm.name_any(), &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+
+    fn bind_patch(&self, vm: &VirtualMachine) -> Value {
+        let mut labels: BTreeMap<String, String> = self.spec.labels.clone();
+        labels.insert(CLAIM_LABEL.to_string(), self.name_any());
+        json!({
+            "metadata": {
+                "resourceVersion": vm.resource_version(),
+                "labels": labels,
+                "ownerReferences": [self.controller_owner_ref(&()).unwrap()],
+            }
+        })
+    }
+
+    // Finalizer cleanup, applying the reclaim policy to the bound VM
+    pub async fn cleanup(&self, ctx: Arc<Context>) -> Result<Action> {
+        info!("Cleaning up VirtualMachineClaim {}", self.name_any());
+        let ns = self.namespace().unwrap();
+        let vms: Api<VirtualMachine> = Api::namespaced(ctx.client.clone(), &ns);
+        let Some(vm_name) = self
+            .status
+            .as_ref()
+            .and_then(|s| s.virtual_machine_name.as_ref())
+        else {
+            return Ok(Action::await_change());
+        };
+        let Some(vm) = vms.get_opt(vm_name).await.map_err(Error::KubeError)? else {
+            return Ok(Action::await_change());
+        };
+        if vm.labels().get(CLAIM_LABEL) != Some(&self.name_any()) {
+            return Ok(Action::await_change());
+        }
+
+        match self.spec.reclaim_policy {
+            ReclaimPolicy::Retain => {
+                info!("Releasing VirtualMachine {} of claim", vm_name);
+                self.unbind(&vms, &vm).await?;
+            }
+            ReclaimPolicy::Delete => {
+                info!("Deleting VirtualMachine {} of claim", vm_name);
+                let _o = vms
+                    .delete(vm_name, &DeleteParams::default())
+                    .await
+                    .map_err(Error::KubeError)?;
+            }
+        }
+        Ok(Action::await_change())
+    }
+
+    // Remove the claim and pool labels and the ownership from the VM
+    async fn unbind(&self, vms: &Api<VirtualMachine>, vm: &VirtualMachine) -> Result<()> {
+        let mut labels: BTreeMap<String, Value> = self
+            .spec
+            .labels
+            .keys()
+            .map(|k| (k.clone(), Value::Null))
+            .collect();
+        labels.insert(CLAIM_LABEL.to_string(), Value::Null);
+        labels.insert(POOL_LABEL.to_string(), Value::Null);
+        let patch = Patch::Merge(json!({
+            "metadata": {
+                "labels": labels,
+                "ownerReferences": [],
+            }
+        }));
+        let _o = vms
+            .patch(&vm.name_any(), &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+
+    async fn update_status(
+        &self,
+        ctx: Arc<Context>,
+        status: VirtualMachineClaimStatus,
+    ) -> Result<()> {
+        if self.status.as_ref() == Some(&status) {
+            return Ok(());
+        }
+
+        let ns = self.namespace().unwrap();
+        let claims: Api<VirtualMachineClaim> = Api::namespaced(ctx.client.clone(), &ns);
+        let patch = Patch::Merge(json!({ "status": status }));
+        let _o = claims
+            .patch_status(&self.name_any(), &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bind_is_guarded_by_the_resource_version() {
+        let spec = serde_json::from_value(json!({
+            "poolName": "warm",
+            "labels": { "user": "alice" },
+        }))
+        .unwrap();
+        let mut claim = VirtualMachineClaim::new("claim", spec);
+        claim.metadata.uid = Some("uid".to_string());
+        let mut vm = VirtualMachine::new(
+            "warm-abcde",
+            serde_json::from_value(json!({ "state": "STARTED" })).unwrap(),
+        );
+        vm.metadata.resource_version = Some("42".to_string());
+
+        let patch = claim.bind_patch(&vm);
+        assert_eq!(patch["metadata"]["resourceVersion"], "42");
+        assert_eq!(
+            patch["metadata"]["labels"],
+            json!({ CLAIM_LABEL: "claim", "user": "alice" })
+        );
+        assert_eq!(patch["metadata"]["ownerReferences"][0]["uid"], "uid");
+        assert_eq!(patch["metadata"]["ownerReferences"][0]["controller"], true);
+    }
+}
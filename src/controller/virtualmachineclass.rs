@@ -0,0 +1,85 @@
+use crate::{
+    controller::{
+        virtualmachine::{VirtualMachine, VirtualMachineOvercommit, VirtualMachineStorage},
+        Context,
+    },
+    errors::Error,
+    utils::Result,
+};
+use kube::{api::Api, CustomResource};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(CustomResource, Debug, Serialize, Deserialize, Default, Clone, JsonSchema)]
+#[kube(
+    group = "codesandbox.io",
+    version = "v1alpha1",
+    kind = "VirtualMachineClass",
+    doc = "A size of FinK VirtualMachines curated by the cluster admins",
+    singular = "virtualmachineclass",
+    plural = "virtualmachineclasses",
+    shortname = "vmclass",
+    printcolumn = r#"{"name":"CPUs", "type":"integer", "description":"Number of vCPUs", "jsonPath":".spec.cpus"}"#,
+    printcolumn = r#"{"name":"Memory", "type":"integer", "description":"Memory in MiB", "jsonPath":".spec.memoryMib"}"#,
+    printcolumn = r#"{"name":"Disk", "type":"string", "description":"Root disk size", "jsonPath":".spec.diskSize"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineClassSpec {
+    /// Number of vCPUs given to the VMs of the class
+    #[schemars(range(min = 1))]
+    pub cpus: u32,
+    /// Memory given to the VMs of the class in MiB
+    #[schemars(range(min = 128))]
+    pub memory_mib: u32,
+    /// Size of the root disk PVC, e.g. `10Gi`, VMs boot from the container filesystem when unset
+    pub disk_size: Option<String>,
+    /// Share of the vCPUs and memory reserved for the VM pods, the rest is overcommitted
+    pub overcommit: Option<VirtualMachineOvercommit>,
+}
+
+impl VirtualMachineClass {
+    // Apply the sizing of the class to `vm`, replacing its own
+    pub fn apply(&self, vm: &mut VirtualMachine) {
+        let spec = &mut vm.spec;
+        spec.cpus = self.spec.cpus;
+        spec.memory_mib = self.spec.memory_mib;
+        spec.overcommit = self.spec.overcommit.clone();
+        if let Some(disk_size) = &self.spec.disk_size {
+            let storage = spec.storage.get_or_insert_with(|| VirtualMachineStorage {
+                size: disk_size.clone(),
+                storage_class_name: None,
+                persistent: true,
+            });
+            storage.size = disk_size.clone();
+        }
+    }
+}
+
+// The VM sized by its class, or the VM itself without `spec.className`. Classes can be required
+// by the config, which leaves VMs being deleted alone so they can still be cleaned up. Overcommit
+// is only taken from the class, VMs without one are guaranteed their full size.
+pub async fn resolve(mut vm: VirtualMachine, ctx: &Context) -> Result<VirtualMachine> {
+    let deleting = vm.metadata.deletion_timestamp.is_some();
+    let Some(class_name) = &vm.spec.class_name else {
+        if ctx.config.controller.require_class_name && !deleting {
+            return Err(Error::InvalidSpec("spec.className is required".to_string()));
+        }
+        vm.spec.overcommit = None;
+        return Ok(vm);
+    };
+    let classes: Api<VirtualMachineClass> = Api::all(ctx.client.clone());
+    match classes
+        .get_opt(class_name)
+        .await
+        .map_err(Error::KubeError)?
+    {
+        Some(class) => class.apply(&mut vm),
+        None if deleting => {}
+        None => {
+            return Err(Error::InvalidSpec(format!(
+                "VirtualMachineClass {class_name} not found"
+            )))
+        }
+    }
+    Ok(vm)
+}
@@ -0,0 +1,289 @@
+use crate::{
+    controller::{
+        virtualmachine::{
+            spec_hash, VirtualMachine, VirtualMachineCurrentState, VirtualMachineDesiredState,
+            VirtualMachineSpec,
+        },
+        virtualmachineclaim::CLAIM_LABEL,
+        Context,
+    },
+    errors::Error,
+    utils::Result,
+};
+use kube::{
+    api::{Api, DeleteParams, ListParams, Patch, PatchParams, PostParams, ResourceExt},
+    core::ObjectMeta,
+    runtime::controller::Action,
+    CustomResource, Resource,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::*;
+
+/// Label of the VMs created by a pool, kept once a VM is handed out to a claim
+pub(crate) static POOL_LABEL: &str = "vms.codesandbox.io/pool";
+
+/// Annotation of the pool VMs holding the hash of the template they were created from
+static POOL_TEMPLATE_HASH_ANNOTATION: &str = "vms.codesandbox.io/pool-template-hash";
+
+#[derive(CustomResource, Debug, Serialize, Deserialize, Default, Clone, JsonSchema)]
+#[kube(
+    group = "codesandbox.io",
+    version = "v1alpha1",
+    kind = "VirtualMachinePool",
+    namespaced,
+    doc = "A pool of booted FinK VirtualMachines waiting to be assigned",
+    singular = "virtualmachinepool",
+    plural = "virtualmachinepools",
+    shortname = "vmpool",
+    status = "VirtualMachinePoolStatus",
+    printcolumn = r#"{"name":"Replicas", "type":"integer", "description":"Desired warm VMs", "jsonPath":".spec.replicas"}"#,
+    printcolumn = r#"{"name":"Ready", "type":"integer", "description":"Booted warm VMs", "jsonPath":".status.readyReplicas"}"#,
+    scale = r#"{"specReplicasPath":".spec.replicas", "statusReplicasPath":".status.replicas", "labelSelectorPath":".status.labelSelector"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachinePoolSpec {
+    /// Number of booted, unassigned VMs kept in the pool
+    #[schemars(range(min = 0))]
+    pub replicas: i32,
+    /// VMs created by the pool, always started regardless of `spec.state`
+    pub template: VirtualMachineInstanceTemplate,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineInstanceTemplate {
+    #[serde(default)]
+    pub metadata: VirtualMachineTemplateMetadata,
+    pub spec: VirtualMachineSpec,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineTemplateMetadata {
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachinePoolStatus {
+    /// VMs currently in the pool, booted or not
+    pub replicas: i32,
+    /// VMs in the pool that are booted and can be handed out
+    pub ready_replicas: i32,
+    pub observed_generation: Option<i64>,
+    /// Selector of the VMs in the pool and their pods, read through the scale subresource
+    pub label_selector: Option<String>,
+}
+
+impl VirtualMachinePool {
+    // Keep `spec.replicas` warm VMs from the current template in the pool
+    pub async fn reconcile(&self, ctx: Arc<Context>) -> Result<Action> {
+        let ns = self.namespace().unwrap();
+        let name = self.name_any();
+        let vms: Api<VirtualMachine> = Api::namespaced(ctx.client.clone(), &ns);
+        let template_hash = spec_hash(&self.spec.template);
+
+        let selector = format!("{POOL_LABEL}={name},!{CLAIM_LABEL}");
+        let members = vms
+            .list(&ListParams::default().labels(&selector))
+            .await
+            .map_err(Error::KubeError)?
+            .items
+            .into_iter()
+            .filter(|vm| vm.metadata.deletion_timestamp.is_none());
+
+        let (mut current, outdated) = split_outdated(members, &template_hash);
+        for vm in &outdated {
+            info!(
+                "Replacing outdated VirtualMachine {} of pool {}",
+                vm.name_any(),
+                name
+            );
+            self.delete_member(&vms, vm).await?;
+        }
+
+        let desired = self.spec.replicas.max(0) as usize;
+        for vm in scale_down(&mut current, desired) {
+            info!("Scaling down pool {}, deleting {}", name, vm.name_any());
+            self.delete_member(&vms, &vm).await?;
+        }
+        for _ in current.len()..desired {
+            let vm = vms
+                .create(&PostParams::default(), &self.member(&template_hash))
+                .await
+                .map_err(Error::KubeError)?;
+            info!("Added VirtualMachine {} to pool {}", vm.name_any(), name);
+            current.push(vm);
+        }
+
+        let status = VirtualMachinePoolStatus {
+            replicas: current.len() as i32,
+            ready_replicas: current.iter().filter(|vm| is_ready(vm)).count() as i32,
+            observed_generation: self.metadata.generation,
+            label_selector: Some(selector),
+        };
+        self.update_status(ctx.clone(), status).await?;
+
+        Ok(Action::requeue(ctx.config.controller.requeue_interval()))
+    }
+
+    // New VM of the pool, named after it
+    fn member(&self, template_hash: &str) -> VirtualMachine {
+        let template = &self.spec.template;
+        let mut labels = template.metadata.labels.clone();
+        labels.insert(POOL_LABEL.to_string(), self.name_any());
+        let mut annotations = template.metadata.annotations.clone();
+        annotations.insert(
+            POOL_TEMPLATE_HASH_ANNOTATION.to_string(),
+            template_hash.to_string(),
+        );
+
+        VirtualMachine {
+            metadata: ObjectMeta {
+                generate_name: Some(format!("{}-", self.name_any())),
+                owner_references: Some(vec![self.controller_owner_ref(&()).unwrap()]),
+                labels: Some(labels),
+                annotations: Some(annotations),
+                ..ObjectMeta::default()
+            },
+            spec: VirtualMachineSpec {
+                state: VirtualMachineDesiredState::STARTED,
+                ..template.spec.clone()
+            },
+            status: None,
+        }
+    }
+
+    async fn delete_member(&self, vms: &Api<VirtualMachine>, vm: &VirtualMachine) -> Result<()> {
+        let _o = vms
+            .delete(&vm.name_any(), &DeleteParams::default())
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+
+    async fn update_status(
+        &self,
+        ctx: Arc<Context>,
+        status: VirtualMachinePoolStatus,
+    ) -> Result<()> {
+        if self.status.as_ref() == Some(&status) {
+            return Ok(());
+        }
+
+        let ns = self.namespace().unwrap();
+        let pools: Api<VirtualMachinePool> = Api::namespaced(ctx.client.clone(), &ns);
+        let patch = Patch::Merge(json!({ "status": status }));
+        let _o = pools
+            .patch_status(&self.name_any(), &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+}
+
+// Split the pool members into the ones created from the current template and the outdated ones.
+// VMs of an older template were never handed out, so they are simply replaced.
+fn split_outdated(
+    members: impl Iterator<Item = VirtualMachine>,
+    template_hash: &str,
+) -> (Vec<VirtualMachine>, Vec<VirtualMachine>) {
+    members.partition(|vm| {
+        vm.annotations()
+            .get(POOL_TEMPLATE_HASH_ANNOTATION)
+            .map(String::as_str)
+            == Some(template_hash)
+    })
+}
+
+// Remove the members over `desired` to delete them, booting VMs first, then the youngest
+pub(crate) fn scale_down(members: &mut Vec<VirtualMachine>, desired: usize) -> Vec<VirtualMachine> {
+    if members.len() <= desired {
+        return Vec::new();
+    }
+    members.sort_by_key(|vm| {
+        (
+            is_ready(vm),
+            std::cmp::Reverse(vm.metadata.creation_timestamp.clone()),
+        )
+    });
+    members.drain(..members.len() - desired).collect()
+}
+
+// Whether the VM is booted and can be handed out
+pub(crate) fn is_ready(vm: &VirtualMachine) -> bool {
+    vm.status
+        .as_ref()
+        .is_some_and(|s| s.state == VirtualMachineCurrentState::STARTED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::virtualmachine::VirtualMachineStatus;
+    use chrono::{TimeZone, Utc};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+    fn pool_vm(name: &str, created_hour: u32, ready: bool, template_hash: &str) -> VirtualMachine {
+        let spec = serde_json::from_value(json!({ "state": "STARTED" })).unwrap();
+        let mut vm = VirtualMachine::new(name, spec);
+        vm.metadata.creation_timestamp = Some(Time(
+            Utc.with_ymd_and_hms(2024, 1, 1, created_hour, 0, 0)
+                .unwrap(),
+        ));
+        vm.metadata.annotations = Some(BTreeMap::from([(
+            POOL_TEMPLATE_HASH_ANNOTATION.to_string(),
+            template_hash.to_string(),
+        )]));
+        if ready {
+            vm.status = Some(VirtualMachineStatus {
+                state: VirtualMachineCurrentState::STARTED,
+                ..VirtualMachineStatus::default()
+            });
+        }
+        vm
+    }
+
+    fn names(vms: &[VirtualMachine]) -> Vec<String> {
+        vms.iter().map(|vm| vm.name_any()).collect()
+    }
+
+    #[test]
+    fn scale_down_deletes_booting_then_youngest() {
+        let mut members = vec![
+            pool_vm("old-ready", 1, true, "a"),
+            pool_vm("young-ready", 3, true, "a"),
+            pool_vm("old-booting", 2, false, "a"),
+            pool_vm("young-booting", 4, false, "a"),
+        ];
+        let deleted = scale_down(&mut members, 1);
+        assert_eq!(
+            names(&deleted),
+            ["young-booting", "old-booting", "young-ready"]
+        );
+        assert_eq!(names(&members), ["old-ready"]);
+        assert!(scale_down(&mut members, 1).is_empty());
+    }
+
+    #[test]
+    fn outdated_members_are_replaced() {
+        let members = vec![
+            pool_vm("current", 1, true, "b"),
+            pool_vm("outdated", 2, true, "a"),
+            pool_vm("unannotated", 3, false, "a"),
+        ];
+        let mut members = members.into_iter();
+        let mut unannotated = members.next_back().unwrap();
+        unannotated.metadata.annotations = None;
+
+        let (current, outdated) = split_outdated(members.chain([unannotated]), "b");
+        assert_eq!(names(&current), ["current"]);
+        assert_eq!(names(&outdated), ["outdated", "unannotated"]);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::{
+    controller::{
+        virtualmachine::{spec_hash, VirtualMachine},
+        virtualmachinepool::{is_ready, scale_down, VirtualMachineInstanceTemplate},
+        Context,
+    },
+    errors::Error,
+    utils::Result,
+};
+use kube::{
+    api::{Api, DeleteParams, ListParams, Patch, PatchParams, ResourceExt},
+    core::ObjectMeta,
+    runtime::controller::Action,
+    CustomResource, Resource,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::*;
+
+/// Label of the VMs created by a replica set
+pub(crate) static REPLICA_SET_LABEL: &str = "vms.codesandbox.io/replicaset";
+
+/// Annotation of the replica set VMs holding the hash of the template last applied to them
+static REPLICA_SET_TEMPLATE_HASH_ANNOTATION: &str = "vms.codesandbox.io/replicaset-template-hash";
+
+/// Field manager of the template applied to the VMs of a replica set
+static REPLICA_SET_FIELD_MANAGER: &str = "fink-replicaset";
+
+#[derive(CustomResource, Debug, Serialize, Deserialize, Default, Clone, JsonSchema)]
+#[kube(
+    group = "codesandbox.io",
+    version = "v1alpha1",
+    kind = "VirtualMachineReplicaSet",
+    namespaced,
+    doc = "A fleet of identical FinK VirtualMachines",
+    singular = "virtualmachinereplicaset",
+    plural = "virtualmachinereplicasets",
+    shortname = "vmrs",
+    status = "VirtualMachineReplicaSetStatus",
+    printcolumn = r#"{"name":"Replicas", "type":"integer", "description":"Desired VMs", "jsonPath":".spec.replicas"}"#,
+    printcolumn = r#"{"name":"Ready", "type":"integer", "description":"Started VMs", "jsonPath":".status.readyReplicas"}"#,
+    scale = r#"{"specReplicasPath":".spec.replicas", "statusReplicasPath":".status.replicas", "labelSelectorPath":".status.labelSelector"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineReplicaSetSpec {
+    /// Number of VMs in the fleet
+    #[schemars(range(min = 0))]
+    pub replicas: i32,
+    /// VMs of the fleet, changes are applied to the existing VMs following their update strategy
+    pub template: VirtualMachineInstanceTemplate,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineReplicaSetStatus {
+    /// VMs currently in the fleet
+    pub replicas: i32,
+    /// VMs in the fleet that are started
+    pub ready_replicas: i32,
+    /// VMs the current template has been applied to
+    pub updated_replicas: i32,
+    pub observed_generation: Option<i64>,
+    /// Selector of the VMs in the replica set and their pods, read through the scale subresource
+    pub label_selector: Option<String>,
+}
+
+impl VirtualMachineReplicaSet {
+    // Create or delete VMs to match `spec.replicas` and keep them on the current template
+    pub async fn reconcile(&self, ctx: Arc<Context>) -> Result<Action> {
+        let ns = self.namespace().unwrap();
+        let name = self.name_any();
+        let vms: Api<VirtualMachine> = Api::namespaced(ctx.client.clone(), &ns);
+        let template_hash = spec_hash(&self.spec.template);
+
+        let selector = format!("{REPLICA_SET_LABEL}={name}");
+        let mut members: Vec<VirtualMachine> = vms
+            .list(&ListParams::default().labels(&selector))
+            .await
+            .map_err(Error::KubeError)?
+            .items
+            .into_iter()
+            .filter(|vm| vm.metadata.deletion_timestamp.is_none())
+            .collect();
+
+        let desired = self.spec.replicas.max(0) as usize;
+        for vm in scale_down(&mut members, desired) {
+            info!(
+                "Scaling down replica set {}, deleting {}",
+                name,
+                vm.name_any()
+            );
+            let _o = vms
+                .delete(&vm.name_any(), &DeleteParams::default())
+                .await
+                .map_err(Error::KubeError)?;
+        }
+
+        for vm in members.iter_mut() {
+            if is_updated(vm, &template_hash) {
+                continue;
+            }
+            info!(
+                "Applying template of replica set {} to {}",
+                name,
+                vm.name_any()
+            );
+            *vm = vms
+                .patch(
+                    &vm.name_any(),
+                    &PatchParams::apply(REPLICA_SET_FIELD_MANAGER).force(),
+                    &Patch::Apply(self.member(&vm.name_any(), &template_hash)),
+                )
+                .await
+                .map_err(Error::KubeError)?;
+        }
+        // Members are applied like their template updates, so fields dropped from the template
+        // are removed from them too. Apply does not support generateName, so a free name with a
+        // random suffix is picked instead.
+        for _ in members.len()..desired {
+            let member_name = loop {
+                let suffix = uuid::Uuid::new_v4().simple().to_string();
+                let member_name = format!("{}-{}", name, &suffix[..5]);
+                if vms
+                    .get_opt(&member_name)
+                    .await
+                    .map_err(Error::KubeError)?
+                    .is_none()
+                {
+                    break member_name;
+                }
+            };
+            let vm = vms
+                .patch(
+                    &member_name,
+                    &PatchParams::apply(REPLICA_SET_FIELD_MANAGER).force(),
+                    &Patch::Apply(self.member(&member_name, &template_hash)),
+                )
+                .await
+                .map_err(Error::KubeError)?;
+            info!(
+                "Added VirtualMachine {} to replica set {}",
+                vm.name_any(),
+                name
+            );
+            members.push(vm);
+        }
+
+        let status = VirtualMachineReplicaSetStatus {
+            replicas: members.len() as i32,
+            ready_replicas: members.iter().filter(|vm| is_ready(vm)).count() as i32,
+            updated_replicas: members
+                .iter()
+                .filter(|vm| is_updated(vm, &template_hash))
+                .count() as i32,
+            observed_generation: self.metadata.generation,
+            label_selector: Some(selector),
+        };
+        self.update_status(ctx.clone(), status).await?;
+
+        Ok(Action::requeue(ctx.config.controller.requeue_interval()))
+    }
+
+    // VM `name` of the replica set from the current template
+    fn member(&self, name: &str, template_hash: &str) -> VirtualMachine {
+        let template = &self.spec.template;
+        let mut labels = template.metadata.labels.clone();
+        labels.insert(REPLICA_SET_LABEL.to_string(), self.name_any());
+        let mut annotations = template.metadata.annotations.clone();
+        annotations.insert(
+            REPLICA_SET_TEMPLATE_HASH_ANNOTATION.to_string(),
+            template_hash.to_string(),
+        );
+
+        VirtualMachine {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                owner_references: Some(vec![self.controller_owner_ref(&()).unwrap()]),
+                labels: Some(labels),
+                annotations: Some(annotations),
+                ..ObjectMeta::default()
+            },
+            spec: template.spec.clone(),
+            status: None,
+        }
+    }
+
+    async fn update_status(
+        &self,
+        ctx: Arc<Context>,
+        status: VirtualMachineReplicaSetStatus,
+    ) -> Result<()> {
+        if self.status.as_ref() == Some(&status) {
+            return Ok(());
+        }
+
+        let ns = self.namespace().unwrap();
+        let replica_sets: Api<VirtualMachineReplicaSet> = Api::namespaced(ctx.client.clone(), &ns);
+        let patch = Patch::Merge(json!({ "status": status }));
+        let _o = replica_sets
+            .patch_status(&self.name_any(), &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+}
+
+// Whether the current template has been applied to the VM
+fn is_updated(vm: &VirtualMachine, template_hash: &str) -> bool {
+    vm.annotations()
+        .get(REPLICA_SET_TEMPLATE_HASH_ANNOTATION)
+        .map(String::as_str)
+        == Some(template_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn web_replica_set(memory_mib: u32) -> VirtualMachineReplicaSet {
+        let spec = serde_json::from_value(json!({
+            "replicas": 2,
+            "template": {
+                "metadata": { "labels": { "app": "web" } },
+                "spec": { "state": "STARTED", "memoryMib": memory_mib },
+            },
+        }))
+        .unwrap();
+        let mut replica_set = VirtualMachineReplicaSet::new("web", spec);
+        replica_set.metadata.uid = Some("uid".to_string());
+        replica_set
+    }
+
+    #[test]
+    fn members_on_an_outdated_template_are_updated() {
+        let replica_set = web_replica_set(1024);
+        let template_hash = spec_hash(&replica_set.spec.template);
+        let member = replica_set.member("web-abcde", &template_hash);
+        assert!(is_updated(&member, &template_hash));
+        assert_eq!(
+            member.labels().get(REPLICA_SET_LABEL).map(String::as_str),
+            Some("web")
+        );
+        assert_eq!(member.labels().get("app").map(String::as_str), Some("web"));
+
+        let resized = web_replica_set(2048);
+        assert!(!is_updated(&member, &spec_hash(&resized.spec.template)));
+    }
+}
@@ -0,0 +1,411 @@
+use crate::{
+    controller::{
+        virtualmachine::{
+            SnapshotLocation, VirtualMachine, VirtualMachineCurrentState, SNAPSHOT_MOUNT_PATH,
+        },
+        virtualmachineclass, virtualmachinetemplate, Context,
+    },
+    errors::Error,
+    utils::Result,
+};
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::batch::v1::{Job, JobSpec};
+use k8s_openapi::api::core::v1::{
+    Container, PersistentVolumeClaim, PersistentVolumeClaimSpec, PersistentVolumeClaimVolumeSource,
+    Pod, PodSpec, PodTemplateSpec, Volume, VolumeMount, VolumeResourceRequirements,
+};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use kube::{
+    api::{Api, DeleteParams, ListParams, Patch, PatchParams, PostParams, ResourceExt},
+    core::ObjectMeta,
+    runtime::controller::Action,
+    CustomResource, Resource,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::*;
+
+pub static VIRTUAL_MACHINE_SNAPSHOT_FINALIZER: &str = "vmsnapshot.codesandbox.io";
+
+/// Mount path of the snapshot PVC inside the copy job
+static COPY_TARGET_PATH: &str = "/target";
+
+#[derive(CustomResource, Debug, Serialize, Deserialize, Default, Clone, JsonSchema)]
+#[kube(
+    group = "codesandbox.io",
+    version = "v1alpha1",
+    kind = "VirtualMachineSnapshot",
+    namespaced,
+    doc = "A point-in-time snapshot of a FinK VirtualMachine",
+    singular = "virtualmachinesnapshot",
+    plural = "virtualmachinesnapshots",
+    shortname = "vmsnap",
+    status = "VirtualMachineSnapshotStatus",
+    printcolumn = r#"{"name":"Source", "type":"string", "description":"Source VirtualMachine", "jsonPath":".spec.source.virtualMachineName"}"#,
+    printcolumn = r#"{"name":"Ready", "type":"boolean", "description":"Snapshot can be restored", "jsonPath":".status.readyToUse"}"#,
+    printcolumn = r#"{"name":"Size", "type":"string", "description":"Snapshot size", "jsonPath":".status.size"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineSnapshotSpec {
+    pub source: VirtualMachineSnapshotSource,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineSnapshotSource {
+    /// Name of the VirtualMachine in the same namespace to snapshot
+    pub virtual_machine_name: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineSnapshotStatus {
+    /// Whether the snapshot was fully written and can be restored
+    pub ready_to_use: bool,
+    /// Where the snapshot files are stored, on a PVC owned by the snapshot
+    pub location: Option<SnapshotLocation>,
+    /// Size of the snapshot files
+    pub size: Option<String>,
+    pub creation_time: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+impl VirtualMachineSnapshot {
+    // Reconcile (for non-finalizer related changes)
+    pub async fn reconcile(&self, ctx: Arc<Context>) -> Result<Action> {
+        let status = self.status.clone().unwrap_or_default();
+        if status.ready_to_use {
+            return Ok(Action::await_change());
+        }
+        let requeue_interval = ctx.config.controller.snapshot_requeue_interval();
+        let poll_interval = ctx.config.controller.snapshot_poll_interval();
+
+        let ns = self.namespace().unwrap();
+        let vms: Api<VirtualMachine> = Api::namespaced(ctx.client.clone(), &ns);
+        let vm_name = &self.spec.source.virtual_machine_name;
+        let Some(vm) = vms.get_opt(vm_name).await.map_err(Error::KubeError)? else {
+            self.update_status(
+                ctx,
+                VirtualMachineSnapshotStatus {
+                    error: Some(format!("VirtualMachine {vm_name} not found")),
+                    ..status
+                },
+            )
+            .await?;
+            return Ok(Action::requeue(requeue_interval));
+        };
+        // Sized and configured as its reconcile sees it, with its template and class applied
+        let vm = virtualmachinetemplate::resolve(vm, &ctx).await?;
+        let vm = virtualmachineclass::resolve(vm, &ctx).await?;
+        self.ensure_pvc(ctx.clone(), &vm).await?;
+
+        // The files are taken once, the copy job then tells how far the snapshot got
+        let jobs: Api<Job> = Api::namespaced(ctx.client.clone(), &ns);
+        let Some(job) = jobs
+            .get_opt(&self.copy_job_name())
+            .await
+            .map_err(Error::KubeError)?
+        else {
+            let vm_status = vm.status.as_ref();
+            let (source, node_name) = match vm_status.map(|s| &s.state) {
+                // Running VMs are snapshotted to their snapshot PVC, which only hibernating VMs have
+                Some(VirtualMachineCurrentState::STARTED) if !vm.spec.hibernation => {
+                    self.update_status(
+                        ctx,
+                        VirtualMachineSnapshotStatus {
+                            error: Some(format!(
+                                "VirtualMachine {vm_name} needs spec.hibernation to be snapshotted while STARTED"
+                            )),
+                            ..status
+                        },
+                    )
+                    .await?;
+                    return Ok(Action::requeue(requeue_interval));
+                }
+                Some(VirtualMachineCurrentState::STARTED) => (
+                    self.snapshot_running(ctx.clone(), &vm).await?,
+                    vm_status.and_then(|s| s.node_name.clone()),
+                ),
+                Some(VirtualMachineCurrentState::HIBERNATED) => {
+                    let Some(hibernation) = vm_status.and_then(|s| s.snapshot.clone()) else {
+                        return Err(Error::InvalidSpec(format!(
+                            "VirtualMachine {vm_name} has no hibernation snapshot"
+                        )));
+                    };
+                    (hibernation, None)
+                }
+                _ => {
+                    self.update_status(
+                        ctx,
+                        VirtualMachineSnapshotStatus {
+                            error: Some(format!(
+                                "VirtualMachine {vm_name} must be STARTED or HIBERNATED"
+                            )),
+                            ..status
+                        },
+                    )
+                    .await?;
+                    return Ok(Action::requeue(requeue_interval));
+                }
+            };
+            let _o = jobs
+                .create(
+                    &PostParams::default(),
+                    &self.copy_job(&ctx, &source, node_name),
+                )
+                .await
+                .map_err(Error::KubeError)?;
+            return Ok(Action::requeue(poll_interval));
+        };
+
+        let job_status = job.status.clone().unwrap_or_default();
+        let failure = job_status
+            .conditions
+            .iter()
+            .flatten()
+            .find(|c| c.type_ == "Failed" && c.status == "True");
+        // The snapshot stays failed until it is recreated
+        if let Some(failure) = failure {
+            self.update_status(
+                ctx,
+                VirtualMachineSnapshotStatus {
+                    error: Some(format!(
+                        "copying the snapshot failed, see job {}: {}",
+                        job.name_any(),
+                        failure.message.as_deref().unwrap_or_default()
+                    )),
+                    ..status
+                },
+            )
+            .await?;
+            return Ok(Action::await_change());
+        }
+        if job_status.succeeded.unwrap_or(0) == 0 {
+            // Wait for the copy job to complete
+            return Ok(Action::requeue(poll_interval));
+        }
+
+        info!("Snapshot {} of {} is ready", self.name_any(), vm_name);
+        let location = SnapshotLocation {
+            pvc_name: self.pvc_name(),
+            snapshot_path: "vmstate".to_string(),
+            mem_file_path: "memory".to_string(),
+            created_at: job.creation_timestamp().map(|t| t.0),
+        };
+        self.update_status(
+            ctx.clone(),
+            VirtualMachineSnapshotStatus {
+                ready_to_use: true,
+                size: self.copied_size(ctx).await?,
+                creation_time: location.created_at,
+                location: Some(location),
+                error: None,
+            },
+        )
+        .await?;
+        Ok(Action::await_change())
+    }
+
+    // Finalizer cleanup, deleting the PVC holding the snapshot files
+    pub async fn cleanup(&self, ctx: Arc<Context>) -> Result<Action> {
+        info!("Cleaning up VirtualMachineSnapshot {}", self.name_any());
+        let ns = self.namespace().unwrap();
+        let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(ctx.client.clone(), &ns);
+        if pvcs
+            .get_opt(&self.pvc_name())
+            .await
+            .map_err(Error::KubeError)?
+            .is_some()
+        {
+            let _o = pvcs
+                .delete(&self.pvc_name(), &DeleteParams::default())
+                .await
+                .map_err(Error::KubeError)?;
+        }
+        Ok(Action::await_change())
+    }
+
+    // PVC of the snapshot files, owned by the snapshot so they outlive the VM
+    fn pvc_name(&self) -> String {
+        format!("{}-vmsnapshot", self.name_any())
+    }
+
+    fn copy_job_name(&self) -> String {
+        format!("{}-copy", self.name_any())
+    }
+
+    // Directory a running VM is snapshotted to, relative to the VM snapshot PVC
+    fn staging_dir(&self) -> String {
+        format!("snapshots/{}", self.name_any())
+    }
+
+    async fn update_status(
+        &self,
+        ctx: Arc<Context>,
+        status: VirtualMachineSnapshotStatus,
+    ) -> Result<()> {
+        if self.status.as_ref() == Some(&status) {
+            return Ok(());
+        }
+
+        let ns = self.namespace().unwrap();
+        let name = self.name_any();
+        let snapshots: Api<VirtualMachineSnapshot> = Api::namespaced(ctx.client.clone(), &ns);
+        let patch = Patch::Merge(json!({ "status": status }));
+        let _o = snapshots
+            .patch_status(&name, &PatchParams::default(), &patch)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+
+    // The PVC is sized like the VM snapshot PVC, to hold the full guest memory plus the device
+    // state
+    async fn ensure_pvc(&self, ctx: Arc<Context>, vm: &VirtualMachine) -> Result<()> {
+        let ns = self.namespace().unwrap();
+        let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(ctx.client.clone(), &ns);
+        if pvcs
+            .get_opt(&self.pvc_name())
+            .await
+            .map_err(Error::KubeError)?
+            .is_some()
+        {
+            return Ok(());
+        }
+        let storage = Quantity(format!("{}Mi", vm.spec.memory_mib + 256));
+        let pvc = PersistentVolumeClaim {
+            metadata: ObjectMeta {
+                name: Some(self.pvc_name()),
+                owner_references: Some(vec![self.controller_owner_ref(&()).unwrap()]),
+                ..ObjectMeta::default()
+            },
+            spec: Some(PersistentVolumeClaimSpec {
+                access_modes: Some(vec!["ReadWriteOnce".to_string()]),
+                resources: Some(VolumeResourceRequirements {
+                    requests: Some(BTreeMap::from([("storage".to_string(), storage)])),
+                    ..VolumeResourceRequirements::default()
+                }),
+                ..PersistentVolumeClaimSpec::default()
+            }),
+            ..PersistentVolumeClaim::default()
+        };
+        let _o = pvcs
+            .create(&PostParams::default(), &pvc)
+            .await
+            .map_err(Error::KubeError)?;
+        Ok(())
+    }
+
+    // Pause the running VM, write a full snapshot to the staging directory of its snapshot PVC
+    // and resume it
+    async fn snapshot_running(
+        &self,
+        ctx: Arc<Context>,
+        vm: &VirtualMachine,
+    ) -> Result<SnapshotLocation> {
+        let location = SnapshotLocation {
+            pvc_name: vm.snapshot_pvc_name(),
+            snapshot_path: format!("{}/vmstate", self.staging_dir()),
+            mem_file_path: format!("{}/memory", self.staging_dir()),
+            created_at: Some(Utc::now()),
+        };
+        let firecracker = vm.firecracker(ctx.client.clone());
+        firecracker.pause().await?;
+        let result = firecracker
+            .create_snapshot(
+                &format!("{SNAPSHOT_MOUNT_PATH}/{}", location.snapshot_path),
+                &format!("{SNAPSHOT_MOUNT_PATH}/{}", location.mem_file_path),
+            )
+            .await;
+        // Always resume, even if the snapshot failed
+        firecracker.resume().await?;
+        result.map(|_| location)
+    }
+
+    // Job copying the snapshot files from `source` to the PVC of the snapshot, removing the
+    // staged files of a running VM. It runs on the node of a running VM, as its snapshot PVC is
+    // still mounted there. The size of the copy is reported as termination message.
+    fn copy_job(&self, ctx: &Context, source: &SnapshotLocation, node_name: Option<String>) -> Job {
+        let mut script = format!(
+            "cp {SNAPSHOT_MOUNT_PATH}/{} {COPY_TARGET_PATH}/vmstate && cp {SNAPSHOT_MOUNT_PATH}/{} {COPY_TARGET_PATH}/memory",
+            source.snapshot_path, source.mem_file_path
+        );
+        if node_name.is_some() {
+            script.push_str(&format!(
+                " && rm -rf {SNAPSHOT_MOUNT_PATH}/{}",
+                self.staging_dir()
+            ));
+        }
+        script.push_str(&format!(
+            " && du -sk {COPY_TARGET_PATH} | cut -f1 > /dev/termination-log"
+        ));
+
+        let volume = |name: &str, claim_name: &str| Volume {
+            name: name.to_string(),
+            persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+                claim_name: claim_name.to_string(),
+                ..PersistentVolumeClaimVolumeSource::default()
+            }),
+            ..Volume::default()
+        };
+        let volume_mount = |name: &str, mount_path: &str| VolumeMount {
+            name: name.to_string(),
+            mount_path: mount_path.to_string(),
+            ..VolumeMount::default()
+        };
+        Job {
+            metadata: ObjectMeta {
+                name: Some(self.copy_job_name()),
+                owner_references: Some(vec![self.controller_owner_ref(&()).unwrap()]),
+                ..ObjectMeta::default()
+            },
+            spec: Some(JobSpec {
+                backoff_limit: Some(3),
+                template: PodTemplateSpec {
+                    spec: Some(PodSpec {
+                        restart_policy: Some("OnFailure".to_string()),
+                        node_name,
+                        containers: vec![Container {
+                            name: "copy".to_string(),
+                            image: Some(ctx.config.controller.snapshot_copy_image.clone()),
+                            command: Some(vec!["sh".to_string(), "-c".to_string(), script]),
+                            volume_mounts: Some(vec![
+                                volume_mount("source", SNAPSHOT_MOUNT_PATH),
+                                volume_mount("target", COPY_TARGET_PATH),
+                            ]),
+                            ..Container::default()
+                        }],
+                        volumes: Some(vec![
+                            volume("source", &source.pvc_name),
+                            volume("target", &self.pvc_name()),
+                        ]),
+                        ..PodSpec::default()
+                    }),
+                    ..PodTemplateSpec::default()
+                },
+                ..JobSpec::default()
+            }),
+            ..Job::default()
+        }
+    }
+
+    // Size of the copied files as reported by the succeeded copy pod
+    async fn copied_size(&self, ctx: Arc<Context>) -> Result<Option<String>> {
+        let ns = self.namespace().unwrap();
+        let pods: Api<Pod> = Api::namespaced(ctx.client.clone(), &ns);
+        let params = ListParams::default().labels(&format!("job-name={}", self.copy_job_name()));
+        let kib = pods
+            .list(&params)
+            .await
+            .map_err(Error::KubeError)?
+            .into_iter()
+            .flat_map(|pod| pod.status?.container_statuses)
+            .flatten()
+            .filter_map(|cs| cs.state?.terminated)
+            .filter(|t| t.exit_code == 0)
+            .find_map(|t| t.message?.trim().parse::<u64>().ok());
+        Ok(kib.map(|kib| format!("{}Mi", kib.div_ceil(1024))))
+    }
+}
@@ -0,0 +1,184 @@
+use crate::{
+    controller::{
+        virtualmachine::{preserve_unknown_fields, VirtualMachine, VirtualMachineSpec},
+        Context,
+    },
+    errors::Error,
+    utils::Result,
+};
+use kube::{
+    api::{Api, ResourceExt},
+    runtime::reflector::{ObjectRef, Store},
+    CustomResource,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(CustomResource, Debug, Serialize, Deserialize, Default, Clone, JsonSchema)]
+#[kube(
+    group = "codesandbox.io",
+    version = "v1alpha1",
+    kind = "VirtualMachineTemplate",
+    namespaced,
+    doc = "Configuration shared by FinK VirtualMachines referencing it with spec.templateRef",
+    singular = "virtualmachinetemplate",
+    plural = "virtualmachinetemplates",
+    shortname = "vmtemplate"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineTemplateSpec {
+    /// Fields of the VirtualMachine spec, used where the VM leaves them at their default
+    #[schemars(schema_with = "preserve_unknown_fields")]
+    pub spec: Value,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineTemplateRef {
+    /// Name of the VirtualMachineTemplate in the same namespace
+    pub name: String,
+}
+
+impl VirtualMachineTemplate {
+    // Spec of `vm` with the fields it leaves at their default taken from the template. The VM keeps
+    // its own state and template reference.
+    pub fn merge(&self, vm: &VirtualMachineSpec) -> Result<VirtualMachineSpec> {
+        let defaults: VirtualMachineSpec = serde_json::from_value(json!({ "state": vm.state }))
+            .map_err(Error::SerializationError)?;
+        let overrides = serde_json::to_value(vm).map_err(Error::SerializationError)?;
+        let defaults = serde_json::to_value(defaults).map_err(Error::SerializationError)?;
+
+        let merged = overlay(&self.spec.spec, &overrides, &defaults);
+        let mut spec: VirtualMachineSpec = serde_json::from_value(merged).map_err(|e| {
+            Error::InvalidSpec(format!(
+                "VirtualMachineTemplate {} does not merge into a valid spec: {e}",
+                self.name_any()
+            ))
+        })?;
+        spec.state = vm.state.clone();
+        spec.template_ref = vm.template_ref.clone();
+        Ok(spec)
+    }
+}
+
+// `overrides` on top of `base`, where values equal to their default fall through to `base`.
+// Objects are merged key by key, everything else is replaced as a whole.
+fn overlay(base: &Value, overrides: &Value, defaults: &Value) -> Value {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            let mut merged = base.clone();
+            for (key, value) in overrides {
+                let default = defaults.get(key).unwrap_or(&Value::Null);
+                let merged_value = match base.get(key) {
+                    Some(base) => overlay(base, value, default),
+                    None => value.clone(),
+                };
+                merged.insert(key.clone(), merged_value);
+            }
+            Value::Object(merged)
+        }
+        _ if overrides == defaults && !base.is_null() => base.clone(),
+        _ => overrides.clone(),
+    }
+}
+
+// The VM with its template merged in, or the VM itself without `spec.templateRef`. A VM being
+// deleted is cleaned up with its own spec once the template is gone.
+pub async fn resolve(vm: VirtualMachine, ctx: &Context) -> Result<VirtualMachine> {
+    let Some(template_ref) = &vm.spec.template_ref else {
+        return Ok(vm);
+    };
+    let ns = vm.namespace().unwrap();
+    let templates: Api<VirtualMachineTemplate> = Api::namespaced(ctx.client.clone(), &ns);
+    let template = match templates
+        .get_opt(&template_ref.name)
+        .await
+        .map_err(Error::KubeError)?
+    {
+        Some(template) => template,
+        None if vm.metadata.deletion_timestamp.is_some() => return Ok(vm),
+        None => {
+            return Err(Error::InvalidSpec(format!(
+                "VirtualMachineTemplate {} not found",
+                template_ref.name
+            )))
+        }
+    };
+    Ok(VirtualMachine {
+        spec: template.merge(&vm.spec)?,
+        ..vm
+    })
+}
+
+// Same as `resolve` with the template read from the cache, for the periodic checks of every VM
+pub fn resolve_cached(
+    vm: VirtualMachine,
+    templates: &Store<VirtualMachineTemplate>,
+) -> Result<VirtualMachine> {
+    let Some(template_ref) = &vm.spec.template_ref else {
+        return Ok(vm);
+    };
+    let key = ObjectRef::new(&template_ref.name).within(&vm.namespace().unwrap());
+    let Some(template) = templates.get(&key) else {
+        return Err(Error::InvalidSpec(format!(
+            "VirtualMachineTemplate {} not found",
+            template_ref.name
+        )));
+    };
+    Ok(VirtualMachine {
+        spec: template.merge(&vm.spec)?,
+        ..vm
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_keeps_values_set_by_the_vm() {
+        let merged = overlay(
+            &json!({ "cpus": 2, "networking": { "serviceType": "LoadBalancer", "headless": false } }),
+            &json!({ "cpus": 4, "networking": { "serviceType": "NodePort", "headless": false } }),
+            &json!({ "cpus": 1, "networking": { "serviceType": "ClusterIP", "headless": false } }),
+        );
+        assert_eq!(
+            merged,
+            json!({ "cpus": 4, "networking": { "serviceType": "NodePort", "headless": false } })
+        );
+    }
+
+    #[test]
+    fn overlay_takes_defaults_from_the_template() {
+        let merged = overlay(
+            &json!({ "cpus": 2, "networking": { "serviceType": "LoadBalancer" }, "ports": [{ "name": "ssh", "port": 22 }] }),
+            &json!({ "cpus": 1, "memoryMib": 512, "networking": { "serviceType": "ClusterIP" }, "ports": [{ "name": "http", "port": 80 }] }),
+            &json!({ "cpus": 1, "memoryMib": 512, "networking": { "serviceType": "ClusterIP" }, "ports": [{ "name": "http", "port": 80 }] }),
+        );
+        assert_eq!(
+            merged,
+            json!({ "cpus": 2, "memoryMib": 512, "networking": { "serviceType": "LoadBalancer" }, "ports": [{ "name": "ssh", "port": 22 }] })
+        );
+    }
+
+    #[test]
+    fn merge_keeps_the_state_of_the_vm() {
+        let template = VirtualMachineTemplate::new(
+            "small",
+            VirtualMachineTemplateSpec {
+                spec: json!({ "state": "STOPPED", "cpus": 2, "memoryMib": 2048 }),
+            },
+        );
+        let vm: VirtualMachineSpec = serde_json::from_value(json!({
+            "state": "STARTED",
+            "memoryMib": 4096,
+            "templateRef": { "name": "small" },
+        }))
+        .unwrap();
+        let spec = template.merge(&vm).unwrap();
+        assert_eq!(spec.state, vm.state);
+        assert_eq!((spec.cpus, spec.memory_mib), (2, 4096));
+        assert_eq!(spec.template_ref.map(|t| t.name).as_deref(), Some("small"));
+    }
+}
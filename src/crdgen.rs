@@ -1,13 +1,65 @@
+pub mod activator;
+pub mod agent;
+pub mod api;
+pub mod cloudevents;
+pub mod config;
 pub mod controller;
+pub mod crds;
+pub mod debug;
 pub mod errors;
+pub mod firecracker;
+pub mod metrics;
+pub mod notifications;
 pub mod state;
+pub mod telemetry;
 pub mod utils;
+pub mod webhook;
 
-use kube::CustomResourceExt;
+use clap::Parser;
+use std::path::PathBuf;
 
-fn main() {
-    print!(
-        "{}",
-        serde_yaml::to_string(&controller::virtualmachine::VirtualMachine::crd()).unwrap()
-    )
+#[derive(Parser, Debug)]
+#[command(about = "Generate the CustomResourceDefinitions of FinK")]
+struct Args {
+    /// Only generate these kinds, by kind, plural or short name, repeatable or comma separated
+    #[arg(long = "kind", value_delimiter = ',')]
+    kinds: Vec<String>,
+    /// Write one file per CRD into this directory instead of a multi-document YAML to stdout
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let mut crds = crds::all();
+    if !args.kinds.is_empty() {
+        if let Some(unknown) = args
+            .kinds
+            .iter()
+            .find(|k| !crds.iter().any(|crd| crds::matches(crd, k)))
+        {
+            anyhow::bail!("unknown kind {unknown}");
+        }
+        crds.retain(|crd| args.kinds.iter().any(|k| crds::matches(crd, k)));
+    }
+
+    match &args.out_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            for crd in &crds {
+                let path = dir.join(format!("{}.yaml", crd.spec.names.plural));
+                std::fs::write(&path, serde_yaml::to_string(crd)?)?;
+                eprintln!("wrote {}", path.display());
+            }
+        }
+        None => {
+            let documents = crds
+                .iter()
+                .map(serde_yaml::to_string)
+                .collect::<Result<Vec<_>, _>>()?;
+            print!("{}", documents.join("---\n"));
+        }
+    }
+    Ok(())
 }
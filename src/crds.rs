@@ -0,0 +1,179 @@
+use crate::{
+    controller::{
+        virtualmachine::VirtualMachine, virtualmachineclaim::VirtualMachineClaim,
+        virtualmachineclass::VirtualMachineClass, virtualmachinepool::VirtualMachinePool,
+        virtualmachinereplicaset::VirtualMachineReplicaSet,
+        virtualmachinesnapshot::VirtualMachineSnapshot,
+        virtualmachinetemplate::VirtualMachineTemplate,
+    },
+    errors::Error,
+    utils::Result,
+};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    CustomResourceDefinition, ValidationRule,
+};
+use kube::{
+    api::{Api, Patch, PatchParams, ResourceExt},
+    runtime::wait::{await_condition, conditions},
+    Client, CustomResourceExt,
+};
+use std::time::Duration;
+use tracing::*;
+
+/// Annotation of the CRDs installed by the controller, holding the version of the controller
+static CONTROLLER_VERSION_ANNOTATION: &str = "vms.codesandbox.io/controller-version";
+
+/// Field manager of the CRDs installed by the controller
+static CRD_FIELD_MANAGER: &str = "fink";
+
+/// Time an installed CRD gets to be established before the controller starts anyway
+static ESTABLISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+// CEL rules of the VirtualMachine spec, checked by the API server even without the webhook. CEL
+// only sees the VM itself, so the sizing set by a template or class is checked by the reconcile.
+fn virtual_machine_crd() -> CustomResourceDefinition {
+    let mut crd = VirtualMachine::crd();
+    validate(
+        &mut crd,
+        &["spec"],
+        &[
+            (
+                "has(self.templateRef) || has(self.className) || !has(self.balloon) || self.balloon.targetMib < self.memoryMib",
+                "balloon.targetMib must be below memoryMib",
+            ),
+            (
+                "!has(oldSelf.storage) || !has(oldSelf.bootSource) || (has(self.bootSource) && self.bootSource.rootfsImage == oldSelf.bootSource.rootfsImage)",
+                "bootSource.rootfsImage is immutable once the root disk is built from it",
+            ),
+            (
+                "!has(oldSelf.storage) || !has(oldSelf.image) || (has(self.image) && self.image == oldSelf.image)",
+                "image is immutable once the root disk is built from it",
+            ),
+            (
+                "has(self.templateRef) ? !(has(self.bootSource) && has(self.image)) : has(self.bootSource) != has(self.image)",
+                "exactly one of bootSource or image must be set, or at most one with templateRef",
+            ),
+            (
+                "!has(self.drives) || self.drives.all(d, d.name != 'rootfs' && self.drives.exists_one(o, o.name == d.name))",
+                "drive names must be unique and not rootfs",
+            ),
+        ],
+    );
+    validate(
+        &mut crd,
+        &["spec", "storage"],
+        &[(
+            "!has(oldSelf.storageClassName) || (has(self.storageClassName) && self.storageClassName == oldSelf.storageClassName)",
+            "storageClassName is immutable once set",
+        )],
+    );
+    crd
+}
+
+// Attach `rules` as x-kubernetes-validations to the schema of the field at `path`
+fn validate(crd: &mut CustomResourceDefinition, path: &[&str], rules: &[(&str, &str)]) {
+    for version in crd.spec.versions.iter_mut() {
+        let mut schema = version
+            .schema
+            .as_mut()
+            .and_then(|s| s.open_api_v3_schema.as_mut())
+            .expect("CRD without schema");
+        for field in path {
+            schema = schema
+                .properties
+                .as_mut()
+                .and_then(|p| p.get_mut(*field))
+                .unwrap_or_else(|| panic!("no field {} in CRD schema", path.join(".")));
+        }
+        schema
+            .x_kubernetes_validations
+            .get_or_insert_with(Vec::new)
+            .extend(rules.iter().map(|(rule, message)| ValidationRule {
+                rule: rule.to_string(),
+                message: Some(message.to_string()),
+                ..ValidationRule::default()
+            }));
+    }
+}
+
+// CRDs of all the kinds of FinK
+pub fn all() -> Vec<CustomResourceDefinition> {
+    vec![
+        virtual_machine_crd(),
+        VirtualMachineSnapshot::crd(),
+        VirtualMachinePool::crd(),
+        VirtualMachineClaim::crd(),
+        VirtualMachineTemplate::crd(),
+        VirtualMachineReplicaSet::crd(),
+        VirtualMachineClass::crd(),
+    ]
+}
+
+// Whether `kind` names the CRD by its kind, plural, singular or short name
+pub fn matches(crd: &CustomResourceDefinition, kind: &str) -> bool {
+    let names = &crd.spec.names;
+    let kind = kind.to_lowercase();
+    names.kind.to_lowercase() == kind
+        || names.plural == kind
+        || names.singular.as_deref() == Some(kind.as_str())
+        || names.short_names.iter().flatten().any(|n| *n == kind)
+}
+
+// Apply the CRDs with server-side apply and wait for them to be established. CRDs installed by a
+// newer controller are left alone, so an older replica in a rolling update does not downgrade them.
+pub async fn install(client: Client) -> Result<()> {
+    let api: Api<CustomResourceDefinition> = Api::all(client);
+    let version = env!("CARGO_PKG_VERSION");
+    for mut crd in all() {
+        let name = crd.name_any();
+        let installed = api
+            .get_opt(&name)
+            .await
+            .map_err(Error::KubeError)?
+            .and_then(|crd| {
+                crd.annotations()
+                    .get(CONTROLLER_VERSION_ANNOTATION)
+                    .cloned()
+            });
+        if let Some(installed) = installed.filter(|v| is_newer(v, version)) {
+            warn!(
+                "Keeping CRD {} installed by the newer controller {}",
+                name, installed
+            );
+            continue;
+        }
+
+        info!("Installing CRD {}", name);
+        crd.annotations_mut().insert(
+            CONTROLLER_VERSION_ANNOTATION.to_string(),
+            version.to_string(),
+        );
+        let _o = api
+            .patch(
+                &name,
+                &PatchParams::apply(CRD_FIELD_MANAGER).force(),
+                &Patch::Apply(&crd),
+            )
+            .await
+            .map_err(Error::KubeError)?;
+
+        let established = await_condition(api.clone(), &name, conditions::is_crd_established());
+        if tokio::time::timeout(ESTABLISH_TIMEOUT, established)
+            .await
+            .is_err()
+        {
+            warn!("CRD {} is not established yet", name);
+        }
+    }
+    Ok(())
+}
+
+// Whether the dotted version `a` is newer than `b`
+fn is_newer(a: &str, b: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.split(['.', '-'])
+            .map_while(|part| part.parse().ok())
+            .collect()
+    };
+    parse(a) > parse(b)
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use pprof::protos::Message;
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use crate::{state::AppState, telemetry};
+
+// Longest CPU profile that can be requested
+const MAX_PROFILE_SECONDS: u64 = 300;
+
+// Endpoints to inspect and tune a live controller, only served with http.debugEndpoints
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/debug/log-level", get(get_log_level).put(put_log_level))
+        .route("/debug/tokio", get(tokio_stats))
+        .route("/debug/pprof/profile", get(cpu_profile))
+        .route("/debug/pprof/flamegraph", get(cpu_flamegraph))
+}
+
+// Current log filter, in the RUST_LOG syntax
+async fn get_log_level(State(state): State<AppState>) -> Result<String, StatusCode> {
+    telemetry::log_filter(&state.log_filter).map_err(|e| {
+        error!("failed to read the log filter: {e:#}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+// Replace the log filter with the directives of the body, e.g. `debug` or `info,fink=debug`
+async fn put_log_level(
+    State(state): State<AppState>,
+    body: String,
+) -> Result<String, (StatusCode, String)> {
+    let directives = body.trim();
+    telemetry::set_log_filter(&state.log_filter, directives)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}\n")))?;
+    warn!("log filter set to {directives}");
+    Ok(format!("{directives}\n"))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TokioStats {
+    workers: usize,
+    alive_tasks: usize,
+    global_queue_depth: usize,
+    worker_stats: Vec<TokioWorkerStats>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TokioWorkerStats {
+    busy_seconds: f64,
+    park_count: u64,
+    park_unpark_count: u64,
+}
+
+// Metrics of the tokio runtime, a growing number of alive tasks or a deep global queue point at
+// stuck or starved futures
+async fn tokio_stats() -> Json<TokioStats> {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let workers = metrics.num_workers();
+    Json(TokioStats {
+        workers,
+        alive_tasks: metrics.num_alive_tasks(),
+        global_queue_depth: metrics.global_queue_depth(),
+        worker_stats: (0..workers)
+            .map(|worker| TokioWorkerStats {
+                busy_seconds: metrics.worker_total_busy_duration(worker).as_secs_f64(),
+                park_count: metrics.worker_park_count(worker),
+                park_unpark_count: metrics.worker_park_unpark_count(worker),
+            })
+            .collect(),
+    })
+}
+
+#[derive(Deserialize)]
+struct ProfileParams {
+    /// How long to sample for
+    #[serde(default = "default_profile_seconds")]
+    seconds: u64,
+    /// Samples per second
+    #[serde(default = "default_profile_frequency")]
+    frequency: i32,
+}
+
+fn default_profile_seconds() -> u64 {
+    30
+}
+
+fn default_profile_frequency() -> i32 {
+    99
+}
+
+// Sample the stacks of every thread for the requested duration. Only one profile runs at a time.
+async fn profile(params: &ProfileParams) -> Result<pprof::Report, (StatusCode, String)> {
+    if params.seconds == 0 || params.seconds > MAX_PROFILE_SECONDS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("seconds must be between 1 and {MAX_PROFILE_SECONDS}\n"),
+        ));
+    }
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(params.frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| (StatusCode::CONFLICT, format!("{e}\n")))?;
+    info!("profiling the CPU for {}s", params.seconds);
+    tokio::time::sleep(Duration::from_secs(params.seconds)).await;
+    guard
+        .report()
+        .build()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}\n")))
+}
+
+// CPU profile in the pprof protobuf format, for `go tool pprof`
+async fn cpu_profile(
+    Query(params): Query<ProfileParams>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let report = profile(&params).await?;
+    let profile = report
+        .pprof()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}\n")))?;
+    let body = profile
+        .write_to_bytes()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}\n")))?;
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], body))
+}
+
+// CPU profile rendered as an SVG flamegraph
+async fn cpu_flamegraph(
+    Query(params): Query<ProfileParams>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let report = profile(&params).await?;
+    let mut body = Vec::new();
+    report
+        .flamegraph(&mut body)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}\n")))?;
+    Ok(([(header::CONTENT_TYPE, "image/svg+xml")], body))
+}
@@ -15,4 +15,102 @@ pub enum Error {
 
     #[error("IllegalDocument")]
     IllegalDocument,
+
+    #[error("Invalid Spec: {0}")]
+    InvalidSpec(String),
+
+    #[error("Firecracker Error: {0}")]
+    FirecrackerError(String),
+
+    #[error("Guest Agent Error: {0}")]
+    GuestAgentError(String),
+}
+
+impl Error {
+    // Low cardinality label of the error for metrics, reconcile errors wrapped by the finalizer
+    // are labelled by their own variant
+    pub fn metric_label(&self) -> &'static str {
+        use kube::runtime::finalizer::Error as FinalizerError;
+
+        match self {
+            Error::SerializationError(_) => "serialization",
+            Error::KubeError(_) => "kube",
+            Error::FinalizerError(e) => match e.as_ref() {
+                FinalizerError::ApplyFailed(e) | FinalizerError::CleanupFailed(e) => {
+                    e.metric_label()
+                }
+                _ => "finalizer",
+            },
+            Error::IllegalDocument => "illegal_document",
+            Error::InvalidSpec(_) => "invalid_spec",
+            Error::FirecrackerError(_) => "firecracker",
+            Error::GuestAgentError(_) => "guest_agent",
+        }
+    }
+
+    // Whether retrying soon can succeed. Missing dependencies, conflicts and unavailable
+    // services are transient, while forbidden requests and invalid specs or documents need a
+    // change to RBAC or the resource first.
+    pub fn is_retryable(&self) -> bool {
+        use kube::runtime::finalizer::Error as FinalizerError;
+
+        match self {
+            Error::KubeError(kube::Error::Api(response)) => {
+                !matches!(response.code, 400 | 401 | 403 | 422)
+            }
+            Error::KubeError(_) => true,
+            Error::FinalizerError(e) => match e.as_ref() {
+                FinalizerError::ApplyFailed(e) | FinalizerError::CleanupFailed(e) => {
+                    e.is_retryable()
+                }
+                _ => true,
+            },
+            Error::SerializationError(_) | Error::IllegalDocument | Error::InvalidSpec(_) => false,
+            Error::FirecrackerError(_) | Error::GuestAgentError(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::runtime::finalizer::Error as FinalizerError;
+
+    fn api_error(code: u16) -> Error {
+        Error::KubeError(kube::Error::Api(kube::error::ErrorResponse {
+            status: "Failure".to_string(),
+            message: String::new(),
+            reason: String::new(),
+            code,
+        }))
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        for code in [400, 401, 403, 422] {
+            assert!(!api_error(code).is_retryable(), "{code}");
+        }
+        for code in [404, 409, 429, 500, 503] {
+            assert!(api_error(code).is_retryable(), "{code}");
+        }
+        assert!(!Error::InvalidSpec(String::new()).is_retryable());
+        assert!(Error::FirecrackerError(String::new()).is_retryable());
+    }
+
+    #[test]
+    fn finalizer_errors_are_classified_by_the_wrapped_error() {
+        let wrapped = |e| Error::FinalizerError(Box::new(FinalizerError::ApplyFailed(e)));
+        let invalid = wrapped(Error::InvalidSpec(String::new()));
+        assert!(!invalid.is_retryable());
+        assert_eq!(invalid.metric_label(), "invalid_spec");
+
+        let cleanup =
+            Error::FinalizerError(Box::new(FinalizerError::CleanupFailed(api_error(409))));
+        assert!(cleanup.is_retryable());
+        assert_eq!(cleanup.metric_label(), "kube");
+
+        let unnamed = Error::FinalizerError(Box::new(FinalizerError::UnnamedObject));
+        assert!(unnamed.is_retryable());
+        assert_eq!(unnamed.metric_label(), "finalizer");
+    }
 }
@@ -0,0 +1,302 @@
+use std::{path::Path, time::Duration};
+
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::{Api, AttachParams},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::AsyncReadExt;
+
+use crate::{controller::virtualmachine::VM_CONTAINER_NAME, errors::Error, utils::Result};
+
+/// Path of the Firecracker API socket inside the vm-container
+pub static API_SOCKET_PATH: &str = "/run/firecracker.socket";
+
+/// Path of the Firecracker config file inside the vm-container
+pub static CONFIG_PATH: &str = "/etc/firecracker/config.json";
+
+/// Path of the MMDS contents loaded with `--metadata` inside the vm-container
+pub static METADATA_PATH: &str = "/etc/firecracker/metadata.json";
+
+/// Custom CPU template referenced by `cpu-config` inside the vm-container
+pub static CPU_TEMPLATE_PATH: &str = "/etc/firecracker/cpu-template.json";
+
+/// Unix socket backing the guest vsock device inside the vm-container
+pub static VSOCK_UDS_PATH: &str = "/run/firecracker-vsock.sock";
+
+/// File Firecracker writes its metrics to as JSON lines inside the vm-container, created by the
+/// runtime before Firecracker starts
+pub static METRICS_PATH: &str = "/run/firecracker-metrics.json";
+
+/// Interval Firecracker flushes its metrics at
+pub const METRICS_FLUSH_INTERVAL_SECS: u64 = 60;
+
+/// Firecracker `--config-file` contents
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    #[serde(rename = "boot-source")]
+    pub boot_source: BootSource,
+    pub drives: Vec<Drive>,
+    #[serde(rename = "machine-config")]
+    pub machine_config: MachineConfig,
+    #[serde(rename = "network-interfaces")]
+    pub network_interfaces: Vec<NetworkInterface>,
+    #[serde(rename = "mmds-config", skip_serializing_if = "Option::is_none")]
+    pub mmds_config: Option<MmdsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vsock: Option<Vsock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balloon: Option<Balloon>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<Metrics>,
+    /// Path of a custom CPU template
+    #[serde(rename = "cpu-config", skip_serializing_if = "Option::is_none")]
+    pub cpu_config: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BootSource {
+    pub kernel_image_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_args: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Drive {
+    pub drive_id: String,
+    pub path_on_host: String,
+    pub is_root_device: bool,
+    pub is_read_only: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RateLimiter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<TokenBucket>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ops: Option<TokenBucket>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TokenBucket {
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub one_time_burst: Option<u64>,
+    /// Refill time in milliseconds
+    pub refill_time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MachineConfig {
+    pub vcpu_count: u32,
+    pub mem_size_mib: u32,
+    /// Static CPU template, e.g. `T2`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_template: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct NetworkInterface {
+    pub iface_id: String,
+    /// Tap device created by the runtime inside the pod
+    pub host_dev_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guest_mac: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rx_rate_limiter: Option<RateLimiter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_rate_limiter: Option<RateLimiter>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MmdsConfig {
+    /// Interfaces the guest can reach MMDS through
+    pub network_interfaces: Vec<String>,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Vsock {
+    pub guest_cid: u32,
+    pub uds_path: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    pub metrics_path: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Balloon {
+    /// Memory reclaimed from the guest
+    pub amount_mib: u32,
+    pub deflate_on_oom: bool,
+    pub stats_polling_interval_s: u32,
+}
+
+/// Body of `PATCH /vm`
+#[derive(Serialize, Debug)]
+struct VmState {
+    state: &'static str,
+}
+
+/// Body of `PUT /snapshot/create`
+#[derive(Serialize, Debug)]
+struct SnapshotCreate<'a> {
+    snapshot_type: &'static str,
+    snapshot_path: &'a str,
+    mem_file_path: &'a str,
+}
+
+/// Body of `PATCH /balloon`
+#[derive(Serialize, Debug)]
+struct BalloonUpdate {
+    amount_mib: u32,
+}
+
+/// Body of `PATCH /balloon/statistics`
+#[derive(Serialize, Debug)]
+struct BalloonStatsUpdate {
+    stats_polling_interval_s: u32,
+}
+
+// Client of the Firecracker of a running VM pod. The API socket and the metrics file only exist
+// inside the vm-container, so they are reached by executing curl and tail there.
+#[derive(Clone)]
+pub struct ApiClient {
+    pods: Api<Pod>,
+    pod_name: String,
+}
+
+impl ApiClient {
+    pub fn new(client: Client, namespace: &str, pod_name: &str) -> Self {
+        Self {
+            pods: Api::namespaced(client, namespace),
+            pod_name: pod_name.to_string(),
+        }
+    }
+
+    pub async fn pause(&self) -> Result<()> {
+        self.request("PATCH", "/vm", &VmState { state: "Paused" })
+            .await
+    }
+
+    pub async fn resume(&self) -> Result<()> {
+        self.request("PATCH", "/vm", &VmState { state: "Resumed" })
+            .await
+    }
+
+    // Write a full snapshot of the paused VM, the paths are within the vm-container and their
+    // directories are created first
+    pub async fn create_snapshot(&self, snapshot_path: &str, mem_file_path: &str) -> Result<()> {
+        let mut command = vec!["mkdir", "-p"];
+        command.extend(
+            [snapshot_path, mem_file_path]
+                .into_iter()
+                .filter_map(|path| Path::new(path).parent()?.to_str()),
+        );
+        if let (output, false) = self.exec(&command).await? {
+            return Err(Error::FirecrackerError(format!(
+                "failed to create the snapshot directory: {}",
+                output.trim()
+            )));
+        }
+        let body = SnapshotCreate {
+            snapshot_type: "Full",
+            snapshot_path,
+            mem_file_path,
+        };
+        self.request("PUT", "/snapshot/create", &body).await
+    }
+
+    pub async fn set_balloon(&self, amount_mib: u32) -> Result<()> {
+        self.request("PATCH", "/balloon", &BalloonUpdate { amount_mib })
+            .await
+    }
+
+    pub async fn set_balloon_stats_interval(&self, seconds: u32) -> Result<()> {
+        let body = BalloonStatsUpdate {
+            stats_polling_interval_s: seconds,
+        };
+        self.request("PATCH", "/balloon/statistics", &body).await
+    }
+
+    // Replace the contents of MMDS
+    pub async fn put_mmds(&self, metadata: &Value) -> Result<()> {
+        self.request("PUT", "/mmds", metadata).await
+    }
+
+    // Flushes of the metrics since the previous read `interval` ago, taken from the metrics file.
+    // Firecracker flushes them every minute. The file is emptied after the read so it does not
+    // grow for the lifetime of the VM; a flush written in between is lost.
+    pub async fn take_metrics(&self, interval: Duration) -> Result<Vec<Value>> {
+        let lines = (interval.as_secs() / METRICS_FLUSH_INTERVAL_SECS + 2).to_string();
+        let script = r#"tail -n "$1" "$0" && : > "$0""#;
+        let command = ["sh", "-c", script, METRICS_PATH, &lines];
+        let (output, _) = self.exec(&command).await?;
+        // Firecracker does not append, so the flush after emptying the file lands behind a hole
+        // of NUL bytes. A line cut by the truncation does not parse and is skipped.
+        Ok(output
+            .lines()
+            .filter_map(|line| serde_json::from_str(line.trim_start_matches('\0')).ok())
+            .collect())
+    }
+
+    async fn request<T: Serialize>(&self, method: &str, path: &str, body: &T) -> Result<()> {
+        let body = serde_json::to_string(body).map_err(Error::SerializationError)?;
+        let url = format!("http://localhost{path}");
+        let command = [
+            "curl",
+            "--silent",
+            "--show-error",
+            "--fail-with-body",
+            "--unix-socket",
+            API_SOCKET_PATH,
+            "-X",
+            method,
+            "-H",
+            "Content-Type: application/json",
+            "-d",
+            &body,
+            &url,
+        ];
+        match self.exec(&command).await? {
+            (_, true) => Ok(()),
+            (output, false) => Err(Error::FirecrackerError(format!(
+                "{method} {path} failed: {}",
+                output.trim()
+            ))),
+        }
+    }
+
+    // Run a command in the vm-container, returning its output and whether it succeeded
+    async fn exec(&self, command: &[&str]) -> Result<(String, bool)> {
+        let params = AttachParams::default()
+            .container(VM_CONTAINER_NAME)
+            .stdout(true)
+            .stderr(true);
+        let mut process = self
+            .pods
+            .exec(&self.pod_name, command.to_vec(), &params)
+            .await
+            .map_err(Error::KubeError)?;
+
+        let mut output = String::new();
+        if let Some(mut stdout) = process.stdout() {
+            let _ = stdout.read_to_string(&mut output).await;
+        }
+        if let Some(mut stderr) = process.stderr() {
+            let _ = stderr.read_to_string(&mut output).await;
+        }
+        let exit_status = match process.take_status() {
+            Some(status) => status.await,
+            None => None,
+        };
+        let success = exit_status.and_then(|s| s.status).as_deref() == Some("Success");
+        Ok((output, success))
+    }
+}
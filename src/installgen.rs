@@ -0,0 +1,297 @@
+pub mod activator;
+pub mod agent;
+pub mod api;
+pub mod cloudevents;
+pub mod config;
+pub mod controller;
+pub mod crds;
+pub mod debug;
+pub mod errors;
+pub mod firecracker;
+pub mod metrics;
+pub mod notifications;
+pub mod state;
+pub mod telemetry;
+pub mod utils;
+pub mod webhook;
+
+use std::collections::BTreeMap;
+
+use clap::Parser;
+use k8s_openapi::{
+    api::{
+        apps::v1::{Deployment, DeploymentSpec},
+        core::v1::{
+            Container, ContainerPort, EnvVar, EnvVarSource, HTTPGetAction, Namespace,
+            ObjectFieldSelector, PodSpec, PodTemplateSpec, Probe, SecretVolumeSource, Service,
+            ServicePort, ServiceSpec, Volume, VolumeMount,
+        },
+    },
+    apimachinery::pkg::{apis::meta::v1::LabelSelector, util::intstr::IntOrString},
+};
+use kube::core::ObjectMeta;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+
+/// RBAC of a controller managing VirtualMachines in all namespaces
+static RBAC: &str = include_str!("../yaml/rbac.yaml");
+
+/// RBAC of a controller only watching some namespaces, see `namespaced_rbac`
+static NAMESPACED_RBAC: &str = include_str!("../yaml/rbac-namespaced.yaml");
+
+/// Mutating webhook, its Service and cert-manager Certificate
+static WEBHOOK: &str = include_str!("../yaml/webhook.yaml");
+
+/// Namespace the bundled manifests are written for
+static MANIFEST_NAMESPACE: &str = "fink-system";
+
+/// Port of the HTTP API, health checks and metrics
+static HTTP_PORT: i32 = 3000;
+
+/// Port of the admission webhooks, see `WebhookConfig::bind`
+static WEBHOOK_PORT: i32 = 8443;
+
+#[derive(Parser, Debug)]
+#[command(
+    about = "Render the FinK install bundle",
+    after_help = "Install with: installgen --tag <tag> | kubectl apply --server-side -f -"
+)]
+struct Args {
+    /// Namespace the controller is installed into, created by the bundle
+    #[arg(long, default_value = "fink-system")]
+    namespace: String,
+    /// Image repository of the controller
+    #[arg(long, default_value = "fink")]
+    image: String,
+    /// Image tag of the controller
+    #[arg(long, default_value = env!("CARGO_PKG_VERSION"))]
+    tag: String,
+    /// Number of controller replicas, leader election is enabled with more than one
+    #[arg(long, default_value_t = 1)]
+    replicas: i32,
+    /// Only watch VirtualMachines in these namespaces, comma separated, granting a Role in each
+    #[arg(long)]
+    watch_namespace: Option<String>,
+    /// Include the mutating webhook, which needs cert-manager for its certificate
+    #[arg(long)]
+    webhook: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let namespace = args.namespace.as_str();
+
+    let mut documents = vec![yaml(&Namespace {
+        metadata: ObjectMeta {
+            name: Some(namespace.to_string()),
+            ..ObjectMeta::default()
+        },
+        ..Namespace::default()
+    })?];
+    for crd in crds::all() {
+        documents.push(yaml(&crd)?);
+    }
+    match &args.watch_namespace {
+        Some(watched) => documents.extend(namespaced_rbac(namespace, watched)?),
+        None => documents.push(in_namespace(RBAC, namespace)),
+    }
+    documents.push(yaml(&deployment(&args))?);
+    documents.push(yaml(&service(namespace))?);
+    if args.webhook {
+        documents.push(in_namespace(WEBHOOK, namespace));
+    }
+
+    print!("{}", documents.join("---\n"));
+    Ok(())
+}
+
+fn yaml<T: Serialize>(object: &T) -> anyhow::Result<String> {
+    Ok(serde_yaml::to_string(object)?)
+}
+
+// Bundled manifest moved to `namespace`, ending with a newline so documents can follow
+fn in_namespace(manifest: &str, namespace: &str) -> String {
+    let manifest = manifest.replace(MANIFEST_NAMESPACE, namespace);
+    if manifest.ends_with('\n') {
+        manifest
+    } else {
+        manifest + "\n"
+    }
+}
+
+// Namespaced RBAC with the Role and RoleBinding repeated in each watched namespace, and in the
+// controller's own namespace where it holds the Lease and the activator endpoints
+fn namespaced_rbac(namespace: &str, watched: &str) -> anyhow::Result<Vec<String>> {
+    let mut role_namespaces: Vec<&str> = watched.split(',').map(str::trim).collect();
+    if !role_namespaces.contains(&namespace) {
+        role_namespaces.push(namespace);
+    }
+
+    let mut documents = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(NAMESPACED_RBAC) {
+        let mut object = Value::deserialize(document)?;
+        // The ServiceAccount and the subjects of the bindings live in the controller's namespace
+        if let Some(subjects) = object.get_mut("subjects").and_then(Value::as_sequence_mut) {
+            for subject in subjects {
+                subject["namespace"] = namespace.into();
+            }
+        }
+        match object["kind"].as_str() {
+            Some("Role" | "RoleBinding") => {
+                for role_namespace in &role_namespaces {
+                    object["metadata"]["namespace"] = (*role_namespace).into();
+                    documents.push(yaml(&object)?);
+                }
+            }
+            Some("ServiceAccount") => {
+                object["metadata"]["namespace"] = namespace.into();
+                documents.push(yaml(&object)?);
+            }
+            _ => documents.push(yaml(&object)?),
+        }
+    }
+    Ok(documents)
+}
+
+fn labels() -> BTreeMap<String, String> {
+    BTreeMap::from([("app".to_string(), "fink".to_string())])
+}
+
+fn env_var(name: &str, value: &str) -> EnvVar {
+    EnvVar {
+        name: name.to_string(),
+        value: Some(value.to_string()),
+        ..EnvVar::default()
+    }
+}
+
+fn http_probe(path: &str) -> Probe {
+    Probe {
+        http_get: Some(HTTPGetAction {
+            path: Some(path.to_string()),
+            port: IntOrString::String("http".to_string()),
+            ..HTTPGetAction::default()
+        }),
+        period_seconds: Some(10),
+        ..Probe::default()
+    }
+}
+
+// Controller Deployment, running the controllers, the HTTP API and the webhook if enabled
+fn deployment(args: &Args) -> Deployment {
+    let container_args = args
+        .watch_namespace
+        .as_ref()
+        .map(|namespaces| vec![format!("--namespace={namespaces}")]);
+
+    let mut env = vec![
+        EnvVar {
+            name: "POD_NAMESPACE".to_string(),
+            value_from: Some(EnvVarSource {
+                field_ref: Some(ObjectFieldSelector {
+                    field_path: "metadata.namespace".to_string(),
+                    ..ObjectFieldSelector::default()
+                }),
+                ..EnvVarSource::default()
+            }),
+            ..EnvVar::default()
+        },
+        env_var("FINK_BIND", &format!("0.0.0.0:{HTTP_PORT}")),
+    ];
+    if args.replicas > 1 {
+        env.push(env_var("FINK_LEADER_ELECTION", "true"));
+    }
+
+    let mut ports = vec![ContainerPort {
+        name: Some("http".to_string()),
+        container_port: HTTP_PORT,
+        ..ContainerPort::default()
+    }];
+    let mut volumes = Vec::new();
+    let mut volume_mounts = Vec::new();
+    if args.webhook {
+        env.push(env_var("FINK_WEBHOOK", "true"));
+        ports.push(ContainerPort {
+            name: Some("webhook".to_string()),
+            container_port: WEBHOOK_PORT,
+            ..ContainerPort::default()
+        });
+        volumes.push(Volume {
+            name: "tls".to_string(),
+            secret: Some(SecretVolumeSource {
+                secret_name: Some("fink-webhook-tls".to_string()),
+                ..SecretVolumeSource::default()
+            }),
+            ..Volume::default()
+        });
+        volume_mounts.push(VolumeMount {
+            name: "tls".to_string(),
+            mount_path: "/tls".to_string(),
+            read_only: Some(true),
+            ..VolumeMount::default()
+        });
+    }
+
+    Deployment {
+        metadata: ObjectMeta {
+            name: Some("fink".to_string()),
+            namespace: Some(args.namespace.clone()),
+            labels: Some(labels()),
+            ..ObjectMeta::default()
+        },
+        spec: Some(DeploymentSpec {
+            replicas: Some(args.replicas),
+            selector: LabelSelector {
+                match_labels: Some(labels()),
+                ..LabelSelector::default()
+            },
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(labels()),
+                    ..ObjectMeta::default()
+                }),
+                spec: Some(PodSpec {
+                    service_account_name: Some("fink".to_string()),
+                    containers: vec![Container {
+                        name: "fink".to_string(),
+                        image: Some(format!("{}:{}", args.image, args.tag)),
+                        args: container_args,
+                        env: Some(env),
+                        ports: Some(ports),
+                        liveness_probe: Some(http_probe("/livez")),
+                        readiness_probe: Some(http_probe("/readyz")),
+                        volume_mounts: Some(volume_mounts),
+                        ..Container::default()
+                    }],
+                    volumes: Some(volumes),
+                    ..PodSpec::default()
+                }),
+            },
+            ..DeploymentSpec::default()
+        }),
+        ..Deployment::default()
+    }
+}
+
+// Service of the HTTP API and the metrics
+fn service(namespace: &str) -> Service {
+    Service {
+        metadata: ObjectMeta {
+            name: Some("fink".to_string()),
+            namespace: Some(namespace.to_string()),
+            labels: Some(labels()),
+            ..ObjectMeta::default()
+        },
+        spec: Some(ServiceSpec {
+            selector: Some(labels()),
+            ports: Some(vec![ServicePort {
+                name: Some("http".to_string()),
+                port: HTTP_PORT,
+                target_port: Some(IntOrString::String("http".to_string())),
+                ..ServicePort::default()
+            }]),
+            ..ServiceSpec::default()
+        }),
+        ..Service::default()
+    }
+}
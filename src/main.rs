@@ -1,43 +1,176 @@
+pub mod activator;
+pub mod agent;
+pub mod api;
+pub mod cloudevents;
+pub mod config;
 pub mod controller;
+pub mod crds;
+pub mod debug;
 pub mod errors;
+pub mod firecracker;
+pub mod metrics;
+pub mod notifications;
 pub mod state;
+pub mod telemetry;
 pub mod utils;
+pub mod webhook;
 
-use std::future::IntoFuture;
+use std::{future::IntoFuture, path::PathBuf};
 
-use axum::{routing::get, Json, Router};
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use clap::Parser;
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use prometheus::{Encoder, TextEncoder};
 
 use serde_json::{json, Value};
 
+#[derive(Parser, Debug)]
+#[command(version, about = "Kubernetes controller running Firecracker VMs")]
+struct Args {
+    /// Config file with the controller settings, YAML or TOML
+    #[arg(long, env = "FINK_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address the HTTP server listens on, overrides http.bind of the config
+    #[arg(long)]
+    bind: Option<String>,
+    /// Kubeconfig to use, defaults to $KUBECONFIG, ~/.kube/config or the in-cluster config
+    #[arg(long)]
+    kubeconfig: Option<String>,
+    /// Only watch VirtualMachines in these namespaces instead of the whole cluster, repeatable or
+    /// comma separated
+    #[arg(long = "namespace", env = "FINK_NAMESPACE", value_delimiter = ',')]
+    namespaces: Vec<String>,
+    /// Only manage VirtualMachines and snapshots matching this label selector, overrides
+    /// controller.watchLabelSelector of the config
+    #[arg(long)]
+    watch_label_selector: Option<String>,
+    /// Apply the CRDs at startup, overrides controller.installCrds of the config
+    #[arg(long)]
+    install_crds: bool,
+    /// Maximum level of the logs, RUST_LOG takes precedence when set
+    #[arg(long, env = "FINK_LOG_LEVEL", default_value = "info")]
+    log_level: tracing::Level,
+    /// Format of the logs
+    #[arg(long, env = "FINK_LOG_FORMAT", value_enum, default_value_t)]
+    log_format: telemetry::LogFormat,
+}
+
 #[tokio::main]
 async fn main() {
-    use tracing_subscriber::FmtSubscriber;
+    let args = Args::parse();
 
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(tracing::Level::INFO)
-        .pretty()
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    let mut config = config::Config::load(args.config.as_deref()).expect("failed to load config");
+    let log_filter = telemetry::init(args.log_level, args.log_format, &config.telemetry);
+    let _error_reporting = telemetry::init_error_reporting(&config.telemetry);
+    if let Some(bind) = args.bind {
+        config.http.bind = bind;
+    }
+    if let Some(selector) = args.watch_label_selector {
+        config.controller.watch_label_selector = Some(selector);
+    }
+    if args.install_crds {
+        config.controller.install_crds = true;
+    }
 
-    let state = state::AppState::default();
+    let client = match &args.kubeconfig {
+        Some(path) => {
+            let kubeconfig = Kubeconfig::read_from(path).expect("failed to read kubeconfig");
+            let config =
+                kube::Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default())
+                    .await
+                    .expect("failed to load kubeconfig");
+            kube::Client::try_from(config)
+        }
+        None => kube::Client::try_default().await,
+    }
+    .expect("failed to create kube Client");
+    let state = state::AppState::new(client, args.namespaces, config, log_filter);
 
-    let app: Router = Router::new().route("/health", get(health));
+    let mut app: Router<state::AppState> = Router::new()
+        .route("/health", get(health))
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .merge(api::router(state.clone()))
+        .merge(api::docs());
+    if state.config.http.debug_endpoints {
+        app = app.merge(debug::router());
+    }
+    let app: Router = app.with_state(state.clone());
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    let listener = tokio::net::TcpListener::bind(&state.config.http.bind)
         .await
         .unwrap();
 
     println!("listening on {}", listener.local_addr().unwrap());
 
     let server = axum::serve(listener, app).into_future();
+    let activator_run = activator::run(state.clone());
+    let webhook_run = webhook::run(state.clone());
     let controller_run = controller::run(state);
     tokio::select! {
         _ = server => println!("Axum server stopped"),
+        result = activator_run => match result {
+            Ok(()) => println!("Activator stopped"),
+            Err(e) => eprintln!("Activator stopped: {e:#}"),
+        },
+        result = webhook_run => match result {
+            Ok(()) => println!("Webhook stopped"),
+            Err(e) => eprintln!("Webhook stopped: {e:#}"),
+        },
         _ = controller_run => println!("Controller stopped"),
     }
+    telemetry::shutdown();
 }
 
 async fn health() -> Json<Value> {
     Json(json!({ "healthy": true}))
 }
+
+// The process is alive as long as it serves requests
+async fn livez() -> Json<Value> {
+    Json(json!({ "alive": true }))
+}
+
+// Ready once the CRD is queryable, the caches are synced and, with leader election, the Lease is
+// held
+async fn readyz(State(state): State<state::AppState>) -> impl IntoResponse {
+    let pending = state.readiness.pending();
+    let code = if pending.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        code,
+        Json(json!({
+            "ready": pending.is_empty(),
+            "pending": pending,
+            "leader": state.readiness.is_leader(),
+        })),
+    )
+}
+
+async fn metrics(State(state): State<state::AppState>) -> impl IntoResponse {
+    // Only the leader reports the VM counts, standbys would count the same VMs again
+    if let Some(vms) = state.vms().filter(|_| state.readiness.is_leader()) {
+        state
+            .metrics
+            .set_vm_counts(vms.state().iter().map(|vm| vm.as_ref()));
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder
+        .encode(&state.registry.gather(), &mut buffer)
+        .unwrap();
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    )
+}
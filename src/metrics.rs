@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    controller::virtualmachine::{VirtualMachine, VirtualMachineCurrentState},
+    errors::Error,
+};
+use kube::{Resource, ResourceExt};
+use prometheus::{histogram_opts, opts, HistogramVec, IntCounterVec, IntGaugeVec, Registry};
+use serde_json::Value;
+use tokio::time::Instant;
+
+// Firecracker metrics exported per VM, by their key in a section of a flush and their label value
+type FirecrackerKeys = [(&'static str, &'static str)];
+
+static VCPU_EXITS: [(&str, &str); 4] = [
+    ("exit_io_in", "io_in"),
+    ("exit_io_out", "io_out"),
+    ("exit_mmio_read", "mmio_read"),
+    ("exit_mmio_write", "mmio_write"),
+];
+static BLOCK_BYTES: [(&str, &str); 2] = [("read_bytes", "read"), ("write_bytes", "write")];
+static NET_BYTES: [(&str, &str); 2] = [("rx_bytes_count", "rx"), ("tx_bytes_count", "tx")];
+static BALLOON_EVENTS: [(&str, &str); 2] =
+    [("inflate_count", "inflate"), ("deflate_count", "deflate")];
+
+#[derive(Clone)]
+pub struct Metrics {
+    /// Reconciles by kind
+    pub reconciliations: IntCounterVec,
+    /// Failed reconciles by kind and error
+    pub failures: IntCounterVec,
+    /// Duration of reconciles by kind
+    pub reconcile_duration: HistogramVec,
+    /// VirtualMachines by current state, refreshed from the controller cache on every scrape
+    pub vms: IntGaugeVec,
+    /// Seconds VirtualMachines spent running, by namespace and VM
+    pub vm_running_seconds: IntCounterVec,
+    /// vCPU exits of VMs collecting Firecracker metrics, by namespace, VM and reason
+    pub vm_vcpu_exits: IntCounterVec,
+    /// Bytes read and written by the block devices of VMs, by namespace, VM and direction
+    pub vm_block_bytes: IntCounterVec,
+    /// Bytes received and sent by the network interfaces of VMs, by namespace, VM and direction
+    pub vm_net_bytes: IntCounterVec,
+    /// Balloon inflates and deflates of VMs, by namespace, VM and event
+    pub vm_balloon_events: IntCounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let reconcile_duration = HistogramVec::new(
+            histogram_opts!(
+                "fink_reconcile_duration_seconds",
+                "The duration of reconciles in seconds"
+            )
+            .buckets(vec![0.01, 0.1, 0.25, 0.5, 1., 5., 15., 60.]),
+            &["kind"],
+        )
+        .unwrap();
+        let failures = IntCounterVec::new(
+            opts!("fink_reconciliation_errors_total", "Reconcile errors"),
+            &["kind", "error"],
+        )
+        .unwrap();
+        let reconciliations =
+            IntCounterVec::new(opts!("fink_reconciliations_total", "Reconciles"), &["kind"])
+                .unwrap();
+        let vms = IntGaugeVec::new(
+            opts!("fink_vms", "VirtualMachines by current state"),
+            &["state"],
+        )
+        .unwrap();
+        let vm_running_seconds = IntCounterVec::new(
+            opts!(
+                "fink_vm_running_seconds_total",
+                "Seconds VirtualMachines spent running"
+            ),
+            &["namespace", "vm"],
+        )
+        .unwrap();
+        let vm_vcpu_exits = IntCounterVec::new(
+            opts!("fink_vm_vcpu_exits_total", "vCPU exits of VirtualMachines"),
+            &["namespace", "vm", "reason"],
+        )
+        .unwrap();
+        let vm_block_bytes = IntCounterVec::new(
+            opts!(
+                "fink_vm_block_bytes_total",
+                "Bytes read and written by the block devices of VirtualMachines"
+            ),
+            &["namespace", "vm", "direction"],
+        )
+        .unwrap();
+        let vm_net_bytes = IntCounterVec::new(
+            opts!(
+                "fink_vm_net_bytes_total",
+                "Bytes received and sent by the network interfaces of VirtualMachines"
+            ),
+            &["namespace", "vm", "direction"],
+        )
+        .unwrap();
+        let vm_balloon_events = IntCounterVec::new(
+            opts!(
+                "fink_vm_balloon_events_total",
+                "Balloon inflates and deflates of VirtualMachines"
+            ),
+            &["namespace", "vm", "event"],
+        )
+        .unwrap();
+        Metrics {
+            reconciliations,
+            failures,
+            reconcile_duration,
+            vms,
+            vm_running_seconds,
+            vm_vcpu_exits,
+            vm_block_bytes,
+            vm_net_bytes,
+            vm_balloon_events,
+        }
+    }
+}
+
+impl Metrics {
+    // Register the metrics with the registry served by the HTTP layer
+    pub fn register(self, registry: &Registry) -> Result<Self, prometheus::Error> {
+        registry.register(Box::new(self.reconcile_duration.clone()))?;
+        registry.register(Box::new(self.failures.clone()))?;
+        registry.register(Box::new(self.reconciliations.clone()))?;
+        registry.register(Box::new(self.vms.clone()))?;
+        registry.register(Box::new(self.vm_running_seconds.clone()))?;
+        registry.register(Box::new(self.vm_vcpu_exits.clone()))?;
+        registry.register(Box::new(self.vm_block_bytes.clone()))?;
+        registry.register(Box::new(self.vm_net_bytes.clone()))?;
+        registry.register(Box::new(self.vm_balloon_events.clone()))?;
+        Ok(self)
+    }
+
+    // Add the metered running time of a VM
+    pub fn vm_running(&self, vm: &VirtualMachine, seconds: u64) {
+        let namespace = vm.namespace().unwrap_or_default();
+        self.vm_running_seconds
+            .with_label_values(&[namespace.as_str(), vm.name_any().as_str()])
+            .inc_by(seconds);
+    }
+
+    // Add a flush of the Firecracker metrics of a VM, its counters only cover the time since the
+    // previous flush
+    pub fn firecracker_flush(&self, vm: &VirtualMachine, flush: &Value) {
+        let namespace = vm.namespace().unwrap_or_default();
+        let name = vm.name_any();
+        for (counter, section, keys) in self.firecracker_counters() {
+            for (key, label) in keys {
+                if let Some(value) = flush[section][key].as_u64() {
+                    counter
+                        .with_label_values(&[namespace.as_str(), name.as_str(), label])
+                        .inc_by(value);
+                }
+            }
+        }
+    }
+
+    fn firecracker_counters(&self) -> [(&IntCounterVec, &str, &FirecrackerKeys); 4] {
+        [
+            (&self.vm_vcpu_exits, "vcpu", &VCPU_EXITS),
+            (&self.vm_block_bytes, "block", &BLOCK_BYTES),
+            (&self.vm_net_bytes, "net", &NET_BYTES),
+            (&self.vm_balloon_events, "balloon", &BALLOON_EVENTS),
+        ]
+    }
+
+    // Drop the series of a deleted VM
+    pub fn forget_vm(&self, vm: &VirtualMachine) {
+        let namespace = vm.namespace().unwrap_or_default();
+        let name = vm.name_any();
+        let _ = self
+            .vm_running_seconds
+            .remove_label_values(&[namespace.as_str(), name.as_str()]);
+        for (counter, _, keys) in self.firecracker_counters() {
+            for (_, label) in keys {
+                let _ = counter.remove_label_values(&[namespace.as_str(), name.as_str(), label]);
+            }
+        }
+    }
+
+    // Count the VMs by current state, VMs without a status yet count as STOPPED. Every state is
+    // exported, so a state no VM is in reads 0 instead of disappearing.
+    pub fn set_vm_counts<'a>(&self, vms: impl IntoIterator<Item = &'a VirtualMachine>) {
+        use VirtualMachineCurrentState::*;
+
+        let mut counts: BTreeMap<String, i64> = [
+            STOPPED,
+            STOPPING,
+            STARTED,
+            STARTING,
+            HIBERNATING,
+            HIBERNATED,
+            FAILED,
+        ]
+        .iter()
+        .map(|state| (format!("{state:?}"), 0))
+        .collect();
+        for vm in vms {
+            let state = vm.status.as_ref().map_or(&STOPPED, |s| &s.state);
+            *counts.entry(format!("{state:?}")).or_default() += 1;
+        }
+        for (state, count) in counts {
+            self.vms.with_label_values(&[&state]).set(count);
+        }
+    }
+
+    // Count a failed reconcile of a `K`, labelled by the error variant. Objects are not labelled, as
+    // their series would outlive them.
+    pub fn reconcile_failure<K>(&self, e: &Error)
+    where
+        K: Resource<DynamicType = ()>,
+    {
+        self.failures
+            .with_label_values(&[&K::kind(&()), e.metric_label()])
+            .inc()
+    }
+
+    // Count a reconcile of a `K` and measure its duration until the returned guard is dropped
+    pub fn count_and_measure<K>(&self) -> ReconcileMeasurer
+    where
+        K: Resource<DynamicType = ()>,
+    {
+        let kind = K::kind(&());
+        self.reconciliations.with_label_values(&[&kind]).inc();
+        ReconcileMeasurer {
+            start: Instant::now(),
+            metric: self.reconcile_duration.with_label_values(&[&kind]),
+        }
+    }
+}
+
+pub struct ReconcileMeasurer {
+    start: Instant,
+    metric: prometheus::Histogram,
+}
+
+impl Drop for ReconcileMeasurer {
+    fn drop(&mut self) {
+        let duration = self.start.elapsed().as_secs_f64();
+        self.metric.observe(duration);
+    }
+}
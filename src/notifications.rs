@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::*;
+
+use crate::config::{NotificationWebhook, NotificationsConfig};
+
+/// What happened to the VM a notification is about
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotificationEvent {
+    /// The VM entered the FAILED state
+    Failed,
+    /// The VM pod keeps exiting shortly after being restarted
+    CrashLoop,
+}
+
+impl NotificationEvent {
+    pub fn all() -> Vec<Self> {
+        vec![Self::Failed, Self::CrashLoop]
+    }
+}
+
+/// Payload POSTed to the notification webhooks
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    pub event: NotificationEvent,
+    pub namespace: String,
+    pub name: String,
+    pub message: Option<String>,
+    pub time: DateTime<Utc>,
+}
+
+// Calls the webhooks of the config subscribed to an event
+#[derive(Clone)]
+pub struct Notifier {
+    client: reqwest::Client,
+    webhooks: Vec<NotificationWebhook>,
+}
+
+impl Notifier {
+    // None without webhooks
+    pub fn new(config: &NotificationsConfig) -> Option<Self> {
+        if config.webhooks.is_empty() {
+            return None;
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()
+            .expect("failed to create the notifications client");
+        Some(Self {
+            client,
+            webhooks: config.webhooks.clone(),
+        })
+    }
+
+    // POST the notification to the subscribed webhooks in the background. With a secret the body
+    // is signed with HMAC-SHA256 in the `X-Fink-Signature: sha256=<hex>` header, like GitHub does.
+    pub fn notify(&self, notification: Notification) {
+        let body = serde_json::to_vec(&notification).unwrap();
+        for webhook in &self.webhooks {
+            if !webhook.events.contains(&notification.event) {
+                continue;
+            }
+            let mut request = self
+                .client
+                .post(&webhook.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(secret) = &webhook.secret {
+                request = request.header("X-Fink-Signature", signature(secret, &body));
+            }
+            let url = webhook.url.clone();
+            let event = notification.event;
+            tokio::spawn(async move {
+                match request.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => debug!("notified {url} of {event:?}"),
+                    Err(e) => warn!("failed to notify {url} of {event:?}: {e}"),
+                }
+            });
+        }
+    }
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
@@ -1,15 +1,155 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
 
-use kube::Client;
+use kube::{runtime::reflector::Store, Client};
+use prometheus::Registry;
 
-use crate::controller::Context;
+use crate::{
+    cloudevents::CloudEvents,
+    config::Config,
+    controller::{virtualmachine::VirtualMachine, Context},
+    metrics::Metrics,
+    notifications::Notifier,
+    telemetry::LogFilterHandle,
+};
 
+#[derive(Clone)]
+pub struct AppState {
+    /// Kubernetes client shared by the controller and the HTTP layer
+    pub client: Client,
+    /// Namespaces the controller is restricted to, all namespaces when empty
+    pub namespaces: Vec<String>,
+    /// Controller settings
+    pub config: Config,
+    /// Registry of the controller metrics
+    pub registry: Registry,
+    /// Metrics recorded by the reconciler
+    pub metrics: Metrics,
+    /// Checks gating /readyz
+    pub readiness: Readiness,
+    /// Cache of the VMs, kept by the controller on the leader and by a reflector on standbys
+    pub vm_store: Arc<RwLock<Option<Store<VirtualMachine>>>>,
+    /// Filter of the logs, changed through /debug/log-level
+    pub log_filter: LogFilterHandle,
+    /// Sink of the VM state transitions, when configured
+    pub cloud_events: Option<CloudEvents>,
+    /// Webhooks notified of failing VMs, when configured
+    pub notifier: Option<Notifier>,
+}
+
+// Readiness of the controller, shared between the controller and the HTTP layer. With leader
+// election, standbys waiting for the Lease keep a VM cache but are only ready once they lead.
 #[derive(Clone, Default)]
-pub struct AppState {}
+pub struct Readiness {
+    /// Whether the replicas elect a leader, which only it is ready as
+    leader_election: bool,
+    crd_queryable: Arc<AtomicBool>,
+    /// Set while the controllers, or the VM cache of a standby, wait for their caches
+    syncing_caches: Arc<AtomicBool>,
+    /// Set while this replica runs the controllers
+    leader: Arc<AtomicBool>,
+}
+
+impl Readiness {
+    pub fn new(leader_election: bool) -> Self {
+        Readiness {
+            leader_election,
+            ..Readiness::default()
+        }
+    }
+
+    pub fn set_crd_queryable(&self) {
+        self.crd_queryable.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_syncing_caches(&self, syncing: bool) {
+        self.syncing_caches.store(syncing, Ordering::Relaxed);
+    }
+
+    pub fn set_leader(&self, leader: bool) {
+        self.leader.store(leader, Ordering::Relaxed);
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    // Names of the checks that have not passed yet
+    pub fn pending(&self) -> Vec<&'static str> {
+        let mut pending = vec![];
+        if !self.crd_queryable.load(Ordering::Relaxed) {
+            pending.push("crd");
+        }
+        if self.syncing_caches.load(Ordering::Relaxed) {
+            pending.push("caches");
+        }
+        if self.leader_election && !self.is_leader() {
+            pending.push("leader");
+        }
+        pending
+    }
+}
 
 impl AppState {
+    pub fn new(
+        client: Client,
+        namespaces: Vec<String>,
+        config: Config,
+        log_filter: LogFilterHandle,
+    ) -> Self {
+        let registry = Registry::default();
+        let metrics = Metrics::default().register(&registry).unwrap();
+        let cloud_events = CloudEvents::new(&config.cloud_events);
+        let notifier = Notifier::new(&config.notifications);
+        let readiness = Readiness::new(config.leader_election.enabled);
+        Self {
+            client,
+            namespaces,
+            config,
+            registry,
+            metrics,
+            readiness,
+            vm_store: Arc::default(),
+            log_filter,
+            cloud_events,
+            notifier,
+        }
+    }
+
+    // Cache of the VMs, None until this replica runs the controller or the standby cache
+    pub fn vms(&self) -> Option<Store<VirtualMachine>> {
+        self.vm_store.read().unwrap().clone()
+    }
+
     // Create a Controller Context that can update State
-    pub fn to_context(&self, client: Client) -> Arc<Context> {
-        Arc::new(Context { client })
+    pub fn to_context(&self) -> Arc<Context> {
+        Arc::new(Context {
+            client: self.client.clone(),
+            config: self.config.clone(),
+            reporter: "fink-controller".into(),
+            metrics: self.metrics.clone(),
+            cloud_events: self.cloud_events.clone(),
+            notifier: self.notifier.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standbys_are_not_ready() {
+        let readiness = Readiness::new(true);
+        readiness.set_crd_queryable();
+        assert_eq!(readiness.pending(), vec!["leader"]);
+        readiness.set_leader(true);
+        assert!(readiness.pending().is_empty());
+
+        let readiness = Readiness::new(false);
+        readiness.set_crd_queryable();
+        assert!(readiness.pending().is_empty());
     }
 }
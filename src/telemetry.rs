@@ -0,0 +1,134 @@
+use kube::{Resource, ResourceExt};
+use opentelemetry::{trace::TraceContextExt, trace::TraceId, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    runtime,
+    trace::{self, Sampler},
+    Resource as OtelResource,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{prelude::*, reload, EnvFilter, Registry};
+
+use crate::{config::TelemetryConfig, errors::Error};
+
+/// Handle swapping the log filter of the running process
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Output format of the logs
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum LogFormat {
+    /// Multi-line human readable logs
+    #[default]
+    Pretty,
+    /// One JSON object per line with the fields of the enclosing spans, for log aggregators
+    Json,
+}
+
+// Trace id of the current span, logged with the reconciles to find their traces
+pub fn get_trace_id() -> TraceId {
+    tracing::Span::current()
+        .context()
+        .span()
+        .span_context()
+        .trace_id()
+}
+
+// Record the trace id on the current span, which has to declare an empty `trace_id` field
+pub fn record_trace_id() {
+    let trace_id = get_trace_id();
+    if trace_id != TraceId::INVALID {
+        tracing::Span::current().record("trace_id", tracing::field::display(&trace_id));
+    }
+}
+
+// Install the global subscriber logging in `format`, and exporting the spans over OTLP when an
+// endpoint is configured. The filter comes from RUST_LOG, logging up to `level` without it, and can
+// be swapped through the returned handle.
+pub fn init(level: tracing::Level, format: LogFormat, config: &TelemetryConfig) -> LogFilterHandle {
+    let filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let otel = config.otlp_endpoint.as_ref().map(|endpoint| {
+        let exporter = opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(endpoint);
+        let trace_config = trace::config()
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                config.sample_ratio,
+            ))))
+            .with_resource(OtelResource::new([KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )]));
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(exporter)
+            .with_trace_config(trace_config)
+            .install_batch(runtime::Tokio)
+            .expect("failed to install the OTLP tracer");
+        tracing_opentelemetry::layer().with_tracer(tracer)
+    });
+
+    let fmt = match format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().pretty().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+
+    let subscriber = Registry::default().with(filter).with(fmt).with(otel);
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    handle
+}
+
+// Replace the log filter with `directives`, in the RUST_LOG syntax
+pub fn set_log_filter(handle: &LogFilterHandle, directives: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::builder().parse(directives)?;
+    handle.reload(filter)?;
+    Ok(())
+}
+
+// Current log filter, in the RUST_LOG syntax
+pub fn log_filter(handle: &LogFilterHandle) -> anyhow::Result<String> {
+    Ok(handle.with_current(|filter| filter.to_string())?)
+}
+
+// Report the panics, and the reconcile failures passed to report_reconcile_error, to Sentry when a
+// DSN is configured. Events are sent until the returned guard is dropped.
+pub fn init_error_reporting(config: &TelemetryConfig) -> Option<sentry::ClientInitGuard> {
+    let dsn = config.sentry_dsn.as_deref()?;
+    let mut options = sentry::ClientOptions::default();
+    options.release = sentry::release_name!();
+    options.environment = config.sentry_environment.clone().map(Into::into);
+    Some(sentry::init((dsn, options)))
+}
+
+// Send a reconcile failure to the error sink, tagged with the object it happened on. A no-op
+// without a Sentry DSN.
+pub fn report_reconcile_error<K>(object: &K, error: &Error)
+where
+    K: Resource<DynamicType = ()>,
+{
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("kind", K::kind(&()));
+            scope.set_tag("name", object.name_any());
+            if let Some(namespace) = object.namespace() {
+                scope.set_tag("namespace", namespace);
+            }
+            scope.set_tag("error", error.metric_label());
+            scope.set_tag("retryable", error.is_retryable());
+        },
+        || sentry::capture_error(error),
+    );
+}
+
+// Flush the spans not exported yet
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,236 @@
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+
+use anyhow::Context as _;
+use axum::{extract::State, routing::post, Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{
+    api::{Api, DynamicObject},
+    core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview},
+};
+use serde_json::Value;
+use tracing::*;
+
+use crate::{
+    controller::{virtualmachine::VirtualMachine, virtualmachineclass::VirtualMachineClass},
+    errors::Error,
+    state::AppState,
+    utils::Result,
+};
+
+/// Namespace annotation naming the VirtualMachineClass of VMs without `spec.className`
+static DEFAULT_CLASS_ANNOTATION: &str = "vms.codesandbox.io/default-class";
+
+/// Namespace annotation holding a JSON object of labels added to the VMs of the namespace
+static DEFAULT_LABELS_ANNOTATION: &str = "vms.codesandbox.io/default-labels";
+
+/// Namespace annotation holding a JSON object used as node selector of VMs without one
+static DEFAULT_NODE_SELECTOR_ANNOTATION: &str = "vms.codesandbox.io/default-node-selector";
+
+// Interval the certificate files are checked for a rotation at
+const CERT_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+// Serve the admission webhooks over TLS when enabled, never returns otherwise
+pub async fn run(state: AppState) -> anyhow::Result<()> {
+    let config = &state.config.webhook;
+    if !config.enabled {
+        return std::future::pending().await;
+    }
+
+    let tls = RustlsConfig::from_pem_file(&config.cert_path, &config.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to load the webhook certificate {}",
+                config.cert_path
+            )
+        })?;
+    let addr: SocketAddr = config
+        .bind
+        .parse()
+        .context("invalid webhook bind address")?;
+    let app = Router::new()
+        .route("/mutate/virtualmachines", post(mutate_vm))
+        .with_state(state.clone());
+
+    info!("webhook listening on {}", addr);
+    let server = axum_server::bind_rustls(addr, tls.clone()).serve(app.into_make_service());
+    tokio::select! {
+        result = server => result.context("webhook server failed"),
+        _ = reload_certificate(tls, &config.cert_path, &config.key_path) => Ok(()),
+    }
+}
+
+// Reload the certificate whenever its files change, e.g. when cert-manager rotates it. The files
+// are polled as Secret volumes are updated by swapping a symlink, which file watches miss.
+async fn reload_certificate(tls: RustlsConfig, cert_path: &str, key_path: &str) {
+    let mut loaded = read_pem_files(cert_path, key_path).await;
+    let mut interval = tokio::time::interval(CERT_RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        let current = read_pem_files(cert_path, key_path).await;
+        if current == loaded {
+            continue;
+        }
+        // A half-updated pair fails to load and is retried on the next tick
+        match tls.reload_from_pem_file(cert_path, key_path).await {
+            Ok(()) => {
+                info!("reloaded the webhook certificate");
+                loaded = current;
+            }
+            Err(e) => warn!("failed to reload the webhook certificate: {e}"),
+        }
+    }
+}
+
+async fn read_pem_files(cert_path: &str, key_path: &str) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+    (
+        tokio::fs::read(cert_path).await.ok(),
+        tokio::fs::read(key_path).await.ok(),
+    )
+}
+
+// Fill in the defaults of a created or updated VirtualMachine, so the stored object is fully
+// specified
+async fn mutate_vm(
+    State(state): State<AppState>,
+    Json(review): Json<AdmissionReview<DynamicObject>>,
+) -> Json<AdmissionReview<DynamicObject>> {
+    let request: AdmissionRequest<DynamicObject> = match review.try_into() {
+        Ok(request) => request,
+        Err(e) => return Json(AdmissionResponse::invalid(e.to_string()).into_review()),
+    };
+    let response = AdmissionResponse::from(&request);
+    // VMs being deleted are left alone, so a missing class never blocks their finalizer
+    let Some(object) = request
+        .object
+        .as_ref()
+        .filter(|o| o.metadata.deletion_timestamp.is_none())
+    else {
+        return Json(response.into_review());
+    };
+
+    let old_class_name = request
+        .old_object
+        .as_ref()
+        .and_then(|o| o.data.get("spec")?.get("className")?.as_str());
+    let response = match defaulting_patch(
+        &state,
+        request.namespace.as_deref(),
+        object,
+        old_class_name,
+    )
+    .await
+    {
+        Ok(patch) => match response.with_patch(patch) {
+            Ok(response) => response,
+            Err(e) => AdmissionResponse::from(&request).deny(e.to_string()),
+        },
+        Err(e) => {
+            warn!("denied VirtualMachine {:?}: {}", object.metadata.name, e);
+            response.deny(e.to_string())
+        }
+    };
+    Json(response.into_review())
+}
+
+// Patch from the submitted VM to the one with the serde defaults, the namespace policy and the
+// sizing of its class applied. The class only has to exist for new VMs and class changes, so
+// updates of a VM whose class was deleted, like state changes, still go through.
+async fn defaulting_patch(
+    state: &AppState,
+    namespace: Option<&str>,
+    object: &DynamicObject,
+    old_class_name: Option<&str>,
+) -> Result<json_patch::Patch> {
+    let original = serde_json::to_value(object).map_err(Error::SerializationError)?;
+    let mut vm: VirtualMachine = serde_json::from_value(original.clone())
+        .map_err(|e| Error::InvalidSpec(format!("invalid VirtualMachine: {e}")))?;
+
+    let policy = match namespace {
+        Some(ns) => Api::<Namespace>::all(state.client.clone())
+            .get_opt(ns)
+            .await
+            .map_err(Error::KubeError)?
+            .and_then(|ns| ns.metadata.annotations)
+            .unwrap_or_default(),
+        None => BTreeMap::new(),
+    };
+
+    if vm.spec.class_name.is_none() {
+        vm.spec.class_name = policy.get(DEFAULT_CLASS_ANNOTATION).cloned();
+    }
+    if let Some(class_name) = &vm.spec.class_name {
+        let classes: Api<VirtualMachineClass> = Api::all(state.client.clone());
+        match classes
+            .get_opt(class_name)
+            .await
+            .map_err(Error::KubeError)?
+        {
+            Some(class) => class.apply(&mut vm),
+            None if old_class_name == Some(class_name.as_str()) => {}
+            None => {
+                return Err(Error::InvalidSpec(format!(
+                    "VirtualMachineClass {class_name} not found"
+                )))
+            }
+        }
+    } else if vm.spec.overcommit.is_some() {
+        return Err(Error::InvalidSpec(
+            "spec.overcommit is only set through spec.className".to_string(),
+        ));
+    }
+
+    if let Some(labels) = policy_map(&policy, DEFAULT_LABELS_ANNOTATION)? {
+        let vm_labels = vm.metadata.labels.get_or_insert_with(BTreeMap::new);
+        for (key, value) in labels {
+            vm_labels.entry(key).or_insert(value);
+        }
+    }
+    if let Some(node_selector) = policy_map(&policy, DEFAULT_NODE_SELECTOR_ANNOTATION)? {
+        let scheduling = vm.spec.scheduling.get_or_insert_with(Default::default);
+        if scheduling.node_selector.is_none() {
+            scheduling.node_selector = Some(node_selector);
+        }
+    }
+
+    let mut spec = serde_json::to_value(&vm.spec).map_err(Error::SerializationError)?;
+    strip_nulls(&mut spec);
+    let mut mutated = original.clone();
+    mutated["spec"] = spec;
+    if let Some(labels) = &vm.metadata.labels {
+        mutated["metadata"]["labels"] =
+            serde_json::to_value(labels).map_err(Error::SerializationError)?;
+    }
+    Ok(json_patch::diff(&original, &mutated))
+}
+
+// String map in the JSON annotation `key` of the namespace
+fn policy_map(
+    annotations: &BTreeMap<String, String>,
+    key: &str,
+) -> Result<Option<BTreeMap<String, String>>> {
+    annotations
+        .get(key)
+        .map(|raw| {
+            serde_json::from_str(raw).map_err(|e| {
+                Error::InvalidSpec(format!("namespace annotation {key} is invalid: {e}"))
+            })
+        })
+        .transpose()
+}
+
+// Unset optional fields are left out instead of being stored as null. Inline metadata documents
+// belong to the guest and are kept as they are.
+fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, v| !v.is_null());
+            map.iter_mut()
+                .filter(|(key, _)| key.as_str() != "inline")
+                .for_each(|(_, v)| strip_nulls(v));
+        }
+        Value::Array(items) => items.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}